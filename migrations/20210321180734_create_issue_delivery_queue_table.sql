@@ -0,0 +1,9 @@
+CREATE TABLE issue_delivery_queue (
+   newsletter_issue_id UUID NOT NULL
+      REFERENCES newsletter_issues (newsletter_issue_id),
+   subscriber_email TEXT NOT NULL,
+   PRIMARY KEY (newsletter_issue_id, subscriber_email),
+
+   n_retries SMALLINT NOT NULL DEFAULT 0,
+   execute_after timestamptz NOT NULL DEFAULT now()
+);
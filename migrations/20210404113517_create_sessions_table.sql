@@ -0,0 +1,9 @@
+CREATE TABLE sessions (
+   session_token TEXT NOT NULL,
+   PRIMARY KEY (session_token),
+
+   user_id UUID NOT NULL
+      REFERENCES users (user_id),
+   created_at timestamptz NOT NULL,
+   expires_at timestamptz NOT NULL
+);
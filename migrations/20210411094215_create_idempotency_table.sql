@@ -0,0 +1,12 @@
+CREATE TABLE idempotency (
+   user_id UUID NOT NULL
+      REFERENCES users (user_id),
+   idempotency_key TEXT NOT NULL,
+   PRIMARY KEY (user_id, idempotency_key),
+
+   -- The response columns stay NULL while the first request is still being processed
+   response_status_code SMALLINT NULL,
+   response_headers JSONB NULL,
+   response_body BYTEA NULL,
+   created_at timestamptz NOT NULL
+);
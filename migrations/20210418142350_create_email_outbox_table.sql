@@ -0,0 +1,14 @@
+-- Emails written in the same transaction as the change that triggers them,
+-- and relayed to the email provider by the background worker
+CREATE TABLE email_outbox (
+   email_id UUID NOT NULL,
+   PRIMARY KEY (email_id),
+
+   recipient TEXT NOT NULL,
+   subject TEXT NOT NULL,
+   html_body TEXT NOT NULL,
+   text_body TEXT NOT NULL,
+   created_at timestamptz NOT NULL,
+   n_retries SMALLINT NOT NULL DEFAULT 0,
+   execute_after timestamptz NOT NULL DEFAULT now()
+);
@@ -0,0 +1,8 @@
+-- Existing tokens are treated as issued now, so they get a full TTL before expiring
+BEGIN;
+    ALTER TABLE subscription_tokens
+        ADD COLUMN created_at timestamptz NOT NULL DEFAULT now(),
+        ADD COLUMN consumed_at timestamptz NULL;
+
+    ALTER TABLE subscription_tokens ALTER COLUMN created_at DROP DEFAULT;
+COMMIT;
@@ -0,0 +1,11 @@
+-- Links already sent keep working: they carry the raw token, which is hashed the same way on lookup
+BEGIN;
+    ALTER TABLE subscription_tokens ADD COLUMN subscription_token_hash BYTEA NULL;
+    UPDATE subscription_tokens
+        SET subscription_token_hash = sha256(convert_to(subscription_token, 'UTF8'));
+    ALTER TABLE subscription_tokens ALTER COLUMN subscription_token_hash SET NOT NULL;
+
+    -- Also drops the primary key on the raw token
+    ALTER TABLE subscription_tokens DROP COLUMN subscription_token;
+    ALTER TABLE subscription_tokens ADD PRIMARY KEY (subscription_token_hash);
+COMMIT;
@@ -0,0 +1,10 @@
+-- Evidence of when each subscriber gave their consent, kept separately from their current status
+CREATE TABLE consent_events (
+   event_id UUID NOT NULL,
+   PRIMARY KEY (event_id),
+
+   subscriber_id UUID NOT NULL
+      REFERENCES subscriptions (id),
+   event_type TEXT NOT NULL,
+   occurred_at timestamptz NOT NULL
+);
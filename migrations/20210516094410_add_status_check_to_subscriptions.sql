@@ -0,0 +1,11 @@
+-- Must list the same values as `domain::SubscriptionStatus`
+ALTER TABLE subscriptions ADD CONSTRAINT subscriptions_status_check CHECK (
+    status IN (
+        'pending_confirmation',
+        'confirmed',
+        'unsubscribed',
+        'bounced',
+        'complained',
+        'suppressed'
+    )
+);
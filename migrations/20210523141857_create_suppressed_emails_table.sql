@@ -0,0 +1,8 @@
+-- Addresses we must never email again, whether or not they belong to a subscriber
+CREATE TABLE suppressed_emails (
+   email TEXT NOT NULL,
+   PRIMARY KEY (email),
+
+   reason TEXT NOT NULL CHECK (reason IN ('hard_bounce', 'spam_complaint')),
+   suppressed_at timestamptz NOT NULL
+);
@@ -0,0 +1,9 @@
+-- How many confirmation emails each address was sent recently, so that signups
+-- can't be used to flood someone's inbox
+CREATE TABLE confirmation_email_throttle (
+   recipient TEXT NOT NULL,
+   PRIMARY KEY (recipient),
+   window_started_at timestamptz NOT NULL,
+   sent_in_window INTEGER NOT NULL,
+   last_sent_at timestamptz NOT NULL
+);
@@ -0,0 +1,14 @@
+-- Record how each consent was given, not just when
+ALTER TABLE consent_events
+   ADD COLUMN ip_address TEXT,
+   ADD COLUMN user_agent TEXT,
+   ADD COLUMN form_source TEXT,
+   -- The confirmation email the subscriber was sent, with its link redacted
+   ADD COLUMN email_subject TEXT,
+   ADD COLUMN email_html_body TEXT,
+   ADD COLUMN email_text_body TEXT;
+
+-- Must list the same values as `subscriber_repository::ConsentEventType`
+ALTER TABLE consent_events
+   ADD CONSTRAINT consent_events_event_type_check
+   CHECK (event_type IN ('signup', 'confirmation_email_resent', 'confirmation'));
@@ -0,0 +1,7 @@
+-- Addresses whose data was erased on request, kept only as a hash so that they are
+-- not signed up again by accident
+CREATE TABLE erased_emails (
+   email_hash BYTEA NOT NULL,
+   PRIMARY KEY (email_hash),
+   erased_at timestamptz NOT NULL
+);
@@ -0,0 +1,16 @@
+-- The newsletters a deployment runs. Subscribers who signed up before there were several
+-- are moved to the default one, which `POST /subscriptions` still uses when no list is given.
+BEGIN;
+    CREATE TABLE lists (
+       list_id UUID NOT NULL,
+       PRIMARY KEY (list_id),
+       slug TEXT NOT NULL UNIQUE,
+       name TEXT NOT NULL,
+       created_at timestamptz NOT NULL
+    );
+
+    -- Must match `domain::DEFAULT_LIST_SLUG`.
+    -- The id is fixed, as `gen_random_uuid()` needs pgcrypto before Postgres 13.
+    INSERT INTO lists (list_id, slug, name, created_at)
+    VALUES ('5a0b4f8e-2c1d-4e3a-9f6b-7d8c9e0a1b2c', 'newsletter', 'Newsletter', now());
+COMMIT;
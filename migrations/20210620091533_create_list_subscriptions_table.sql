@@ -0,0 +1,30 @@
+-- Who is subscribed to which list. The status moves here from `subscriptions`, which now
+-- only holds the subscriber's contact details.
+BEGIN;
+    CREATE TABLE list_subscriptions (
+       subscriber_id UUID NOT NULL
+          REFERENCES subscriptions (id),
+       list_id UUID NOT NULL
+          REFERENCES lists (list_id),
+       PRIMARY KEY (subscriber_id, list_id),
+       -- Must list the same values as `domain::SubscriptionStatus`
+       status TEXT NOT NULL CHECK (
+          status IN (
+             'pending_confirmation',
+             'confirmed',
+             'unsubscribed',
+             'bounced',
+             'complained',
+             'suppressed'
+          )
+       ),
+       subscribed_at timestamptz NOT NULL
+    );
+
+    INSERT INTO list_subscriptions (subscriber_id, list_id, status, subscribed_at)
+    SELECT s.id, l.list_id, s.status, s.subscribed_at
+    FROM subscriptions s, lists l
+    WHERE l.slug = 'newsletter';
+
+    ALTER TABLE subscriptions DROP COLUMN status;
+COMMIT;
@@ -0,0 +1,15 @@
+-- Confirmation, consent and newsletter issues are all per list now.
+-- Everything that came before belongs to the default list.
+BEGIN;
+    ALTER TABLE subscription_tokens ADD COLUMN list_id UUID NULL REFERENCES lists (list_id);
+    ALTER TABLE consent_events ADD COLUMN list_id UUID NULL REFERENCES lists (list_id);
+    ALTER TABLE newsletter_issues ADD COLUMN list_id UUID NULL REFERENCES lists (list_id);
+
+    UPDATE subscription_tokens SET list_id = (SELECT list_id FROM lists WHERE slug = 'newsletter');
+    UPDATE consent_events SET list_id = (SELECT list_id FROM lists WHERE slug = 'newsletter');
+    UPDATE newsletter_issues SET list_id = (SELECT list_id FROM lists WHERE slug = 'newsletter');
+
+    ALTER TABLE subscription_tokens ALTER COLUMN list_id SET NOT NULL;
+    ALTER TABLE consent_events ALTER COLUMN list_id SET NOT NULL;
+    ALTER TABLE newsletter_issues ALTER COLUMN list_id SET NOT NULL;
+COMMIT;
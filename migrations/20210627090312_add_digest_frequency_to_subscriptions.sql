@@ -0,0 +1,12 @@
+-- Chosen on the preference page. Everyone who subscribed before gets each issue straight away.
+BEGIN;
+    ALTER TABLE subscriptions
+        ADD COLUMN digest_frequency TEXT NOT NULL DEFAULT 'immediately';
+
+    -- Must list the same values as `domain::DigestFrequency`
+    ALTER TABLE subscriptions
+        ADD CONSTRAINT subscriptions_digest_frequency_check
+        CHECK (digest_frequency IN ('immediately', 'daily', 'weekly'));
+
+    ALTER TABLE subscriptions ALTER COLUMN digest_frequency DROP DEFAULT;
+COMMIT;
@@ -0,0 +1,11 @@
+-- Joining a list from the preference page is consent too
+BEGIN;
+    ALTER TABLE consent_events DROP CONSTRAINT consent_events_event_type_check;
+
+    -- Must list the same values as `subscriber_repository::ConsentEventType`
+    ALTER TABLE consent_events
+        ADD CONSTRAINT consent_events_event_type_check
+        CHECK (event_type IN (
+            'signup', 'confirmation_email_resent', 'confirmation', 'preference_center_signup'
+        ));
+COMMIT;
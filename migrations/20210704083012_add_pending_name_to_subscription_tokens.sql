@@ -0,0 +1,3 @@
+-- A name given when signing up an existing address, applied only once the token confirms it,
+-- so that someone who merely knows the address can't rename the subscriber
+ALTER TABLE subscription_tokens ADD COLUMN pending_name TEXT NULL;
@@ -0,0 +1,13 @@
+-- Mail servers ignore the case of an address, so a bounce for `User@Example.com` must stop
+-- sends to `user@example.com` too. Addresses are kept lowercased, keeping the oldest suppression.
+BEGIN;
+    DELETE FROM suppressed_emails a
+    USING suppressed_emails b
+    WHERE lower(a.email) = lower(b.email)
+        AND (a.suppressed_at, a.email) > (b.suppressed_at, b.email);
+
+    UPDATE suppressed_emails SET email = lower(email);
+
+    ALTER TABLE suppressed_emails
+        ADD CONSTRAINT suppressed_emails_email_lowercase CHECK (email = lower(email));
+COMMIT;
@@ -0,0 +1,54 @@
+-- Mail servers ignore the case of an address, so `User@Example.com` and `user@example.com` are
+-- the same subscriber and share a confirmation email throttle. Subscribers keep the address as
+-- they typed it, but only one subscriber may hold it in any case. Existing duplicates are merged
+-- into the oldest subscriber; throttle rows are kept lowercased, keeping the busiest window.
+BEGIN;
+    CREATE TEMPORARY TABLE duplicate_subscribers ON COMMIT DROP AS
+    SELECT s.id AS duplicate_id, kept.id AS kept_id
+    FROM subscriptions s
+    JOIN LATERAL (
+        SELECT o.id
+        FROM subscriptions o
+        WHERE lower(o.email) = lower(s.email)
+        ORDER BY o.subscribed_at, o.id
+        LIMIT 1
+    ) kept ON kept.id <> s.id;
+
+    INSERT INTO list_subscriptions (subscriber_id, list_id, status, subscribed_at)
+    SELECT d.kept_id, m.list_id, m.status, m.subscribed_at
+    FROM list_subscriptions m
+    JOIN duplicate_subscribers d ON d.duplicate_id = m.subscriber_id
+    ON CONFLICT (subscriber_id, list_id) DO NOTHING;
+
+    DELETE FROM list_subscriptions m
+    USING duplicate_subscribers d
+    WHERE m.subscriber_id = d.duplicate_id;
+
+    UPDATE subscription_tokens t
+    SET subscriber_id = d.kept_id
+    FROM duplicate_subscribers d
+    WHERE t.subscriber_id = d.duplicate_id;
+
+    UPDATE consent_events e
+    SET subscriber_id = d.kept_id
+    FROM duplicate_subscribers d
+    WHERE e.subscriber_id = d.duplicate_id;
+
+    DELETE FROM subscriptions s
+    USING duplicate_subscribers d
+    WHERE s.id = d.duplicate_id;
+
+    ALTER TABLE subscriptions DROP CONSTRAINT subscriptions_email_key;
+    CREATE UNIQUE INDEX subscriptions_lower_email_key ON subscriptions (lower(email));
+
+    DELETE FROM confirmation_email_throttle a
+    USING confirmation_email_throttle b
+    WHERE lower(a.recipient) = lower(b.recipient)
+        AND (a.sent_in_window, a.recipient) < (b.sent_in_window, b.recipient);
+
+    UPDATE confirmation_email_throttle SET recipient = lower(recipient);
+
+    ALTER TABLE confirmation_email_throttle
+        ADD CONSTRAINT confirmation_email_throttle_recipient_lowercase
+        CHECK (recipient = lower(recipient));
+COMMIT;
@@ -0,0 +1,10 @@
+-- Which issues each address was sent, kept once the delivery task is gone from the queue,
+-- so that it can be exported or erased with the rest of a subscriber's personal data
+CREATE TABLE issue_deliveries (
+   newsletter_issue_id UUID NOT NULL
+      REFERENCES newsletter_issues (newsletter_issue_id),
+   subscriber_email TEXT NOT NULL,
+   PRIMARY KEY (newsletter_issue_id, subscriber_email),
+
+   delivered_at timestamptz NOT NULL
+);
@@ -0,0 +1,8 @@
+-- Issues held for a digest are sent together, one email per subscriber and list, by their own
+-- worker. Held deliveries that are already queued join their subscriber's next digest.
+ALTER TABLE issue_delivery_queue ADD COLUMN digest BOOLEAN NOT NULL DEFAULT false;
+
+UPDATE issue_delivery_queue q
+SET digest = true
+FROM subscriptions s
+WHERE lower(s.email) = lower(q.subscriber_email) AND s.digest_frequency <> 'immediately';
@@ -0,0 +1,224 @@
+use crate::telemetry::spawn_blocking_with_tracing;
+use actix_web::http::HeaderMap;
+use argon2::password_hash::SaltString;
+use argon2::{Algorithm, Argon2, Params, PasswordHash, PasswordHasher, PasswordVerifier, Version};
+use sqlx::PgPool;
+use uuid::Uuid;
+
+pub struct Credentials {
+    pub username: String,
+    pub password: String,
+}
+
+#[derive(Debug)]
+pub enum AuthError {
+    InvalidCredentials(String),
+    UnexpectedError(String),
+}
+
+/// Parse credentials out of an HTTP Basic `Authorization` header
+pub fn basic_authentication(headers: &HeaderMap) -> Result<Credentials, String> {
+    let header_value = headers
+        .get("Authorization")
+        .ok_or_else(|| "The 'Authorization' header was missing".to_string())?
+        .to_str()
+        .map_err(|_| "The 'Authorization' header was not a valid UTF8 string".to_string())?;
+    let base64_encoded_segment = header_value
+        .strip_prefix("Basic ")
+        .ok_or_else(|| "The authorization scheme was not 'Basic'".to_string())?;
+    let decoded_bytes = base64::decode(base64_encoded_segment)
+        .map_err(|_| "Failed to base64-decode 'Basic' credentials".to_string())?;
+    let decoded_credentials = String::from_utf8(decoded_bytes)
+        .map_err(|_| "The decoded credential string is not valid UTF8".to_string())?;
+
+    let mut credentials = decoded_credentials.splitn(2, ':');
+    let username = credentials
+        .next()
+        .ok_or_else(|| "A username must be provided in 'Basic' auth".to_string())?
+        .to_string();
+    let password = credentials
+        .next()
+        .ok_or_else(|| "A password must be provided in 'Basic' auth".to_string())?
+        .to_string();
+
+    Ok(Credentials { username, password })
+}
+
+#[tracing::instrument(name = "Validate credentials", skip(credentials, pool))]
+pub async fn validate_credentials(
+    credentials: Credentials,
+    pool: &PgPool,
+) -> Result<Uuid, AuthError> {
+    let mut user_id = None;
+    // Verify against a fallback hash when the user is unknown, so that the response time
+    // doesn't reveal which usernames exist
+    let mut expected_password_hash = "$argon2id$v=19$m=15000,t=2,p=1$\
+        gZiV/M1gPc22ElAH/Jh1Hw$\
+        CWOrkoo7oJBQ/iyh7uJ0LO2aLEfrHwTWllSAxT0zRno"
+        .to_string();
+
+    if let Some((stored_user_id, stored_password_hash)) =
+        get_stored_credentials(&credentials.username, pool)
+            .await
+            .map_err(|e| AuthError::UnexpectedError(e.to_string()))?
+    {
+        user_id = Some(stored_user_id);
+        expected_password_hash = stored_password_hash;
+    }
+
+    spawn_blocking_with_tracing(move || {
+        verify_password_hash(expected_password_hash, credentials.password)
+    })
+    .await
+    .map_err(|e| AuthError::UnexpectedError(format!("Failed to spawn blocking task: {}", e)))??;
+
+    user_id.ok_or_else(|| AuthError::InvalidCredentials("Unknown username".into()))
+}
+
+#[tracing::instrument(
+    name = "Verify password hash",
+    skip(expected_password_hash, password_candidate)
+)]
+fn verify_password_hash(
+    expected_password_hash: String,
+    password_candidate: String,
+) -> Result<(), AuthError> {
+    let expected_password_hash = PasswordHash::new(&expected_password_hash).map_err(|e| {
+        AuthError::UnexpectedError(format!("Failed to parse hash in PHC string format: {}", e))
+    })?;
+
+    Argon2::default()
+        .verify_password(password_candidate.as_bytes(), &expected_password_hash)
+        .map_err(|_| AuthError::InvalidCredentials("Invalid password".into()))
+}
+
+#[tracing::instrument(name = "Get stored credentials", skip(username, pool))]
+async fn get_stored_credentials(
+    username: &str,
+    pool: &PgPool,
+) -> Result<Option<(Uuid, String)>, sqlx::Error> {
+    let row = sqlx::query!(
+        r#"
+            SELECT user_id, password_hash FROM users WHERE username = $1
+        "#,
+        username,
+    )
+    .fetch_optional(pool)
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to execute query: {:?}", e);
+        e
+    })?
+    .map(|row| (row.user_id, row.password_hash));
+
+    Ok(row)
+}
+
+#[tracing::instrument(name = "Get username", skip(pool))]
+pub async fn get_username(user_id: Uuid, pool: &PgPool) -> Result<String, sqlx::Error> {
+    let row = sqlx::query!(
+        r#"
+            SELECT username FROM users WHERE user_id = $1
+        "#,
+        user_id,
+    )
+    .fetch_one(pool)
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to execute query: {:?}", e);
+        e
+    })?;
+
+    Ok(row.username)
+}
+
+/// Hash a password with argon2id, returning it in PHC string format
+pub fn compute_password_hash(password: &str) -> Result<String, String> {
+    let salt = SaltString::generate(&mut rand::thread_rng());
+    let params = Params::new(15000, 2, 1, None).map_err(|e| e.to_string())?;
+
+    Argon2::new(Algorithm::Argon2id, Version::V0x13, params)
+        .hash_password(password.as_bytes(), &salt)
+        .map(|hash| hash.to_string())
+        .map_err(|e| e.to_string())
+}
+
+#[tracing::instrument(name = "Create an admin user", skip(password, pool))]
+pub async fn create_user(pool: &PgPool, username: &str, password: &str) -> Result<Uuid, String> {
+    let user_id = Uuid::new_v4();
+    let password = password.to_string();
+    let password_hash = spawn_blocking_with_tracing(move || compute_password_hash(&password))
+        .await
+        .map_err(|e| format!("Failed to spawn blocking task: {}", e))??;
+
+    sqlx::query!(
+        r#"
+            INSERT INTO users (user_id, username, password_hash)
+            VALUES ($1, $2, $3)
+        "#,
+        user_id,
+        username,
+        password_hash,
+    )
+    .execute(pool)
+    .await
+    .map_err(|e| format!("Failed to store the new user: {}", e))?;
+
+    Ok(user_id)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::http::{HeaderName, HeaderValue};
+    use claim::{assert_err, assert_ok};
+
+    fn headers_with_authorization(value: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            HeaderName::from_static("authorization"),
+            HeaderValue::from_str(value).unwrap(),
+        );
+        headers
+    }
+
+    #[test]
+    fn basic_credentials_are_decoded() {
+        let encoded = base64::encode("ursula:le guin:earthsea");
+        let headers = headers_with_authorization(&format!("Basic {}", encoded));
+
+        let credentials = basic_authentication(&headers).unwrap();
+
+        assert_eq!(credentials.username, "ursula");
+        assert_eq!(credentials.password, "le guin:earthsea");
+    }
+
+    #[test]
+    fn missing_authorization_header_is_rejected() {
+        assert!(basic_authentication(&HeaderMap::new()).is_err());
+    }
+
+    #[test]
+    fn non_basic_schemes_are_rejected() {
+        let headers = headers_with_authorization("Bearer some-token");
+        assert!(basic_authentication(&headers).is_err());
+    }
+
+    #[test]
+    fn credentials_without_a_password_are_rejected() {
+        let encoded = base64::encode("ursula");
+        let headers = headers_with_authorization(&format!("Basic {}", encoded));
+        assert!(basic_authentication(&headers).is_err());
+    }
+
+    #[test]
+    fn a_computed_hash_verifies_the_original_password() {
+        let hash = compute_password_hash("everythinghastostartsomewhere").unwrap();
+
+        assert_ok!(verify_password_hash(
+            hash.clone(),
+            "everythinghastostartsomewhere".into()
+        ));
+        assert_err!(verify_password_hash(hash, "something else".into()));
+    }
+}
@@ -0,0 +1,31 @@
+use std::io::BufRead;
+use zero2prod::authentication::create_user;
+use zero2prod::configuration::get_configuration;
+use zero2prod::startup::get_connection_pool;
+
+/// Create an admin user: `create_admin <username>`, reading the password from stdin
+#[actix_web::main]
+async fn main() -> std::io::Result<()> {
+    let username = std::env::args()
+        .nth(1)
+        .expect("Usage: create_admin <username> (the password is read from stdin)");
+
+    let mut password = String::new();
+    std::io::stdin().lock().read_line(&mut password)?;
+    let password = password.trim_end_matches(&['\r', '\n'][..]);
+    if password.is_empty() {
+        panic!("The admin password must not be empty");
+    }
+
+    let config = get_configuration().expect("Failed to read configuration");
+    let pool = get_connection_pool(&config.database)
+        .await
+        .expect("Failed to connect to Postgres");
+
+    let user_id = create_user(&pool, &username, password)
+        .await
+        .expect("Failed to create admin user");
+    println!("Created admin user {} with id {}", username, user_id);
+
+    Ok(())
+}
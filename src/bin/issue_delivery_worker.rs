@@ -0,0 +1,14 @@
+use zero2prod::configuration::get_configuration;
+use zero2prod::issue_delivery_worker::run_worker_until_stopped;
+use zero2prod::telemetry::{get_subscriber, init_subscriber};
+
+#[actix_web::main]
+async fn main() -> std::io::Result<()> {
+    let subscriber = get_subscriber("issue_delivery_worker".into(), "info".into());
+    init_subscriber(subscriber);
+
+    let config = get_configuration().expect("Failed to read configuration");
+    run_worker_until_stopped(config).await?;
+
+    Ok(())
+}
@@ -1,14 +1,24 @@
+use crate::confirmation_email_throttle::ConfirmationEmailThrottle;
 use crate::domain::SubscriberEmail;
+use crate::email_client::{
+    EmailSender, FileEmailSender, PostmarkClient, RetryPolicy, SmtpEmailSender, SmtpTls,
+};
+use crate::rate_limit::Quota;
+use crate::signup_protection::SignupProtection;
 use serde::Deserialize;
 use serde_aux::field_attributes::deserialize_number_from_string;
 use sqlx::postgres::{PgConnectOptions, PgSslMode};
 use std::convert::{TryFrom, TryInto};
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::time::Duration;
 
 #[derive(Deserialize, Clone)]
 pub struct Settings {
     pub database: DatabaseSettings,
     pub application: ApplicationSettings,
     pub email_client: EmailClientSettings,
+    pub postmark_webhook: PostmarkWebhookSettings,
 }
 
 #[derive(Deserialize, Clone)]
@@ -17,6 +27,64 @@ pub struct ApplicationSettings {
     pub port: u16,
     pub host: String,
     pub base_url: String,
+    pub hmac_secret: String,
+    /// How long a confirmation link stays valid
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub subscription_token_ttl_hours: i64,
+    /// How long the preference link in each email stays valid
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub preferences_link_ttl_days: i64,
+    /// Proxies in front of the app, whose `X-Forwarded-For` header names the client
+    #[serde(default)]
+    pub trusted_proxies: Vec<IpAddr>,
+    pub rate_limits: RateLimitsSettings,
+    pub confirmation_emails: ConfirmationEmailSettings,
+    pub signup_protection: SignupProtectionSettings,
+}
+
+/// Defenses against scripted signups, all off by default
+#[derive(Deserialize, Clone)]
+pub struct SignupProtectionSettings {
+    pub honeypot: bool,
+    /// Forms submitted sooner than this after being served are rejected; 0 turns the check off
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub min_fill_seconds: i64,
+    /// The difficulty of the proof of work required with each signup; 0 turns it off
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub proof_of_work_bits: u32,
+}
+
+/// How often the same address can be sent a confirmation email
+#[derive(Deserialize, Clone)]
+pub struct ConfirmationEmailSettings {
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub cooldown_seconds: i64,
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub daily_cap: u32,
+}
+
+/// Per client IP, for the routes that can be abused to make us send emails
+#[derive(Deserialize, Clone)]
+pub struct RateLimitsSettings {
+    pub subscriptions: RateLimitSettings,
+    pub resend_confirmation: RateLimitSettings,
+}
+
+#[derive(Deserialize, Clone)]
+pub struct RateLimitSettings {
+    /// How many requests a client can make at once
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub burst: u32,
+    /// How quickly the allowance comes back
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub per_minute: u32,
+}
+
+/// The basic auth credentials Postmark must send with bounce and spam complaint webhooks
+#[derive(Deserialize, Clone)]
+pub struct PostmarkWebhookSettings {
+    pub username: String,
+    pub password: String,
 }
 
 #[derive(Deserialize, Clone)]
@@ -32,9 +100,51 @@ pub struct DatabaseSettings {
 
 #[derive(Deserialize, Clone)]
 pub struct EmailClientSettings {
-    pub base_url: String,
     pub sender_email: String,
+    pub backend: EmailBackendSettings,
+}
+
+/// The provider emails are sent through, chosen by its `type` key
+#[derive(Deserialize, Clone)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum EmailBackendSettings {
+    Postmark(PostmarkSettings),
+    Smtp(SmtpSettings),
+    File(FileSettings),
+}
+
+#[derive(Deserialize, Clone)]
+pub struct PostmarkSettings {
+    pub base_url: String,
     pub authorization_token: String,
+    pub retry: EmailRetrySettings,
+}
+
+#[derive(Deserialize, Clone)]
+pub struct EmailRetrySettings {
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub max_attempts: u32,
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub base_delay_milliseconds: u64,
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub max_delay_milliseconds: u64,
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub jitter: f64,
+}
+
+#[derive(Deserialize, Clone)]
+pub struct SmtpSettings {
+    pub host: String,
+    #[serde(deserialize_with = "deserialize_number_from_string")]
+    pub port: u16,
+    pub tls: SmtpTls,
+    pub username: Option<String>,
+    pub password: Option<String>,
+}
+
+#[derive(Deserialize, Clone)]
+pub struct FileSettings {
+    pub directory: String,
 }
 
 impl EmailClientSettings {
@@ -42,9 +152,102 @@ impl EmailClientSettings {
         SubscriberEmail::parse(self.sender_email.clone())
     }
 
+    pub fn client(self) -> Arc<dyn EmailSender> {
+        let sender_email = self.sender().expect("Invalid sender email address");
+
+        match self.backend {
+            EmailBackendSettings::Postmark(postmark) => {
+                let base_url = postmark.base_url().expect("Invalid email client base url");
+                let retry_policy = postmark.retry_policy();
+                let client = PostmarkClient::new(
+                    base_url,
+                    sender_email,
+                    postmark.authorization_token,
+                    retry_policy,
+                )
+                .expect("Invalid email url path");
+
+                Arc::new(client)
+            }
+            EmailBackendSettings::Smtp(smtp) => {
+                let credentials = match (smtp.username, smtp.password) {
+                    (Some(username), Some(password)) => Some((username, password)),
+                    (None, None) => None,
+                    _ => panic!("SMTP username and password must be set together"),
+                };
+                let sender = SmtpEmailSender::new(
+                    &smtp.host,
+                    smtp.port,
+                    smtp.tls,
+                    credentials,
+                    sender_email,
+                )
+                .expect("Invalid SMTP relay");
+
+                Arc::new(sender)
+            }
+            EmailBackendSettings::File(file) => {
+                let sender = FileEmailSender::new(&file.directory, sender_email)
+                    .expect("Failed to create the email directory");
+
+                Arc::new(sender)
+            }
+        }
+    }
+}
+
+impl PostmarkSettings {
     pub fn base_url(&self) -> Result<reqwest::Url, url::ParseError> {
         reqwest::Url::parse(&self.base_url)
     }
+
+    pub fn retry_policy(&self) -> RetryPolicy {
+        RetryPolicy {
+            max_attempts: self.retry.max_attempts,
+            base_delay: Duration::from_millis(self.retry.base_delay_milliseconds),
+            max_delay: Duration::from_millis(self.retry.max_delay_milliseconds),
+            jitter: self.retry.jitter,
+        }
+    }
+}
+
+impl ApplicationSettings {
+    pub fn subscription_token_ttl(&self) -> chrono::Duration {
+        chrono::Duration::hours(self.subscription_token_ttl_hours)
+    }
+
+    pub fn preferences_link_ttl(&self) -> chrono::Duration {
+        chrono::Duration::days(self.preferences_link_ttl_days)
+    }
+}
+
+impl ConfirmationEmailSettings {
+    pub fn throttle(&self) -> ConfirmationEmailThrottle {
+        ConfirmationEmailThrottle {
+            cooldown: chrono::Duration::seconds(self.cooldown_seconds),
+            daily_cap: self.daily_cap,
+        }
+    }
+}
+
+impl SignupProtectionSettings {
+    pub fn protection(&self) -> SignupProtection {
+        SignupProtection {
+            honeypot: self.honeypot,
+            min_fill_time: Some(chrono::Duration::seconds(self.min_fill_seconds))
+                .filter(|_| self.min_fill_seconds > 0),
+            proof_of_work_bits: Some(self.proof_of_work_bits).filter(|bits| *bits > 0),
+        }
+    }
+}
+
+impl RateLimitSettings {
+    pub fn quota(&self) -> Quota {
+        Quota {
+            burst: self.burst,
+            per_minute: self.per_minute,
+        }
+    }
 }
 
 impl DatabaseSettings {
@@ -0,0 +1,134 @@
+use chrono::{DateTime, Duration, Utc};
+
+/// Limits how often confirmation emails are sent to the same address, however many
+/// times it is signed up
+#[derive(Debug, Clone, Copy)]
+pub struct ConfirmationEmailThrottle {
+    /// The minimum time between two emails
+    pub cooldown: Duration,
+    /// The maximum number of emails in a day
+    pub daily_cap: u32,
+}
+
+/// The confirmation emails sent to an address, in the current day-long window
+#[derive(Debug, Clone, PartialEq, serde::Serialize)]
+pub struct ConfirmationEmailHistory {
+    pub window_started_at: DateTime<Utc>,
+    pub sent_in_window: i32,
+    pub last_sent_at: DateTime<Utc>,
+}
+
+#[derive(thiserror::Error, Debug, PartialEq)]
+pub enum ConfirmationEmailThrottled {
+    #[error("A confirmation email was sent to this address less than {0} seconds ago.")]
+    Cooldown(i64),
+    #[error("This address has been sent {0} confirmation emails today.")]
+    DailyCap(u32),
+}
+
+impl ConfirmationEmailThrottle {
+    /// The history once another email is sent at `now`, if it is allowed
+    pub fn record_send(
+        &self,
+        history: Option<&ConfirmationEmailHistory>,
+        now: DateTime<Utc>,
+    ) -> Result<ConfirmationEmailHistory, ConfirmationEmailThrottled> {
+        let history = match history {
+            Some(history) if now - history.window_started_at < Duration::days(1) => history,
+            _ => {
+                return Ok(ConfirmationEmailHistory {
+                    window_started_at: now,
+                    sent_in_window: 1,
+                    last_sent_at: now,
+                })
+            }
+        };
+        if now - history.last_sent_at < self.cooldown {
+            return Err(ConfirmationEmailThrottled::Cooldown(
+                self.cooldown.num_seconds(),
+            ));
+        }
+        if history.sent_in_window as i64 >= self.daily_cap as i64 {
+            return Err(ConfirmationEmailThrottled::DailyCap(self.daily_cap));
+        }
+
+        Ok(ConfirmationEmailHistory {
+            window_started_at: history.window_started_at,
+            sent_in_window: history.sent_in_window + 1,
+            last_sent_at: now,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ConfirmationEmailHistory, ConfirmationEmailThrottle, ConfirmationEmailThrottled};
+    use chrono::{Duration, Utc};
+
+    fn throttle() -> ConfirmationEmailThrottle {
+        ConfirmationEmailThrottle {
+            cooldown: Duration::zero(),
+            daily_cap: 3,
+        }
+    }
+
+    #[test]
+    fn the_first_email_to_an_address_is_allowed() {
+        let now = Utc::now();
+
+        let history = throttle().record_send(None, now).unwrap();
+
+        assert_eq!(history.window_started_at, now);
+        assert_eq!(history.sent_in_window, 1);
+        assert_eq!(history.last_sent_at, now);
+    }
+
+    #[test]
+    fn emails_within_the_cooldown_are_refused() {
+        let throttle = ConfirmationEmailThrottle {
+            cooldown: Duration::minutes(1),
+            ..throttle()
+        };
+        let now = Utc::now();
+        let history = throttle.record_send(None, now).unwrap();
+
+        let soon = now + Duration::seconds(59);
+        assert_eq!(
+            throttle.record_send(Some(&history), soon),
+            Err(ConfirmationEmailThrottled::Cooldown(60))
+        );
+        let later = now + Duration::seconds(60);
+        assert!(throttle.record_send(Some(&history), later).is_ok());
+    }
+
+    #[test]
+    fn emails_beyond_the_daily_cap_are_refused() {
+        let throttle = throttle();
+        let now = Utc::now();
+        let mut history = throttle.record_send(None, now).unwrap();
+        for _ in 1..throttle.daily_cap {
+            history = throttle.record_send(Some(&history), now).unwrap();
+        }
+
+        assert_eq!(
+            throttle.record_send(Some(&history), now + Duration::hours(23)),
+            Err(ConfirmationEmailThrottled::DailyCap(3))
+        );
+    }
+
+    #[test]
+    fn the_cap_resets_a_day_after_the_first_email() {
+        let now = Utc::now();
+        let history = ConfirmationEmailHistory {
+            window_started_at: now,
+            sent_in_window: 3,
+            last_sent_at: now,
+        };
+
+        let tomorrow = now + Duration::days(1);
+        let history = throttle().record_send(Some(&history), tomorrow).unwrap();
+
+        assert_eq!(history.window_started_at, tomorrow);
+        assert_eq!(history.sent_in_window, 1);
+    }
+}
@@ -0,0 +1,58 @@
+/// How often a subscriber wants to hear from us, stored as text in `subscriptions.digest_frequency`.
+/// The database has a CHECK constraint listing the same values.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, sqlx::Type, serde::Serialize)]
+#[sqlx(type_name = "text", rename_all = "snake_case")]
+#[serde(rename_all = "snake_case")]
+pub enum DigestFrequency {
+    /// Each issue as soon as it is published
+    Immediately,
+    /// The issues of a day in one email, sent at the next midnight UTC
+    Daily,
+    /// The issues of a week in one email, sent on the next Monday at midnight UTC
+    Weekly,
+}
+
+impl DigestFrequency {
+    pub const ALL: [DigestFrequency; 3] = [
+        DigestFrequency::Immediately,
+        DigestFrequency::Daily,
+        DigestFrequency::Weekly,
+    ];
+
+    pub fn parse(s: &str) -> Result<Self, String> {
+        match s {
+            "immediately" => Ok(DigestFrequency::Immediately),
+            "daily" => Ok(DigestFrequency::Daily),
+            "weekly" => Ok(DigestFrequency::Weekly),
+            other => Err(format!("{} is not a valid digest frequency", other)),
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            DigestFrequency::Immediately => "immediately",
+            DigestFrequency::Daily => "daily",
+            DigestFrequency::Weekly => "weekly",
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::DigestFrequency;
+    use claim::{assert_err, assert_ok_eq};
+
+    #[test]
+    fn every_frequency_parses_from_its_own_name() {
+        for frequency in DigestFrequency::ALL.iter() {
+            assert_ok_eq!(DigestFrequency::parse(frequency.as_str()), *frequency);
+        }
+    }
+
+    #[test]
+    fn unknown_frequencies_are_rejected() {
+        assert_err!(DigestFrequency::parse("hourly"));
+        assert_err!(DigestFrequency::parse("Daily"));
+        assert_err!(DigestFrequency::parse(""));
+    }
+}
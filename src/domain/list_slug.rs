@@ -0,0 +1,63 @@
+/// The list that signups and issues go to when they don't name one.
+/// The migration that created `lists` inserts it.
+pub const DEFAULT_LIST_SLUG: &str = "newsletter";
+
+/// The name a list goes by in URLs and forms, e.g. `?list=weekly-digest`
+#[derive(Debug, Clone, PartialEq)]
+pub struct ListSlug(String);
+
+impl ListSlug {
+    pub fn parse(s: String) -> Result<Self, String> {
+        let is_valid = !s.is_empty()
+            && s.len() <= 64
+            && s.bytes()
+                .all(|b| b.is_ascii_lowercase() || b.is_ascii_digit() || b == b'-');
+
+        if is_valid {
+            Ok(Self(s))
+        } else {
+            Err(format!("{} is not a valid list slug", s))
+        }
+    }
+
+    pub fn default_list() -> Self {
+        Self(DEFAULT_LIST_SLUG.into())
+    }
+}
+
+impl AsRef<str> for ListSlug {
+    fn as_ref(&self) -> &str {
+        &self.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use claim::{assert_err, assert_ok};
+
+    #[test]
+    fn lowercase_words_with_dashes_are_valid() {
+        assert_ok!(ListSlug::parse("weekly-digest-2".into()));
+        assert_ok!(ListSlug::parse(DEFAULT_LIST_SLUG.into()));
+    }
+
+    #[test]
+    fn empty_and_long_slugs_are_rejected() {
+        assert_err!(ListSlug::parse("".into()));
+        assert_err!(ListSlug::parse("a".repeat(65)));
+    }
+
+    #[test]
+    fn slugs_that_need_escaping_are_rejected() {
+        for slug in &[
+            "Weekly",
+            "weekly digest",
+            "weekly/digest",
+            "wöchentlich",
+            "a&b=c",
+        ] {
+            assert_err!(ListSlug::parse(slug.to_string()));
+        }
+    }
+}
@@ -1,7 +1,13 @@
+mod digest_frequency;
+mod list_slug;
 mod new_subscriber;
 mod subscriber_email;
 mod subscriber_name;
+mod subscription_status;
 
+pub use digest_frequency::DigestFrequency;
+pub use list_slug::{ListSlug, DEFAULT_LIST_SLUG};
 pub use new_subscriber::NewSubscriber;
 pub use subscriber_email::SubscriberEmail;
 pub use subscriber_name::SubscriberName;
+pub use subscription_status::{InvalidStatusTransition, SubscriptionStatus};
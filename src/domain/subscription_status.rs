@@ -0,0 +1,105 @@
+/// Where a subscriber is in their lifecycle, stored as text in `list_subscriptions.status`.
+/// The database has a CHECK constraint listing the same values.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, sqlx::Type, serde::Serialize)]
+#[sqlx(type_name = "text", rename_all = "snake_case")]
+#[serde(rename_all = "snake_case")]
+pub enum SubscriptionStatus {
+    PendingConfirmation,
+    Confirmed,
+    Unsubscribed,
+    /// Their email address doesn't accept our messages
+    Bounced,
+    /// They marked one of our emails as spam
+    Complained,
+    /// We stopped sending to them, whatever they did before
+    Suppressed,
+}
+
+#[derive(thiserror::Error, Debug, PartialEq)]
+#[error("A subscriber cannot go from `{from:?}` to `{to:?}`")]
+pub struct InvalidStatusTransition {
+    pub from: SubscriptionStatus,
+    pub to: SubscriptionStatus,
+}
+
+impl SubscriptionStatus {
+    pub fn can_transition_to(self, to: SubscriptionStatus) -> bool {
+        use SubscriptionStatus::*;
+
+        match (self, to) {
+            (PendingConfirmation, Confirmed | Unsubscribed) => true,
+            (Confirmed, Unsubscribed) => true,
+            // Signing up again starts a new double opt-in
+            (Unsubscribed, PendingConfirmation) => true,
+            // Delivery problems can be reported for any email we sent, even after an unsubscribe
+            (PendingConfirmation | Confirmed | Unsubscribed, Bounced | Complained) => true,
+            (from, Suppressed) => from != Suppressed,
+            _ => false,
+        }
+    }
+
+    /// Return the new status if the move is allowed
+    pub fn transition_to(
+        self,
+        to: SubscriptionStatus,
+    ) -> Result<SubscriptionStatus, InvalidStatusTransition> {
+        if self.can_transition_to(to) {
+            Ok(to)
+        } else {
+            Err(InvalidStatusTransition { from: self, to })
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::SubscriptionStatus::{self, *};
+    use claim::{assert_err, assert_ok_eq};
+
+    const ALL: [SubscriptionStatus; 6] = [
+        PendingConfirmation,
+        Confirmed,
+        Unsubscribed,
+        Bounced,
+        Complained,
+        Suppressed,
+    ];
+
+    #[test]
+    fn pending_subscribers_can_confirm() {
+        assert_ok_eq!(PendingConfirmation.transition_to(Confirmed), Confirmed);
+    }
+
+    #[test]
+    fn unsubscribed_subscribers_must_confirm_again() {
+        assert_err!(Unsubscribed.transition_to(Confirmed));
+        assert_ok_eq!(
+            Unsubscribed.transition_to(PendingConfirmation),
+            PendingConfirmation
+        );
+    }
+
+    #[test]
+    fn staying_in_the_same_status_is_not_a_transition() {
+        for status in ALL.iter() {
+            assert_err!(status.transition_to(*status));
+        }
+    }
+
+    #[test]
+    fn anyone_can_be_suppressed_and_suppression_is_final() {
+        for status in ALL.iter().filter(|s| **s != Suppressed) {
+            assert_ok_eq!(status.transition_to(Suppressed), Suppressed);
+            assert_err!(Suppressed.transition_to(*status));
+        }
+    }
+
+    #[test]
+    fn bounced_and_complained_subscribers_cannot_come_back() {
+        for from in [Bounced, Complained].iter() {
+            for to in [PendingConfirmation, Confirmed, Unsubscribed].iter() {
+                assert_err!(from.transition_to(*to));
+            }
+        }
+    }
+}
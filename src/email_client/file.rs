@@ -0,0 +1,72 @@
+use super::{build_message, Email, EmailError, EmailSender};
+use crate::domain::SubscriberEmail;
+use async_trait::async_trait;
+use lettre::{AsyncFileTransport, AsyncTransport, Tokio1Executor};
+use std::path::Path;
+
+/// Writes each email to an `.eml` file in a directory instead of sending it; for development
+pub struct FileEmailSender {
+    transport: AsyncFileTransport<Tokio1Executor>,
+    sender: SubscriberEmail,
+}
+
+impl FileEmailSender {
+    pub fn new(directory: impl AsRef<Path>, sender: SubscriberEmail) -> std::io::Result<Self> {
+        std::fs::create_dir_all(&directory)?;
+
+        Ok(Self {
+            transport: AsyncFileTransport::new(directory),
+            sender,
+        })
+    }
+}
+
+#[async_trait]
+impl EmailSender for FileEmailSender {
+    async fn send(&self, email: &Email) -> Result<(), EmailError> {
+        let (envelope, message) = build_message(&self.sender, email)?;
+        let id = self.transport.send_raw(&envelope, &message).await?;
+        tracing::info!("Wrote email {}.eml", id);
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::FileEmailSender;
+    use crate::domain::SubscriberEmail;
+    use crate::email_client::EmailSender;
+    use claim::assert_ok;
+    use uuid::Uuid;
+
+    #[tokio::test]
+    async fn send_email_writes_an_eml_file_to_the_directory() {
+        // Arrange
+        let directory = std::env::temp_dir().join(Uuid::new_v4().to_string());
+        let sender = SubscriberEmail::parse("sender@example.com".into()).unwrap();
+        let recipient = SubscriberEmail::parse("recipient@example.com".into()).unwrap();
+        let email_sender = FileEmailSender::new(&directory, sender).unwrap();
+
+        // Act
+        let outcome = email_sender
+            .send_email(recipient, "Welcome!", "<p>Hello!</p>", "Hello!")
+            .await;
+
+        // Assert
+        assert_ok!(outcome);
+        let files: Vec<_> = std::fs::read_dir(&directory)
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .collect();
+        assert_eq!(files.len(), 1);
+        assert_eq!(files[0].extension().unwrap(), "eml");
+
+        let contents = std::fs::read_to_string(&files[0]).unwrap();
+        assert!(contents.contains("To: recipient@example.com"));
+        assert!(contents.contains("Subject: Welcome!"));
+        assert!(contents.contains("Hello!"));
+
+        std::fs::remove_dir_all(directory).unwrap();
+    }
+}
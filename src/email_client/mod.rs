@@ -0,0 +1,197 @@
+mod file;
+mod postmark;
+mod smtp;
+
+pub use file::FileEmailSender;
+pub use postmark::{PostmarkClient, RetryPolicy, MAX_BATCH_SIZE};
+pub use smtp::{SmtpEmailSender, SmtpTls};
+
+use crate::domain::SubscriberEmail;
+use crate::utils::error_chain_fmt;
+use async_trait::async_trait;
+use lettre::address::Envelope;
+use lettre::message::{Mailbox, MultiPart};
+use lettre::Message;
+use std::sync::Arc;
+
+/// An email to a single recipient
+#[derive(Clone, Debug)]
+pub struct Email {
+    pub recipient: SubscriberEmail,
+    pub subject: String,
+    pub html_body: String,
+    pub text_body: String,
+    /// Headers to add on top of the ones the provider sets, like `List-Unsubscribe`
+    pub headers: Vec<EmailHeader>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct EmailHeader {
+    pub name: String,
+    pub value: String,
+}
+
+impl EmailHeader {
+    pub fn new(name: impl Into<String>, value: impl Into<String>) -> Self {
+        Self {
+            name: name.into(),
+            value: value.into(),
+        }
+    }
+}
+
+/// Delivers emails; implemented once per provider so that the rest of the app
+/// doesn't need to know which one is configured
+#[async_trait]
+pub trait EmailSender: Send + Sync {
+    async fn send(&self, email: &Email) -> Result<(), EmailError>;
+
+    /// Send an email without any extra headers
+    async fn send_email(
+        &self,
+        recipient: SubscriberEmail,
+        subject: &str,
+        html_body: &str,
+        text_body: &str,
+    ) -> Result<(), EmailError> {
+        let email = Email {
+            recipient,
+            subject: subject.into(),
+            html_body: html_body.into(),
+            text_body: text_body.into(),
+            headers: vec![],
+        };
+
+        self.send(&email).await
+    }
+
+    /// Send each email, returning their outcomes in the same order
+    async fn send_batch(&self, emails: &[Email]) -> Vec<BatchOutcome> {
+        let mut outcomes = Vec::with_capacity(emails.len());
+        for email in emails {
+            outcomes.push(match self.send(email).await {
+                Ok(()) => BatchOutcome::Sent,
+                Err(e) => BatchOutcome::Failed(Arc::new(e)),
+            });
+        }
+
+        outcomes
+    }
+}
+
+/// What happened to one recipient's message in a batch
+#[derive(Debug)]
+pub enum BatchOutcome {
+    Sent,
+    /// The provider refused this message, so sending it again won't help
+    Rejected {
+        error_code: i64,
+        message: String,
+    },
+    /// The request carrying this message failed, and may succeed if retried
+    Failed(Arc<EmailError>),
+}
+
+#[derive(thiserror::Error)]
+pub enum EmailError {
+    #[error("Failed to build the email message")]
+    InvalidMessage(#[from] lettre::error::Error),
+    #[error("Invalid email address")]
+    InvalidAddress(#[from] lettre::address::AddressError),
+    #[error("Invalid email header `{0}`")]
+    InvalidHeader(String),
+    #[error("Failed to send the email through Postmark")]
+    Postmark(#[source] reqwest::Error),
+    #[error("Failed to send the email over SMTP")]
+    Smtp(#[from] lettre::transport::smtp::Error),
+    #[error("Failed to write the email to a file")]
+    File(#[from] lettre::transport::file::Error),
+}
+
+impl std::fmt::Debug for EmailError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        error_chain_fmt(self, f)
+    }
+}
+
+/// Build a MIME message with both the html and plain text bodies, for the backends that
+/// speak SMTP rather than a provider's HTTP API.
+/// Returns the envelope and the raw message with the custom headers prepended.
+fn build_message(
+    sender: &SubscriberEmail,
+    email: &Email,
+) -> Result<(Envelope, Vec<u8>), EmailError> {
+    let message = Message::builder()
+        .from(sender.as_ref().parse::<Mailbox>()?)
+        .to(email.recipient.as_ref().parse::<Mailbox>()?)
+        .subject(email.subject.as_str())
+        .multipart(MultiPart::alternative_plain_html(
+            email.text_body.clone(),
+            email.html_body.clone(),
+        ))?;
+
+    // lettre only supports headers known at compile time, but the order of
+    // header fields doesn't matter, so custom ones can go before the rest
+    let mut raw = Vec::new();
+    for header in &email.headers {
+        let is_valid = |s: &str| s.is_ascii() && !s.contains(|c: char| c.is_ascii_control());
+        if header.name.is_empty() || header.name.contains(':') || !is_valid(&header.name) {
+            return Err(EmailError::InvalidHeader(header.name.clone()));
+        }
+        if !is_valid(&header.value) {
+            return Err(EmailError::InvalidHeader(header.name.clone()));
+        }
+        raw.extend_from_slice(format!("{}: {}\r\n", header.name, header.value).as_bytes());
+    }
+    raw.extend(message.formatted());
+
+    Ok((message.envelope().clone(), raw))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{build_message, Email, EmailHeader};
+    use crate::domain::SubscriberEmail;
+    use claim::assert_err;
+
+    fn email(headers: Vec<EmailHeader>) -> Email {
+        Email {
+            recipient: SubscriberEmail::parse("recipient@example.com".into()).unwrap(),
+            subject: "Welcome!".into(),
+            html_body: "<p>Hello!</p>".into(),
+            text_body: "Hello!".into(),
+            headers,
+        }
+    }
+
+    fn sender() -> SubscriberEmail {
+        SubscriberEmail::parse("sender@example.com".into()).unwrap()
+    }
+
+    #[test]
+    fn custom_headers_are_added_to_the_message() {
+        let email = email(vec![EmailHeader::new(
+            "List-Unsubscribe",
+            "<https://example.com/unsubscribe>",
+        )]);
+
+        let (_, raw) = build_message(&sender(), &email).unwrap();
+
+        let raw = String::from_utf8(raw).unwrap();
+        assert!(raw.contains("List-Unsubscribe: <https://example.com/unsubscribe>\r\n"));
+        assert!(raw.contains("Subject: Welcome!\r\n"));
+    }
+
+    #[test]
+    fn headers_that_would_break_the_message_are_rejected() {
+        let headers = vec![
+            EmailHeader::new("Bcc", "victim@example.com\r\nSubject: Injected"),
+            EmailHeader::new("Not: A Name", "value"),
+            EmailHeader::new("", "value"),
+        ];
+
+        for header in headers {
+            assert_err!(build_message(&sender(), &email(vec![header])));
+        }
+    }
+}
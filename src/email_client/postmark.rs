@@ -0,0 +1,737 @@
+use super::{BatchOutcome, Email, EmailError, EmailHeader, EmailSender};
+use crate::domain::SubscriberEmail;
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use rand::Rng;
+use reqwest::header::RETRY_AFTER;
+use reqwest::{Client, Response, StatusCode};
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// The most messages Postmark accepts in one batch request
+pub const MAX_BATCH_SIZE: usize = 500;
+
+/// Sends emails through Postmark's HTTP API
+pub struct PostmarkClient {
+    http_client: Client,
+    sender: SubscriberEmail,
+    authorization_token: String,
+    email_url: reqwest::Url,
+    batch_url: reqwest::Url,
+    retry_policy: RetryPolicy,
+}
+
+/// How `PostmarkClient` retries requests that failed for reasons that may be temporary
+#[derive(Clone, Debug)]
+pub struct RetryPolicy {
+    /// The total number of requests made for one email, including the first one
+    pub max_attempts: u32,
+    pub base_delay: Duration,
+    pub max_delay: Duration,
+    /// The fraction of each delay that is randomised, between 0.0 and 1.0
+    pub jitter: f64,
+}
+
+impl RetryPolicy {
+    pub fn no_retries() -> Self {
+        Self {
+            max_attempts: 1,
+            base_delay: Duration::from_millis(0),
+            max_delay: Duration::from_millis(0),
+            jitter: 0.0,
+        }
+    }
+
+    /// The delay before the next attempt, doubling after each failed one
+    fn backoff(&self, failed_attempts: u32) -> Duration {
+        let exponent = failed_attempts.saturating_sub(1).min(31);
+        let delay = self
+            .base_delay
+            .checked_mul(2u32.pow(exponent))
+            .map_or(self.max_delay, |delay| delay.min(self.max_delay));
+
+        let jitter = self.jitter.clamp(0.0, 1.0) * rand::thread_rng().gen::<f64>();
+        delay.mul_f64(1.0 - jitter)
+    }
+}
+
+impl PostmarkClient {
+    pub fn new(
+        base_url: reqwest::Url,
+        sender: SubscriberEmail,
+        authorization_token: String,
+        retry_policy: RetryPolicy,
+    ) -> Result<Self, url::ParseError> {
+        let http_client = Client::builder()
+            .timeout(Duration::from_secs(10))
+            .build()
+            .unwrap();
+
+        let email_url = base_url.join("email")?;
+        let batch_url = base_url.join("email/batch")?;
+
+        Ok(Self {
+            http_client,
+            sender,
+            authorization_token,
+            email_url,
+            batch_url,
+            retry_policy,
+        })
+    }
+
+    fn request_body<'a>(&'a self, email: &'a Email) -> SendEmailRequest<'a> {
+        SendEmailRequest {
+            from: self.sender.as_ref(),
+            to: email.recipient.as_ref(),
+            subject: &email.subject,
+            html_body: &email.html_body,
+            text_body: &email.text_body,
+            headers: email.headers.iter().map(Into::into).collect(),
+        }
+    }
+
+    /// Send one request for a chunk of at most `MAX_BATCH_SIZE` emails
+    async fn send_chunk(&self, emails: &[Email]) -> Result<Vec<BatchResponseItem>, reqwest::Error> {
+        let request_body: Vec<_> = emails
+            .iter()
+            .map(|email| self.request_body(email))
+            .collect();
+
+        self.post_with_retries(&self.batch_url, &request_body)
+            .await?
+            .json()
+            .await
+    }
+
+    /// Post the body, retrying failures that may be temporary according to the retry policy
+    async fn post_with_retries(
+        &self,
+        url: &reqwest::Url,
+        body: &impl Serialize,
+    ) -> Result<Response, reqwest::Error> {
+        let mut failed_attempts = 0;
+        loop {
+            let outcome = self
+                .http_client
+                .post(url.clone())
+                .header("X-Postmark-Server-Token", &self.authorization_token)
+                .json(body)
+                .send()
+                .await;
+            failed_attempts += 1;
+            let out_of_attempts = failed_attempts >= self.retry_policy.max_attempts;
+
+            let delay = match outcome {
+                Ok(response) if !is_retryable(response.status()) || out_of_attempts => {
+                    return response.error_for_status();
+                }
+                Ok(response) => {
+                    let backoff = self.retry_policy.backoff(failed_attempts);
+                    match retry_after(&response) {
+                        // Waiting less than the server asked for would be pointless,
+                        // and waiting longer than our limit would hold up the caller
+                        Some(retry_after) if retry_after > self.retry_policy.max_delay => {
+                            return response.error_for_status();
+                        }
+                        Some(retry_after) => retry_after.max(backoff),
+                        None => backoff,
+                    }
+                }
+                Err(e) if out_of_attempts => return Err(e),
+                Err(_) => self.retry_policy.backoff(failed_attempts),
+            };
+
+            tracing::warn!(
+                "Email request attempt {} of {} failed, retrying in {:?}",
+                failed_attempts,
+                self.retry_policy.max_attempts,
+                delay
+            );
+            tokio::time::sleep(delay).await;
+        }
+    }
+}
+
+#[async_trait]
+impl EmailSender for PostmarkClient {
+    async fn send(&self, email: &Email) -> Result<(), EmailError> {
+        self.post_with_retries(&self.email_url, &self.request_body(email))
+            .await
+            .map_err(EmailError::Postmark)?;
+
+        Ok(())
+    }
+
+    /// Send through `/email/batch`, one request per `MAX_BATCH_SIZE` emails
+    async fn send_batch(&self, emails: &[Email]) -> Vec<BatchOutcome> {
+        let mut outcomes = Vec::with_capacity(emails.len());
+        for chunk in emails.chunks(MAX_BATCH_SIZE) {
+            match self.send_chunk(chunk).await {
+                Ok(results) => {
+                    let mut results = results.into_iter();
+                    for email in chunk {
+                        let outcome = match results.next() {
+                            Some(result) if result.error_code == 0 => BatchOutcome::Sent,
+                            Some(result) => BatchOutcome::Rejected {
+                                error_code: result.error_code,
+                                message: result.message,
+                            },
+                            None => {
+                                tracing::error!(
+                                    "Postmark did not report a result for {}",
+                                    email.recipient.as_ref()
+                                );
+                                BatchOutcome::Rejected {
+                                    error_code: -1,
+                                    message: "Missing from the batch response".into(),
+                                }
+                            }
+                        };
+                        outcomes.push(outcome);
+                    }
+                }
+                Err(e) => {
+                    let e = Arc::new(EmailError::Postmark(e));
+                    outcomes.extend(chunk.iter().map(|_| BatchOutcome::Failed(e.clone())));
+                }
+            }
+        }
+
+        outcomes
+    }
+}
+
+/// Rate limiting and server errors may go away; any other client error will not
+fn is_retryable(status: StatusCode) -> bool {
+    status == StatusCode::TOO_MANY_REQUESTS || status.is_server_error()
+}
+
+/// Parse a `Retry-After` header, given either in seconds or as an HTTP date
+fn retry_after(response: &Response) -> Option<Duration> {
+    let value = response.headers().get(RETRY_AFTER)?.to_str().ok()?;
+    parse_retry_after(value, Utc::now())
+}
+
+fn parse_retry_after(value: &str, now: DateTime<Utc>) -> Option<Duration> {
+    if let Ok(seconds) = value.trim().parse::<u64>() {
+        return Some(Duration::from_secs(seconds));
+    }
+
+    let date = DateTime::parse_from_rfc2822(value.trim()).ok()?;
+    Some(
+        (date.with_timezone(&Utc) - now)
+            .to_std()
+            .unwrap_or_default(),
+    )
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "PascalCase")]
+struct SendEmailRequest<'a> {
+    from: &'a str,
+    to: &'a str,
+    subject: &'a str,
+    html_body: &'a str,
+    text_body: &'a str,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    headers: Vec<Header<'a>>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "PascalCase")]
+struct Header<'a> {
+    name: &'a str,
+    value: &'a str,
+}
+
+impl<'a> From<&'a EmailHeader> for Header<'a> {
+    fn from(header: &'a EmailHeader) -> Self {
+        Self {
+            name: &header.name,
+            value: &header.value,
+        }
+    }
+}
+
+/// Postmark's result for one message of a batch, in the same order as the request
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct BatchResponseItem {
+    error_code: i64,
+    message: String,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_retry_after, PostmarkClient, RetryPolicy, MAX_BATCH_SIZE};
+    use crate::domain::SubscriberEmail;
+    use crate::email_client::{BatchOutcome, Email, EmailHeader, EmailSender};
+    use chrono::{TimeZone, Utc};
+    use claim::{assert_err, assert_none, assert_ok, assert_some_eq};
+    use fake::faker::internet::en::SafeEmail;
+    use fake::faker::lorem::en::{Paragraph, Sentence};
+    use fake::{Fake, Faker};
+    use std::time::Duration;
+    use wiremock::matchers::{any, header, header_exists, method, path};
+    use wiremock::Request;
+    use wiremock::{Mock, MockServer, Respond, ResponseTemplate};
+
+    struct SendEmailBodyMatcher;
+
+    impl wiremock::Match for SendEmailBodyMatcher {
+        fn matches(&self, request: &Request) -> bool {
+            let result: Result<serde_json::Value, _> = serde_json::from_slice(&request.body);
+
+            if let Ok(body) = result {
+                dbg!(&body);
+                body.get("To").is_some()
+                    && body.get("Subject").is_some()
+                    && body.get("HtmlBody").is_some()
+                    && body.get("TextBody").is_some()
+            } else {
+                false
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn send_email_sends_the_expected_request() {
+        // Arrange
+        let mock_server = MockServer::start().await;
+        let email_client = email_client(mock_server.uri());
+
+        Mock::given(header_exists("X-Postmark-Server-Token"))
+            .and(header("Content-Type", "application/json"))
+            .and(path("/email"))
+            .and(method("POST"))
+            .and(SendEmailBodyMatcher)
+            .respond_with(ResponseTemplate::new(200))
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+
+        let subscriber_email = email();
+        let subject = subject();
+        let content = content();
+
+        // Act
+        let _ = email_client
+            .send_email(subscriber_email, &subject, &content, &content)
+            .await;
+
+        // Assert
+        // relies on Mock::expect
+    }
+
+    #[tokio::test]
+    async fn send_email_succeeds_if_the_server_returns_200() {
+        // Arrange
+        let mock_server = MockServer::start().await;
+        let email_client = email_client(mock_server.uri());
+
+        let subscriber_email = email();
+        let subject = subject();
+        let content = content();
+
+        Mock::given(any())
+            .respond_with(ResponseTemplate::new(200))
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+
+        // Act
+        let outcome = email_client
+            .send_email(subscriber_email, &subject, &content, &content)
+            .await;
+
+        // Assert
+        assert_ok!(outcome);
+    }
+
+    #[tokio::test]
+    async fn send_email_fails_if_the_server_returns_500() {
+        // Arrange
+        let mock_server = MockServer::start().await;
+        let email_client = email_client(mock_server.uri());
+
+        let subscriber_email = email();
+        let subject = subject();
+        let content = content();
+
+        Mock::given(any())
+            .respond_with(ResponseTemplate::new(500))
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+
+        // Act
+        let outcome = email_client
+            .send_email(subscriber_email, &subject, &content, &content)
+            .await;
+
+        // Assert
+        assert_err!(outcome);
+    }
+
+    #[tokio::test]
+    async fn send_email_times_out_if_the_server_takes_too_long() {
+        // Arrange
+        let mock_server = MockServer::start().await;
+        let email_client = email_client(mock_server.uri());
+
+        let subscriber_email = email();
+        let subject = subject();
+        let content = content();
+
+        let three_minutes = Duration::from_secs(180);
+        let response = ResponseTemplate::new(200).set_delay(three_minutes);
+        Mock::given(any())
+            .respond_with(response)
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+
+        // Act
+        let outcome = email_client
+            .send_email(subscriber_email, &subject, &content, &content)
+            .await;
+
+        // Assert
+        assert_err!(outcome);
+    }
+
+    #[tokio::test]
+    async fn send_email_retries_server_errors_until_one_succeeds() {
+        // Arrange
+        let mock_server = MockServer::start().await;
+        let email_client = email_client_with_retries(mock_server.uri(), fast_retries());
+
+        Mock::given(any())
+            .respond_with(ResponseTemplate::new(503))
+            .up_to_n_times(2)
+            .expect(2)
+            .mount(&mock_server)
+            .await;
+        Mock::given(any())
+            .respond_with(ResponseTemplate::new(200))
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+
+        // Act
+        let outcome = email_client
+            .send_email(email(), &subject(), &content(), &content())
+            .await;
+
+        // Assert
+        assert_ok!(outcome);
+    }
+
+    #[tokio::test]
+    async fn send_email_gives_up_after_the_maximum_number_of_attempts() {
+        // Arrange
+        let mock_server = MockServer::start().await;
+        let email_client = email_client_with_retries(mock_server.uri(), fast_retries());
+
+        Mock::given(any())
+            .respond_with(ResponseTemplate::new(500))
+            .expect(3)
+            .mount(&mock_server)
+            .await;
+
+        // Act
+        let outcome = email_client
+            .send_email(email(), &subject(), &content(), &content())
+            .await;
+
+        // Assert
+        assert_err!(outcome);
+    }
+
+    #[tokio::test]
+    async fn send_email_retries_rate_limited_requests() {
+        // Arrange
+        let mock_server = MockServer::start().await;
+        let email_client = email_client_with_retries(mock_server.uri(), fast_retries());
+
+        Mock::given(any())
+            .respond_with(ResponseTemplate::new(429).insert_header("Retry-After", "1"))
+            .up_to_n_times(1)
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+        Mock::given(any())
+            .respond_with(ResponseTemplate::new(200))
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+
+        // Act
+        let outcome = email_client
+            .send_email(email(), &subject(), &content(), &content())
+            .await;
+
+        // Assert
+        assert_ok!(outcome);
+    }
+
+    #[tokio::test]
+    async fn send_email_does_not_wait_longer_than_the_maximum_delay() {
+        // Arrange
+        let mock_server = MockServer::start().await;
+        let email_client = email_client_with_retries(mock_server.uri(), fast_retries());
+
+        Mock::given(any())
+            .respond_with(ResponseTemplate::new(429).insert_header("Retry-After", "3600"))
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+
+        // Act
+        let outcome = email_client
+            .send_email(email(), &subject(), &content(), &content())
+            .await;
+
+        // Assert
+        assert_err!(outcome);
+    }
+
+    #[tokio::test]
+    async fn send_email_does_not_retry_client_errors() {
+        // Arrange
+        let mock_server = MockServer::start().await;
+        let email_client = email_client_with_retries(mock_server.uri(), fast_retries());
+
+        Mock::given(any())
+            .respond_with(ResponseTemplate::new(422))
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+
+        // Act
+        let outcome = email_client
+            .send_email(email(), &subject(), &content(), &content())
+            .await;
+
+        // Assert
+        assert_err!(outcome);
+    }
+
+    #[tokio::test]
+    async fn send_includes_custom_headers() {
+        // Arrange
+        let mock_server = MockServer::start().await;
+        let email_client = email_client(mock_server.uri());
+        let email = Email {
+            headers: vec![EmailHeader::new(
+                "List-Unsubscribe-Post",
+                "List-Unsubscribe=One-Click",
+            )],
+            ..outgoing_email()
+        };
+
+        Mock::given(any())
+            .respond_with(ResponseTemplate::new(200))
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+
+        // Act
+        let outcome = email_client.send(&email).await;
+
+        // Assert
+        assert_ok!(outcome);
+        let request = &mock_server.received_requests().await.unwrap()[0];
+        let body: serde_json::Value = serde_json::from_slice(&request.body).unwrap();
+        assert_eq!(
+            body["Headers"],
+            serde_json::json!([{ "Name": "List-Unsubscribe-Post", "Value": "List-Unsubscribe=One-Click" }])
+        );
+    }
+
+    /// Accepts every message of a batch request
+    struct AcceptBatch;
+
+    impl Respond for AcceptBatch {
+        fn respond(&self, request: &Request) -> ResponseTemplate {
+            let messages: Vec<serde_json::Value> = serde_json::from_slice(&request.body).unwrap();
+            let results: Vec<_> = messages
+                .iter()
+                .map(|_| serde_json::json!({ "ErrorCode": 0, "Message": "OK" }))
+                .collect();
+
+            ResponseTemplate::new(200).set_body_json(results)
+        }
+    }
+
+    #[tokio::test]
+    async fn send_batch_splits_recipients_into_chunks_of_the_batch_size() {
+        // Arrange
+        let mock_server = MockServer::start().await;
+        let email_client = email_client(mock_server.uri());
+        let emails: Vec<_> = (0..MAX_BATCH_SIZE + 1).map(|_| outgoing_email()).collect();
+
+        Mock::given(path("/email/batch"))
+            .and(method("POST"))
+            .and(header_exists("X-Postmark-Server-Token"))
+            .respond_with(AcceptBatch)
+            .expect(2)
+            .mount(&mock_server)
+            .await;
+
+        // Act
+        let outcomes = email_client.send_batch(&emails).await;
+
+        // Assert
+        assert_eq!(outcomes.len(), emails.len());
+        assert!(outcomes
+            .iter()
+            .all(|outcome| matches!(outcome, BatchOutcome::Sent)));
+    }
+
+    #[tokio::test]
+    async fn send_batch_reports_the_result_of_each_message() {
+        // Arrange
+        let mock_server = MockServer::start().await;
+        let email_client = email_client(mock_server.uri());
+        let emails = vec![outgoing_email(), outgoing_email()];
+
+        let results = serde_json::json!([
+            { "ErrorCode": 0, "Message": "OK" },
+            { "ErrorCode": 406, "Message": "You tried to send to a recipient that has been marked as inactive." },
+        ]);
+        Mock::given(any())
+            .respond_with(ResponseTemplate::new(200).set_body_json(results))
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+
+        // Act
+        let outcomes = email_client.send_batch(&emails).await;
+
+        // Assert
+        assert!(matches!(outcomes[0], BatchOutcome::Sent));
+        assert!(matches!(
+            &outcomes[1],
+            BatchOutcome::Rejected { error_code: 406, message } if message.contains("inactive")
+        ));
+    }
+
+    #[tokio::test]
+    async fn send_batch_fails_every_message_if_the_request_fails() {
+        // Arrange
+        let mock_server = MockServer::start().await;
+        let email_client = email_client(mock_server.uri());
+        let emails = vec![outgoing_email(), outgoing_email()];
+
+        Mock::given(any())
+            .respond_with(ResponseTemplate::new(500))
+            .expect(1)
+            .mount(&mock_server)
+            .await;
+
+        // Act
+        let outcomes = email_client.send_batch(&emails).await;
+
+        // Assert
+        assert_eq!(outcomes.len(), 2);
+        assert!(outcomes
+            .iter()
+            .all(|outcome| matches!(outcome, BatchOutcome::Failed(_))));
+    }
+
+    #[test]
+    fn backoff_doubles_up_to_the_maximum_delay() {
+        let policy = RetryPolicy {
+            max_attempts: 10,
+            base_delay: Duration::from_millis(100),
+            max_delay: Duration::from_millis(500),
+            jitter: 0.0,
+        };
+
+        assert_eq!(policy.backoff(1), Duration::from_millis(100));
+        assert_eq!(policy.backoff(2), Duration::from_millis(200));
+        assert_eq!(policy.backoff(3), Duration::from_millis(400));
+        assert_eq!(policy.backoff(4), Duration::from_millis(500));
+        assert_eq!(policy.backoff(100), Duration::from_millis(500));
+    }
+
+    #[test]
+    fn jitter_only_shortens_the_delay() {
+        let policy = RetryPolicy {
+            max_attempts: 10,
+            base_delay: Duration::from_millis(100),
+            max_delay: Duration::from_millis(500),
+            jitter: 1.0,
+        };
+
+        for _ in 0..100 {
+            assert!(policy.backoff(2) <= Duration::from_millis(200));
+        }
+    }
+
+    #[test]
+    fn retry_after_accepts_seconds_and_http_dates() {
+        let now = Utc.ymd(2021, 4, 25).and_hms(8, 49, 37);
+
+        assert_some_eq!(parse_retry_after("120", now), Duration::from_secs(120));
+        assert_some_eq!(
+            parse_retry_after("Sun, 25 Apr 2021 08:50:07 GMT", now),
+            Duration::from_secs(30)
+        );
+        assert_some_eq!(
+            parse_retry_after("Sun, 25 Apr 2021 08:00:00 GMT", now),
+            Duration::from_secs(0)
+        );
+        assert_none!(parse_retry_after("soon", now));
+    }
+
+    /// Generate a random email without custom headers
+    fn outgoing_email() -> Email {
+        Email {
+            recipient: email(),
+            subject: subject(),
+            html_body: content(),
+            text_body: content(),
+            headers: vec![],
+        }
+    }
+
+    /// Generate a random email subject
+    fn subject() -> String {
+        Sentence(1..2).fake()
+    }
+
+    /// Generate a random email content
+    fn content() -> String {
+        Paragraph(1..10).fake()
+    }
+
+    /// Generate a random subscriber email
+    fn email() -> SubscriberEmail {
+        SubscriberEmail::parse(SafeEmail().fake()).unwrap()
+    }
+
+    /// Get a test instance of `PostmarkClient` that doesn't retry failed requests.
+    fn email_client(base_url: String) -> PostmarkClient {
+        email_client_with_retries(base_url, RetryPolicy::no_retries())
+    }
+
+    /// Get a test instance of `PostmarkClient` with the given retry policy.
+    fn email_client_with_retries(base_url: String, retry_policy: RetryPolicy) -> PostmarkClient {
+        let base_url = reqwest::Url::parse(&base_url).unwrap();
+        PostmarkClient::new(base_url, email(), Faker.fake(), retry_policy).unwrap()
+    }
+
+    /// A policy with three attempts and short delays, to keep the tests fast.
+    fn fast_retries() -> RetryPolicy {
+        RetryPolicy {
+            max_attempts: 3,
+            base_delay: Duration::from_millis(10),
+            max_delay: Duration::from_secs(2),
+            jitter: 0.5,
+        }
+    }
+}
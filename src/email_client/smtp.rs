@@ -0,0 +1,61 @@
+use super::{build_message, Email, EmailError, EmailSender};
+use crate::domain::SubscriberEmail;
+use async_trait::async_trait;
+use lettre::transport::smtp::authentication::Credentials;
+use lettre::{AsyncSmtpTransport, AsyncTransport, Tokio1Executor};
+use serde::Deserialize;
+use std::time::Duration;
+
+/// How the connection to the SMTP server is secured
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum SmtpTls {
+    /// Connect in plain text and upgrade with STARTTLS, usually on port 587
+    Starttls,
+    /// Connect over TLS from the start, usually on port 465
+    Implicit,
+    /// Never encrypt; only for local test servers
+    None,
+}
+
+/// Sends emails through any SMTP server
+pub struct SmtpEmailSender {
+    transport: AsyncSmtpTransport<Tokio1Executor>,
+    sender: SubscriberEmail,
+}
+
+impl SmtpEmailSender {
+    pub fn new(
+        host: &str,
+        port: u16,
+        tls: SmtpTls,
+        credentials: Option<(String, String)>,
+        sender: SubscriberEmail,
+    ) -> Result<Self, EmailError> {
+        let builder = match tls {
+            SmtpTls::Starttls => AsyncSmtpTransport::<Tokio1Executor>::starttls_relay(host)?,
+            SmtpTls::Implicit => AsyncSmtpTransport::<Tokio1Executor>::relay(host)?,
+            SmtpTls::None => AsyncSmtpTransport::<Tokio1Executor>::builder_dangerous(host),
+        };
+        let builder = builder.port(port).timeout(Some(Duration::from_secs(10)));
+        let builder = match credentials {
+            Some((username, password)) => builder.credentials(Credentials::new(username, password)),
+            None => builder,
+        };
+
+        Ok(Self {
+            transport: builder.build(),
+            sender,
+        })
+    }
+}
+
+#[async_trait]
+impl EmailSender for SmtpEmailSender {
+    async fn send(&self, email: &Email) -> Result<(), EmailError> {
+        let (envelope, message) = build_message(&self.sender, email)?;
+        self.transport.send_raw(&envelope, &message).await?;
+
+        Ok(())
+    }
+}
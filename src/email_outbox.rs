@@ -0,0 +1,188 @@
+use crate::domain::SubscriberEmail;
+use crate::email_client::EmailSender;
+use crate::issue_delivery_worker::{retry_delay, ExecutionOutcome, MAX_RETRIES};
+use crate::suppression_list::is_suppressed;
+use chrono::Utc;
+use sqlx::{PgPool, Postgres, Transaction};
+use std::sync::Arc;
+use std::time::Duration;
+use uuid::Uuid;
+
+/// Relays emails written to the outbox to the email provider
+pub struct EmailOutboxRelay {
+    pool: PgPool,
+    email_client: Arc<dyn EmailSender>,
+}
+
+impl EmailOutboxRelay {
+    pub fn new(pool: PgPool, email_client: Arc<dyn EmailSender>) -> Self {
+        Self { pool, email_client }
+    }
+
+    pub async fn run_until_stopped(self) -> Result<(), std::io::Error> {
+        loop {
+            match try_relay_email(&self.pool, self.email_client.as_ref()).await {
+                Ok(ExecutionOutcome::EmptyQueue) => {
+                    tokio::time::sleep(Duration::from_secs(1)).await;
+                }
+                Ok(ExecutionOutcome::TaskCompleted) => {}
+                Err(_) => {
+                    tokio::time::sleep(Duration::from_secs(1)).await;
+                }
+            }
+        }
+    }
+}
+
+/// Write an email to the outbox. It is only sent once the surrounding transaction commits.
+#[tracing::instrument(
+    name = "Add an email to the outbox",
+    skip(transaction, recipient, html_body, text_body)
+)]
+pub async fn enqueue_email(
+    transaction: &mut Transaction<'_, Postgres>,
+    recipient: &SubscriberEmail,
+    subject: &str,
+    html_body: &str,
+    text_body: &str,
+) -> Result<Uuid, sqlx::Error> {
+    let email_id = Uuid::new_v4();
+    sqlx::query!(
+        r#"
+            INSERT INTO email_outbox (email_id, recipient, subject, html_body, text_body, created_at)
+            VALUES ($1, $2, $3, $4, $5, $6)
+        "#,
+        email_id,
+        recipient.as_ref(),
+        subject,
+        html_body,
+        text_body,
+        Utc::now()
+    )
+    .execute(transaction)
+    .await?;
+
+    Ok(email_id)
+}
+
+#[tracing::instrument(
+    skip(pool, email_client),
+    fields(email_id = tracing::field::Empty),
+    err
+)]
+pub async fn try_relay_email(
+    pool: &PgPool,
+    email_client: &dyn EmailSender,
+) -> Result<ExecutionOutcome, sqlx::Error> {
+    let mut transaction = pool.begin().await?;
+    let email = sqlx::query_as!(
+        OutboxEmail,
+        r#"
+            SELECT email_id, recipient, subject, html_body, text_body, n_retries
+            FROM email_outbox
+            WHERE execute_after <= now()
+            ORDER BY created_at
+            FOR UPDATE
+            SKIP LOCKED
+            LIMIT 1
+        "#,
+    )
+    .fetch_optional(&mut transaction)
+    .await?;
+    let email = match email {
+        Some(email) => email,
+        None => return Ok(ExecutionOutcome::EmptyQueue),
+    };
+
+    tracing::Span::current().record("email_id", &tracing::field::display(email.email_id));
+
+    if is_suppressed(&mut transaction, &email.recipient).await? {
+        tracing::info!("Dropping an email to a suppressed address");
+        delete_email(&mut transaction, email.email_id).await?;
+        transaction.commit().await?;
+        return Ok(ExecutionOutcome::TaskCompleted);
+    }
+
+    match SubscriberEmail::parse(email.recipient.clone()) {
+        Ok(recipient) => {
+            let outcome = email_client
+                .send_email(
+                    recipient,
+                    &email.subject,
+                    &email.html_body,
+                    &email.text_body,
+                )
+                .await;
+
+            match outcome {
+                Ok(()) => delete_email(&mut transaction, email.email_id).await?,
+                Err(e) if email.n_retries + 1 >= MAX_RETRIES => {
+                    tracing::error!(
+                        "Giving up on relaying an email after {} attempts: {:?}",
+                        MAX_RETRIES,
+                        e
+                    );
+                    delete_email(&mut transaction, email.email_id).await?;
+                }
+                Err(e) => {
+                    tracing::warn!("Failed to relay an email. Retrying later: {:?}", e);
+                    reschedule_email(&mut transaction, &email).await?;
+                }
+            }
+        }
+        Err(e) => {
+            tracing::error!("Dropping an email with an invalid recipient: {}", e);
+            delete_email(&mut transaction, email.email_id).await?;
+        }
+    }
+
+    transaction.commit().await?;
+
+    Ok(ExecutionOutcome::TaskCompleted)
+}
+
+struct OutboxEmail {
+    email_id: Uuid,
+    recipient: String,
+    subject: String,
+    html_body: String,
+    text_body: String,
+    n_retries: i16,
+}
+
+async fn delete_email(
+    transaction: &mut Transaction<'_, Postgres>,
+    email_id: Uuid,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        r#"
+            DELETE FROM email_outbox WHERE email_id = $1
+        "#,
+        email_id
+    )
+    .execute(transaction)
+    .await?;
+
+    Ok(())
+}
+
+async fn reschedule_email(
+    transaction: &mut Transaction<'_, Postgres>,
+    email: &OutboxEmail,
+) -> Result<(), sqlx::Error> {
+    let delay = retry_delay(email.n_retries);
+    sqlx::query!(
+        r#"
+            UPDATE email_outbox
+            SET n_retries = n_retries + 1,
+                execute_after = now() + make_interval(secs => $2)
+            WHERE email_id = $1
+        "#,
+        email.email_id,
+        delay.as_secs_f64()
+    )
+    .execute(transaction)
+    .await?;
+
+    Ok(())
+}
@@ -0,0 +1,101 @@
+use actix_web::cookie::{Cookie, SameSite};
+use actix_web::{HttpMessage, HttpRequest};
+
+const FLASH_COOKIE_NAME: &str = "_flash";
+
+#[derive(Debug, PartialEq)]
+pub enum Level {
+    Info,
+    Error,
+}
+
+/// A message stored in a cookie across a redirect, and shown once by the page that reads it
+#[derive(Debug, PartialEq)]
+pub struct FlashMessage {
+    pub level: Level,
+    pub content: String,
+}
+
+impl FlashMessage {
+    pub fn info(content: impl Into<String>) -> Self {
+        Self {
+            level: Level::Info,
+            content: content.into(),
+        }
+    }
+
+    pub fn error(content: impl Into<String>) -> Self {
+        Self {
+            level: Level::Error,
+            content: content.into(),
+        }
+    }
+
+    /// Read the message left by the previous response, if any.
+    /// The caller should also send `removal_cookie` so that it is only shown once.
+    pub fn from_request(request: &HttpRequest) -> Option<Self> {
+        let cookie = request.cookie(FLASH_COOKIE_NAME)?;
+        Self::decode(cookie.value())
+    }
+
+    pub fn cookie(&self) -> Cookie<'static> {
+        Cookie::build(FLASH_COOKIE_NAME, self.encode())
+            .path("/")
+            .http_only(true)
+            .same_site(SameSite::Strict)
+            .finish()
+    }
+
+    pub fn removal_cookie() -> Cookie<'static> {
+        Cookie::build(FLASH_COOKIE_NAME, "").path("/").finish()
+    }
+
+    fn encode(&self) -> String {
+        let level = match self.level {
+            Level::Info => "info",
+            Level::Error => "error",
+        };
+        let content = base64::encode_config(&self.content, base64::URL_SAFE_NO_PAD);
+
+        format!("{}.{}", level, content)
+    }
+
+    fn decode(value: &str) -> Option<Self> {
+        let mut parts = value.splitn(2, '.');
+        let level = match parts.next()? {
+            "info" => Level::Info,
+            "error" => Level::Error,
+            _ => return None,
+        };
+        let content = base64::decode_config(parts.next()?, base64::URL_SAFE_NO_PAD).ok()?;
+        let content = String::from_utf8(content).ok()?;
+
+        Some(Self { level, content })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use claim::{assert_none, assert_some_eq};
+
+    #[test]
+    fn encoded_messages_can_be_decoded() {
+        let message = FlashMessage::error("Authentication failed; try again.");
+
+        let decoded = FlashMessage::decode(&message.encode());
+
+        assert_some_eq!(decoded, message);
+    }
+
+    #[test]
+    fn unknown_levels_are_ignored() {
+        let value = format!("warning.{}", base64::encode("Careful!"));
+        assert_none!(FlashMessage::decode(&value));
+    }
+
+    #[test]
+    fn malformed_content_is_ignored() {
+        assert_none!(FlashMessage::decode("info.not base64!"));
+    }
+}
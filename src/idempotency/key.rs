@@ -0,0 +1,47 @@
+#[derive(Debug)]
+pub struct IdempotencyKey(String);
+
+impl IdempotencyKey {
+    pub fn parse(s: String) -> Result<Self, String> {
+        let max_length = 50;
+
+        if s.is_empty() {
+            Err("The idempotency key cannot be empty".into())
+        } else if s.len() >= max_length {
+            Err(format!(
+                "The idempotency key must be shorter than {} characters",
+                max_length
+            ))
+        } else {
+            Ok(Self(s))
+        }
+    }
+}
+
+impl AsRef<str> for IdempotencyKey {
+    fn as_ref(&self) -> &str {
+        &self.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use claim::{assert_err, assert_ok};
+
+    #[test]
+    fn empty_string_is_rejected() {
+        assert_err!(IdempotencyKey::parse("".into()));
+    }
+
+    #[test]
+    fn a_50_character_key_is_rejected() {
+        assert_err!(IdempotencyKey::parse("a".repeat(50)));
+    }
+
+    #[test]
+    fn a_uuid_is_a_valid_key() {
+        let key = uuid::Uuid::new_v4().to_string();
+        assert_ok!(IdempotencyKey::parse(key));
+    }
+}
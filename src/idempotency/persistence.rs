@@ -0,0 +1,158 @@
+use super::IdempotencyKey;
+use actix_web::http::StatusCode;
+use actix_web::HttpResponse;
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use sqlx::{PgPool, Postgres, Transaction};
+use uuid::Uuid;
+
+/// A response as stored in the idempotency table, so that it can be replayed for retries
+pub struct SavedResponse {
+    pub status_code: u16,
+    pub headers: Vec<HeaderPair>,
+    pub body: Vec<u8>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct HeaderPair {
+    pub name: String,
+    pub value: String,
+}
+
+impl SavedResponse {
+    pub fn to_http_response(&self) -> HttpResponse {
+        let status_code =
+            StatusCode::from_u16(self.status_code).unwrap_or(StatusCode::INTERNAL_SERVER_ERROR);
+        let mut response = HttpResponse::build(status_code);
+        for HeaderPair { name, value } in &self.headers {
+            response.append_header((name.as_str(), value.as_str()));
+        }
+
+        response.body(self.body.clone())
+    }
+}
+
+pub enum NextAction {
+    /// This is the first request with the key; save the response in the transaction before committing
+    StartProcessing(Box<Transaction<'static, Postgres>>),
+    ReturnSavedResponse(HttpResponse),
+}
+
+/// Claim the idempotency key for this request, or fetch the response to an earlier one.
+/// A concurrent request with the same key waits on the row lock until the first one commits.
+#[tracing::instrument(name = "Check idempotency key", skip(pool))]
+pub async fn try_processing(
+    pool: &PgPool,
+    idempotency_key: &IdempotencyKey,
+    user_id: Uuid,
+) -> Result<NextAction, sqlx::Error> {
+    let mut transaction = pool.begin().await?;
+    let n_inserted_rows = sqlx::query!(
+        r#"
+            INSERT INTO idempotency (user_id, idempotency_key, created_at)
+            VALUES ($1, $2, $3)
+            ON CONFLICT DO NOTHING
+        "#,
+        user_id,
+        idempotency_key.as_ref(),
+        Utc::now()
+    )
+    .execute(&mut transaction)
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to execute query: {:?}", e);
+        e
+    })?
+    .rows_affected();
+
+    if n_inserted_rows > 0 {
+        return Ok(NextAction::StartProcessing(Box::new(transaction)));
+    }
+
+    match get_saved_response(pool, idempotency_key, user_id).await? {
+        Some(saved_response) => Ok(NextAction::ReturnSavedResponse(
+            saved_response.to_http_response(),
+        )),
+        None => {
+            tracing::error!("Expected a saved response for a used idempotency key, found none");
+            Err(sqlx::Error::RowNotFound)
+        }
+    }
+}
+
+#[tracing::instrument(name = "Get saved response", skip(pool))]
+async fn get_saved_response(
+    pool: &PgPool,
+    idempotency_key: &IdempotencyKey,
+    user_id: Uuid,
+) -> Result<Option<SavedResponse>, sqlx::Error> {
+    let row = sqlx::query!(
+        r#"
+            SELECT
+                response_status_code AS "response_status_code!",
+                response_headers AS "response_headers!",
+                response_body AS "response_body!"
+            FROM idempotency
+            WHERE user_id = $1 AND idempotency_key = $2
+        "#,
+        user_id,
+        idempotency_key.as_ref()
+    )
+    .fetch_optional(pool)
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to execute query: {:?}", e);
+        e
+    })?;
+
+    let row = match row {
+        Some(row) => row,
+        None => return Ok(None),
+    };
+    let headers = serde_json::from_value(row.response_headers)
+        .map_err(|e| sqlx::Error::Decode(Box::new(e)))?;
+
+    Ok(Some(SavedResponse {
+        status_code: row.response_status_code as u16,
+        headers,
+        body: row.response_body,
+    }))
+}
+
+#[tracing::instrument(
+    name = "Save response for idempotency key",
+    skip(transaction, response)
+)]
+pub async fn save_response(
+    transaction: &mut Transaction<'static, Postgres>,
+    idempotency_key: &IdempotencyKey,
+    user_id: Uuid,
+    response: &SavedResponse,
+) -> Result<(), sqlx::Error> {
+    let headers = serde_json::to_value(&response.headers)
+        .map_err(|e| sqlx::Error::Protocol(e.to_string()))?;
+
+    sqlx::query!(
+        r#"
+            UPDATE idempotency
+            SET
+                response_status_code = $3,
+                response_headers = $4,
+                response_body = $5
+            WHERE user_id = $1 AND idempotency_key = $2
+        "#,
+        user_id,
+        idempotency_key.as_ref(),
+        response.status_code as i16,
+        headers,
+        response.body
+    )
+    .execute(transaction)
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to execute query: {:?}", e);
+        e
+    })?;
+
+    Ok(())
+}
@@ -0,0 +1,648 @@
+use crate::configuration::Settings;
+use crate::domain::{SubscriberEmail, SubscriptionStatus};
+use crate::email_client::{BatchOutcome, Email, EmailHeader, EmailSender, MAX_BATCH_SIZE};
+use crate::email_outbox::EmailOutboxRelay;
+use crate::routes::{preferences_link, unsubscribe_link};
+use crate::signing::HmacSecret;
+use crate::startup::get_connection_pool;
+use crate::suppression_list::suppressed_among;
+use crate::token_cleanup::TokenCleanupWorker;
+use chrono::{DateTime, Utc};
+use sqlx::{PgPool, Postgres, Transaction};
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+use uuid::Uuid;
+
+/// Emails that have failed to send this many times are dropped from their queue
+pub(crate) const MAX_RETRIES: i16 = 10;
+const BASE_RETRY_DELAY: Duration = Duration::from_secs(10);
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(60 * 60);
+
+pub enum ExecutionOutcome {
+    TaskCompleted,
+    EmptyQueue,
+}
+
+/// Drains the issue delivery queue, sending the emails for many tasks in one batch,
+/// and the digests of the subscribers who asked for them
+pub struct IssueDeliveryWorker {
+    pool: PgPool,
+    email_client: Arc<dyn EmailSender>,
+    /// For the unsubscribe and preference links added to each email
+    base_url: String,
+    hmac_secret: HmacSecret,
+    preferences_link_ttl: chrono::Duration,
+}
+
+impl IssueDeliveryWorker {
+    pub fn new(
+        pool: PgPool,
+        email_client: Arc<dyn EmailSender>,
+        base_url: String,
+        hmac_secret: HmacSecret,
+        preferences_link_ttl: chrono::Duration,
+    ) -> Self {
+        Self {
+            pool,
+            email_client,
+            base_url,
+            hmac_secret,
+            preferences_link_ttl,
+        }
+    }
+
+    pub async fn run_until_stopped(self) -> Result<(), std::io::Error> {
+        loop {
+            let outcome = try_execute_task(
+                &self.pool,
+                self.email_client.as_ref(),
+                &self.base_url,
+                &self.hmac_secret,
+                self.preferences_link_ttl,
+            )
+            .await;
+            // Digests go out once no issue is waiting to be sent on its own
+            let outcome = match outcome {
+                Ok(ExecutionOutcome::EmptyQueue) => {
+                    try_send_digests(
+                        &self.pool,
+                        self.email_client.as_ref(),
+                        &self.base_url,
+                        &self.hmac_secret,
+                        self.preferences_link_ttl,
+                    )
+                    .await
+                }
+                outcome => outcome,
+            };
+            match outcome {
+                Ok(ExecutionOutcome::EmptyQueue) => {
+                    tokio::time::sleep(Duration::from_secs(10)).await;
+                }
+                Ok(ExecutionOutcome::TaskCompleted) => {}
+                Err(_) => {
+                    tokio::time::sleep(Duration::from_secs(1)).await;
+                }
+            }
+        }
+    }
+}
+
+/// Run the delivery worker, the email outbox relay and the token cleanup on their own,
+/// without the HTTP server
+pub async fn run_worker_until_stopped(config: Settings) -> Result<(), std::io::Error> {
+    let pool = get_connection_pool(&config.database)
+        .await
+        .expect("Failed to connect to Postgres");
+    let email_client = config.email_client.client();
+    let token_ttl = config.application.subscription_token_ttl();
+    let preferences_link_ttl = config.application.preferences_link_ttl();
+
+    let token_cleanup = TokenCleanupWorker::new(pool.clone(), token_ttl);
+    let delivery_worker = IssueDeliveryWorker::new(
+        pool.clone(),
+        email_client.clone(),
+        config.application.base_url,
+        HmacSecret(config.application.hmac_secret),
+        preferences_link_ttl,
+    );
+    let outbox_relay = EmailOutboxRelay::new(pool, email_client);
+
+    tokio::select! {
+        outcome = delivery_worker.run_until_stopped() => outcome,
+        outcome = outbox_relay.run_until_stopped() => outcome,
+        outcome = token_cleanup.run_until_stopped() => outcome,
+    }
+}
+
+#[tracing::instrument(
+    skip(pool, email_client, hmac_secret),
+    fields(n_tasks = tracing::field::Empty),
+    err
+)]
+pub async fn try_execute_task(
+    pool: &PgPool,
+    email_client: &dyn EmailSender,
+    base_url: &str,
+    hmac_secret: &HmacSecret,
+    preferences_link_ttl: chrono::Duration,
+) -> Result<ExecutionOutcome, sqlx::Error> {
+    let (mut transaction, tasks) = dequeue_tasks(pool).await?;
+    if tasks.is_empty() {
+        return Ok(ExecutionOutcome::EmptyQueue);
+    }
+
+    tracing::Span::current().record("n_tasks", &tasks.len());
+
+    let mut tasks_by_issue: HashMap<Uuid, Vec<Delivery>> = HashMap::new();
+    for delivery in deliverable(&mut transaction, tasks).await? {
+        tasks_by_issue
+            .entry(delivery.task.newsletter_issue_id)
+            .or_default()
+            .push(delivery);
+    }
+
+    let preferences_link_expires_at = Utc::now() + preferences_link_ttl;
+    for (newsletter_issue_id, deliveries) in tasks_by_issue {
+        let issue = get_issue(&mut transaction, newsletter_issue_id).await?;
+        let emails: Vec<_> = deliveries
+            .iter()
+            .map(|delivery| {
+                let links =
+                    IssueLinks::new(base_url, hmac_secret, delivery, preferences_link_expires_at);
+                issue_email(&issue, delivery.recipient.clone(), &links)
+            })
+            .collect();
+        let outcomes = email_client.send_batch(&emails).await;
+
+        for (delivery, outcome) in deliveries.iter().zip(outcomes) {
+            complete_task(&mut transaction, &delivery.task, &outcome).await?;
+        }
+    }
+
+    transaction.commit().await?;
+
+    Ok(ExecutionOutcome::TaskCompleted)
+}
+
+/// Send the issues held for digest subscribers whose day or week is over. Everything held for
+/// a subscriber on one list goes in a single email, so that its unsubscribe link covers it all.
+#[tracing::instrument(
+    skip(pool, email_client, hmac_secret),
+    fields(n_tasks = tracing::field::Empty),
+    err
+)]
+pub async fn try_send_digests(
+    pool: &PgPool,
+    email_client: &dyn EmailSender,
+    base_url: &str,
+    hmac_secret: &HmacSecret,
+    preferences_link_ttl: chrono::Duration,
+) -> Result<ExecutionOutcome, sqlx::Error> {
+    let (mut transaction, tasks) = dequeue_digest_tasks(pool).await?;
+    if tasks.is_empty() {
+        return Ok(ExecutionOutcome::EmptyQueue);
+    }
+
+    tracing::Span::current().record("n_tasks", &tasks.len());
+
+    // The tasks come oldest issue first, and keep that order within each digest
+    let mut digests: HashMap<(Uuid, Uuid), Vec<Delivery>> = HashMap::new();
+    for delivery in deliverable(&mut transaction, tasks).await? {
+        digests
+            .entry((delivery.subscriber_id, delivery.task.list_id))
+            .or_default()
+            .push(delivery);
+    }
+
+    let preferences_link_expires_at = Utc::now() + preferences_link_ttl;
+    let mut issues: HashMap<Uuid, NewsletterIssue> = HashMap::new();
+    let mut list_names: HashMap<Uuid, String> = HashMap::new();
+    let mut emails = Vec::with_capacity(digests.len());
+    for deliveries in digests.values() {
+        let first = &deliveries[0];
+        for delivery in deliveries {
+            let newsletter_issue_id = delivery.task.newsletter_issue_id;
+            if let Entry::Vacant(entry) = issues.entry(newsletter_issue_id) {
+                entry.insert(get_issue(&mut transaction, newsletter_issue_id).await?);
+            }
+        }
+        if let Entry::Vacant(entry) = list_names.entry(first.task.list_id) {
+            entry.insert(get_list_name(&mut transaction, first.task.list_id).await?);
+        }
+
+        let digest_issues: Vec<_> = deliveries
+            .iter()
+            .map(|delivery| &issues[&delivery.task.newsletter_issue_id])
+            .collect();
+        let links = IssueLinks::new(base_url, hmac_secret, first, preferences_link_expires_at);
+        emails.push(digest_email(
+            &list_names[&first.task.list_id],
+            &digest_issues,
+            first.recipient.clone(),
+            &links,
+        ));
+    }
+    let outcomes = email_client.send_batch(&emails).await;
+
+    // A digest is one email, so its issues share its outcome
+    for (deliveries, outcome) in digests.values().zip(outcomes) {
+        for delivery in deliveries {
+            complete_task(&mut transaction, &delivery.task, &outcome).await?;
+        }
+    }
+
+    transaction.commit().await?;
+
+    Ok(ExecutionOutcome::TaskCompleted)
+}
+
+/// Drop the tasks that must not be sent, keeping the rest with who to send them to
+async fn deliverable(
+    transaction: &mut PgTransaction,
+    tasks: Vec<Task>,
+) -> Result<Vec<Delivery>, sqlx::Error> {
+    let recipients: Vec<_> = tasks.iter().map(|t| t.subscriber_email.clone()).collect();
+    let suppressed = suppressed_among(transaction, &recipients).await?;
+
+    let mut deliveries = Vec::with_capacity(tasks.len());
+    for task in tasks {
+        if suppressed.contains(&task.subscriber_email) {
+            tracing::info!("Skipping a suppressed address");
+            delete_task(transaction, &task).await?;
+            continue;
+        }
+        let subscriber_id = match (task.subscriber_id, task.subscriber_status) {
+            (Some(subscriber_id), Some(SubscriptionStatus::Confirmed)) => subscriber_id,
+            _ => {
+                tracing::info!("Skipping a subscriber who is no longer confirmed");
+                delete_task(transaction, &task).await?;
+                continue;
+            }
+        };
+        match SubscriberEmail::parse(task.subscriber_email.clone()) {
+            Ok(recipient) => deliveries.push(Delivery {
+                task,
+                recipient,
+                subscriber_id,
+            }),
+            Err(e) => {
+                tracing::error!(
+                    "Skipping a confirmed subscriber. Their stored contact details are invalid: {}",
+                    e
+                );
+                delete_task(transaction, &task).await?;
+            }
+        }
+    }
+
+    Ok(deliveries)
+}
+
+/// Log and dequeue a task once its email was sent, or will never be, or reschedule it
+async fn complete_task(
+    transaction: &mut PgTransaction,
+    task: &Task,
+    outcome: &BatchOutcome,
+) -> Result<(), sqlx::Error> {
+    match outcome {
+        BatchOutcome::Sent => {
+            record_delivery(transaction, task).await?;
+            delete_task(transaction, task).await?;
+        }
+        BatchOutcome::Rejected {
+            error_code,
+            message,
+        } => {
+            tracing::error!(
+                newsletter_issue_id = %task.newsletter_issue_id,
+                "The email provider rejected an issue for a confirmed subscriber ({}): {}",
+                error_code,
+                message
+            );
+            delete_task(transaction, task).await?;
+        }
+        BatchOutcome::Failed(e) if task.n_retries + 1 >= MAX_RETRIES => {
+            tracing::error!(
+                newsletter_issue_id = %task.newsletter_issue_id,
+                "Giving up on delivering a newsletter issue to a confirmed subscriber after {} attempts: {:?}",
+                MAX_RETRIES,
+                e
+            );
+            delete_task(transaction, task).await?;
+        }
+        BatchOutcome::Failed(e) => {
+            tracing::warn!(
+                newsletter_issue_id = %task.newsletter_issue_id,
+                "Failed to deliver issue to a confirmed subscriber. Retrying later: {:?}",
+                e
+            );
+            reschedule_task(transaction, task).await?;
+        }
+    }
+
+    Ok(())
+}
+
+type PgTransaction = Transaction<'static, Postgres>;
+
+struct Task {
+    newsletter_issue_id: Uuid,
+    /// The list the issue was published to
+    list_id: Uuid,
+    subscriber_email: String,
+    n_retries: i16,
+    /// Missing if the subscriber was deleted after the issue was published
+    subscriber_id: Option<Uuid>,
+    /// Their status on the issue's list
+    subscriber_status: Option<SubscriptionStatus>,
+}
+
+/// A task that passed the checks before sending
+struct Delivery {
+    task: Task,
+    recipient: SubscriberEmail,
+    subscriber_id: Uuid,
+}
+
+/// Lock up to one provider batch worth of due tasks, skipping those held by other workers.
+/// Digest tasks are left for `dequeue_digest_tasks`.
+#[tracing::instrument(skip(pool))]
+async fn dequeue_tasks(pool: &PgPool) -> Result<(PgTransaction, Vec<Task>), sqlx::Error> {
+    let mut transaction = pool.begin().await?;
+    let tasks = sqlx::query_as!(
+        Task,
+        r#"
+            SELECT
+                q.newsletter_issue_id,
+                i.list_id,
+                q.subscriber_email,
+                q.n_retries,
+                s.id AS "subscriber_id?",
+                m.status AS "subscriber_status?: SubscriptionStatus"
+            FROM issue_delivery_queue q
+            JOIN newsletter_issues i ON i.newsletter_issue_id = q.newsletter_issue_id
+            LEFT JOIN subscriptions s ON lower(s.email) = lower(q.subscriber_email)
+            LEFT JOIN list_subscriptions m ON m.subscriber_id = s.id AND m.list_id = i.list_id
+            WHERE q.execute_after <= now() AND NOT q.digest
+            FOR UPDATE OF q
+            SKIP LOCKED
+            LIMIT $1
+        "#,
+        MAX_BATCH_SIZE as i64
+    )
+    .fetch_all(&mut transaction)
+    .await?;
+
+    Ok((transaction, tasks))
+}
+
+/// Lock every due digest task of up to one provider batch worth of subscribers, oldest issue
+/// first. Workers lock the subscribers, skipping those held by others, rather than the tasks,
+/// so that a digest is never split between two of them.
+#[tracing::instrument(skip(pool))]
+async fn dequeue_digest_tasks(pool: &PgPool) -> Result<(PgTransaction, Vec<Task>), sqlx::Error> {
+    let mut transaction = pool.begin().await?;
+    let subscriber_ids: Vec<Uuid> = sqlx::query!(
+        r#"
+            SELECT id
+            FROM subscriptions
+            WHERE lower(email) IN (
+                SELECT lower(subscriber_email)
+                FROM issue_delivery_queue
+                WHERE digest AND execute_after <= now()
+            )
+            FOR UPDATE
+            SKIP LOCKED
+            LIMIT $1
+        "#,
+        MAX_BATCH_SIZE as i64
+    )
+    .fetch_all(&mut transaction)
+    .await?
+    .into_iter()
+    .map(|r| r.id)
+    .collect();
+    let tasks = sqlx::query_as!(
+        Task,
+        r#"
+            SELECT
+                q.newsletter_issue_id,
+                i.list_id,
+                q.subscriber_email,
+                q.n_retries,
+                s.id AS "subscriber_id?",
+                m.status AS "subscriber_status?: SubscriptionStatus"
+            FROM issue_delivery_queue q
+            JOIN newsletter_issues i ON i.newsletter_issue_id = q.newsletter_issue_id
+            JOIN subscriptions s ON lower(s.email) = lower(q.subscriber_email)
+            LEFT JOIN list_subscriptions m ON m.subscriber_id = s.id AND m.list_id = i.list_id
+            WHERE q.digest AND q.execute_after <= now() AND s.id = ANY($1)
+            ORDER BY i.published_at
+            FOR UPDATE OF q
+        "#,
+        &subscriber_ids
+    )
+    .fetch_all(&mut transaction)
+    .await?;
+
+    Ok((transaction, tasks))
+}
+
+#[tracing::instrument(skip(transaction, task))]
+async fn delete_task(transaction: &mut PgTransaction, task: &Task) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        r#"
+            DELETE FROM issue_delivery_queue
+            WHERE newsletter_issue_id = $1 AND subscriber_email = $2
+        "#,
+        task.newsletter_issue_id,
+        task.subscriber_email
+    )
+    .execute(transaction)
+    .await?;
+
+    Ok(())
+}
+
+/// Log the issue as sent to the address, for when its owner asks what we hold about them
+#[tracing::instrument(skip(transaction, task))]
+async fn record_delivery(transaction: &mut PgTransaction, task: &Task) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        r#"
+            INSERT INTO issue_deliveries (newsletter_issue_id, subscriber_email, delivered_at)
+            VALUES ($1, $2, now())
+            ON CONFLICT (newsletter_issue_id, subscriber_email) DO NOTHING
+        "#,
+        task.newsletter_issue_id,
+        task.subscriber_email
+    )
+    .execute(transaction)
+    .await?;
+
+    Ok(())
+}
+
+/// Keep a failed delivery in the queue, backing off exponentially between attempts
+#[tracing::instrument(skip(transaction, task))]
+async fn reschedule_task(transaction: &mut PgTransaction, task: &Task) -> Result<(), sqlx::Error> {
+    let delay = retry_delay(task.n_retries);
+    sqlx::query!(
+        r#"
+            UPDATE issue_delivery_queue
+            SET n_retries = n_retries + 1,
+                execute_after = now() + make_interval(secs => $3)
+            WHERE newsletter_issue_id = $1 AND subscriber_email = $2
+        "#,
+        task.newsletter_issue_id,
+        task.subscriber_email,
+        delay.as_secs_f64()
+    )
+    .execute(transaction)
+    .await?;
+
+    Ok(())
+}
+
+pub(crate) fn retry_delay(n_retries: i16) -> Duration {
+    let exponent = n_retries.clamp(0, 16) as u32;
+    BASE_RETRY_DELAY
+        .checked_mul(2u32.pow(exponent))
+        .map_or(MAX_RETRY_DELAY, |delay| delay.min(MAX_RETRY_DELAY))
+}
+
+struct NewsletterIssue {
+    title: String,
+    text_content: String,
+    html_content: String,
+}
+
+/// The signed links in the footer of an issue, which are different for each subscriber
+struct IssueLinks {
+    unsubscribe: String,
+    preferences: String,
+}
+
+impl IssueLinks {
+    fn new(
+        base_url: &str,
+        hmac_secret: &HmacSecret,
+        delivery: &Delivery,
+        preferences_link_expires_at: DateTime<Utc>,
+    ) -> Self {
+        Self {
+            unsubscribe: unsubscribe_link(
+                base_url,
+                hmac_secret,
+                delivery.subscriber_id,
+                delivery.task.list_id,
+            ),
+            preferences: preferences_link(
+                base_url,
+                hmac_secret,
+                delivery.subscriber_id,
+                preferences_link_expires_at,
+            ),
+        }
+    }
+}
+
+/// The issue as sent to one subscriber
+fn issue_email(issue: &NewsletterIssue, recipient: SubscriberEmail, links: &IssueLinks) -> Email {
+    email_with_footer(
+        recipient,
+        issue.title.clone(),
+        &issue.html_content,
+        &issue.text_content,
+        links,
+    )
+}
+
+/// The issues held for one subscriber on one list, oldest first, as a single email
+fn digest_email(
+    list_name: &str,
+    issues: &[&NewsletterIssue],
+    recipient: SubscriberEmail,
+    links: &IssueLinks,
+) -> Email {
+    let html_content: Vec<_> = issues
+        .iter()
+        .map(|issue| {
+            format!(
+                "<h1>{}</h1>{}",
+                htmlescape::encode_minimal(&issue.title),
+                issue.html_content
+            )
+        })
+        .collect();
+    let text_content: Vec<_> = issues
+        .iter()
+        .map(|issue| format!("{}\n\n{}", issue.title, issue.text_content))
+        .collect();
+    email_with_footer(
+        recipient,
+        format!("Your {} digest", list_name),
+        &html_content.join("<hr>"),
+        &text_content.join("\n\n---\n\n"),
+        links,
+    )
+}
+
+/// Add the subscriber's unsubscribe and preference links in the footer and the
+/// `List-Unsubscribe` headers that let mail clients offer one-click unsubscribing (RFC 8058)
+fn email_with_footer(
+    recipient: SubscriberEmail,
+    subject: String,
+    html_content: &str,
+    text_content: &str,
+    links: &IssueLinks,
+) -> Email {
+    Email {
+        recipient,
+        subject,
+        html_body: format!(
+            r#"{}<p><a href="{}">Manage your preferences</a> | <a href="{}">Unsubscribe</a></p>"#,
+            html_content, links.preferences, links.unsubscribe
+        ),
+        text_body: format!(
+            "{}\n\nManage your preferences: {}\nUnsubscribe: {}",
+            text_content, links.preferences, links.unsubscribe
+        ),
+        headers: vec![
+            EmailHeader::new("List-Unsubscribe", format!("<{}>", links.unsubscribe)),
+            EmailHeader::new("List-Unsubscribe-Post", "List-Unsubscribe=One-Click"),
+        ],
+    }
+}
+
+#[tracing::instrument(skip(transaction))]
+async fn get_issue(
+    transaction: &mut PgTransaction,
+    newsletter_issue_id: Uuid,
+) -> Result<NewsletterIssue, sqlx::Error> {
+    let issue = sqlx::query_as!(
+        NewsletterIssue,
+        r#"
+            SELECT title, text_content, html_content
+            FROM newsletter_issues
+            WHERE newsletter_issue_id = $1
+        "#,
+        newsletter_issue_id
+    )
+    .fetch_one(transaction)
+    .await?;
+
+    Ok(issue)
+}
+
+#[tracing::instrument(skip(transaction))]
+async fn get_list_name(
+    transaction: &mut PgTransaction,
+    list_id: Uuid,
+) -> Result<String, sqlx::Error> {
+    let list = sqlx::query!("SELECT name FROM lists WHERE list_id = $1", list_id)
+        .fetch_one(transaction)
+        .await?;
+
+    Ok(list.name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn retry_delay_doubles_with_each_attempt() {
+        assert_eq!(retry_delay(0), BASE_RETRY_DELAY);
+        assert_eq!(retry_delay(1), BASE_RETRY_DELAY * 2);
+        assert_eq!(retry_delay(2), BASE_RETRY_DELAY * 4);
+    }
+
+    #[test]
+    fn retry_delay_is_capped() {
+        assert_eq!(retry_delay(MAX_RETRIES), MAX_RETRY_DELAY);
+        assert_eq!(retry_delay(i16::MAX), MAX_RETRY_DELAY);
+    }
+}
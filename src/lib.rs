@@ -1,6 +1,22 @@
+pub mod authentication;
 pub mod configuration;
+pub mod confirmation_email_throttle;
 pub mod domain;
 pub mod email_client;
+pub mod email_outbox;
+pub mod flash_messages;
+pub mod idempotency;
+pub mod issue_delivery_worker;
+pub mod personal_data;
+pub mod rate_limit;
+pub mod request_origin;
 pub mod routes;
+pub mod session;
+pub mod signing;
+pub mod signup_protection;
 pub mod startup;
+pub mod subscriber_repository;
+pub mod suppression_list;
 pub mod telemetry;
+pub mod token_cleanup;
+pub mod utils;
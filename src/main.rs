@@ -9,7 +9,7 @@ async fn main() -> std::io::Result<()> {
 
     let config = get_configuration().expect("Failed to read configuration");
     let app = Application::build(config).await?;
-    app.run_until_stopped().await?;
+    app.run_with_workers_until_stopped().await?;
 
     Ok(())
 }
@@ -0,0 +1,368 @@
+use crate::confirmation_email_throttle::ConfirmationEmailHistory;
+use crate::domain::{DigestFrequency, SubscriptionStatus};
+use crate::subscriber_repository::{ConsentEvent, ConsentEventType};
+use crate::suppression_list::SuppressionReason;
+use chrono::{DateTime, Utc};
+use sha2::{Digest, Sha256};
+use sqlx::{Postgres, Transaction};
+use uuid::Uuid;
+
+/// Everything stored about an email address, for a subject access request.
+/// Addresses are matched ignoring case, so there can be a record for each way it was typed.
+#[derive(Debug, serde::Serialize)]
+pub struct PersonalData {
+    pub email: String,
+    pub subscriptions: Vec<SubscriptionRecord>,
+    pub list_subscriptions: Vec<ListSubscriptionRecord>,
+    pub subscription_tokens: Vec<SubscriptionTokenRecord>,
+    pub consent_events: Vec<ConsentEvent>,
+    pub confirmation_emails: Vec<ConfirmationEmailHistory>,
+    /// Emails waiting in the outbox. Their bodies are left out, as they can hold live tokens.
+    pub queued_emails: Vec<QueuedEmailRecord>,
+    pub newsletter_deliveries: Vec<NewsletterDeliveryRecord>,
+    pub delivered_newsletters: Vec<DeliveredNewsletterRecord>,
+    pub suppressions: Vec<SuppressionRecord>,
+    /// Set if the address was erased, and its owner has not confirmed a subscription since
+    pub erased_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, serde::Serialize)]
+pub struct SubscriptionRecord {
+    pub id: Uuid,
+    pub email: String,
+    pub name: String,
+    pub subscribed_at: DateTime<Utc>,
+    pub digest_frequency: DigestFrequency,
+}
+
+#[derive(Debug, serde::Serialize)]
+pub struct ListSubscriptionRecord {
+    pub list: String,
+    pub status: SubscriptionStatus,
+    pub subscribed_at: DateTime<Utc>,
+}
+
+/// Only the hash of a token is stored, and it is not worth exporting
+#[derive(Debug, serde::Serialize)]
+pub struct SubscriptionTokenRecord {
+    pub created_at: DateTime<Utc>,
+    pub consumed_at: Option<DateTime<Utc>>,
+    /// The name to switch to once the token is used
+    pub pending_name: Option<String>,
+}
+
+#[derive(Debug, serde::Serialize)]
+pub struct QueuedEmailRecord {
+    pub subject: String,
+    pub created_at: DateTime<Utc>,
+    pub n_retries: i16,
+}
+
+/// A newsletter issue that has not been delivered yet
+#[derive(Debug, serde::Serialize)]
+pub struct NewsletterDeliveryRecord {
+    pub newsletter_issue_id: Uuid,
+    pub title: String,
+    pub n_retries: i16,
+    pub execute_after: DateTime<Utc>,
+}
+
+/// A newsletter issue that was sent to the address
+#[derive(Debug, serde::Serialize)]
+pub struct DeliveredNewsletterRecord {
+    pub newsletter_issue_id: Uuid,
+    pub title: String,
+    pub delivered_at: DateTime<Utc>,
+}
+
+#[derive(Debug, serde::Serialize)]
+pub struct SuppressionRecord {
+    pub reason: SuppressionReason,
+    pub suppressed_at: DateTime<Utc>,
+}
+
+/// How many rows were deleted from each table
+#[derive(Debug, Default, serde::Serialize)]
+pub struct ErasureReport {
+    pub subscriptions: u64,
+    pub list_subscriptions: u64,
+    pub subscription_tokens: u64,
+    pub consent_events: u64,
+    pub confirmation_emails: u64,
+    pub queued_emails: u64,
+    pub newsletter_deliveries: u64,
+    pub delivered_newsletters: u64,
+    pub suppressions: u64,
+}
+
+/// The tombstone of an erased address. Case is ignored, as most mail servers ignore it.
+pub fn hash_erased_email(email: &str) -> Vec<u8> {
+    Sha256::digest(email.to_lowercase().as_bytes()).to_vec()
+}
+
+/// Leave the tombstone of an erased address, see `erase_personal_data` to delete the rest
+async fn record_erasure(
+    transaction: &mut Transaction<'_, Postgres>,
+    email: &str,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        r#"
+            INSERT INTO erased_emails (email_hash, erased_at)
+            VALUES ($1, $2)
+            ON CONFLICT (email_hash) DO NOTHING
+        "#,
+        hash_erased_email(email),
+        Utc::now()
+    )
+    .execute(transaction)
+    .await?;
+    Ok(())
+}
+
+/// Remove the tombstone of an address whose owner confirmed a new subscription,
+/// as they chose to be signed up again
+pub async fn clear_erasure(
+    transaction: &mut Transaction<'_, Postgres>,
+    email: &str,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        "DELETE FROM erased_emails WHERE email_hash = $1",
+        hash_erased_email(email)
+    )
+    .execute(transaction)
+    .await?;
+    Ok(())
+}
+
+#[tracing::instrument(name = "Export personal data", skip(transaction, email))]
+pub async fn export_personal_data(
+    transaction: &mut Transaction<'_, Postgres>,
+    email: &str,
+) -> Result<PersonalData, sqlx::Error> {
+    let subscriptions = sqlx::query_as!(
+        SubscriptionRecord,
+        r#"
+            SELECT id, email, name, subscribed_at,
+                digest_frequency AS "digest_frequency: DigestFrequency"
+            FROM subscriptions
+            WHERE lower(email) = lower($1)
+            ORDER BY subscribed_at
+        "#,
+        email
+    )
+    .fetch_all(&mut *transaction)
+    .await?;
+    let list_subscriptions = sqlx::query_as!(
+        ListSubscriptionRecord,
+        r#"
+            SELECT l.slug AS list, m.status AS "status: SubscriptionStatus", m.subscribed_at
+            FROM list_subscriptions m
+            JOIN lists l ON l.list_id = m.list_id
+            JOIN subscriptions s ON s.id = m.subscriber_id
+            WHERE lower(s.email) = lower($1)
+            ORDER BY l.slug
+        "#,
+        email
+    )
+    .fetch_all(&mut *transaction)
+    .await?;
+    let subscription_tokens = sqlx::query_as!(
+        SubscriptionTokenRecord,
+        r#"
+            SELECT t.created_at, t.consumed_at, t.pending_name
+            FROM subscription_tokens t
+            JOIN subscriptions s ON s.id = t.subscriber_id
+            WHERE lower(s.email) = lower($1)
+            ORDER BY t.created_at
+        "#,
+        email
+    )
+    .fetch_all(&mut *transaction)
+    .await?;
+    let consent_events = sqlx::query_as!(
+        ConsentEvent,
+        r#"
+            SELECT c.subscriber_id, c.list_id, c.event_type AS "event_type: ConsentEventType",
+                c.occurred_at, c.ip_address, c.user_agent, c.form_source,
+                c.email_subject, c.email_html_body, c.email_text_body
+            FROM consent_events c
+            JOIN subscriptions s ON s.id = c.subscriber_id
+            WHERE lower(s.email) = lower($1)
+            ORDER BY c.occurred_at
+        "#,
+        email
+    )
+    .fetch_all(&mut *transaction)
+    .await?;
+    let confirmation_emails = sqlx::query_as!(
+        ConfirmationEmailHistory,
+        r#"
+            SELECT window_started_at, sent_in_window, last_sent_at
+            FROM confirmation_email_throttle
+            WHERE lower(recipient) = lower($1)
+        "#,
+        email
+    )
+    .fetch_all(&mut *transaction)
+    .await?;
+    let queued_emails = sqlx::query_as!(
+        QueuedEmailRecord,
+        r#"
+            SELECT subject, created_at, n_retries
+            FROM email_outbox
+            WHERE lower(recipient) = lower($1)
+            ORDER BY created_at
+        "#,
+        email
+    )
+    .fetch_all(&mut *transaction)
+    .await?;
+    let newsletter_deliveries = sqlx::query_as!(
+        NewsletterDeliveryRecord,
+        r#"
+            SELECT q.newsletter_issue_id, i.title, q.n_retries, q.execute_after
+            FROM issue_delivery_queue q
+            JOIN newsletter_issues i ON i.newsletter_issue_id = q.newsletter_issue_id
+            WHERE lower(q.subscriber_email) = lower($1)
+            ORDER BY i.published_at
+        "#,
+        email
+    )
+    .fetch_all(&mut *transaction)
+    .await?;
+    let delivered_newsletters = sqlx::query_as!(
+        DeliveredNewsletterRecord,
+        r#"
+            SELECT d.newsletter_issue_id, i.title, d.delivered_at
+            FROM issue_deliveries d
+            JOIN newsletter_issues i ON i.newsletter_issue_id = d.newsletter_issue_id
+            WHERE lower(d.subscriber_email) = lower($1)
+            ORDER BY d.delivered_at
+        "#,
+        email
+    )
+    .fetch_all(&mut *transaction)
+    .await?;
+    let suppressions = sqlx::query_as!(
+        SuppressionRecord,
+        r#"
+            SELECT reason AS "reason: SuppressionReason", suppressed_at
+            FROM suppressed_emails
+            WHERE lower(email) = lower($1)
+        "#,
+        email
+    )
+    .fetch_all(&mut *transaction)
+    .await?;
+    let erased_at = sqlx::query!(
+        "SELECT erased_at FROM erased_emails WHERE email_hash = $1",
+        hash_erased_email(email)
+    )
+    .fetch_optional(&mut *transaction)
+    .await?
+    .map(|r| r.erased_at);
+
+    Ok(PersonalData {
+        email: email.into(),
+        subscriptions,
+        list_subscriptions,
+        subscription_tokens,
+        consent_events,
+        confirmation_emails,
+        queued_emails,
+        newsletter_deliveries,
+        delivered_newsletters,
+        suppressions,
+        erased_at,
+    })
+}
+
+/// Delete everything stored about the address and leave a tombstone in its place.
+/// Erasing an unknown address still leaves a tombstone, which stays until its owner signs up
+/// again and confirms, so that the address is never brought back without their consent.
+/// Like the tombstone, every lookup ignores the case the address was typed in.
+#[tracing::instrument(name = "Erase personal data", skip(transaction, email))]
+pub async fn erase_personal_data(
+    transaction: &mut Transaction<'_, Postgres>,
+    email: &str,
+) -> Result<ErasureReport, sqlx::Error> {
+    let mut report = ErasureReport::default();
+    // Lock the subscribers first, like every other change to them
+    let subscriber_ids: Vec<Uuid> = sqlx::query!(
+        "SELECT id FROM subscriptions WHERE lower(email) = lower($1) FOR UPDATE",
+        email
+    )
+    .fetch_all(&mut *transaction)
+    .await?
+    .into_iter()
+    .map(|r| r.id)
+    .collect();
+
+    report.subscription_tokens = sqlx::query!(
+        "DELETE FROM subscription_tokens WHERE subscriber_id = ANY($1)",
+        &subscriber_ids
+    )
+    .execute(&mut *transaction)
+    .await?
+    .rows_affected();
+    report.list_subscriptions = sqlx::query!(
+        "DELETE FROM list_subscriptions WHERE subscriber_id = ANY($1)",
+        &subscriber_ids
+    )
+    .execute(&mut *transaction)
+    .await?
+    .rows_affected();
+    report.consent_events = sqlx::query!(
+        "DELETE FROM consent_events WHERE subscriber_id = ANY($1)",
+        &subscriber_ids
+    )
+    .execute(&mut *transaction)
+    .await?
+    .rows_affected();
+    report.subscriptions = sqlx::query!(
+        "DELETE FROM subscriptions WHERE id = ANY($1)",
+        &subscriber_ids
+    )
+    .execute(&mut *transaction)
+    .await?
+    .rows_affected();
+    report.confirmation_emails = sqlx::query!(
+        "DELETE FROM confirmation_email_throttle WHERE lower(recipient) = lower($1)",
+        email
+    )
+    .execute(&mut *transaction)
+    .await?
+    .rows_affected();
+    report.queued_emails = sqlx::query!(
+        "DELETE FROM email_outbox WHERE lower(recipient) = lower($1)",
+        email
+    )
+    .execute(&mut *transaction)
+    .await?
+    .rows_affected();
+    report.newsletter_deliveries = sqlx::query!(
+        "DELETE FROM issue_delivery_queue WHERE lower(subscriber_email) = lower($1)",
+        email
+    )
+    .execute(&mut *transaction)
+    .await?
+    .rows_affected();
+    report.delivered_newsletters = sqlx::query!(
+        "DELETE FROM issue_deliveries WHERE lower(subscriber_email) = lower($1)",
+        email
+    )
+    .execute(&mut *transaction)
+    .await?
+    .rows_affected();
+    // Nothing can be sent to the address without a subscriber, so the suppression can go too
+    report.suppressions = sqlx::query!(
+        "DELETE FROM suppressed_emails WHERE lower(email) = lower($1)",
+        email
+    )
+    .execute(&mut *transaction)
+    .await?
+    .rows_affected();
+    record_erasure(transaction, email).await?;
+
+    Ok(report)
+}
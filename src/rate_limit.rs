@@ -0,0 +1,298 @@
+use crate::request_origin::TrustedProxies;
+use crate::utils::log_error_response;
+use actix_web::dev::{Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::http::header::{HeaderValue, RETRY_AFTER};
+use actix_web::http::StatusCode;
+use actix_web::{Error, HttpResponse, ResponseError};
+use std::collections::HashMap;
+use std::future::{ready, Future, Ready};
+use std::net::{IpAddr, Ipv6Addr};
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+
+/// Past this many clients, buckets are evicted until at most half of them are left
+const MAX_TRACKED_CLIENTS: usize = 10_000;
+
+/// How many requests a client can make at once, and how quickly that allowance comes back
+#[derive(Debug, Clone, Copy)]
+pub struct Quota {
+    pub burst: u32,
+    pub per_minute: u32,
+}
+
+impl Quota {
+    fn refill_interval(&self) -> Duration {
+        Duration::from_secs(60) / self.per_minute.max(1)
+    }
+}
+
+/// Middleware that gives every client IP, or IPv6 /64, a token bucket, and rejects their requests
+/// with a 429 once it is empty.
+/// Clones share their buckets, so a single limiter can be used by every worker.
+#[derive(Clone)]
+pub struct RateLimiter {
+    quota: Quota,
+    trusted_proxies: Arc<TrustedProxies>,
+    buckets: Arc<Mutex<HashMap<IpAddr, Bucket>>>,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Bucket {
+    tokens: f64,
+    updated_at: Instant,
+}
+
+impl RateLimiter {
+    pub fn new(quota: Quota, trusted_proxies: TrustedProxies) -> Self {
+        Self {
+            quota,
+            trusted_proxies: Arc::new(trusted_proxies),
+            buckets: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// Take a token from the client's bucket, or say how long until one is available
+    fn check(&self, client: IpAddr, now: Instant) -> Result<(), Duration> {
+        let mut buckets = self.buckets.lock().unwrap();
+        if buckets.len() >= MAX_TRACKED_CLIENTS {
+            evict_buckets(&mut buckets, self.quota, now);
+        }
+
+        let bucket = buckets.entry(bucket_key(client)).or_insert(Bucket {
+            tokens: self.quota.burst as f64,
+            updated_at: now,
+        });
+        *bucket = bucket.refill(self.quota, now);
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(self.quota.refill_interval().mul_f64(1.0 - bucket.tokens))
+        }
+    }
+
+    fn client_ip(&self, request: &ServiceRequest) -> Option<IpAddr> {
+        self.trusted_proxies
+            .client_ip(request.peer_addr(), request.headers())
+    }
+}
+
+/// Every IPv6 client is given at least a /64, so all of its addresses share a bucket
+fn bucket_key(client: IpAddr) -> IpAddr {
+    match client {
+        IpAddr::V4(_) => client,
+        IpAddr::V6(address) => {
+            let prefix = u128::from(address) & !u128::from(u64::MAX);
+            IpAddr::V6(Ipv6Addr::from(prefix))
+        }
+    }
+}
+
+/// Forget the buckets that have refilled, as they are no different from new ones, then the
+/// least recently used until half of the map is free.
+/// Halving it means this only runs once every `MAX_TRACKED_CLIENTS / 2` new clients.
+fn evict_buckets(buckets: &mut HashMap<IpAddr, Bucket>, quota: Quota, now: Instant) {
+    buckets.retain(|_, bucket| bucket.refill(quota, now).tokens < quota.burst as f64);
+    let keep = MAX_TRACKED_CLIENTS / 2;
+    if buckets.len() > keep {
+        let mut last_used: Vec<Instant> =
+            buckets.values().map(|bucket| bucket.updated_at).collect();
+        let (_, cutoff, _) = last_used.select_nth_unstable(buckets.len() - keep - 1);
+        let cutoff = *cutoff;
+        buckets.retain(|_, bucket| bucket.updated_at > cutoff);
+    }
+}
+
+impl Bucket {
+    fn refill(self, quota: Quota, now: Instant) -> Self {
+        let elapsed = now.saturating_duration_since(self.updated_at);
+        let refilled = elapsed.as_secs_f64() / quota.refill_interval().as_secs_f64();
+        Self {
+            tokens: (self.tokens + refilled).min(quota.burst as f64),
+            updated_at: now,
+        }
+    }
+}
+
+#[derive(thiserror::Error, Debug)]
+#[error("Too many requests from {client}.")]
+pub struct RateLimitExceeded {
+    client: IpAddr,
+    retry_after: Duration,
+}
+
+impl ResponseError for RateLimitExceeded {
+    fn status_code(&self) -> StatusCode {
+        StatusCode::TOO_MANY_REQUESTS
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        let mut response = log_error_response(self);
+        // Round up, so that clients don't retry before a token is available
+        let retry_after = self.retry_after.as_secs() + (self.retry_after.subsec_nanos() > 0) as u64;
+        response
+            .headers_mut()
+            .insert(RETRY_AFTER, HeaderValue::from(retry_after));
+        response
+    }
+}
+
+impl<S, B> Transform<S, ServiceRequest> for RateLimiter
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Transform = RateLimitMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(RateLimitMiddleware {
+            service,
+            limiter: self.clone(),
+        }))
+    }
+}
+
+pub struct RateLimitMiddleware<S> {
+    service: S,
+    limiter: RateLimiter,
+}
+
+impl<S, B> Service<ServiceRequest> for RateLimitMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>>>>;
+
+    fn poll_ready(&self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(cx)
+    }
+
+    fn call(&self, request: ServiceRequest) -> Self::Future {
+        // Without a peer address there is nobody to limit, which only happens in tests
+        if let Some(client) = self.limiter.client_ip(&request) {
+            if let Err(retry_after) = self.limiter.check(client, Instant::now()) {
+                let error = RateLimitExceeded {
+                    client,
+                    retry_after,
+                };
+                return Box::pin(ready(Err(error.into())));
+            }
+        }
+        Box::pin(self.service.call(request))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Quota, RateLimiter, MAX_TRACKED_CLIENTS};
+    use crate::request_origin::TrustedProxies;
+    use std::net::IpAddr;
+    use std::time::{Duration, Instant};
+
+    fn ip(address: &str) -> IpAddr {
+        address.parse().unwrap()
+    }
+
+    fn limiter() -> RateLimiter {
+        let quota = Quota {
+            burst: 2,
+            per_minute: 6,
+        };
+        RateLimiter::new(quota, TrustedProxies::default())
+    }
+
+    #[test]
+    fn requests_are_rejected_once_the_burst_is_used_up() {
+        let limiter = limiter();
+        let now = Instant::now();
+
+        assert!(limiter.check(ip("203.0.113.1"), now).is_ok());
+        assert!(limiter.check(ip("203.0.113.1"), now).is_ok());
+        assert_eq!(
+            limiter.check(ip("203.0.113.1"), now),
+            Err(Duration::from_secs(10))
+        );
+    }
+
+    #[test]
+    fn clients_have_separate_buckets() {
+        let limiter = limiter();
+        let now = Instant::now();
+        limiter.check(ip("203.0.113.1"), now).unwrap();
+        limiter.check(ip("203.0.113.1"), now).unwrap();
+
+        assert!(limiter.check(ip("203.0.113.2"), now).is_ok());
+    }
+
+    #[test]
+    fn addresses_in_the_same_ipv6_64_share_a_bucket() {
+        let limiter = limiter();
+        let now = Instant::now();
+        limiter.check(ip("2001:db8:0:1::1"), now).unwrap();
+        limiter.check(ip("2001:db8:0:1:ffff::2"), now).unwrap();
+
+        assert!(limiter.check(ip("2001:db8:0:1::3"), now).is_err());
+        assert!(limiter.check(ip("2001:db8:0:2::1"), now).is_ok());
+    }
+
+    #[test]
+    fn the_least_recently_used_buckets_are_evicted_once_too_many_clients_are_tracked() {
+        let limiter = limiter();
+        let start = Instant::now();
+        let client = |i: usize| IpAddr::from([10, 0, (i / 256) as u8, (i % 256) as u8]);
+        for i in 0..MAX_TRACKED_CLIENTS {
+            limiter
+                .check(client(i), start + Duration::from_millis(i as u64))
+                .unwrap();
+        }
+        let now = start + Duration::from_millis(MAX_TRACKED_CLIENTS as u64);
+        let recent_client = client(MAX_TRACKED_CLIENTS - 1);
+        limiter.check(recent_client, now).unwrap();
+
+        limiter.check(ip("203.0.113.1"), now).unwrap();
+
+        let buckets = limiter.buckets.lock().unwrap();
+        assert!(buckets.len() <= MAX_TRACKED_CLIENTS / 2 + 1);
+        assert!(!buckets.contains_key(&client(0)));
+        drop(buckets);
+        assert!(limiter.check(recent_client, now).is_err());
+    }
+
+    #[test]
+    fn buckets_refill_over_time() {
+        let limiter = limiter();
+        let now = Instant::now();
+        limiter.check(ip("203.0.113.1"), now).unwrap();
+        limiter.check(ip("203.0.113.1"), now).unwrap();
+
+        let later = now + Duration::from_secs(4);
+        let retry_after = limiter.check(ip("203.0.113.1"), later).unwrap_err();
+        assert!((retry_after.as_secs_f64() - 6.0).abs() < 0.001);
+        let later = now + Duration::from_secs(11);
+        assert!(limiter.check(ip("203.0.113.1"), later).is_ok());
+        assert!(limiter.check(ip("203.0.113.1"), later).is_err());
+    }
+
+    #[test]
+    fn buckets_never_hold_more_than_the_burst() {
+        let limiter = limiter();
+        let now = Instant::now();
+        limiter.check(ip("203.0.113.1"), now).unwrap();
+
+        let later = now + Duration::from_secs(3600);
+        assert!(limiter.check(ip("203.0.113.1"), later).is_ok());
+        assert!(limiter.check(ip("203.0.113.1"), later).is_ok());
+        assert!(limiter.check(ip("203.0.113.1"), later).is_err());
+    }
+}
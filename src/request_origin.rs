@@ -0,0 +1,147 @@
+use actix_web::dev::Payload;
+use actix_web::http::header::{REFERER, USER_AGENT};
+use actix_web::http::HeaderMap;
+use actix_web::{web, FromRequest, HttpRequest};
+use std::convert::Infallible;
+use std::future::{ready, Ready};
+use std::net::{IpAddr, SocketAddr};
+
+/// The proxies in front of the app, whose `X-Forwarded-For` header can be believed
+#[derive(Debug, Clone, Default)]
+pub struct TrustedProxies(pub Vec<IpAddr>);
+
+impl TrustedProxies {
+    /// The address of whoever made the request, if it came in over the network
+    pub fn client_ip(&self, peer: Option<SocketAddr>, headers: &HeaderMap) -> Option<IpAddr> {
+        let forwarded_for = headers
+            .get("X-Forwarded-For")
+            .and_then(|value| value.to_str().ok());
+        resolve_client_ip(peer.map(|address| address.ip()), forwarded_for, &self.0)
+    }
+}
+
+/// Who sent a request and how they got to us, as far as the request says
+#[derive(Debug, Clone)]
+pub struct RequestOrigin {
+    pub client_ip: Option<IpAddr>,
+    pub user_agent: Option<String>,
+    pub referer: Option<String>,
+}
+
+impl FromRequest for RequestOrigin {
+    type Config = ();
+    type Error = Infallible;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(request: &HttpRequest, _payload: &mut Payload) -> Self::Future {
+        let client_ip = match request.app_data::<web::Data<TrustedProxies>>() {
+            Some(trusted_proxies) => {
+                trusted_proxies.client_ip(request.peer_addr(), request.headers())
+            }
+            None => request.peer_addr().map(|address| address.ip()),
+        };
+        let header = |name| {
+            request
+                .headers()
+                .get(name)
+                .and_then(|value| value.to_str().ok())
+                .map(String::from)
+        };
+
+        ready(Ok(Self {
+            client_ip,
+            user_agent: header(USER_AGENT),
+            referer: header(REFERER),
+        }))
+    }
+}
+
+/// Walk `X-Forwarded-For` from the nearest hop back, while the hop that added each entry is
+/// one of our proxies. Entries before the first untrusted hop could have been made up by the
+/// client, so they are ignored.
+fn resolve_client_ip(
+    peer: Option<IpAddr>,
+    forwarded_for: Option<&str>,
+    trusted_proxies: &[IpAddr],
+) -> Option<IpAddr> {
+    let mut client = peer?;
+    if let Some(forwarded_for) = forwarded_for {
+        for hop in forwarded_for.rsplit(',') {
+            if !trusted_proxies.contains(&client) {
+                break;
+            }
+            match hop.trim().parse() {
+                Ok(hop) => client = hop,
+                Err(_) => break,
+            }
+        }
+    }
+    Some(client)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::resolve_client_ip;
+    use std::net::IpAddr;
+
+    fn ip(address: &str) -> IpAddr {
+        address.parse().unwrap()
+    }
+
+    #[test]
+    fn the_peer_is_the_client_without_forwarding() {
+        let proxies = [ip("10.0.0.1")];
+
+        assert_eq!(
+            resolve_client_ip(Some(ip("203.0.113.1")), None, &proxies),
+            Some(ip("203.0.113.1"))
+        );
+        assert_eq!(resolve_client_ip(None, Some("203.0.113.1"), &proxies), None);
+    }
+
+    #[test]
+    fn forwarded_for_is_ignored_from_untrusted_peers() {
+        let proxies = [ip("10.0.0.1")];
+
+        assert_eq!(
+            resolve_client_ip(Some(ip("203.0.113.1")), Some("198.51.100.7"), &proxies),
+            Some(ip("203.0.113.1"))
+        );
+    }
+
+    #[test]
+    fn forwarded_for_names_the_client_behind_trusted_proxies() {
+        let proxies = [ip("10.0.0.1"), ip("10.0.0.2")];
+
+        assert_eq!(
+            resolve_client_ip(Some(ip("10.0.0.1")), Some("203.0.113.1"), &proxies),
+            Some(ip("203.0.113.1"))
+        );
+        assert_eq!(
+            resolve_client_ip(
+                Some(ip("10.0.0.1")),
+                Some("203.0.113.1, 10.0.0.2"),
+                &proxies
+            ),
+            Some(ip("203.0.113.1"))
+        );
+    }
+
+    #[test]
+    fn spoofed_entries_before_the_first_untrusted_hop_are_ignored() {
+        let proxies = [ip("10.0.0.1")];
+
+        assert_eq!(
+            resolve_client_ip(
+                Some(ip("10.0.0.1")),
+                Some("198.51.100.7, 203.0.113.1"),
+                &proxies
+            ),
+            Some(ip("203.0.113.1"))
+        );
+        assert_eq!(
+            resolve_client_ip(Some(ip("10.0.0.1")), Some("not-an-ip"), &proxies),
+            Some(ip("10.0.0.1"))
+        );
+    }
+}
@@ -0,0 +1,400 @@
+use crate::authentication::get_username;
+use crate::domain::{ListSlug, SubscriptionStatus};
+use crate::flash_messages::FlashMessage;
+use crate::personal_data::{erase_personal_data, export_personal_data};
+use crate::session::{delete_session, get_session_user_id, session_removal_cookie};
+use crate::subscriber_repository::{ConsentEvent, SubscriberRepository};
+use crate::utils::{error_chain_fmt, log_error_response};
+use actix_web::http::header::{ContentType, LOCATION};
+use actix_web::http::StatusCode;
+use actix_web::{web, HttpRequest, HttpResponse, ResponseError};
+use sqlx::PgPool;
+use uuid::Uuid;
+
+#[tracing::instrument(name = "Show the admin dashboard", skip(request, pool))]
+pub async fn admin_dashboard(
+    request: HttpRequest,
+    pool: web::Data<PgPool>,
+) -> Result<HttpResponse, HttpResponse> {
+    let user_id = get_session_user_id(&request, &pool)
+        .await
+        .map_err(|_| HttpResponse::InternalServerError().finish())?;
+    let user_id = match user_id {
+        Some(user_id) => user_id,
+        None => return Ok(redirect_to_login()),
+    };
+
+    let username = get_username(user_id, &pool)
+        .await
+        .map_err(|_| HttpResponse::InternalServerError().finish())?;
+    let counts = get_subscriber_counts(&pool)
+        .await
+        .map_err(|_| HttpResponse::InternalServerError().finish())?;
+
+    Ok(HttpResponse::Ok()
+        .content_type(ContentType::html())
+        .body(format!(
+            r#"<!DOCTYPE html>
+<html lang="en">
+<head>
+    <meta http-equiv="content-type" content="text/html; charset=utf-8">
+    <title>Admin dashboard</title>
+</head>
+<body>
+    <p>Welcome {}!</p>
+    <p>Confirmed subscribers: {}</p>
+    <p>Pending subscribers: {}</p>
+    <form name="logoutForm" action="/admin/logout" method="post">
+        <input type="submit" value="Logout">
+    </form>
+</body>
+</html>"#,
+            htmlescape::encode_minimal(&username),
+            counts.confirmed,
+            counts.pending
+        )))
+}
+
+#[tracing::instrument(name = "Log out", skip(request, pool))]
+pub async fn log_out(
+    request: HttpRequest,
+    pool: web::Data<PgPool>,
+) -> Result<HttpResponse, HttpResponse> {
+    delete_session(&request, &pool)
+        .await
+        .map_err(|_| HttpResponse::InternalServerError().finish())?;
+
+    Ok(HttpResponse::SeeOther()
+        .insert_header((LOCATION, "/login"))
+        .del_cookie(&session_removal_cookie())
+        .cookie(FlashMessage::info("You have successfully logged out.").cookie())
+        .finish())
+}
+
+#[derive(thiserror::Error)]
+pub enum ConsentHistoryError {
+    #[error("You must be logged in to see a subscriber's consent history.")]
+    Unauthorized,
+    #[error("There is no subscriber with the provided id.")]
+    UnknownSubscriber,
+    #[error("Failed to look up the session of the request.")]
+    SessionError(#[source] sqlx::Error),
+    #[error("Failed to acquire a Postgres connection from the pool.")]
+    PoolError(#[source] sqlx::Error),
+    #[error("Failed to retrieve the subscriber.")]
+    GetSubscriberError(#[source] sqlx::Error),
+    #[error("Failed to retrieve the subscriber's consent events.")]
+    GetConsentEventsError(#[source] sqlx::Error),
+}
+
+impl std::fmt::Debug for ConsentHistoryError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        error_chain_fmt(self, f)
+    }
+}
+
+impl ResponseError for ConsentHistoryError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            ConsentHistoryError::Unauthorized => StatusCode::UNAUTHORIZED,
+            ConsentHistoryError::UnknownSubscriber => StatusCode::NOT_FOUND,
+            ConsentHistoryError::SessionError(_)
+            | ConsentHistoryError::PoolError(_)
+            | ConsentHistoryError::GetSubscriberError(_)
+            | ConsentHistoryError::GetConsentEventsError(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        log_error_response(self)
+    }
+}
+
+#[derive(serde::Serialize)]
+struct ConsentHistory {
+    subscriber_id: Uuid,
+    email: String,
+    events: Vec<ConsentEvent>,
+}
+
+/// Everything recorded about how a subscriber consented, oldest first, as JSON
+#[tracing::instrument(
+    name = "Show a subscriber's consent history",
+    skip(request, pool, repository)
+)]
+pub async fn subscriber_consent_history(
+    request: HttpRequest,
+    pool: web::Data<PgPool>,
+    repository: web::Data<dyn SubscriberRepository>,
+    subscriber_id: web::Path<Uuid>,
+) -> Result<HttpResponse, ConsentHistoryError> {
+    get_session_user_id(&request, &pool)
+        .await
+        .map_err(ConsentHistoryError::SessionError)?
+        .ok_or(ConsentHistoryError::Unauthorized)?;
+
+    let subscriber_id = subscriber_id.into_inner();
+    let mut transaction = repository
+        .begin()
+        .await
+        .map_err(ConsentHistoryError::PoolError)?;
+    let subscriber = transaction
+        .get_subscriber(subscriber_id)
+        .await
+        .map_err(ConsentHistoryError::GetSubscriberError)?
+        .ok_or(ConsentHistoryError::UnknownSubscriber)?;
+    let events = transaction
+        .get_consent_events(subscriber_id)
+        .await
+        .map_err(ConsentHistoryError::GetConsentEventsError)?;
+
+    Ok(HttpResponse::Ok().json(&ConsentHistory {
+        subscriber_id,
+        email: subscriber.email,
+        events,
+    }))
+}
+
+#[derive(thiserror::Error)]
+pub enum MailingListError {
+    #[error("You must be logged in to manage mailing lists.")]
+    Unauthorized,
+    #[error("{0}")]
+    ValidationError(String),
+    #[error("There already is a list called {0}.")]
+    ListExists(String),
+    #[error("Failed to look up the session of the request.")]
+    SessionError(#[source] sqlx::Error),
+    #[error("Failed to acquire a Postgres connection from the pool.")]
+    PoolError(#[source] sqlx::Error),
+    #[error("Failed to retrieve the mailing lists.")]
+    GetListsError(#[source] sqlx::Error),
+    #[error("Failed to insert the new mailing list in the database.")]
+    CreateListError(#[source] sqlx::Error),
+    #[error("Failed to commit SQL transaction to create a mailing list.")]
+    TransactionCommitError(#[source] sqlx::Error),
+}
+
+impl std::fmt::Debug for MailingListError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        error_chain_fmt(self, f)
+    }
+}
+
+impl ResponseError for MailingListError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            MailingListError::Unauthorized => StatusCode::UNAUTHORIZED,
+            MailingListError::ValidationError(_) => StatusCode::BAD_REQUEST,
+            MailingListError::ListExists(_) => StatusCode::CONFLICT,
+            MailingListError::SessionError(_)
+            | MailingListError::PoolError(_)
+            | MailingListError::GetListsError(_)
+            | MailingListError::CreateListError(_)
+            | MailingListError::TransactionCommitError(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        log_error_response(self)
+    }
+}
+
+/// Every mailing list of this deployment, as JSON
+#[tracing::instrument(name = "List mailing lists", skip(request, pool, repository))]
+pub async fn get_mailing_lists(
+    request: HttpRequest,
+    pool: web::Data<PgPool>,
+    repository: web::Data<dyn SubscriberRepository>,
+) -> Result<HttpResponse, MailingListError> {
+    get_session_user_id(&request, &pool)
+        .await
+        .map_err(MailingListError::SessionError)?
+        .ok_or(MailingListError::Unauthorized)?;
+
+    let mut transaction = repository
+        .begin()
+        .await
+        .map_err(MailingListError::PoolError)?;
+    let lists = transaction
+        .get_lists()
+        .await
+        .map_err(MailingListError::GetListsError)?;
+
+    Ok(HttpResponse::Ok().json(&lists))
+}
+
+#[derive(serde::Deserialize)]
+pub struct NewListRequest {
+    slug: String,
+    name: String,
+}
+
+/// Add a mailing list that people can subscribe to and issues can be published to
+#[tracing::instrument(
+    name = "Create a mailing list",
+    skip(request, pool, repository, body),
+    fields(slug = %body.slug)
+)]
+pub async fn create_mailing_list(
+    request: HttpRequest,
+    pool: web::Data<PgPool>,
+    repository: web::Data<dyn SubscriberRepository>,
+    body: web::Json<NewListRequest>,
+) -> Result<HttpResponse, MailingListError> {
+    get_session_user_id(&request, &pool)
+        .await
+        .map_err(MailingListError::SessionError)?
+        .ok_or(MailingListError::Unauthorized)?;
+
+    let body = body.into_inner();
+    let slug = ListSlug::parse(body.slug).map_err(MailingListError::ValidationError)?;
+    let name = body.name.trim();
+    if name.is_empty() {
+        return Err(MailingListError::ValidationError(
+            "The list needs a name".into(),
+        ));
+    }
+    let mut transaction = repository
+        .begin()
+        .await
+        .map_err(MailingListError::PoolError)?;
+    let list_id = transaction
+        .create_list(&slug, name)
+        .await
+        .map_err(MailingListError::CreateListError)?
+        .ok_or_else(|| MailingListError::ListExists(slug.as_ref().into()))?;
+    // Our transaction just inserted it
+    let list = transaction
+        .get_list(list_id)
+        .await
+        .map_err(MailingListError::CreateListError)?
+        .ok_or(MailingListError::CreateListError(sqlx::Error::RowNotFound))?;
+    transaction
+        .commit()
+        .await
+        .map_err(MailingListError::TransactionCommitError)?;
+
+    Ok(HttpResponse::Created().json(&list))
+}
+
+#[derive(serde::Deserialize)]
+pub struct PersonalDataRequest {
+    email: String,
+}
+
+#[derive(thiserror::Error)]
+pub enum PersonalDataError {
+    #[error("You must be logged in to export or erase personal data.")]
+    Unauthorized,
+    #[error("Failed to look up the session of the request.")]
+    SessionError(#[source] sqlx::Error),
+    #[error("Failed to acquire a Postgres connection from the pool.")]
+    PoolError(#[source] sqlx::Error),
+    #[error("Failed to export the personal data stored about the address.")]
+    ExportError(#[source] sqlx::Error),
+    #[error("Failed to erase the personal data stored about the address.")]
+    EraseError(#[source] sqlx::Error),
+    #[error("Failed to commit SQL transaction to erase personal data.")]
+    TransactionCommitError(#[source] sqlx::Error),
+}
+
+impl std::fmt::Debug for PersonalDataError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        error_chain_fmt(self, f)
+    }
+}
+
+impl ResponseError for PersonalDataError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            PersonalDataError::Unauthorized => StatusCode::UNAUTHORIZED,
+            PersonalDataError::SessionError(_)
+            | PersonalDataError::PoolError(_)
+            | PersonalDataError::ExportError(_)
+            | PersonalDataError::EraseError(_)
+            | PersonalDataError::TransactionCommitError(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        log_error_response(self)
+    }
+}
+
+/// Everything stored about an email address, as JSON, to answer a subject access request
+#[tracing::instrument(name = "Export personal data", skip(request, pool, body))]
+pub async fn export_subscriber_data(
+    request: HttpRequest,
+    pool: web::Data<PgPool>,
+    body: web::Json<PersonalDataRequest>,
+) -> Result<HttpResponse, PersonalDataError> {
+    get_session_user_id(&request, &pool)
+        .await
+        .map_err(PersonalDataError::SessionError)?
+        .ok_or(PersonalDataError::Unauthorized)?;
+
+    let mut transaction = pool.begin().await.map_err(PersonalDataError::PoolError)?;
+    let personal_data = export_personal_data(&mut transaction, &body.email)
+        .await
+        .map_err(PersonalDataError::ExportError)?;
+
+    Ok(HttpResponse::Ok().json(&personal_data))
+}
+
+/// Delete everything stored about an email address, in one transaction, and say what was deleted
+#[tracing::instrument(name = "Erase personal data", skip(request, pool, body))]
+pub async fn erase_subscriber_data(
+    request: HttpRequest,
+    pool: web::Data<PgPool>,
+    body: web::Json<PersonalDataRequest>,
+) -> Result<HttpResponse, PersonalDataError> {
+    get_session_user_id(&request, &pool)
+        .await
+        .map_err(PersonalDataError::SessionError)?
+        .ok_or(PersonalDataError::Unauthorized)?;
+
+    let mut transaction = pool.begin().await.map_err(PersonalDataError::PoolError)?;
+    let report = erase_personal_data(&mut transaction, &body.email)
+        .await
+        .map_err(PersonalDataError::EraseError)?;
+    transaction
+        .commit()
+        .await
+        .map_err(PersonalDataError::TransactionCommitError)?;
+    tracing::info!(?report, "Erased personal data");
+
+    Ok(HttpResponse::Ok().json(&report))
+}
+
+fn redirect_to_login() -> HttpResponse {
+    HttpResponse::SeeOther()
+        .insert_header((LOCATION, "/login"))
+        .finish()
+}
+
+struct SubscriberCounts {
+    confirmed: i64,
+    pending: i64,
+}
+
+#[tracing::instrument(name = "Count subscribers", skip(pool))]
+async fn get_subscriber_counts(pool: &PgPool) -> Result<SubscriberCounts, sqlx::Error> {
+    sqlx::query_as!(
+        SubscriberCounts,
+        r#"
+            SELECT
+                COUNT(DISTINCT subscriber_id) FILTER (WHERE status = $1) AS "confirmed!",
+                COUNT(DISTINCT subscriber_id) FILTER (WHERE status = $2) AS "pending!"
+            FROM list_subscriptions
+        "#,
+        SubscriptionStatus::Confirmed as SubscriptionStatus,
+        SubscriptionStatus::PendingConfirmation as SubscriptionStatus
+    )
+    .fetch_one(pool)
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to execute query: {:?}", e);
+        e
+    })
+}
@@ -0,0 +1,100 @@
+use crate::authentication::{validate_credentials, AuthError, Credentials};
+use crate::flash_messages::{FlashMessage, Level};
+use crate::session::{create_session, session_cookie};
+use crate::startup::ApplicationBaseUrl;
+use actix_web::http::header::{ContentType, LOCATION};
+use actix_web::{web, HttpRequest, HttpResponse};
+use serde::Deserialize;
+use sqlx::PgPool;
+
+pub async fn login_form(request: HttpRequest) -> HttpResponse {
+    let flash_html = match FlashMessage::from_request(&request) {
+        Some(message) => {
+            let class = match message.level {
+                Level::Info => "info",
+                Level::Error => "error",
+            };
+            format!(
+                r#"<p class="{}"><i>{}</i></p>"#,
+                class,
+                htmlescape::encode_minimal(&message.content)
+            )
+        }
+        None => String::new(),
+    };
+
+    HttpResponse::Ok()
+        .content_type(ContentType::html())
+        .del_cookie(&FlashMessage::removal_cookie())
+        .body(format!(
+            r#"<!DOCTYPE html>
+<html lang="en">
+<head>
+    <meta http-equiv="content-type" content="text/html; charset=utf-8">
+    <title>Login</title>
+</head>
+<body>
+    {}
+    <form action="/login" method="post">
+        <label>Username
+            <input type="text" placeholder="Enter Username" name="username">
+        </label>
+        <label>Password
+            <input type="password" placeholder="Enter Password" name="password">
+        </label>
+        <button type="submit">Login</button>
+    </form>
+</body>
+</html>"#,
+            flash_html
+        ))
+}
+
+#[derive(Deserialize)]
+pub struct LoginFormData {
+    username: String,
+    password: String,
+}
+
+#[tracing::instrument(
+    name = "Log in",
+    skip(form, pool, base_url),
+    fields(username = %form.username, user_id = tracing::field::Empty)
+)]
+pub async fn login(
+    form: web::Form<LoginFormData>,
+    pool: web::Data<PgPool>,
+    base_url: web::Data<ApplicationBaseUrl>,
+) -> Result<HttpResponse, HttpResponse> {
+    let form = form.into_inner();
+    let credentials = Credentials {
+        username: form.username,
+        password: form.password,
+    };
+
+    let user_id = match validate_credentials(credentials, &pool).await {
+        Ok(user_id) => user_id,
+        Err(AuthError::InvalidCredentials(reason)) => {
+            tracing::warn!("Rejected a login attempt: {}", reason);
+            return Ok(HttpResponse::SeeOther()
+                .insert_header((LOCATION, "/login"))
+                .cookie(FlashMessage::error("Authentication failed").cookie())
+                .finish());
+        }
+        Err(AuthError::UnexpectedError(reason)) => {
+            tracing::error!("Failed to validate credentials: {}", reason);
+            return Err(HttpResponse::InternalServerError().finish());
+        }
+    };
+    tracing::Span::current().record("user_id", &tracing::field::display(&user_id));
+
+    let session_token = create_session(&pool, user_id)
+        .await
+        .map_err(|_| HttpResponse::InternalServerError().finish())?;
+    let secure = base_url.0.starts_with("https://");
+
+    Ok(HttpResponse::SeeOther()
+        .insert_header((LOCATION, "/admin/dashboard"))
+        .cookie(session_cookie(session_token, secure))
+        .finish())
+}
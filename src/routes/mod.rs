@@ -1,7 +1,19 @@
+mod admin;
 mod health_check;
+mod login;
+mod newsletters;
+mod postmark_webhook;
+mod preferences;
 mod subscriptions;
 mod subscriptions_confirm;
+mod unsubscribe;
 
+pub use admin::*;
 pub use health_check::*;
+pub use login::*;
+pub use newsletters::*;
+pub use postmark_webhook::*;
+pub use preferences::*;
 pub use subscriptions::*;
 pub use subscriptions_confirm::*;
+pub use unsubscribe::*;
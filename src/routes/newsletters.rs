@@ -0,0 +1,234 @@
+use crate::authentication::{basic_authentication, validate_credentials, AuthError};
+use crate::domain::{DigestFrequency, ListSlug, SubscriptionStatus};
+use crate::idempotency::{
+    save_response, try_processing, HeaderPair, IdempotencyKey, NextAction, SavedResponse,
+};
+use actix_web::http::header::{HeaderMap, HeaderValue, WWW_AUTHENTICATE};
+use actix_web::{web, HttpRequest, HttpResponse};
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use sqlx::{PgPool, Postgres, Transaction};
+use uuid::Uuid;
+
+#[derive(Deserialize)]
+pub struct BodyData {
+    title: String,
+    content: Content,
+    /// The slug of the list to publish to, the default list if missing
+    list: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct Content {
+    html: String,
+    text: String,
+}
+
+/// The outcome of a publish request, returned to the caller as JSON
+#[derive(Serialize)]
+pub struct PublishReport {
+    newsletter_issue_id: Uuid,
+    queued: u64,
+}
+
+#[tracing::instrument(
+    name = "Publish a newsletter issue",
+    skip(body, pool, request),
+    fields(title = %body.title, username = tracing::field::Empty, user_id = tracing::field::Empty)
+)]
+pub async fn publish_newsletter(
+    body: web::Json<BodyData>,
+    pool: web::Data<PgPool>,
+    request: HttpRequest,
+) -> Result<HttpResponse, HttpResponse> {
+    let credentials = basic_authentication(request.headers()).map_err(|e| {
+        tracing::warn!(
+            "Rejected a publish request without valid credentials: {}",
+            e
+        );
+        unauthorized()
+    })?;
+    tracing::Span::current().record("username", &tracing::field::display(&credentials.username));
+
+    let user_id = validate_credentials(credentials, &pool)
+        .await
+        .map_err(|e| match e {
+            AuthError::InvalidCredentials(reason) => {
+                tracing::warn!("Rejected a publish request: {}", reason);
+                unauthorized()
+            }
+            AuthError::UnexpectedError(reason) => {
+                tracing::error!("Failed to validate credentials: {}", reason);
+                HttpResponse::InternalServerError().finish()
+            }
+        })?;
+    tracing::Span::current().record("user_id", &tracing::field::display(&user_id));
+
+    let idempotency_key = get_idempotency_key(request.headers())?;
+    let list_slug = match &body.list {
+        Some(slug) => {
+            ListSlug::parse(slug.clone()).map_err(|e| HttpResponse::BadRequest().body(e))?
+        }
+        None => ListSlug::default_list(),
+    };
+    let list_id = get_list_id(&pool, &list_slug)
+        .await
+        .map_err(|_| HttpResponse::InternalServerError().finish())?
+        .ok_or_else(|| {
+            HttpResponse::NotFound().body(format!("There is no list called {}", list_slug.as_ref()))
+        })?;
+    let mut transaction = match &idempotency_key {
+        Some(idempotency_key) => match try_processing(&pool, idempotency_key, user_id)
+            .await
+            .map_err(|_| HttpResponse::InternalServerError().finish())?
+        {
+            NextAction::StartProcessing(transaction) => *transaction,
+            NextAction::ReturnSavedResponse(saved_response) => return Ok(saved_response),
+        },
+        None => pool
+            .begin()
+            .await
+            .map_err(|_| HttpResponse::InternalServerError().finish())?,
+    };
+
+    let newsletter_issue_id = insert_newsletter_issue(&mut transaction, &body, list_id)
+        .await
+        .map_err(|_| HttpResponse::InternalServerError().finish())?;
+    let queued = enqueue_delivery_tasks(&mut transaction, newsletter_issue_id, list_id)
+        .await
+        .map_err(|_| HttpResponse::InternalServerError().finish())?;
+
+    let report = PublishReport {
+        newsletter_issue_id,
+        queued,
+    };
+    let response = SavedResponse {
+        status_code: 202,
+        headers: vec![HeaderPair {
+            name: "Content-Type".into(),
+            value: "application/json".into(),
+        }],
+        body: serde_json::to_vec(&report)
+            .map_err(|_| HttpResponse::InternalServerError().finish())?,
+    };
+
+    if let Some(idempotency_key) = &idempotency_key {
+        save_response(&mut transaction, idempotency_key, user_id, &response)
+            .await
+            .map_err(|_| HttpResponse::InternalServerError().finish())?;
+    }
+    transaction
+        .commit()
+        .await
+        .map_err(|_| HttpResponse::InternalServerError().finish())?;
+
+    Ok(response.to_http_response())
+}
+
+fn get_idempotency_key(headers: &HeaderMap) -> Result<Option<IdempotencyKey>, HttpResponse> {
+    let header_value = match headers.get("Idempotency-Key") {
+        Some(header_value) => header_value,
+        None => return Ok(None),
+    };
+    let key = header_value
+        .to_str()
+        .map_err(|_| HttpResponse::BadRequest().body("The idempotency key must be valid UTF8"))?;
+
+    IdempotencyKey::parse(key.to_string())
+        .map(Some)
+        .map_err(|e| HttpResponse::BadRequest().body(e))
+}
+
+fn unauthorized() -> HttpResponse {
+    HttpResponse::Unauthorized()
+        .insert_header((
+            WWW_AUTHENTICATE,
+            HeaderValue::from_static(r#"Basic realm="publish""#),
+        ))
+        .finish()
+}
+
+#[tracing::instrument(name = "Find the list to publish to", skip(pool))]
+async fn get_list_id(pool: &PgPool, slug: &ListSlug) -> Result<Option<Uuid>, sqlx::Error> {
+    let list = sqlx::query!("SELECT list_id FROM lists WHERE slug = $1", slug.as_ref())
+        .fetch_optional(pool)
+        .await
+        .map_err(|e| {
+            tracing::error!("Failed to execute query: {:?}", e);
+            e
+        })?;
+
+    Ok(list.map(|l| l.list_id))
+}
+
+#[tracing::instrument(name = "Save newsletter issue details", skip(transaction, body))]
+async fn insert_newsletter_issue(
+    transaction: &mut Transaction<'_, Postgres>,
+    body: &BodyData,
+    list_id: Uuid,
+) -> Result<Uuid, sqlx::Error> {
+    let newsletter_issue_id = Uuid::new_v4();
+    sqlx::query!(
+        r#"
+            INSERT INTO newsletter_issues (
+                newsletter_issue_id, list_id, title, text_content, html_content, published_at
+            )
+            VALUES ($1, $2, $3, $4, $5, $6)
+        "#,
+        newsletter_issue_id,
+        list_id,
+        body.title,
+        body.content.text,
+        body.content.html,
+        Utc::now()
+    )
+    .execute(transaction)
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to execute query: {:?}", e);
+        e
+    })?;
+
+    Ok(newsletter_issue_id)
+}
+
+/// Queue one delivery per confirmed subscriber of the list, returning the number of queued deliveries.
+/// Deliveries to digest subscribers wait for the start of the next day or week (UTC), when the
+/// delivery worker gathers everything held for them into one digest email.
+#[tracing::instrument(name = "Enqueue issue delivery tasks", skip(transaction))]
+async fn enqueue_delivery_tasks(
+    transaction: &mut Transaction<'_, Postgres>,
+    newsletter_issue_id: Uuid,
+    list_id: Uuid,
+) -> Result<u64, sqlx::Error> {
+    let result = sqlx::query!(
+        r#"
+            INSERT INTO issue_delivery_queue
+                (newsletter_issue_id, subscriber_email, digest, execute_after)
+            SELECT $1, s.email, s.digest_frequency <> $6, CASE s.digest_frequency
+                WHEN $4 THEN date_trunc('day', now() AT TIME ZONE 'UTC') AT TIME ZONE 'UTC'
+                    + interval '1 day'
+                WHEN $5 THEN date_trunc('week', now() AT TIME ZONE 'UTC') AT TIME ZONE 'UTC'
+                    + interval '1 week'
+                ELSE now()
+            END
+            FROM subscriptions s
+            JOIN list_subscriptions m ON m.subscriber_id = s.id
+            WHERE m.list_id = $2 AND m.status = $3
+        "#,
+        newsletter_issue_id,
+        list_id,
+        SubscriptionStatus::Confirmed as SubscriptionStatus,
+        DigestFrequency::Daily as DigestFrequency,
+        DigestFrequency::Weekly as DigestFrequency,
+        DigestFrequency::Immediately as DigestFrequency
+    )
+    .execute(transaction)
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to execute query: {:?}", e);
+        e
+    })?;
+
+    Ok(result.rows_affected())
+}
@@ -0,0 +1,180 @@
+use crate::authentication::basic_authentication;
+use crate::configuration::PostmarkWebhookSettings;
+use crate::domain::{SubscriberEmail, SubscriptionStatus};
+use crate::subscriber_repository::SubscriberRepository;
+use crate::suppression_list::SuppressionReason;
+use crate::utils::{error_chain_fmt, log_error_response};
+use actix_web::http::header::{HeaderValue, WWW_AUTHENTICATE};
+use actix_web::http::StatusCode;
+use actix_web::{web, HttpRequest, HttpResponse, ResponseError};
+use subtle::ConstantTimeEq;
+
+/// Bounce types that mean the address will never accept our emails
+const HARD_BOUNCE_TYPES: [&str; 2] = ["HardBounce", "BadEmailAddress"];
+
+/// The fields we need from Postmark's webhook payloads, which carry many more
+#[derive(serde::Deserialize, Debug)]
+#[serde(tag = "RecordType")]
+pub enum PostmarkEvent {
+    Bounce {
+        #[serde(rename = "Type")]
+        bounce_type: String,
+        #[serde(rename = "Email")]
+        email: String,
+    },
+    SpamComplaint {
+        #[serde(rename = "Email")]
+        email: String,
+    },
+    /// Deliveries, opens, clicks and anything else we don't act on
+    #[serde(other)]
+    Other,
+}
+
+#[derive(thiserror::Error)]
+pub enum PostmarkWebhookError {
+    #[error("Failed to authenticate the webhook: {0}")]
+    AuthError(String),
+    #[error("The payload is not a valid Postmark webhook.")]
+    InvalidPayload(#[source] serde_json::Error),
+    #[error("Failed to acquire a Postgres connection from the pool.")]
+    PoolError(#[source] sqlx::Error),
+    #[error("Failed to retrieve the subscriber with the reported email.")]
+    GetSubscriberError(#[source] sqlx::Error),
+    #[error("Failed to update the subscriber status.")]
+    UpdateStatusError(#[source] sqlx::Error),
+    #[error("Failed to add the email to the suppression list.")]
+    SuppressEmailError(#[source] sqlx::Error),
+    #[error("Failed to commit SQL transaction to handle a Postmark webhook.")]
+    TransactionCommitError(#[source] sqlx::Error),
+}
+
+impl std::fmt::Debug for PostmarkWebhookError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        error_chain_fmt(self, f)
+    }
+}
+
+impl ResponseError for PostmarkWebhookError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            PostmarkWebhookError::AuthError(_) => StatusCode::UNAUTHORIZED,
+            PostmarkWebhookError::InvalidPayload(_) => StatusCode::BAD_REQUEST,
+            PostmarkWebhookError::PoolError(_)
+            | PostmarkWebhookError::GetSubscriberError(_)
+            | PostmarkWebhookError::UpdateStatusError(_)
+            | PostmarkWebhookError::SuppressEmailError(_)
+            | PostmarkWebhookError::TransactionCommitError(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        let mut response = log_error_response(self);
+        if let PostmarkWebhookError::AuthError(_) = self {
+            response.headers_mut().insert(
+                WWW_AUTHENTICATE,
+                HeaderValue::from_static(r#"Basic realm="postmark""#),
+            );
+        }
+        response
+    }
+}
+
+/// Stop emailing addresses that hard bounced or complained about spam
+#[tracing::instrument(
+    name = "Handle a Postmark webhook",
+    skip(body, request, repository, settings),
+    fields(record_type = tracing::field::Empty)
+)]
+pub async fn postmark_webhook(
+    body: web::Bytes,
+    request: HttpRequest,
+    repository: web::Data<dyn SubscriberRepository>,
+    settings: web::Data<PostmarkWebhookSettings>,
+) -> Result<HttpResponse, PostmarkWebhookError> {
+    check_credentials(&request, &settings)?;
+    let event: PostmarkEvent =
+        serde_json::from_slice(&body).map_err(PostmarkWebhookError::InvalidPayload)?;
+
+    let (email, status, reason) = match event {
+        PostmarkEvent::Bounce { bounce_type, email } => {
+            if !HARD_BOUNCE_TYPES.contains(&bounce_type.as_str()) {
+                tracing::info!(%bounce_type, "Ignoring a bounce that may be temporary");
+                return Ok(HttpResponse::Ok().finish());
+            }
+            (
+                email,
+                SubscriptionStatus::Bounced,
+                SuppressionReason::HardBounce,
+            )
+        }
+        PostmarkEvent::SpamComplaint { email } => (
+            email,
+            SubscriptionStatus::Complained,
+            SuppressionReason::SpamComplaint,
+        ),
+        PostmarkEvent::Other => return Ok(HttpResponse::Ok().finish()),
+    };
+    tracing::Span::current().record("record_type", &tracing::field::debug(&reason));
+
+    let mut transaction = repository
+        .begin()
+        .await
+        .map_err(PostmarkWebhookError::PoolError)?;
+    // Addresses we couldn't have stored can still be suppressed
+    if let Ok(subscriber_email) = SubscriberEmail::parse(email.clone()) {
+        let subscriber = transaction
+            .get_subscriber_by_email(&subscriber_email)
+            .await
+            .map_err(PostmarkWebhookError::GetSubscriberError)?;
+        if let Some(subscriber) = subscriber {
+            // The problem is with the address, so it affects every list
+            let subscriptions = transaction
+                .get_list_subscriptions(subscriber.id)
+                .await
+                .map_err(PostmarkWebhookError::GetSubscriberError)?;
+            for subscription in subscriptions {
+                if subscription.status.can_transition_to(status) {
+                    transaction
+                        .update_list_status(subscriber.id, subscription.list_id, status)
+                        .await
+                        .map_err(PostmarkWebhookError::UpdateStatusError)?;
+                }
+            }
+        }
+    }
+    transaction
+        .suppress_email(&email, reason)
+        .await
+        .map_err(PostmarkWebhookError::SuppressEmailError)?;
+    transaction
+        .commit()
+        .await
+        .map_err(PostmarkWebhookError::TransactionCommitError)?;
+
+    Ok(HttpResponse::Ok().finish())
+}
+
+fn check_credentials(
+    request: &HttpRequest,
+    settings: &PostmarkWebhookSettings,
+) -> Result<(), PostmarkWebhookError> {
+    let credentials =
+        basic_authentication(request.headers()).map_err(PostmarkWebhookError::AuthError)?;
+
+    let username_matches = credentials
+        .username
+        .as_bytes()
+        .ct_eq(settings.username.as_bytes());
+    let password_matches = credentials
+        .password
+        .as_bytes()
+        .ct_eq(settings.password.as_bytes());
+    if bool::from(username_matches & password_matches) {
+        Ok(())
+    } else {
+        Err(PostmarkWebhookError::AuthError(
+            "The username or password is wrong".into(),
+        ))
+    }
+}
@@ -0,0 +1,551 @@
+use crate::domain::{DigestFrequency, SubscriberName, SubscriptionStatus};
+use crate::request_origin::RequestOrigin;
+use crate::routes::consent_event;
+use crate::signing::HmacSecret;
+use crate::subscriber_repository::{
+    ConsentEvent, ConsentEventType, ListSubscription, MailingList, SubscriberRepository,
+    SubscriberTransaction,
+};
+use crate::utils::{error_chain_fmt, log_error_response};
+use actix_web::http::header::ContentType;
+use actix_web::http::StatusCode;
+use actix_web::{web, HttpResponse, ResponseError};
+use chrono::{DateTime, TimeZone, Utc};
+use std::collections::HashMap;
+use uuid::Uuid;
+
+/// The link to the preference page included in every email, signed so that it works without
+/// an account. Unlike the unsubscribe link it can change a lot, so it stops working after a while.
+pub fn preferences_link(
+    base_url: &str,
+    hmac_secret: &HmacSecret,
+    subscriber_id: Uuid,
+    expires_at: DateTime<Utc>,
+) -> String {
+    let expires_at = expires_at.timestamp();
+    let signature = hmac_secret.sign(&signed_message(subscriber_id, expires_at));
+    format!(
+        "{}/subscriptions/preferences?token={}.{}.{}",
+        base_url, subscriber_id, expires_at, signature
+    )
+}
+
+/// Prefixed so that a signature for another kind of link can't be reused here
+fn signed_message(subscriber_id: Uuid, expires_at: i64) -> String {
+    format!("preferences:{}:{}", subscriber_id, expires_at)
+}
+
+#[derive(Debug, PartialEq)]
+enum PreferencesToken {
+    Valid { subscriber_id: Uuid },
+    Expired,
+}
+
+/// Returns `None` if the token wasn't made by `preferences_link`
+fn parse_token(
+    hmac_secret: &HmacSecret,
+    token: &str,
+    now: DateTime<Utc>,
+) -> Option<PreferencesToken> {
+    let parts: Vec<_> = token.split('.').collect();
+    let (subscriber_id, expires_at, signature) = match parts.as_slice() {
+        [subscriber_id, expires_at, signature] => (subscriber_id, expires_at, signature),
+        _ => return None,
+    };
+    let subscriber_id = Uuid::parse_str(subscriber_id).ok()?;
+    let expires_at: i64 = expires_at.parse().ok()?;
+
+    if !hmac_secret.verify(&signed_message(subscriber_id, expires_at), signature) {
+        return None;
+    }
+    if Utc.timestamp_opt(expires_at, 0).single()? < now {
+        Some(PreferencesToken::Expired)
+    } else {
+        Some(PreferencesToken::Valid { subscriber_id })
+    }
+}
+
+#[derive(serde::Deserialize)]
+pub struct PreferencesParameters {
+    token: String,
+}
+
+/// The lists are checkboxes sharing the name `list`, which serde can't collect into a struct,
+/// so the form is read as a sequence of pairs
+struct PreferencesForm {
+    name: Option<String>,
+    digest_frequency: Option<String>,
+    lists: Vec<String>,
+    unsubscribe_all: bool,
+}
+
+impl PreferencesForm {
+    fn from_pairs(pairs: Vec<(String, String)>) -> Self {
+        let mut form = PreferencesForm {
+            name: None,
+            digest_frequency: None,
+            lists: vec![],
+            unsubscribe_all: false,
+        };
+        for (key, value) in pairs {
+            match key.as_str() {
+                "name" => form.name = Some(value),
+                "digest_frequency" => form.digest_frequency = Some(value),
+                "list" => form.lists.push(value),
+                "unsubscribe_all" => form.unsubscribe_all = value == "true",
+                _ => {}
+            }
+        }
+        form
+    }
+}
+
+#[derive(thiserror::Error)]
+pub enum PreferencesError {
+    #[error("The preference link is invalid.")]
+    InvalidToken,
+    #[error("{0}")]
+    ValidationError(String),
+    #[error("Failed to acquire a Postgres connection from the pool.")]
+    PoolError(#[source] sqlx::Error),
+    #[error("Failed to retrieve the subscriber and their lists.")]
+    GetSubscriberError(#[source] sqlx::Error),
+    #[error("Failed to save the subscriber's preferences.")]
+    UpdatePreferencesError(#[source] sqlx::Error),
+    #[error("Failed to change which lists the subscriber is on.")]
+    UpdateListsError(#[source] sqlx::Error),
+    #[error("Failed to record the subscriber's consent.")]
+    RecordConsentError(#[source] sqlx::Error),
+    #[error("Failed to commit SQL transaction to save a subscriber's preferences.")]
+    TransactionCommitError(#[source] sqlx::Error),
+}
+
+impl std::fmt::Debug for PreferencesError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        error_chain_fmt(self, f)
+    }
+}
+
+impl ResponseError for PreferencesError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            PreferencesError::InvalidToken | PreferencesError::ValidationError(_) => {
+                StatusCode::BAD_REQUEST
+            }
+            PreferencesError::PoolError(_)
+            | PreferencesError::GetSubscriberError(_)
+            | PreferencesError::UpdatePreferencesError(_)
+            | PreferencesError::UpdateListsError(_)
+            | PreferencesError::RecordConsentError(_)
+            | PreferencesError::TransactionCommitError(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        log_error_response(self)
+    }
+}
+
+/// Every email has a fresh link, so there is no need to offer a new one here
+fn expired_link_page() -> HttpResponse {
+    HttpResponse::Gone().content_type(ContentType::html()).body(
+        r#"<!DOCTYPE html>
+<html lang="en">
+<head>
+    <meta http-equiv="content-type" content="text/html; charset=utf-8">
+    <title>Preference link expired</title>
+</head>
+<body>
+    <p>This link has expired. The link at the bottom of our latest email will work.</p>
+</body>
+</html>"#,
+    )
+}
+
+fn frequency_label(frequency: DigestFrequency) -> &'static str {
+    match frequency {
+        DigestFrequency::Immediately => "Each issue as soon as it is published",
+        DigestFrequency::Daily => "One digest a day, at midnight UTC",
+        DigestFrequency::Weekly => "One digest a week, on Monday at midnight UTC",
+    }
+}
+
+/// A checkbox per list, ticked for the lists they are on. Lists we can't deliver to them any
+/// more are shown, but can't be changed from here.
+fn list_checkbox(list: &MailingList, status: Option<SubscriptionStatus>) -> String {
+    let (checked, disabled, note) = match status {
+        Some(SubscriptionStatus::Confirmed) => (" checked", "", ""),
+        Some(SubscriptionStatus::PendingConfirmation) => {
+            (" checked", "", " (saving confirms your subscription)")
+        }
+        None | Some(SubscriptionStatus::Unsubscribed) => ("", "", ""),
+        Some(SubscriptionStatus::Bounced)
+        | Some(SubscriptionStatus::Complained)
+        | Some(SubscriptionStatus::Suppressed) => {
+            ("", " disabled", " (we can no longer send to your address)")
+        }
+    };
+    format!(
+        r#"<label><input type="checkbox" name="list" value="{}"{}{}> {}{}</label>"#,
+        htmlescape::encode_attribute(&list.slug),
+        checked,
+        disabled,
+        htmlescape::encode_minimal(&list.name),
+        note
+    )
+}
+
+#[tracing::instrument(
+    name = "Show the preference page",
+    skip(parameters, repository, hmac_secret),
+    fields(subscriber_id = tracing::field::Empty)
+)]
+pub async fn preferences_form(
+    parameters: web::Query<PreferencesParameters>,
+    repository: web::Data<dyn SubscriberRepository>,
+    hmac_secret: web::Data<HmacSecret>,
+) -> Result<HttpResponse, PreferencesError> {
+    let subscriber_id = match parse_token(&hmac_secret, &parameters.token, Utc::now()) {
+        Some(PreferencesToken::Valid { subscriber_id }) => subscriber_id,
+        Some(PreferencesToken::Expired) => return Ok(expired_link_page()),
+        None => return Err(PreferencesError::InvalidToken),
+    };
+    tracing::Span::current().record("subscriber_id", &tracing::field::display(&subscriber_id));
+
+    let mut transaction = repository
+        .begin()
+        .await
+        .map_err(PreferencesError::PoolError)?;
+    // They may have been erased since the email was sent
+    let subscriber = transaction
+        .get_subscriber(subscriber_id)
+        .await
+        .map_err(PreferencesError::GetSubscriberError)?
+        .ok_or(PreferencesError::InvalidToken)?;
+    let lists = transaction
+        .get_lists()
+        .await
+        .map_err(PreferencesError::GetSubscriberError)?;
+    let statuses: HashMap<Uuid, SubscriptionStatus> = transaction
+        .get_list_subscriptions(subscriber_id)
+        .await
+        .map_err(PreferencesError::GetSubscriberError)?
+        .into_iter()
+        .map(|m| (m.list_id, m.status))
+        .collect();
+
+    let list_checkboxes: Vec<_> = lists
+        .iter()
+        .map(|list| list_checkbox(list, statuses.get(&list.id).copied()))
+        .collect();
+    let frequency_radios: Vec<_> = DigestFrequency::ALL
+        .iter()
+        .map(|frequency| {
+            format!(
+                r#"<label><input type="radio" name="digest_frequency" value="{}"{}> {}</label>"#,
+                frequency.as_str(),
+                if *frequency == subscriber.digest_frequency {
+                    " checked"
+                } else {
+                    ""
+                },
+                frequency_label(*frequency)
+            )
+        })
+        .collect();
+
+    Ok(HttpResponse::Ok()
+        .content_type(ContentType::html())
+        .body(format!(
+            r#"<!DOCTYPE html>
+<html lang="en">
+<head>
+    <meta http-equiv="content-type" content="text/html; charset=utf-8">
+    <title>Your preferences</title>
+</head>
+<body>
+    <form action="/subscriptions/preferences?token={token}" method="post">
+        <label>Name
+            <input type="text" name="name" value="{name}">
+        </label>
+        <fieldset>
+            <legend>Lists</legend>
+            {lists}
+        </fieldset>
+        <fieldset>
+            <legend>How often</legend>
+            {frequencies}
+        </fieldset>
+        <button type="submit">Save my preferences</button>
+    </form>
+    <form action="/subscriptions/preferences?token={token}" method="post">
+        <input type="hidden" name="unsubscribe_all" value="true">
+        <button type="submit">Unsubscribe from all our lists</button>
+    </form>
+</body>
+</html>"#,
+            token = htmlescape::encode_attribute(&parameters.token),
+            name = htmlescape::encode_attribute(&subscriber.name),
+            lists = list_checkboxes.join("\n            "),
+            frequencies = frequency_radios.join("\n            "),
+        )))
+}
+
+#[tracing::instrument(
+    name = "Save a subscriber's preferences",
+    skip(parameters, form, repository, hmac_secret, origin),
+    fields(subscriber_id = tracing::field::Empty)
+)]
+pub async fn update_preferences(
+    parameters: web::Query<PreferencesParameters>,
+    form: web::Form<Vec<(String, String)>>,
+    repository: web::Data<dyn SubscriberRepository>,
+    hmac_secret: web::Data<HmacSecret>,
+    origin: RequestOrigin,
+) -> Result<HttpResponse, PreferencesError> {
+    let subscriber_id = match parse_token(&hmac_secret, &parameters.token, Utc::now()) {
+        Some(PreferencesToken::Valid { subscriber_id }) => subscriber_id,
+        Some(PreferencesToken::Expired) => return Ok(expired_link_page()),
+        None => return Err(PreferencesError::InvalidToken),
+    };
+    tracing::Span::current().record("subscriber_id", &tracing::field::display(&subscriber_id));
+    let form = PreferencesForm::from_pairs(form.into_inner());
+
+    let mut transaction = repository
+        .begin()
+        .await
+        .map_err(PreferencesError::PoolError)?;
+    transaction
+        .get_subscriber(subscriber_id)
+        .await
+        .map_err(PreferencesError::GetSubscriberError)?
+        .ok_or(PreferencesError::InvalidToken)?;
+    let memberships = transaction
+        .get_list_subscriptions(subscriber_id)
+        .await
+        .map_err(PreferencesError::GetSubscriberError)?;
+
+    let message = if form.unsubscribe_all {
+        for membership in memberships {
+            leave_list(transaction.as_mut(), &membership).await?;
+        }
+        "You have been unsubscribed from all our lists."
+    } else {
+        let name = SubscriberName::parse(form.name.unwrap_or_default())
+            .map_err(PreferencesError::ValidationError)?;
+        let digest_frequency =
+            DigestFrequency::parse(form.digest_frequency.as_deref().unwrap_or(""))
+                .map_err(PreferencesError::ValidationError)?;
+        transaction
+            .update_preferences(subscriber_id, &name, digest_frequency)
+            .await
+            .map_err(PreferencesError::UpdatePreferencesError)?;
+
+        let lists = transaction
+            .get_lists()
+            .await
+            .map_err(PreferencesError::GetSubscriberError)?;
+        for list in lists {
+            let membership = memberships.iter().find(|m| m.list_id == list.id);
+            if form.lists.contains(&list.slug) {
+                join_list(
+                    transaction.as_mut(),
+                    subscriber_id,
+                    &list,
+                    membership,
+                    &origin,
+                )
+                .await?;
+            } else if let Some(membership) = membership {
+                leave_list(transaction.as_mut(), membership).await?;
+            }
+        }
+        "Your preferences have been saved."
+    };
+    transaction
+        .commit()
+        .await
+        .map_err(PreferencesError::TransactionCommitError)?;
+
+    Ok(HttpResponse::Ok()
+        .content_type(ContentType::html())
+        .body(format!(
+            r#"<!DOCTYPE html>
+<html lang="en">
+<head>
+    <meta http-equiv="content-type" content="text/html; charset=utf-8">
+    <title>Preferences saved</title>
+</head>
+<body>
+    <p>{}</p>
+    <p><a href="/subscriptions/preferences?token={}">Back to your preferences</a></p>
+</body>
+</html>"#,
+            message,
+            htmlescape::encode_attribute(&parameters.token)
+        )))
+}
+
+/// Subscribe them to the list straight away: the link came from their inbox, which is all a
+/// confirmation email would prove
+async fn join_list(
+    transaction: &mut dyn SubscriberTransaction,
+    subscriber_id: Uuid,
+    list: &MailingList,
+    membership: Option<&ListSubscription>,
+    origin: &RequestOrigin,
+) -> Result<(), PreferencesError> {
+    match membership.map(|m| m.status) {
+        None => transaction
+            .add_list_subscription(subscriber_id, list.id)
+            .await
+            .map_err(PreferencesError::UpdateListsError)?,
+        Some(SubscriptionStatus::PendingConfirmation) => {}
+        Some(SubscriptionStatus::Unsubscribed) => transaction
+            .resubscribe(subscriber_id, list.id)
+            .await
+            .map_err(PreferencesError::UpdateListsError)?,
+        // Already on the list, or we can't send to them anyway
+        Some(_) => return Ok(()),
+    }
+    transaction
+        .update_list_status(subscriber_id, list.id, SubscriptionStatus::Confirmed)
+        .await
+        .map_err(PreferencesError::UpdateListsError)?;
+    let event = ConsentEvent {
+        form_source: Some("preference_center".into()),
+        ..consent_event(
+            subscriber_id,
+            list.id,
+            ConsentEventType::PreferenceCenterSignup,
+            origin,
+            None,
+        )
+    };
+    transaction
+        .record_consent_event(&event)
+        .await
+        .map_err(PreferencesError::RecordConsentError)
+}
+
+async fn leave_list(
+    transaction: &mut dyn SubscriberTransaction,
+    membership: &ListSubscription,
+) -> Result<(), PreferencesError> {
+    // Anyone who can't move to `unsubscribed` already gets nothing from that list
+    if membership
+        .status
+        .can_transition_to(SubscriptionStatus::Unsubscribed)
+    {
+        transaction
+            .update_list_status(
+                membership.subscriber_id,
+                membership.list_id,
+                SubscriptionStatus::Unsubscribed,
+            )
+            .await
+            .map_err(PreferencesError::UpdateListsError)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use claim::{assert_none, assert_some_eq};
+
+    fn token_from_link(link: &str) -> &str {
+        link.split("token=").nth(1).unwrap()
+    }
+
+    #[test]
+    fn tokens_from_preference_links_are_accepted_until_they_expire() {
+        let secret = HmacSecret("secret".into());
+        let subscriber_id = Uuid::new_v4();
+        let expires_at = Utc.timestamp(1_625_000_000, 0);
+        let link = preferences_link("https://example.com", &secret, subscriber_id, expires_at);
+        let token = token_from_link(&link);
+
+        assert_some_eq!(
+            parse_token(&secret, token, expires_at - chrono::Duration::days(1)),
+            PreferencesToken::Valid { subscriber_id }
+        );
+        assert_some_eq!(
+            parse_token(&secret, token, expires_at + chrono::Duration::seconds(1)),
+            PreferencesToken::Expired
+        );
+    }
+
+    #[test]
+    fn tokens_with_a_changed_subscriber_or_expiry_are_rejected() {
+        let secret = HmacSecret("secret".into());
+        let subscriber_id = Uuid::new_v4();
+        let expires_at = Utc.timestamp(1_625_000_000, 0);
+        let link = preferences_link("https://example.com", &secret, subscriber_id, expires_at);
+        let signature = token_from_link(&link).split('.').nth(2).unwrap();
+        let now = expires_at - chrono::Duration::days(1);
+
+        let other_subscriber = format!(
+            "{}.{}.{}",
+            Uuid::new_v4(),
+            expires_at.timestamp(),
+            signature
+        );
+        let later_expiry = format!(
+            "{}.{}.{}",
+            subscriber_id,
+            expires_at.timestamp() + 86_400,
+            signature
+        );
+
+        assert_none!(parse_token(&secret, &other_subscriber, now));
+        assert_none!(parse_token(&secret, &later_expiry, now));
+    }
+
+    #[test]
+    fn unsubscribe_signatures_are_not_accepted() {
+        let secret = HmacSecret("secret".into());
+        let subscriber_id = Uuid::new_v4();
+        let signature = secret.sign(&format!("unsubscribe:{}", subscriber_id));
+
+        let token = format!("{}.{}.{}", subscriber_id, 4_000_000_000i64, signature);
+
+        assert_none!(parse_token(&secret, &token, Utc::now()));
+    }
+
+    #[test]
+    fn malformed_tokens_are_rejected() {
+        let secret = HmacSecret("secret".into());
+        let now = Utc::now();
+
+        assert_none!(parse_token(&secret, "", now));
+        assert_none!(parse_token(&secret, "not-a-uuid.1.signature", now));
+        assert_none!(parse_token(
+            &secret,
+            &format!("{}.not-a-number.signature", Uuid::new_v4()),
+            now
+        ));
+        assert_none!(parse_token(
+            &secret,
+            &format!("{}.signature", Uuid::new_v4()),
+            now
+        ));
+    }
+
+    #[test]
+    fn repeated_list_fields_are_all_kept() {
+        let pairs = vec![
+            ("name".to_string(), "Ursula".to_string()),
+            ("list".to_string(), "newsletter".to_string()),
+            ("digest_frequency".to_string(), "weekly".to_string()),
+            ("list".to_string(), "book-club".to_string()),
+        ];
+
+        let form = PreferencesForm::from_pairs(pairs);
+
+        assert_eq!(form.name.as_deref(), Some("Ursula"));
+        assert_eq!(form.digest_frequency.as_deref(), Some("weekly"));
+        assert_eq!(form.lists, vec!["newsletter", "book-club"]);
+        assert!(!form.unsubscribe_all);
+    }
+}
@@ -1,12 +1,23 @@
-use crate::startup::ApplicationBaseUrl;
-use crate::{domain::*, email_client::EmailClient};
-use actix_web::{web, HttpResponse};
+use crate::confirmation_email_throttle::ConfirmationEmailThrottle;
+use crate::domain::*;
+use crate::request_origin::RequestOrigin;
+use crate::routes::preferences_link;
+use crate::signing::HmacSecret;
+use crate::signup_protection::{
+    check_form_token, issue_form_token, verify_proof_of_work, SignupProtection, SignupRejected,
+};
+use crate::startup::{ApplicationBaseUrl, PreferencesLinkTtl};
+use crate::subscriber_repository::{
+    ConsentEvent, ConsentEventType, MailingList, SubscriberRepository, SubscriberTransaction,
+};
+use crate::utils::{error_chain_fmt, log_error_response};
+use actix_web::http::header::ContentType;
+use actix_web::http::StatusCode;
+use actix_web::{web, HttpResponse, ResponseError};
 use chrono::Utc;
 use rand::distributions::Alphanumeric;
 use rand::{thread_rng, Rng};
 use serde::Deserialize;
-use sqlx::PgPool;
-use sqlx::{Postgres, Transaction};
 use std::convert::{TryFrom, TryInto};
 use uuid::Uuid;
 
@@ -14,6 +25,15 @@ use uuid::Uuid;
 pub struct FormData {
     email: String,
     name: String,
+    /// The honeypot: it is hidden from humans, so only bots fill it in
+    #[serde(default)]
+    website: String,
+    form_token: Option<String>,
+    proof_of_work: Option<String>,
+    /// Which form the signup came from, for the consent record
+    source: Option<String>,
+    /// The slug of the list to join, the default list if missing
+    list: Option<String>,
 }
 
 impl TryFrom<FormData> for NewSubscriber {
@@ -27,141 +47,438 @@ impl TryFrom<FormData> for NewSubscriber {
     }
 }
 
+/// Mints the proof of work for the stamp format `verify_proof_of_work` expects
+const PROOF_OF_WORK_SCRIPT: &str = r#"<script>
+    const form = document.querySelector("form");
+    const bits = Number(form.dataset.proofOfWorkBits);
+
+    function leadingZeroBits(hash) {
+        let zeros = 0;
+        for (const byte of hash) {
+            if (byte !== 0) {
+                return zeros + Math.clz32(byte) - 24;
+            }
+            zeros += 8;
+        }
+        return zeros;
+    }
+
+    form.addEventListener("submit", async (event) => {
+        event.preventDefault();
+        const email = form.elements["email"].value;
+        const mintedAt = Math.floor(Date.now() / 1000);
+        const random = Math.random().toString(36).slice(2);
+        for (let counter = 0; ; counter++) {
+            const stamp = `1:${bits}:${mintedAt}:${email}::${random}:${counter}`;
+            const digest = await crypto.subtle.digest("SHA-256", new TextEncoder().encode(stamp));
+            if (leadingZeroBits(new Uint8Array(digest)) >= bits) {
+                form.elements["proof_of_work"].value = stamp;
+                form.submit();
+                return;
+            }
+        }
+    });
+</script>"#;
+
+/// The signup form, with the fields the configured spam defenses need
+pub async fn subscribe_form(
+    protection: web::Data<SignupProtection>,
+    hmac_secret: web::Data<HmacSecret>,
+) -> HttpResponse {
+    let form_token = issue_form_token(&hmac_secret, Utc::now());
+    let (proof_of_work_bits, proof_of_work_script) = match protection.proof_of_work_bits {
+        Some(bits) => (bits, PROOF_OF_WORK_SCRIPT),
+        None => (0, ""),
+    };
+
+    HttpResponse::Ok()
+        .content_type(ContentType::html())
+        .body(format!(
+            r#"<!DOCTYPE html>
+<html lang="en">
+<head>
+    <meta http-equiv="content-type" content="text/html; charset=utf-8">
+    <title>Subscribe</title>
+</head>
+<body>
+    <form action="/subscriptions" method="post" data-proof-of-work-bits="{}">
+        <label>Name
+            <input type="text" placeholder="Enter your name" name="name">
+        </label>
+        <label>Email
+            <input type="email" placeholder="Enter your email" name="email">
+        </label>
+        <div style="display: none" aria-hidden="true">
+            <label>Leave this empty
+                <input type="text" name="website" tabindex="-1" autocomplete="off">
+            </label>
+        </div>
+        <input type="hidden" name="form_token" value="{}">
+        <input type="hidden" name="proof_of_work" value="">
+        <input type="hidden" name="source" value="subscribe_page">
+        <button type="submit">Subscribe</button>
+    </form>
+    {}
+</body>
+</html>"#,
+            proof_of_work_bits, form_token, proof_of_work_script
+        ))
+}
+
+#[derive(thiserror::Error)]
+pub enum SubscribeError {
+    #[error("{0}")]
+    ValidationError(String),
+    #[error("The signup looks automated.")]
+    SpamCheckError(#[source] SignupRejected),
+    #[error("Failed to acquire a Postgres connection from the pool.")]
+    PoolError(#[source] sqlx::Error),
+    #[error("There is no list called {0}.")]
+    UnknownList(String),
+    #[error("Failed to look up the list to subscribe to.")]
+    GetListError(#[source] sqlx::Error),
+    #[error("Failed to insert new subscriber in the database.")]
+    InsertSubscriberError(#[source] sqlx::Error),
+    #[error("Failed to retrieve the existing subscriber with the same email.")]
+    GetExistingSubscriberError(#[source] sqlx::Error),
+    #[error("Failed to add the subscriber to the list.")]
+    AddListSubscriptionError(#[source] sqlx::Error),
+    #[error("Failed to restart the subscription of an existing subscriber.")]
+    ResubscribeError(#[source] sqlx::Error),
+    #[error("Failed to check how many confirmation emails were sent to the address.")]
+    ThrottleConfirmationEmailError(#[source] sqlx::Error),
+    #[error("Failed to store the confirmation token for a new subscriber.")]
+    StoreTokenError(#[source] sqlx::Error),
+    #[error("Failed to queue the confirmation email for a new subscriber.")]
+    EnqueueConfirmationEmailError(#[source] sqlx::Error),
+    #[error("Failed to record the subscriber's consent.")]
+    RecordConsentError(#[source] sqlx::Error),
+    #[error("Failed to commit SQL transaction to store a new subscriber.")]
+    TransactionCommitError(#[source] sqlx::Error),
+}
+
+impl std::fmt::Debug for SubscribeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        error_chain_fmt(self, f)
+    }
+}
+
+impl ResponseError for SubscribeError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            SubscribeError::ValidationError(_)
+            | SubscribeError::UnknownList(_)
+            | SubscribeError::SpamCheckError(_) => StatusCode::BAD_REQUEST,
+            SubscribeError::PoolError(_)
+            | SubscribeError::GetListError(_)
+            | SubscribeError::InsertSubscriberError(_)
+            | SubscribeError::GetExistingSubscriberError(_)
+            | SubscribeError::AddListSubscriptionError(_)
+            | SubscribeError::ResubscribeError(_)
+            | SubscribeError::ThrottleConfirmationEmailError(_)
+            | SubscribeError::StoreTokenError(_)
+            | SubscribeError::EnqueueConfirmationEmailError(_)
+            | SubscribeError::RecordConsentError(_)
+            | SubscribeError::TransactionCommitError(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        log_error_response(self)
+    }
+}
+
 #[tracing::instrument(
     name = "Adding a new subscriber",
-    skip(form, pool, email_client, base_url),
+    skip(
+        form,
+        repository,
+        base_url,
+        throttle,
+        protection,
+        hmac_secret,
+        preferences_link_ttl,
+        origin
+    ),
     fields(email = %form.email, name = %form.name)
 )]
+#[allow(clippy::too_many_arguments)]
 pub async fn subscribe(
     form: web::Form<FormData>,
-    pool: web::Data<PgPool>,
-    email_client: web::Data<EmailClient>,
+    repository: web::Data<dyn SubscriberRepository>,
     base_url: web::Data<ApplicationBaseUrl>,
-) -> Result<HttpResponse, HttpResponse> {
-    let new_subscriber: NewSubscriber = form
-        .0
-        .try_into()
-        .map_err(|_| HttpResponse::BadRequest().finish())?;
+    throttle: web::Data<ConfirmationEmailThrottle>,
+    protection: web::Data<SignupProtection>,
+    hmac_secret: web::Data<HmacSecret>,
+    preferences_link_ttl: web::Data<PreferencesLinkTtl>,
+    origin: RequestOrigin,
+) -> Result<HttpResponse, SubscribeError> {
+    // Look like a success, so that bots don't learn to leave it empty
+    if protection.honeypot && !form.website.is_empty() {
+        tracing::info!("Ignoring a signup that filled in the honeypot");
+        return Ok(HttpResponse::Ok().finish());
+    }
+    check_signup_is_human(&form, &protection, &hmac_secret)
+        .map_err(SubscribeError::SpamCheckError)?;
+    let form_source = form.source.clone().or_else(|| origin.referer.clone());
+    let list_slug = match form.list.clone() {
+        Some(slug) => ListSlug::parse(slug).map_err(SubscribeError::ValidationError)?,
+        None => ListSlug::default_list(),
+    };
+    let new_subscriber: NewSubscriber =
+        form.0.try_into().map_err(SubscribeError::ValidationError)?;
 
-    let mut transaction = pool
+    let mut transaction = repository
         .begin()
         .await
-        .map_err(|_| HttpResponse::InternalServerError().finish())?;
-    let subscriber_id = insert_subscriber(&mut transaction, &new_subscriber)
+        .map_err(SubscribeError::PoolError)?;
+    let list = transaction
+        .get_list_by_slug(&list_slug)
         .await
-        .map_err(|_| HttpResponse::InternalServerError().finish())?;
-    let subscription_token = generate_subscription_token();
-    store_token(&mut transaction, subscriber_id, &subscription_token)
+        .map_err(SubscribeError::GetListError)?
+        .ok_or_else(|| SubscribeError::UnknownList(list_slug.as_ref().into()))?;
+    let (subscriber_id, pending_name) = match transaction
+        .insert_subscriber(&new_subscriber)
+        .await
+        .map_err(SubscribeError::InsertSubscriberError)?
+    {
+        Some(subscriber_id) => (subscriber_id, None),
+        // The email was taken, so the subscriber is there until our transaction ends.
+        // Anyone can type in their address, so a new name waits until they confirm.
+        None => {
+            let subscriber_id = transaction
+                .get_subscriber_by_email(&new_subscriber.email)
+                .await
+                .and_then(|subscriber| subscriber.ok_or(sqlx::Error::RowNotFound))
+                .map_err(SubscribeError::GetExistingSubscriberError)?
+                .id;
+            (subscriber_id, Some(&new_subscriber.name))
+        }
+    };
+    let membership = transaction
+        .get_list_subscription(subscriber_id, list.id)
+        .await
+        .map_err(SubscribeError::GetExistingSubscriberError)?;
+    match membership.map(|m| m.status) {
+        None => transaction
+            .add_list_subscription(subscriber_id, list.id)
+            .await
+            .map_err(SubscribeError::AddListSubscriptionError)?,
+        // They may have lost the first email, so send another one
+        Some(SubscriptionStatus::PendingConfirmation) => {}
+        Some(status) if status.can_transition_to(SubscriptionStatus::PendingConfirmation) => {
+            transaction
+                .resubscribe(subscriber_id, list.id)
+                .await
+                .map_err(SubscribeError::ResubscribeError)?
+        }
+        // Respond as for a new address, so the form can't be used to find out who is subscribed
+        Some(status) => {
+            tracing::info!(
+                %subscriber_id,
+                list = %list.slug,
+                ?status,
+                "Ignoring a signup for an existing subscriber"
+            );
+            return Ok(HttpResponse::Ok().finish());
+        }
+    }
+    // The subscriber is saved either way, and the response doesn't say whether an email was sent
+    let confirmation_email =
+        if reserve_confirmation_email(transaction.as_mut(), &throttle, &new_subscriber.email)
+            .await
+            .map_err(SubscribeError::ThrottleConfirmationEmailError)?
+        {
+            let subscription_token = generate_subscription_token();
+            transaction
+                .store_token(subscriber_id, list.id, &subscription_token, pending_name)
+                .await
+                .map_err(SubscribeError::StoreTokenError)?;
+            let preferences_link = preferences_link(
+                &base_url.0,
+                &hmac_secret,
+                subscriber_id,
+                Utc::now() + preferences_link_ttl.0,
+            );
+            let email = enqueue_confirmation_email(
+                transaction.as_mut(),
+                &new_subscriber.email,
+                &list,
+                &base_url.0,
+                &subscription_token,
+                &preferences_link,
+            )
+            .await
+            .map_err(SubscribeError::EnqueueConfirmationEmailError)?;
+            Some(email)
+        } else {
+            None
+        };
+    let event = ConsentEvent {
+        form_source,
+        ..consent_event(
+            subscriber_id,
+            list.id,
+            ConsentEventType::Signup,
+            &origin,
+            confirmation_email,
+        )
+    };
+    transaction
+        .record_consent_event(&event)
         .await
-        .map_err(|_| HttpResponse::InternalServerError().finish())?;
+        .map_err(SubscribeError::RecordConsentError)?;
     transaction
         .commit()
         .await
-        .map_err(|_| HttpResponse::InternalServerError().finish())?;
-
-    // TODO handle error
-    let _ = send_confirmation_email(
-        &email_client,
-        new_subscriber,
-        &base_url.0,
-        &subscription_token,
-    )
-    .await;
+        .map_err(SubscribeError::TransactionCommitError)?;
 
     Ok(HttpResponse::Ok().finish())
 }
 
-#[tracing::instrument(
-    name = "Send a confirmation email to a new subscriber",
-    skip(email_client, new_subscriber, base_url, subscription_token)
-)]
-pub async fn send_confirmation_email(
-    email_client: &EmailClient,
-    new_subscriber: NewSubscriber,
-    base_url: &str,
-    subscription_token: &str,
-) -> Result<(), reqwest::Error> {
-    let confirmation_link = format!(
-        "{}/subscriptions/confirm?subscription_token={}",
-        base_url, subscription_token
-    );
-
-    let html_content = format!(
-        "Welcome to our newsletter!<br />Click <a href=\"{}\">here</a> to confirm your subscription.",
-        confirmation_link
-    );
-    let text_content = format!(
-        "Welcome to our newsletter!\nVisit {} to confirm your subscription.",
-        confirmation_link
-    );
-
-    email_client
-        .send_email(
-            new_subscriber.email,
-            "Welcome!",
-            &html_content,
-            &text_content,
-        )
-        .await
+/// Run the checks against scripted signups that are turned on
+fn check_signup_is_human(
+    form: &FormData,
+    protection: &SignupProtection,
+    hmac_secret: &HmacSecret,
+) -> Result<(), SignupRejected> {
+    let now = Utc::now();
+    if let Some(min_fill_time) = protection.min_fill_time {
+        check_form_token(hmac_secret, form.form_token.as_deref(), min_fill_time, now)?;
+    }
+    if let Some(bits) = protection.proof_of_work_bits {
+        verify_proof_of_work(form.proof_of_work.as_deref(), &form.email, bits, now)?;
+    }
+    Ok(())
 }
 
-#[tracing::instrument(
-    name = "Saving new subscriber details to the database",
-    skip(transaction, new_subscriber)
-)]
-pub async fn insert_subscriber(
-    transaction: &mut Transaction<'_, Postgres>,
-    new_subscriber: &NewSubscriber,
-) -> Result<Uuid, sqlx::Error> {
-    let subscriber_id = Uuid::new_v4();
-    sqlx::query!(
-        r#"
-            INSERT INTO subscriptions (id, email, name, subscribed_at, status)
-            VALUES ($1, $2, $3, $4, 'pending_confirmation')
-        "#,
+/// Count a confirmation email to the address, unless it was sent too many recently.
+/// Returns whether the email should be sent.
+pub async fn reserve_confirmation_email(
+    transaction: &mut dyn SubscriberTransaction,
+    throttle: &ConfirmationEmailThrottle,
+    recipient: &SubscriberEmail,
+) -> Result<bool, sqlx::Error> {
+    let history = transaction
+        .get_confirmation_email_history(recipient)
+        .await?;
+    match throttle.record_send(history.as_ref(), Utc::now()) {
+        Ok(history) => {
+            transaction
+                .save_confirmation_email_history(recipient, &history)
+                .await?;
+            Ok(true)
+        }
+        Err(e) => {
+            tracing::warn!(error.message = %e, "Skipping a confirmation email");
+            Ok(false)
+        }
+    }
+}
+
+/// A consent event happening now, in response to a request from `origin`
+pub fn consent_event(
+    subscriber_id: Uuid,
+    list_id: Uuid,
+    event_type: ConsentEventType,
+    origin: &RequestOrigin,
+    email: Option<ConfirmationEmail>,
+) -> ConsentEvent {
+    let (email_subject, email_html_body, email_text_body) = match email {
+        Some(email) => (
+            Some(email.subject),
+            Some(email.html_body),
+            Some(email.text_body),
+        ),
+        None => (None, None, None),
+    };
+    ConsentEvent {
         subscriber_id,
-        new_subscriber.email.as_ref(),
-        new_subscriber.name.as_ref(),
-        Utc::now()
-    )
-    .execute(transaction)
-    .await
-    .map_err(|e| {
-        tracing::error!("Failed to execute query: {:?}", e);
-        e
-    })?;
-
-    Ok(subscriber_id)
+        list_id,
+        event_type,
+        occurred_at: Utc::now(),
+        ip_address: origin.client_ip.map(|ip| ip.to_string()),
+        user_agent: origin.user_agent.clone(),
+        form_source: None,
+        email_subject,
+        email_html_body,
+        email_text_body,
+    }
+}
+
+/// A confirmation email as it was sent, except for the token in the link and the preference link
+pub struct ConfirmationEmail {
+    pub subject: String,
+    pub html_body: String,
+    pub text_body: String,
 }
 
+impl ConfirmationEmail {
+    fn new(
+        base_url: &str,
+        list: &MailingList,
+        subscription_token: &str,
+        preferences_link: &str,
+    ) -> Self {
+        let confirmation_link = format!(
+            "{}/subscriptions/confirm?subscription_token={}",
+            base_url, subscription_token
+        );
+
+        Self {
+            subject: "Welcome!".into(),
+            html_body: format!(
+                "Welcome!<br />Click <a href=\"{}\">here</a> to confirm your subscription to {}.\
+                <p><a href=\"{}\">Manage your preferences</a></p>",
+                confirmation_link, list.name, preferences_link
+            ),
+            text_body: format!(
+                "Welcome!\nVisit {} to confirm your subscription to {}.\n\n\
+                Manage your preferences: {}",
+                confirmation_link, list.name, preferences_link
+            ),
+        }
+    }
+}
+
+/// Write the confirmation email to the outbox, to be sent once the subscriber is committed.
+/// Returns the email with its links redacted, so that records of it can't be used to confirm
+/// or to change preferences.
 #[tracing::instrument(
-    name = "Store subscription token in the database",
-    skip(transaction, subscription_token)
-)]
-async fn store_token(
-    transaction: &mut Transaction<'_, Postgres>,
-    subscriber_id: Uuid,
-    subscription_token: &str,
-) -> Result<(), sqlx::Error> {
-    sqlx::query!(
-        r#"
-            INSERT INTO subscription_tokens (subscription_token, subscriber_id)
-            VALUES ($1, $2)
-        "#,
+    name = "Queue a confirmation email to a new subscriber",
+    skip(
+        transaction,
+        recipient,
+        list,
+        base_url,
         subscription_token,
-        subscriber_id
+        preferences_link
     )
-    .execute(transaction)
-    .await
-    .map_err(|e| {
-        tracing::error!("Failed to execute query: {:?}", e);
-        e
-    })?;
+)]
+pub async fn enqueue_confirmation_email(
+    transaction: &mut dyn SubscriberTransaction,
+    recipient: &SubscriberEmail,
+    list: &MailingList,
+    base_url: &str,
+    subscription_token: &str,
+    preferences_link: &str,
+) -> Result<ConfirmationEmail, sqlx::Error> {
+    let email = ConfirmationEmail::new(base_url, list, subscription_token, preferences_link);
+    transaction
+        .enqueue_email(
+            recipient,
+            &email.subject,
+            &email.html_body,
+            &email.text_body,
+        )
+        .await?;
 
-    Ok(())
+    Ok(ConfirmationEmail::new(
+        base_url, list, "REDACTED", "REDACTED",
+    ))
 }
 
-fn generate_subscription_token() -> String {
+pub fn generate_subscription_token() -> String {
     let mut rng = thread_rng();
     std::iter::repeat_with(|| rng.sample(Alphanumeric))
         .map(char::from)
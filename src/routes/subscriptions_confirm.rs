@@ -1,68 +1,369 @@
-use actix_web::{web, HttpResponse};
-use sqlx::PgPool;
-use uuid::Uuid;
+use crate::confirmation_email_throttle::ConfirmationEmailThrottle;
+use crate::domain::{InvalidStatusTransition, SubscriberEmail, SubscriberName, SubscriptionStatus};
+use crate::request_origin::RequestOrigin;
+use crate::routes::{
+    consent_event, enqueue_confirmation_email, generate_subscription_token, preferences_link,
+    reserve_confirmation_email,
+};
+use crate::signing::HmacSecret;
+use crate::startup::{ApplicationBaseUrl, PreferencesLinkTtl, SubscriptionTokenTtl};
+use crate::subscriber_repository::{
+    ConsentEventType, MailingList, Subscriber, SubscriberRepository, SubscriberTransaction,
+    SubscriptionToken,
+};
+use crate::utils::{error_chain_fmt, log_error_response};
+use actix_web::http::header::ContentType;
+use actix_web::http::StatusCode;
+use actix_web::{web, HttpResponse, ResponseError};
+use chrono::Utc;
 
 #[derive(serde::Deserialize)]
 pub struct Parameters {
     subscription_token: String,
 }
 
-#[tracing::instrument(name = "Confirm a pending subscriber", skip(parameters, pool))]
+#[derive(thiserror::Error)]
+pub enum ConfirmError {
+    #[error("There is no subscriber associated with the provided token.")]
+    UnknownToken,
+    #[error("The provided token has already been used.")]
+    UsedToken,
+    #[error("The subscriber can no longer be confirmed.")]
+    CannotConfirm(#[source] InvalidStatusTransition),
+    #[error("Failed to acquire a Postgres connection from the pool.")]
+    PoolError(#[source] sqlx::Error),
+    #[error("Failed to retrieve the subscriber associated with the provided token.")]
+    GetSubscriberIdError(#[source] sqlx::Error),
+    #[error("Failed to update the subscriber status to `confirmed`.")]
+    ConfirmSubscriberError(#[source] sqlx::Error),
+    #[error("Failed to mark the subscription token as consumed.")]
+    ConsumeTokenError(#[source] sqlx::Error),
+    #[error("Failed to record the subscriber's consent.")]
+    RecordConsentError(#[source] sqlx::Error),
+    #[error("Failed to commit SQL transaction to confirm a subscriber.")]
+    TransactionCommitError(#[source] sqlx::Error),
+}
+
+impl std::fmt::Debug for ConfirmError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        error_chain_fmt(self, f)
+    }
+}
+
+impl ResponseError for ConfirmError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            ConfirmError::UnknownToken | ConfirmError::UsedToken => StatusCode::UNAUTHORIZED,
+            ConfirmError::CannotConfirm(_) => StatusCode::CONFLICT,
+            ConfirmError::PoolError(_)
+            | ConfirmError::GetSubscriberIdError(_)
+            | ConfirmError::ConfirmSubscriberError(_)
+            | ConfirmError::ConsumeTokenError(_)
+            | ConfirmError::RecordConsentError(_)
+            | ConfirmError::TransactionCommitError(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        log_error_response(self)
+    }
+}
+
+#[tracing::instrument(
+    name = "Confirm a pending subscriber",
+    skip(parameters, repository, token_ttl, origin),
+    fields(subscriber_id = tracing::field::Empty)
+)]
 pub async fn confirm(
     parameters: web::Query<Parameters>,
-    pool: web::Data<PgPool>,
-) -> Result<HttpResponse, HttpResponse> {
-    let id = get_subscriber_id_from_token(&pool, &parameters.subscription_token)
+    repository: web::Data<dyn SubscriberRepository>,
+    token_ttl: web::Data<SubscriptionTokenTtl>,
+    origin: RequestOrigin,
+) -> Result<HttpResponse, ConfirmError> {
+    let mut transaction = repository.begin().await.map_err(ConfirmError::PoolError)?;
+    let token = transaction
+        .get_token(&parameters.subscription_token)
+        .await
+        .map_err(ConfirmError::GetSubscriberIdError)?
+        .ok_or(ConfirmError::UnknownToken)?;
+    tracing::Span::current().record(
+        "subscriber_id",
+        &tracing::field::display(&token.subscriber_id),
+    );
+    // The token confirms the one list it was sent for
+    let membership = transaction
+        .get_list_subscription(token.subscriber_id, token.list_id)
         .await
-        .map_err(|_| HttpResponse::InternalServerError().finish())?;
+        .map_err(ConfirmError::GetSubscriberIdError)?
+        .ok_or(ConfirmError::UnknownToken)?;
 
-    match id {
-        None => Err(HttpResponse::Unauthorized().finish()),
-        Some(subscriber_id) => {
-            confirm_subscriber(&pool, subscriber_id)
-                .await
-                .map_err(|_| HttpResponse::InternalServerError().finish())?;
+    // Clicking the link again, or a second concurrent click, changes nothing
+    if membership.status == SubscriptionStatus::Confirmed {
+        return Ok(HttpResponse::Ok().finish());
+    }
+    if token.consumed_at.is_some() {
+        return Err(ConfirmError::UsedToken);
+    }
+    if token.created_at + token_ttl.0 < Utc::now() {
+        tracing::warn!("Rejected an expired subscription token");
+        return Ok(expired_token_page(&parameters.subscription_token));
+    }
+    let status = membership
+        .status
+        .transition_to(SubscriptionStatus::Confirmed)
+        .map_err(ConfirmError::CannotConfirm)?;
+
+    transaction
+        .update_list_status(membership.subscriber_id, membership.list_id, status)
+        .await
+        .map_err(ConfirmError::ConfirmSubscriberError)?;
+    // Having opted in again, they no longer want the address kept off our lists
+    let subscriber = transaction
+        .get_subscriber(membership.subscriber_id)
+        .await
+        .map_err(ConfirmError::GetSubscriberIdError)?
+        .ok_or(ConfirmError::UnknownToken)?;
+    transaction
+        .clear_erasure(&subscriber.email)
+        .await
+        .map_err(ConfirmError::ConfirmSubscriberError)?;
+    // The name they gave when signing up again, held back until now
+    if let Some(pending_name) = token.pending_name {
+        // The name was validated when it was stored
+        let name = SubscriberName::parse(pending_name)
+            .map_err(|e| ConfirmError::ConfirmSubscriberError(sqlx::Error::Decode(e.into())))?;
+        transaction
+            .rename_subscriber(membership.subscriber_id, &name)
+            .await
+            .map_err(ConfirmError::ConfirmSubscriberError)?;
+    }
+    transaction
+        .consume_token(&parameters.subscription_token)
+        .await
+        .map_err(ConfirmError::ConsumeTokenError)?;
+    transaction
+        .record_consent_event(&consent_event(
+            membership.subscriber_id,
+            membership.list_id,
+            ConsentEventType::Confirmation,
+            &origin,
+            None,
+        ))
+        .await
+        .map_err(ConfirmError::RecordConsentError)?;
+    transaction
+        .commit()
+        .await
+        .map_err(ConfirmError::TransactionCommitError)?;
 
-            Ok(HttpResponse::Ok().finish())
+    Ok(HttpResponse::Ok().finish())
+}
+
+/// Offer to send a fresh confirmation link in place of the expired one
+fn expired_token_page(subscription_token: &str) -> HttpResponse {
+    HttpResponse::Gone()
+        .content_type(ContentType::html())
+        .body(format!(
+            r#"<!DOCTYPE html>
+<html lang="en">
+<head>
+    <meta http-equiv="content-type" content="text/html; charset=utf-8">
+    <title>Confirmation link expired</title>
+</head>
+<body>
+    <p>This confirmation link has expired.</p>
+    <form action="/subscriptions/confirm/resend" method="post">
+        <input type="hidden" name="subscription_token" value="{}">
+        <button type="submit">Send me a new link</button>
+    </form>
+</body>
+</html>"#,
+            htmlescape::encode_attribute(subscription_token)
+        ))
+}
+
+#[derive(serde::Deserialize)]
+pub struct ResendFormData {
+    subscription_token: String,
+}
+
+#[derive(thiserror::Error)]
+pub enum ResendConfirmationError {
+    #[error("There is no pending subscriber associated with the provided token.")]
+    UnknownToken,
+    #[error("Failed to acquire a Postgres connection from the pool.")]
+    PoolError(#[source] sqlx::Error),
+    #[error("Failed to retrieve the subscriber associated with the provided token.")]
+    GetSubscriberError(#[source] sqlx::Error),
+    #[error("Failed to check how many confirmation emails were sent to the address.")]
+    ThrottleConfirmationEmailError(#[source] sqlx::Error),
+    #[error("Failed to replace the subscription token.")]
+    ReplaceTokenError(#[source] sqlx::Error),
+    #[error("Failed to queue the confirmation email.")]
+    EnqueueConfirmationEmailError(#[source] sqlx::Error),
+    #[error("Failed to record the subscriber's consent.")]
+    RecordConsentError(#[source] sqlx::Error),
+    #[error("Failed to commit SQL transaction to resend a confirmation email.")]
+    TransactionCommitError(#[source] sqlx::Error),
+}
+
+impl std::fmt::Debug for ResendConfirmationError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        error_chain_fmt(self, f)
+    }
+}
+
+impl ResponseError for ResendConfirmationError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            ResendConfirmationError::UnknownToken => StatusCode::UNAUTHORIZED,
+            ResendConfirmationError::PoolError(_)
+            | ResendConfirmationError::GetSubscriberError(_)
+            | ResendConfirmationError::ThrottleConfirmationEmailError(_)
+            | ResendConfirmationError::ReplaceTokenError(_)
+            | ResendConfirmationError::EnqueueConfirmationEmailError(_)
+            | ResendConfirmationError::RecordConsentError(_)
+            | ResendConfirmationError::TransactionCommitError(_) => {
+                StatusCode::INTERNAL_SERVER_ERROR
+            }
         }
     }
+
+    fn error_response(&self) -> HttpResponse {
+        log_error_response(self)
+    }
 }
 
-#[tracing::instrument(name = "Mark subscriber as confirmed", skip(pool, subscriber_id))]
-async fn confirm_subscriber(pool: &PgPool, subscriber_id: Uuid) -> Result<(), sqlx::Error> {
-    sqlx::query!(
-        r#"
-            UPDATE subscriptions SET status = 'confirmed' WHERE id = $1
-        "#,
-        subscriber_id
-    )
-    .execute(pool)
-    .await
-    .map_err(|e| {
-        tracing::error!("Failed to execute query: {:?}", e);
-        e
-    })?;
-
-    Ok(())
+/// Replace an unused (usually expired) token with a new one, and email the new link
+#[tracing::instrument(
+    name = "Resend a confirmation email",
+    skip(
+        form,
+        repository,
+        base_url,
+        throttle,
+        hmac_secret,
+        preferences_link_ttl,
+        origin
+    ),
+    fields(subscriber_id = tracing::field::Empty)
+)]
+pub async fn resend_confirmation(
+    form: web::Form<ResendFormData>,
+    repository: web::Data<dyn SubscriberRepository>,
+    base_url: web::Data<ApplicationBaseUrl>,
+    throttle: web::Data<ConfirmationEmailThrottle>,
+    hmac_secret: web::Data<HmacSecret>,
+    preferences_link_ttl: web::Data<PreferencesLinkTtl>,
+    origin: RequestOrigin,
+) -> Result<HttpResponse, ResendConfirmationError> {
+    let mut transaction = repository
+        .begin()
+        .await
+        .map_err(ResendConfirmationError::PoolError)?;
+    let (subscriber, list, old_token) =
+        get_pending_subscriber(transaction.as_mut(), &form.subscription_token)
+            .await
+            .map_err(ResendConfirmationError::GetSubscriberError)?
+            .ok_or(ResendConfirmationError::UnknownToken)?;
+    tracing::Span::current().record("subscriber_id", &tracing::field::display(&subscriber.id));
+
+    // The address was validated when it was stored
+    let email = SubscriberEmail::parse(subscriber.email)
+        .map_err(|e| ResendConfirmationError::GetSubscriberError(sqlx::Error::Decode(e.into())))?;
+    // When throttled, the old token stays usable so that the form can be submitted again later
+    if reserve_confirmation_email(transaction.as_mut(), &throttle, &email)
+        .await
+        .map_err(ResendConfirmationError::ThrottleConfirmationEmailError)?
+    {
+        let subscription_token = generate_subscription_token();
+        // The new link carries over the name the old one would have applied
+        let pending_name = old_token
+            .pending_name
+            .map(SubscriberName::parse)
+            .transpose()
+            .map_err(|e| {
+                ResendConfirmationError::GetSubscriberError(sqlx::Error::Decode(e.into()))
+            })?;
+        transaction
+            .consume_token(&form.subscription_token)
+            .await
+            .map_err(ResendConfirmationError::ReplaceTokenError)?;
+        transaction
+            .store_token(
+                subscriber.id,
+                list.id,
+                &subscription_token,
+                pending_name.as_ref(),
+            )
+            .await
+            .map_err(ResendConfirmationError::ReplaceTokenError)?;
+        let preferences_link = preferences_link(
+            &base_url.0,
+            &hmac_secret,
+            subscriber.id,
+            Utc::now() + preferences_link_ttl.0,
+        );
+        let confirmation_email = enqueue_confirmation_email(
+            transaction.as_mut(),
+            &email,
+            &list,
+            &base_url.0,
+            &subscription_token,
+            &preferences_link,
+        )
+        .await
+        .map_err(ResendConfirmationError::EnqueueConfirmationEmailError)?;
+        transaction
+            .record_consent_event(&consent_event(
+                subscriber.id,
+                list.id,
+                ConsentEventType::ConfirmationEmailResent,
+                &origin,
+                Some(confirmation_email),
+            ))
+            .await
+            .map_err(ResendConfirmationError::RecordConsentError)?;
+    }
+    transaction
+        .commit()
+        .await
+        .map_err(ResendConfirmationError::TransactionCommitError)?;
+
+    Ok(HttpResponse::Ok().content_type(ContentType::html()).body(
+        r#"<!DOCTYPE html>
+<html lang="en">
+<head>
+    <meta http-equiv="content-type" content="text/html; charset=utf-8">
+    <title>Confirmation email sent</title>
+</head>
+<body>
+    <p>We have sent you a new confirmation link.</p>
+</body>
+</html>"#,
+    ))
 }
 
-#[tracing::instrument(name = "Get subscriber_id from token", skip(pool, subscription_token))]
-async fn get_subscriber_id_from_token(
-    pool: &PgPool,
+/// The subscriber and list behind the token, as long as the token is unused and they haven't
+/// confirmed that list yet
+async fn get_pending_subscriber(
+    transaction: &mut dyn SubscriberTransaction,
     subscription_token: &str,
-) -> Result<Option<Uuid>, sqlx::Error> {
-    let result = sqlx::query!(
-        r#"
-            SELECT subscriber_id FROM subscription_tokens WHERE subscription_token = $1
-        "#,
-        subscription_token
-    )
-    .fetch_optional(pool)
-    .await
-    .map_err(|e| {
-        tracing::error!("Failed to execute query: {:?}", e);
-        e
-    })?;
-
-    Ok(result.map(|row| row.subscriber_id))
+) -> Result<Option<(Subscriber, MailingList, SubscriptionToken)>, sqlx::Error> {
+    let token = match transaction.get_token(subscription_token).await? {
+        Some(token) if token.consumed_at.is_none() => token,
+        _ => return Ok(None),
+    };
+    let membership = transaction
+        .get_list_subscription(token.subscriber_id, token.list_id)
+        .await?;
+    if membership.map(|m| m.status) != Some(SubscriptionStatus::PendingConfirmation) {
+        return Ok(None);
+    }
+    let subscriber = transaction.get_subscriber(token.subscriber_id).await?;
+    let list = transaction.get_list(token.list_id).await?;
+
+    Ok(subscriber
+        .zip(list)
+        .map(|(subscriber, list)| (subscriber, list, token)))
 }
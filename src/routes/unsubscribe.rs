@@ -0,0 +1,317 @@
+use crate::domain::{ListSlug, SubscriptionStatus};
+use crate::signing::HmacSecret;
+use crate::subscriber_repository::{
+    ListSubscription, MailingList, SubscriberRepository, SubscriberTransaction,
+};
+use crate::utils::{error_chain_fmt, log_error_response};
+use actix_web::http::header::ContentType;
+use actix_web::http::StatusCode;
+use actix_web::{web, HttpResponse, ResponseError};
+use uuid::Uuid;
+
+/// The link included in every newsletter email, signed so that it works without logging in.
+/// It unsubscribes from the list the email was sent for.
+pub fn unsubscribe_link(
+    base_url: &str,
+    hmac_secret: &HmacSecret,
+    subscriber_id: Uuid,
+    list_id: Uuid,
+) -> String {
+    let signature = hmac_secret.sign(&signed_message(subscriber_id, Some(list_id)));
+    format!(
+        "{}/subscriptions/unsubscribe?token={}.{}.{}",
+        base_url, subscriber_id, list_id, signature
+    )
+}
+
+/// Prefixed so that a signature for another kind of link can't be reused here
+fn signed_message(subscriber_id: Uuid, list_id: Option<Uuid>) -> String {
+    match list_id {
+        Some(list_id) => format!("unsubscribe:{}:{}", subscriber_id, list_id),
+        None => format!("unsubscribe:{}", subscriber_id),
+    }
+}
+
+/// Links sent before there were several lists don't name one, as they were all for the default
+#[derive(Debug, PartialEq)]
+struct UnsubscribeToken {
+    subscriber_id: Uuid,
+    list_id: Option<Uuid>,
+}
+
+fn parse_token(hmac_secret: &HmacSecret, token: &str) -> Option<UnsubscribeToken> {
+    let parts: Vec<_> = token.split('.').collect();
+    let (subscriber_id, list_id, signature) = match parts.as_slice() {
+        [subscriber_id, signature] => (subscriber_id, None, signature),
+        [subscriber_id, list_id, signature] => (
+            subscriber_id,
+            Some(Uuid::parse_str(list_id).ok()?),
+            signature,
+        ),
+        _ => return None,
+    };
+    let subscriber_id = Uuid::parse_str(subscriber_id).ok()?;
+
+    if hmac_secret.verify(&signed_message(subscriber_id, list_id), signature) {
+        Some(UnsubscribeToken {
+            subscriber_id,
+            list_id,
+        })
+    } else {
+        None
+    }
+}
+
+async fn get_list(
+    transaction: &mut dyn SubscriberTransaction,
+    token: &UnsubscribeToken,
+) -> Result<Option<MailingList>, sqlx::Error> {
+    match token.list_id {
+        Some(list_id) => transaction.get_list(list_id).await,
+        None => {
+            transaction
+                .get_list_by_slug(&ListSlug::default_list())
+                .await
+        }
+    }
+}
+
+#[derive(serde::Deserialize)]
+pub struct UnsubscribeParameters {
+    token: String,
+    /// Leave every list rather than the one the link was sent for
+    #[serde(default)]
+    all: bool,
+}
+
+#[derive(thiserror::Error)]
+pub enum UnsubscribeError {
+    #[error("The unsubscribe link is invalid.")]
+    InvalidToken,
+    #[error("Failed to acquire a Postgres connection from the pool.")]
+    PoolError(#[source] sqlx::Error),
+    #[error("Failed to retrieve the subscriber status.")]
+    GetSubscriberStatusError(#[source] sqlx::Error),
+    #[error("Failed to update the subscriber status to `unsubscribed`.")]
+    UnsubscribeError(#[source] sqlx::Error),
+    #[error("Failed to commit SQL transaction to unsubscribe a subscriber.")]
+    TransactionCommitError(#[source] sqlx::Error),
+}
+
+impl std::fmt::Debug for UnsubscribeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        error_chain_fmt(self, f)
+    }
+}
+
+impl ResponseError for UnsubscribeError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            UnsubscribeError::InvalidToken => StatusCode::BAD_REQUEST,
+            UnsubscribeError::PoolError(_)
+            | UnsubscribeError::GetSubscriberStatusError(_)
+            | UnsubscribeError::UnsubscribeError(_)
+            | UnsubscribeError::TransactionCommitError(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        log_error_response(self)
+    }
+}
+
+/// Ask for confirmation first, so that link scanners following the link don't unsubscribe anyone
+#[tracing::instrument(
+    name = "Show the unsubscribe form",
+    skip(parameters, repository, hmac_secret)
+)]
+pub async fn unsubscribe_form(
+    parameters: web::Query<UnsubscribeParameters>,
+    repository: web::Data<dyn SubscriberRepository>,
+    hmac_secret: web::Data<HmacSecret>,
+) -> Result<HttpResponse, UnsubscribeError> {
+    let token =
+        parse_token(&hmac_secret, &parameters.token).ok_or(UnsubscribeError::InvalidToken)?;
+    let mut transaction = repository
+        .begin()
+        .await
+        .map_err(UnsubscribeError::PoolError)?;
+    let list = get_list(transaction.as_mut(), &token)
+        .await
+        .map_err(UnsubscribeError::GetSubscriberStatusError)?
+        .ok_or(UnsubscribeError::InvalidToken)?;
+    let token = htmlescape::encode_attribute(&parameters.token);
+
+    Ok(HttpResponse::Ok()
+        .content_type(ContentType::html())
+        .body(format!(
+            r#"<!DOCTYPE html>
+<html lang="en">
+<head>
+    <meta http-equiv="content-type" content="text/html; charset=utf-8">
+    <title>Unsubscribe</title>
+</head>
+<body>
+    <p>Do you want to stop receiving {list_name}?</p>
+    <form action="/subscriptions/unsubscribe?token={token}" method="post">
+        <button type="submit">Unsubscribe from {list_name}</button>
+    </form>
+    <form action="/subscriptions/unsubscribe?token={token}&amp;all=true" method="post">
+        <button type="submit">Unsubscribe from all our lists</button>
+    </form>
+</body>
+</html>"#,
+            list_name = htmlescape::encode_minimal(&list.name),
+            token = token
+        )))
+}
+
+/// Handles both the form above and RFC 8058 one-click requests from mail clients
+#[tracing::instrument(
+    name = "Unsubscribe a subscriber",
+    skip(parameters, repository, hmac_secret),
+    fields(subscriber_id = tracing::field::Empty, all = %parameters.all)
+)]
+pub async fn unsubscribe(
+    parameters: web::Query<UnsubscribeParameters>,
+    repository: web::Data<dyn SubscriberRepository>,
+    hmac_secret: web::Data<HmacSecret>,
+) -> Result<HttpResponse, UnsubscribeError> {
+    let token =
+        parse_token(&hmac_secret, &parameters.token).ok_or(UnsubscribeError::InvalidToken)?;
+    tracing::Span::current().record(
+        "subscriber_id",
+        &tracing::field::display(&token.subscriber_id),
+    );
+
+    let mut transaction = repository
+        .begin()
+        .await
+        .map_err(UnsubscribeError::PoolError)?;
+    let subscriptions: Vec<ListSubscription> = if parameters.all {
+        transaction
+            .get_list_subscriptions(token.subscriber_id)
+            .await
+            .map_err(UnsubscribeError::GetSubscriberStatusError)?
+    } else {
+        match get_list(transaction.as_mut(), &token)
+            .await
+            .map_err(UnsubscribeError::GetSubscriberStatusError)?
+        {
+            Some(list) => transaction
+                .get_list_subscription(token.subscriber_id, list.id)
+                .await
+                .map_err(UnsubscribeError::GetSubscriberStatusError)?
+                .into_iter()
+                .collect(),
+            None => vec![],
+        }
+    };
+    // Anyone who can't move to `unsubscribed` already gets nothing from that list
+    for subscription in subscriptions {
+        if subscription
+            .status
+            .can_transition_to(SubscriptionStatus::Unsubscribed)
+        {
+            transaction
+                .update_list_status(
+                    subscription.subscriber_id,
+                    subscription.list_id,
+                    SubscriptionStatus::Unsubscribed,
+                )
+                .await
+                .map_err(UnsubscribeError::UnsubscribeError)?;
+        }
+    }
+    transaction
+        .commit()
+        .await
+        .map_err(UnsubscribeError::TransactionCommitError)?;
+
+    Ok(HttpResponse::Ok().content_type(ContentType::html()).body(
+        r#"<!DOCTYPE html>
+<html lang="en">
+<head>
+    <meta http-equiv="content-type" content="text/html; charset=utf-8">
+    <title>Unsubscribed</title>
+</head>
+<body>
+    <p>You have been unsubscribed.</p>
+</body>
+</html>"#,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use claim::{assert_none, assert_some_eq};
+
+    fn token_from_link(link: &str) -> &str {
+        link.split("token=").nth(1).unwrap()
+    }
+
+    #[test]
+    fn tokens_from_unsubscribe_links_are_accepted() {
+        let secret = HmacSecret("secret".into());
+        let subscriber_id = Uuid::new_v4();
+        let list_id = Uuid::new_v4();
+
+        let link = unsubscribe_link("https://example.com", &secret, subscriber_id, list_id);
+
+        assert_some_eq!(
+            parse_token(&secret, token_from_link(&link)),
+            UnsubscribeToken {
+                subscriber_id,
+                list_id: Some(list_id)
+            }
+        );
+    }
+
+    #[test]
+    fn tokens_from_before_there_were_lists_are_accepted() {
+        let secret = HmacSecret("secret".into());
+        let subscriber_id = Uuid::new_v4();
+        let signature = secret.sign(&format!("unsubscribe:{}", subscriber_id));
+
+        let token = format!("{}.{}", subscriber_id, signature);
+
+        assert_some_eq!(
+            parse_token(&secret, &token),
+            UnsubscribeToken {
+                subscriber_id,
+                list_id: None
+            }
+        );
+    }
+
+    #[test]
+    fn tokens_for_another_subscriber_or_list_are_rejected() {
+        let secret = HmacSecret("secret".into());
+        let subscriber_id = Uuid::new_v4();
+        let list_id = Uuid::new_v4();
+        let link = unsubscribe_link("https://example.com", &secret, subscriber_id, list_id);
+        let signature = token_from_link(&link).split('.').nth(2).unwrap();
+
+        let other_subscriber = format!("{}.{}.{}", Uuid::new_v4(), list_id, signature);
+        let other_list = format!("{}.{}.{}", subscriber_id, Uuid::new_v4(), signature);
+        let any_list = format!("{}.{}", subscriber_id, signature);
+
+        assert_none!(parse_token(&secret, &other_subscriber));
+        assert_none!(parse_token(&secret, &other_list));
+        assert_none!(parse_token(&secret, &any_list));
+    }
+
+    #[test]
+    fn malformed_tokens_are_rejected() {
+        let secret = HmacSecret("secret".into());
+
+        assert_none!(parse_token(&secret, ""));
+        assert_none!(parse_token(&secret, "not-a-uuid.signature"));
+        assert_none!(parse_token(&secret, &Uuid::new_v4().to_string()));
+        assert_none!(parse_token(
+            &secret,
+            &format!("{}.not-a-uuid.signature", Uuid::new_v4())
+        ));
+    }
+}
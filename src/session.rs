@@ -0,0 +1,112 @@
+use actix_web::cookie::{Cookie, SameSite};
+use actix_web::{HttpMessage, HttpRequest};
+use chrono::{Duration, Utc};
+use rand::distributions::Alphanumeric;
+use rand::{thread_rng, Rng};
+use sqlx::PgPool;
+use uuid::Uuid;
+
+pub const SESSION_COOKIE_NAME: &str = "id";
+
+/// Sessions are stored in Postgres and expire this long after login
+const SESSION_TTL_HOURS: i64 = 12;
+
+#[tracing::instrument(name = "Start a new session", skip(pool))]
+pub async fn create_session(pool: &PgPool, user_id: Uuid) -> Result<String, sqlx::Error> {
+    let session_token = generate_session_token();
+    let now = Utc::now();
+
+    sqlx::query!(
+        r#"
+            INSERT INTO sessions (session_token, user_id, created_at, expires_at)
+            VALUES ($1, $2, $3, $4)
+        "#,
+        session_token,
+        user_id,
+        now,
+        now + Duration::hours(SESSION_TTL_HOURS)
+    )
+    .execute(pool)
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to execute query: {:?}", e);
+        e
+    })?;
+
+    Ok(session_token)
+}
+
+/// Look up the user behind the request's session cookie, if it refers to a live session
+#[tracing::instrument(name = "Get session user", skip(request, pool))]
+pub async fn get_session_user_id(
+    request: &HttpRequest,
+    pool: &PgPool,
+) -> Result<Option<Uuid>, sqlx::Error> {
+    let session_token = match request.cookie(SESSION_COOKIE_NAME) {
+        Some(cookie) => cookie.value().to_string(),
+        None => return Ok(None),
+    };
+
+    let row = sqlx::query!(
+        r#"
+            SELECT user_id FROM sessions
+            WHERE session_token = $1 AND expires_at > now()
+        "#,
+        session_token
+    )
+    .fetch_optional(pool)
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to execute query: {:?}", e);
+        e
+    })?;
+
+    Ok(row.map(|r| r.user_id))
+}
+
+/// Remove the request's session from the store, if it has one
+#[tracing::instrument(name = "End the current session", skip(request, pool))]
+pub async fn delete_session(request: &HttpRequest, pool: &PgPool) -> Result<(), sqlx::Error> {
+    let session_token = match request.cookie(SESSION_COOKIE_NAME) {
+        Some(cookie) => cookie.value().to_string(),
+        None => return Ok(()),
+    };
+
+    sqlx::query!(
+        r#"
+            DELETE FROM sessions WHERE session_token = $1
+        "#,
+        session_token
+    )
+    .execute(pool)
+    .await
+    .map_err(|e| {
+        tracing::error!("Failed to execute query: {:?}", e);
+        e
+    })?;
+
+    Ok(())
+}
+
+/// The cookie handed to the browser; `secure` should be set whenever the app is served over https
+pub fn session_cookie(session_token: String, secure: bool) -> Cookie<'static> {
+    Cookie::build(SESSION_COOKIE_NAME, session_token)
+        .path("/")
+        .http_only(true)
+        .same_site(SameSite::Strict)
+        .secure(secure)
+        .finish()
+}
+
+/// A cookie to pass to `del_cookie`, so that the browser forgets its session
+pub fn session_removal_cookie() -> Cookie<'static> {
+    Cookie::build(SESSION_COOKIE_NAME, "").path("/").finish()
+}
+
+fn generate_session_token() -> String {
+    let mut rng = thread_rng();
+    std::iter::repeat_with(|| rng.sample(Alphanumeric))
+        .map(char::from)
+        .take(32)
+        .collect()
+}
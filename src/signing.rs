@@ -0,0 +1,58 @@
+use hmac::{Hmac, Mac, NewMac};
+use sha2::Sha256;
+
+/// The key for signing links in emails, so that subscribers can act on them without logging in
+#[derive(Clone)]
+pub struct HmacSecret(pub String);
+
+impl HmacSecret {
+    /// Sign the message, returning the signature in url-safe base64
+    pub fn sign(&self, message: &str) -> String {
+        let tag = self.mac(message).finalize().into_bytes();
+        base64::encode_config(tag, base64::URL_SAFE_NO_PAD)
+    }
+
+    /// Check a signature made by `sign`, in constant time
+    pub fn verify(&self, message: &str, signature: &str) -> bool {
+        match base64::decode_config(signature, base64::URL_SAFE_NO_PAD) {
+            Ok(tag) => self.mac(message).verify(&tag).is_ok(),
+            Err(_) => false,
+        }
+    }
+
+    fn mac(&self, message: &str) -> Hmac<Sha256> {
+        let mut mac =
+            Hmac::<Sha256>::new_varkey(self.0.as_bytes()).expect("HMAC accepts keys of any length");
+        mac.update(message.as_bytes());
+        mac
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::HmacSecret;
+
+    #[test]
+    fn signatures_can_be_verified() {
+        let secret = HmacSecret("secret".into());
+        let signature = secret.sign("a message");
+
+        assert!(secret.verify("a message", &signature));
+    }
+
+    #[test]
+    fn signatures_do_not_match_other_messages() {
+        let secret = HmacSecret("secret".into());
+        let signature = secret.sign("a message");
+
+        assert!(!secret.verify("another message", &signature));
+    }
+
+    #[test]
+    fn signatures_do_not_match_other_secrets() {
+        let signature = HmacSecret("secret".into()).sign("a message");
+
+        assert!(!HmacSecret("another secret".into()).verify("a message", &signature));
+        assert!(!HmacSecret("secret".into()).verify("a message", "not base64!"));
+    }
+}
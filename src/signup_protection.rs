@@ -0,0 +1,328 @@
+use crate::signing::HmacSecret;
+use chrono::{DateTime, Duration, TimeZone, Utc};
+use sha2::{Digest, Sha256};
+
+/// A served form can be submitted for this long
+const FORM_TOKEN_TTL_HOURS: i64 = 24;
+/// Proof-of-work stamps must be minted this recently
+const STAMP_TTL_MINUTES: i64 = 60;
+/// How far ahead of our clock a client's stamp can be
+const MAX_CLOCK_SKEW_MINUTES: i64 = 5;
+
+/// Defenses against scripted signups. Each one is off unless configured.
+#[derive(Debug, Clone, Copy)]
+pub struct SignupProtection {
+    /// Silently ignore signups that fill in a field hidden from humans
+    pub honeypot: bool,
+    /// Reject forms submitted sooner than this after they were served
+    pub min_fill_time: Option<Duration>,
+    /// Require a hashcash stamp for the email whose SHA-256 starts with this many zero bits
+    pub proof_of_work_bits: Option<u32>,
+}
+
+#[derive(thiserror::Error, Debug, PartialEq)]
+pub enum SignupRejected {
+    #[error("The form token is missing or has not been signed by us.")]
+    InvalidFormToken,
+    #[error("The form was submitted {0} seconds after it was served.")]
+    SubmittedTooFast(i64),
+    #[error("The form was served too long ago.")]
+    FormExpired,
+    #[error("The proof of work is missing.")]
+    MissingProofOfWork,
+    #[error("The proof of work is malformed, stale, or for another email: {0}")]
+    InvalidProofOfWork(&'static str),
+    #[error("The proof of work has {0} leading zero bits, fewer than required.")]
+    InsufficientProofOfWork(u32),
+}
+
+/// Sign the time a signup form is served, for `check_form_token` to verify on submission
+pub fn issue_form_token(hmac_secret: &HmacSecret, now: DateTime<Utc>) -> String {
+    let issued_at = now.timestamp();
+    format!(
+        "{}.{}",
+        issued_at,
+        hmac_secret.sign(&form_token_message(issued_at))
+    )
+}
+
+pub fn check_form_token(
+    hmac_secret: &HmacSecret,
+    form_token: Option<&str>,
+    min_fill_time: Duration,
+    now: DateTime<Utc>,
+) -> Result<(), SignupRejected> {
+    let (issued_at, signature) = form_token
+        .and_then(|token| token.split_once('.'))
+        .ok_or(SignupRejected::InvalidFormToken)?;
+    let issued_at: i64 = issued_at
+        .parse()
+        .map_err(|_| SignupRejected::InvalidFormToken)?;
+    if !hmac_secret.verify(&form_token_message(issued_at), signature) {
+        return Err(SignupRejected::InvalidFormToken);
+    }
+
+    let issued_at = Utc
+        .timestamp_opt(issued_at, 0)
+        .single()
+        .ok_or(SignupRejected::InvalidFormToken)?;
+    if issued_at > now - min_fill_time {
+        let elapsed = now.signed_duration_since(issued_at);
+        return Err(SignupRejected::SubmittedTooFast(elapsed.num_seconds()));
+    }
+    if issued_at < now - Duration::hours(FORM_TOKEN_TTL_HOURS) {
+        return Err(SignupRejected::FormExpired);
+    }
+    Ok(())
+}
+
+// Keeps these signatures from being valid anywhere else the secret is used
+fn form_token_message(issued_at: i64) -> String {
+    format!("signup-form:{}", issued_at)
+}
+
+/// Check a hashcash stamp, `1:bits:unix_time:email:extension:random:counter`.
+/// Stamps are bound to the email and expire, so they are not worth collecting.
+pub fn verify_proof_of_work(
+    stamp: Option<&str>,
+    email: &str,
+    required_bits: u32,
+    now: DateTime<Utc>,
+) -> Result<(), SignupRejected> {
+    let stamp = stamp
+        .filter(|stamp| !stamp.is_empty())
+        .ok_or(SignupRejected::MissingProofOfWork)?;
+    // The email is the only field that could contain a colon
+    let mut head = stamp.splitn(4, ':');
+    let (version, minted_at, rest) = match (head.next(), head.nth(1), head.next()) {
+        (Some(version), Some(minted_at), Some(rest)) => (version, minted_at, rest),
+        _ => return Err(SignupRejected::InvalidProofOfWork("too few fields")),
+    };
+    let resource = match rest.rsplitn(4, ':').nth(3) {
+        Some(resource) => resource,
+        None => return Err(SignupRejected::InvalidProofOfWork("too few fields")),
+    };
+    if version != "1" {
+        return Err(SignupRejected::InvalidProofOfWork("unknown version"));
+    }
+    if !resource.eq_ignore_ascii_case(email) {
+        return Err(SignupRejected::InvalidProofOfWork("wrong email"));
+    }
+    let minted_at = minted_at
+        .parse()
+        .ok()
+        .and_then(|minted_at| Utc.timestamp_opt(minted_at, 0).single())
+        .ok_or(SignupRejected::InvalidProofOfWork("invalid time"))?;
+    // Compare against `now` rather than subtracting, as the time comes straight from the client
+    if minted_at < now - Duration::minutes(STAMP_TTL_MINUTES)
+        || minted_at > now + Duration::minutes(MAX_CLOCK_SKEW_MINUTES)
+    {
+        return Err(SignupRejected::InvalidProofOfWork("stale"));
+    }
+
+    let bits = leading_zero_bits(&Sha256::digest(stamp.as_bytes()));
+    if bits < required_bits {
+        return Err(SignupRejected::InsufficientProofOfWork(bits));
+    }
+    Ok(())
+}
+
+fn leading_zero_bits(hash: &[u8]) -> u32 {
+    let mut bits = 0;
+    for byte in hash {
+        bits += byte.leading_zeros();
+        if *byte != 0 {
+            break;
+        }
+    }
+    bits
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use claim::{assert_err, assert_ok};
+
+    fn secret() -> HmacSecret {
+        HmacSecret("secret".into())
+    }
+
+    fn mint(resource: &str, minted_at: DateTime<Utc>, bits: u32) -> String {
+        (0u64..)
+            .map(|counter| {
+                format!(
+                    "1:{}:{}:{}::c2FsdA:{}",
+                    bits,
+                    minted_at.timestamp(),
+                    resource,
+                    counter
+                )
+            })
+            .find(|stamp| leading_zero_bits(&Sha256::digest(stamp.as_bytes())) >= bits)
+            .unwrap()
+    }
+
+    #[test]
+    fn leading_zero_bits_are_counted_across_bytes() {
+        assert_eq!(leading_zero_bits(&[0xff]), 0);
+        assert_eq!(leading_zero_bits(&[0x00, 0x10, 0x00]), 11);
+        assert_eq!(leading_zero_bits(&[0x00, 0x00]), 16);
+    }
+
+    #[test]
+    fn form_tokens_are_accepted_after_the_minimum_fill_time() {
+        let now = Utc::now();
+        let token = issue_form_token(&secret(), now);
+
+        assert_ok!(check_form_token(
+            &secret(),
+            Some(&token),
+            Duration::seconds(3),
+            now + Duration::seconds(3)
+        ));
+    }
+
+    #[test]
+    fn forms_submitted_too_fast_are_rejected() {
+        let now = Utc::now();
+        let token = issue_form_token(&secret(), now);
+
+        assert_eq!(
+            check_form_token(
+                &secret(),
+                Some(&token),
+                Duration::seconds(3),
+                now + Duration::seconds(1)
+            ),
+            Err(SignupRejected::SubmittedTooFast(1))
+        );
+    }
+
+    #[test]
+    fn old_forms_are_rejected() {
+        let now = Utc::now();
+        let token = issue_form_token(&secret(), now);
+
+        assert_eq!(
+            check_form_token(
+                &secret(),
+                Some(&token),
+                Duration::seconds(3),
+                now + Duration::days(2)
+            ),
+            Err(SignupRejected::FormExpired)
+        );
+    }
+
+    #[test]
+    fn missing_and_tampered_form_tokens_are_rejected() {
+        let now = Utc::now();
+        let token = issue_form_token(&secret(), now - Duration::minutes(1));
+        let (issued_at, signature) = token.split_once('.').unwrap();
+        let issued_at: i64 = issued_at.parse().unwrap();
+        let backdated = format!("{}.{}", issued_at - 60, signature);
+        let test_cases = vec![
+            None,
+            Some("garbage"),
+            Some("1622000000.not-a-signature"),
+            Some(backdated.as_str()),
+        ];
+
+        for form_token in test_cases {
+            assert_eq!(
+                check_form_token(&secret(), form_token, Duration::seconds(3), now),
+                Err(SignupRejected::InvalidFormToken),
+                "{:?} was accepted",
+                form_token
+            );
+        }
+        let other_secret = HmacSecret("another secret".into());
+        assert_err!(check_form_token(
+            &other_secret,
+            Some(&token),
+            Duration::seconds(3),
+            now
+        ));
+    }
+
+    #[test]
+    fn valid_stamps_are_accepted() {
+        let now = Utc::now();
+        let stamp = mint("ursula@example.com", now, 8);
+
+        assert_ok!(verify_proof_of_work(
+            Some(&stamp),
+            "ursula@example.com",
+            8,
+            now
+        ));
+    }
+
+    #[test]
+    fn stamps_for_another_email_are_rejected() {
+        let now = Utc::now();
+        let stamp = mint("someone_else@example.com", now, 8);
+
+        assert_err!(verify_proof_of_work(
+            Some(&stamp),
+            "ursula@example.com",
+            8,
+            now
+        ));
+    }
+
+    #[test]
+    fn stale_stamps_are_rejected() {
+        let now = Utc::now();
+        let stamp = mint("ursula@example.com", now - Duration::hours(2), 8);
+
+        assert_err!(verify_proof_of_work(
+            Some(&stamp),
+            "ursula@example.com",
+            8,
+            now
+        ));
+    }
+
+    #[test]
+    fn stamps_with_an_out_of_range_time_are_rejected() {
+        let now = Utc::now();
+        let stamp = "1:8:99999999999999999:ursula@example.com::x:0";
+
+        assert_eq!(
+            verify_proof_of_work(Some(stamp), "ursula@example.com", 8, now),
+            Err(SignupRejected::InvalidProofOfWork("invalid time"))
+        );
+    }
+
+    #[test]
+    fn stamps_without_enough_work_are_rejected() {
+        let now = Utc::now();
+        // Claiming more bits than were worked for doesn't help
+        let stamp = mint("ursula@example.com", now, 0).replacen(":0:", ":20:", 1);
+
+        assert!(matches!(
+            verify_proof_of_work(Some(&stamp), "ursula@example.com", 20, now),
+            Err(SignupRejected::InsufficientProofOfWork(_))
+        ));
+    }
+
+    #[test]
+    fn missing_and_malformed_stamps_are_rejected() {
+        let now = Utc::now();
+        let test_cases = vec![
+            None,
+            Some(""),
+            Some("1:8"),
+            Some("2:8:0:ursula@example.com::x:1"),
+        ];
+
+        for stamp in test_cases {
+            assert_err!(
+                verify_proof_of_work(stamp, "ursula@example.com", 8, now),
+                "{:?} was accepted",
+                stamp
+            );
+        }
+    }
+}
@@ -1,18 +1,29 @@
 use actix_web::dev::Server;
-use actix_web::{web, App, HttpServer};
+use actix_web::{guard, web, App, HttpServer};
 use sqlx::PgPool;
 use std::net::TcpListener;
+use std::sync::Arc;
 use std::time::Duration;
 use tracing_actix_web::TracingLogger;
 
-use crate::configuration::{DatabaseSettings, EmailClientSettings, Settings};
-use crate::email_client::EmailClient;
+use crate::configuration::{DatabaseSettings, Settings};
+use crate::email_client::EmailSender;
+use crate::email_outbox::EmailOutboxRelay;
+use crate::issue_delivery_worker::IssueDeliveryWorker;
+use crate::rate_limit::RateLimiter;
+use crate::request_origin::TrustedProxies;
 use crate::routes::*;
+use crate::signing::HmacSecret;
+use crate::subscriber_repository::{PostgresSubscriberRepository, SubscriberRepository};
+use crate::token_cleanup::TokenCleanupWorker;
 use sqlx::postgres::PgPoolOptions;
 
 pub struct Application {
     port: u16,
     server: Server,
+    delivery_worker: IssueDeliveryWorker,
+    outbox_relay: EmailOutboxRelay,
+    token_cleanup: TokenCleanupWorker,
 }
 
 impl Application {
@@ -20,23 +31,78 @@ impl Application {
         let db_pool = get_connection_pool(&config.database)
             .await
             .expect("Failed to connect to Postgres");
-        let email_client = email_client(config.email_client);
+        let subscriber_repository = Arc::new(PostgresSubscriberRepository::new(db_pool.clone()));
+        Self::build_with_pool(config, db_pool, subscriber_repository)
+    }
+
+    /// Store subscribers somewhere other than Postgres, e.g. in memory for tests
+    pub async fn build_with_subscriber_repository(
+        config: Settings,
+        subscriber_repository: Arc<dyn SubscriberRepository>,
+    ) -> Result<Self, std::io::Error> {
+        let db_pool = get_connection_pool(&config.database)
+            .await
+            .expect("Failed to connect to Postgres");
+        Self::build_with_pool(config, db_pool, subscriber_repository)
+    }
+
+    fn build_with_pool(
+        config: Settings,
+        db_pool: PgPool,
+        subscriber_repository: Arc<dyn SubscriberRepository>,
+    ) -> Result<Self, std::io::Error> {
+        let email_client = config.email_client.clone().client();
+        let token_ttl = config.application.subscription_token_ttl();
+        let hmac_secret = HmacSecret(config.application.hmac_secret.clone());
+        let delivery_worker = IssueDeliveryWorker::new(
+            db_pool.clone(),
+            email_client.clone(),
+            config.application.base_url.clone(),
+            hmac_secret.clone(),
+            config.application.preferences_link_ttl(),
+        );
+        let outbox_relay = EmailOutboxRelay::new(db_pool.clone(), email_client.clone());
+        let token_cleanup = TokenCleanupWorker::new(db_pool.clone(), token_ttl);
 
         let address = format!("{}:{}", config.application.host, config.application.port);
         let listener = TcpListener::bind(address)?;
         let port = listener.local_addr().unwrap().port();
-        let server = run(listener, db_pool, email_client, config.application.base_url)?;
+        let server = run(
+            listener,
+            db_pool,
+            subscriber_repository,
+            email_client,
+            &config,
+        )?;
 
-        Ok(Self { port, server })
+        Ok(Self {
+            port,
+            server,
+            delivery_worker,
+            outbox_relay,
+            token_cleanup,
+        })
     }
 
     pub fn port(&self) -> u16 {
         self.port
     }
 
+    /// Run only the HTTP server; queued emails are left for a separate worker process
     pub async fn run_until_stopped(self) -> Result<(), std::io::Error> {
         self.server.await
     }
+
+    /// Run the HTTP server alongside the issue delivery worker, the email outbox relay
+    /// and the subscription token cleanup
+    pub async fn run_with_workers_until_stopped(self) -> Result<(), std::io::Error> {
+        tokio::select! {
+            outcome = self.server => outcome,
+            outcome = self.delivery_worker.run_until_stopped() => outcome,
+            outcome = self.outbox_relay.run_until_stopped() => outcome,
+            outcome = self.token_cleanup.run_until_stopped() => outcome,
+        }
+    }
 }
 
 pub async fn get_connection_pool(db_config: &DatabaseSettings) -> Result<PgPool, sqlx::Error> {
@@ -46,35 +112,102 @@ pub async fn get_connection_pool(db_config: &DatabaseSettings) -> Result<PgPool,
         .await
 }
 
-fn email_client(email_config: EmailClientSettings) -> EmailClient {
-    let sender_email = email_config.sender().expect("Invalid sender email address");
-    let base_url = email_config
-        .base_url()
-        .expect("Invalid email client base url");
+pub struct ApplicationBaseUrl(pub String);
 
-    EmailClient::new(base_url, sender_email, email_config.authorization_token)
-        .expect("Invalid email url path")
-}
+/// How long a subscription token can be used to confirm a subscription
+pub struct SubscriptionTokenTtl(pub chrono::Duration);
 
-pub struct ApplicationBaseUrl(pub String);
+/// How long the link to the preference page in an email can be used
+pub struct PreferencesLinkTtl(pub chrono::Duration);
 
 fn run(
     listener: TcpListener,
     db_pool: PgPool,
-    email_client: EmailClient,
-    base_url: String,
+    subscriber_repository: Arc<dyn SubscriberRepository>,
+    email_client: Arc<dyn EmailSender>,
+    config: &Settings,
 ) -> Result<Server, std::io::Error> {
+    let base_url = config.application.base_url.clone();
+    let hmac_secret = HmacSecret(config.application.hmac_secret.clone());
+    let token_ttl = config.application.subscription_token_ttl();
+    let preferences_link_ttl = config.application.preferences_link_ttl();
+    let postmark_webhook_settings = config.postmark_webhook.clone();
+    let confirmation_email_throttle = config.application.confirmation_emails.throttle();
+    let signup_protection = config.application.signup_protection.protection();
+    // Created outside the app factory, so that every worker shares the same buckets
+    let trusted_proxies = TrustedProxies(config.application.trusted_proxies.clone());
+    let rate_limits = &config.application.rate_limits;
+    let subscriptions_limiter =
+        RateLimiter::new(rate_limits.subscriptions.quota(), trusted_proxies.clone());
+    let resend_confirmation_limiter = RateLimiter::new(
+        rate_limits.resend_confirmation.quota(),
+        trusted_proxies.clone(),
+    );
     let db_pool = web::Data::new(db_pool);
-    let email_client = web::Data::new(email_client);
+    let subscriber_repository = web::Data::from(subscriber_repository);
+    let email_client = web::Data::from(email_client);
     let server = HttpServer::new(move || {
         App::new()
             .wrap(TracingLogger)
             .route("/health_check", web::get().to(health_check))
-            .route("/subscriptions", web::post().to(subscribe))
+            // Only signups are limited; other methods fall through to the routes below
+            .service(
+                web::resource("/subscriptions")
+                    .guard(guard::Post())
+                    .wrap(subscriptions_limiter.clone())
+                    .route(web::post().to(subscribe)),
+            )
+            .route("/subscriptions", web::get().to(subscribe_form))
             .route("/subscriptions/confirm", web::get().to(confirm))
+            .service(
+                web::resource("/subscriptions/confirm/resend")
+                    .wrap(resend_confirmation_limiter.clone())
+                    .route(web::post().to(resend_confirmation)),
+            )
+            .route(
+                "/subscriptions/unsubscribe",
+                web::get().to(unsubscribe_form),
+            )
+            .route("/subscriptions/unsubscribe", web::post().to(unsubscribe))
+            .route(
+                "/subscriptions/preferences",
+                web::get().to(preferences_form),
+            )
+            .route(
+                "/subscriptions/preferences",
+                web::post().to(update_preferences),
+            )
+            .route("/newsletters", web::post().to(publish_newsletter))
+            .route("/webhooks/postmark", web::post().to(postmark_webhook))
+            .route("/login", web::get().to(login_form))
+            .route("/login", web::post().to(login))
+            .route("/admin/dashboard", web::get().to(admin_dashboard))
+            .route("/admin/logout", web::post().to(log_out))
+            .route("/admin/lists", web::get().to(get_mailing_lists))
+            .route("/admin/lists", web::post().to(create_mailing_list))
+            .route(
+                "/admin/subscribers/{subscriber_id}/consent",
+                web::get().to(subscriber_consent_history),
+            )
+            .route(
+                "/admin/personal_data/export",
+                web::post().to(export_subscriber_data),
+            )
+            .route(
+                "/admin/personal_data/erase",
+                web::post().to(erase_subscriber_data),
+            )
             .app_data(db_pool.clone())
+            .app_data(subscriber_repository.clone())
             .app_data(email_client.clone())
             .data(ApplicationBaseUrl(base_url.clone()))
+            .data(hmac_secret.clone())
+            .data(SubscriptionTokenTtl(token_ttl))
+            .data(PreferencesLinkTtl(preferences_link_ttl))
+            .data(confirmation_email_throttle)
+            .data(signup_protection)
+            .data(trusted_proxies.clone())
+            .data(postmark_webhook_settings.clone())
     })
     .listen(listener)?
     .run();
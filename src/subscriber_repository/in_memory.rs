@@ -0,0 +1,421 @@
+use super::{
+    hash_subscription_token, ConsentEvent, ListSubscription, MailingList, Subscriber,
+    SubscriberRepository, SubscriberTransaction, SubscriptionToken,
+};
+use crate::confirmation_email_throttle::ConfirmationEmailHistory;
+use crate::domain::{
+    DigestFrequency, ListSlug, NewSubscriber, SubscriberEmail, SubscriberName, SubscriptionStatus,
+    DEFAULT_LIST_SLUG,
+};
+use crate::email_client::Email;
+use crate::suppression_list::SuppressionReason;
+use async_trait::async_trait;
+use chrono::Utc;
+use std::collections::HashMap;
+use std::sync::Arc;
+use tokio::sync::{Mutex, OwnedMutexGuard};
+use uuid::Uuid;
+
+/// Keeps everything in memory, for testing handlers without Postgres.
+/// Transactions run one at a time and work on a copy of the data, which replaces
+/// the original when they are committed.
+/// Clones share the same data, which starts with the default list, as a migrated database does.
+#[derive(Clone, Default)]
+pub struct InMemorySubscriberRepository {
+    data: Arc<Mutex<Data>>,
+}
+
+#[derive(Clone)]
+struct Data {
+    subscribers: HashMap<Uuid, Subscriber>,
+    lists: HashMap<Uuid, MailingList>,
+    list_subscriptions: HashMap<(Uuid, Uuid), ListSubscription>,
+    tokens: HashMap<Vec<u8>, SubscriptionToken>,
+    consent_events: Vec<ConsentEvent>,
+    confirmation_emails: HashMap<String, ConfirmationEmailHistory>,
+    suppressed_emails: HashMap<String, SuppressionReason>,
+    outbox: Vec<Email>,
+}
+
+impl Default for Data {
+    fn default() -> Self {
+        let default_list = MailingList {
+            id: Uuid::new_v4(),
+            slug: DEFAULT_LIST_SLUG.into(),
+            name: "Newsletter".into(),
+        };
+        Self {
+            subscribers: HashMap::new(),
+            lists: vec![(default_list.id, default_list)].into_iter().collect(),
+            list_subscriptions: HashMap::new(),
+            tokens: HashMap::new(),
+            consent_events: Vec::new(),
+            confirmation_emails: HashMap::new(),
+            suppressed_emails: HashMap::new(),
+            outbox: Vec::new(),
+        }
+    }
+}
+
+impl InMemorySubscriberRepository {
+    pub async fn subscribers(&self) -> Vec<Subscriber> {
+        self.data
+            .lock()
+            .await
+            .subscribers
+            .values()
+            .cloned()
+            .collect()
+    }
+
+    pub async fn list_subscriptions(&self) -> Vec<ListSubscription> {
+        self.data
+            .lock()
+            .await
+            .list_subscriptions
+            .values()
+            .cloned()
+            .collect()
+    }
+
+    pub async fn suppressed_emails(&self) -> HashMap<String, SuppressionReason> {
+        self.data.lock().await.suppressed_emails.clone()
+    }
+
+    /// The emails queued by committed transactions, oldest first
+    pub async fn outbox(&self) -> Vec<Email> {
+        self.data.lock().await.outbox.clone()
+    }
+}
+
+#[async_trait]
+impl SubscriberRepository for InMemorySubscriberRepository {
+    async fn begin(&self) -> Result<Box<dyn SubscriberTransaction>, sqlx::Error> {
+        let committed = self.data.clone().lock_owned().await;
+        let data = committed.clone();
+        Ok(Box::new(InMemorySubscriberTransaction { committed, data }))
+    }
+}
+
+struct InMemorySubscriberTransaction {
+    committed: OwnedMutexGuard<Data>,
+    data: Data,
+}
+
+#[async_trait]
+impl SubscriberTransaction for InMemorySubscriberTransaction {
+    async fn insert_subscriber(
+        &mut self,
+        new_subscriber: &NewSubscriber,
+    ) -> Result<Option<Uuid>, sqlx::Error> {
+        let email = new_subscriber.email.as_ref();
+        if self
+            .data
+            .subscribers
+            .values()
+            .any(|s| s.email.to_lowercase() == email.to_lowercase())
+        {
+            return Ok(None);
+        }
+
+        let subscriber = Subscriber {
+            id: Uuid::new_v4(),
+            email: email.into(),
+            name: new_subscriber.name.as_ref().into(),
+            digest_frequency: DigestFrequency::Immediately,
+        };
+        let subscriber_id = subscriber.id;
+        self.data.subscribers.insert(subscriber_id, subscriber);
+
+        Ok(Some(subscriber_id))
+    }
+
+    async fn get_subscriber(
+        &mut self,
+        subscriber_id: Uuid,
+    ) -> Result<Option<Subscriber>, sqlx::Error> {
+        Ok(self.data.subscribers.get(&subscriber_id).cloned())
+    }
+
+    async fn get_subscriber_by_email(
+        &mut self,
+        email: &SubscriberEmail,
+    ) -> Result<Option<Subscriber>, sqlx::Error> {
+        Ok(self
+            .data
+            .subscribers
+            .values()
+            .find(|s| s.email.to_lowercase() == email.as_ref().to_lowercase())
+            .cloned())
+    }
+
+    async fn create_list(
+        &mut self,
+        slug: &ListSlug,
+        name: &str,
+    ) -> Result<Option<Uuid>, sqlx::Error> {
+        if self.data.lists.values().any(|l| l.slug == slug.as_ref()) {
+            return Ok(None);
+        }
+        let list = MailingList {
+            id: Uuid::new_v4(),
+            slug: slug.as_ref().into(),
+            name: name.into(),
+        };
+        let list_id = list.id;
+        self.data.lists.insert(list_id, list);
+
+        Ok(Some(list_id))
+    }
+
+    async fn get_lists(&mut self) -> Result<Vec<MailingList>, sqlx::Error> {
+        let mut lists: Vec<_> = self.data.lists.values().cloned().collect();
+        lists.sort_by(|a, b| a.name.cmp(&b.name));
+        Ok(lists)
+    }
+
+    async fn get_list(&mut self, list_id: Uuid) -> Result<Option<MailingList>, sqlx::Error> {
+        Ok(self.data.lists.get(&list_id).cloned())
+    }
+
+    async fn get_list_by_slug(
+        &mut self,
+        slug: &ListSlug,
+    ) -> Result<Option<MailingList>, sqlx::Error> {
+        Ok(self
+            .data
+            .lists
+            .values()
+            .find(|l| l.slug == slug.as_ref())
+            .cloned())
+    }
+
+    async fn get_list_subscription(
+        &mut self,
+        subscriber_id: Uuid,
+        list_id: Uuid,
+    ) -> Result<Option<ListSubscription>, sqlx::Error> {
+        Ok(self
+            .data
+            .list_subscriptions
+            .get(&(subscriber_id, list_id))
+            .cloned())
+    }
+
+    async fn get_list_subscriptions(
+        &mut self,
+        subscriber_id: Uuid,
+    ) -> Result<Vec<ListSubscription>, sqlx::Error> {
+        Ok(self
+            .data
+            .list_subscriptions
+            .values()
+            .filter(|s| s.subscriber_id == subscriber_id)
+            .cloned()
+            .collect())
+    }
+
+    async fn add_list_subscription(
+        &mut self,
+        subscriber_id: Uuid,
+        list_id: Uuid,
+    ) -> Result<(), sqlx::Error> {
+        let key = (subscriber_id, list_id);
+        if !self.data.subscribers.contains_key(&subscriber_id)
+            || !self.data.lists.contains_key(&list_id)
+            || self.data.list_subscriptions.contains_key(&key)
+        {
+            return Err(sqlx::Error::RowNotFound);
+        }
+        self.data.list_subscriptions.insert(
+            key,
+            ListSubscription {
+                subscriber_id,
+                list_id,
+                status: SubscriptionStatus::PendingConfirmation,
+            },
+        );
+        Ok(())
+    }
+
+    async fn update_list_status(
+        &mut self,
+        subscriber_id: Uuid,
+        list_id: Uuid,
+        status: SubscriptionStatus,
+    ) -> Result<(), sqlx::Error> {
+        if let Some(subscription) = self
+            .data
+            .list_subscriptions
+            .get_mut(&(subscriber_id, list_id))
+        {
+            subscription.status = status;
+        }
+        Ok(())
+    }
+
+    async fn resubscribe(&mut self, subscriber_id: Uuid, list_id: Uuid) -> Result<(), sqlx::Error> {
+        self.update_list_status(
+            subscriber_id,
+            list_id,
+            SubscriptionStatus::PendingConfirmation,
+        )
+        .await
+    }
+
+    async fn rename_subscriber(
+        &mut self,
+        subscriber_id: Uuid,
+        name: &SubscriberName,
+    ) -> Result<(), sqlx::Error> {
+        if let Some(subscriber) = self.data.subscribers.get_mut(&subscriber_id) {
+            subscriber.name = name.as_ref().into();
+        }
+        Ok(())
+    }
+
+    async fn update_preferences(
+        &mut self,
+        subscriber_id: Uuid,
+        name: &SubscriberName,
+        digest_frequency: DigestFrequency,
+    ) -> Result<(), sqlx::Error> {
+        if let Some(subscriber) = self.data.subscribers.get_mut(&subscriber_id) {
+            subscriber.name = name.as_ref().into();
+            subscriber.digest_frequency = digest_frequency;
+        }
+        Ok(())
+    }
+
+    async fn store_token(
+        &mut self,
+        subscriber_id: Uuid,
+        list_id: Uuid,
+        subscription_token: &str,
+        pending_name: Option<&SubscriberName>,
+    ) -> Result<(), sqlx::Error> {
+        if !self.data.subscribers.contains_key(&subscriber_id)
+            || !self.data.lists.contains_key(&list_id)
+        {
+            return Err(sqlx::Error::RowNotFound);
+        }
+
+        let token = SubscriptionToken {
+            subscriber_id,
+            list_id,
+            created_at: Utc::now(),
+            consumed_at: None,
+            pending_name: pending_name.map(|name| name.as_ref().into()),
+        };
+        self.data
+            .tokens
+            .insert(hash_subscription_token(subscription_token), token);
+        Ok(())
+    }
+
+    async fn get_token(
+        &mut self,
+        subscription_token: &str,
+    ) -> Result<Option<SubscriptionToken>, sqlx::Error> {
+        let hash = hash_subscription_token(subscription_token);
+        Ok(self.data.tokens.get(&hash).cloned())
+    }
+
+    async fn consume_token(&mut self, subscription_token: &str) -> Result<(), sqlx::Error> {
+        let hash = hash_subscription_token(subscription_token);
+        if let Some(token) = self.data.tokens.get_mut(&hash) {
+            token.consumed_at = Some(Utc::now());
+        }
+        Ok(())
+    }
+
+    async fn record_consent_event(&mut self, event: &ConsentEvent) -> Result<(), sqlx::Error> {
+        if !self.data.subscribers.contains_key(&event.subscriber_id)
+            || !self.data.lists.contains_key(&event.list_id)
+        {
+            return Err(sqlx::Error::RowNotFound);
+        }
+        self.data.consent_events.push(event.clone());
+        Ok(())
+    }
+
+    async fn get_consent_events(
+        &mut self,
+        subscriber_id: Uuid,
+    ) -> Result<Vec<ConsentEvent>, sqlx::Error> {
+        let mut events: Vec<_> = self
+            .data
+            .consent_events
+            .iter()
+            .filter(|event| event.subscriber_id == subscriber_id)
+            .cloned()
+            .collect();
+        events.sort_by_key(|event| event.occurred_at);
+        Ok(events)
+    }
+
+    async fn get_confirmation_email_history(
+        &mut self,
+        recipient: &SubscriberEmail,
+    ) -> Result<Option<ConfirmationEmailHistory>, sqlx::Error> {
+        Ok(self
+            .data
+            .confirmation_emails
+            .get(&recipient.as_ref().to_lowercase())
+            .cloned())
+    }
+
+    async fn save_confirmation_email_history(
+        &mut self,
+        recipient: &SubscriberEmail,
+        history: &ConfirmationEmailHistory,
+    ) -> Result<(), sqlx::Error> {
+        self.data
+            .confirmation_emails
+            .insert(recipient.as_ref().to_lowercase(), history.clone());
+        Ok(())
+    }
+
+    async fn suppress_email(
+        &mut self,
+        email: &str,
+        reason: SuppressionReason,
+    ) -> Result<(), sqlx::Error> {
+        self.data
+            .suppressed_emails
+            .entry(email.to_lowercase())
+            .or_insert(reason);
+        Ok(())
+    }
+
+    /// Personal data is only ever erased from Postgres, so there are no tombstones here
+    async fn clear_erasure(&mut self, _email: &str) -> Result<(), sqlx::Error> {
+        Ok(())
+    }
+
+    async fn enqueue_email(
+        &mut self,
+        recipient: &SubscriberEmail,
+        subject: &str,
+        html_body: &str,
+        text_body: &str,
+    ) -> Result<(), sqlx::Error> {
+        self.data.outbox.push(Email {
+            recipient: recipient.clone(),
+            subject: subject.into(),
+            html_body: html_body.into(),
+            text_body: text_body.into(),
+            headers: vec![],
+        });
+        Ok(())
+    }
+
+    async fn commit(self: Box<Self>) -> Result<(), sqlx::Error> {
+        let Self {
+            mut committed,
+            data,
+        } = *self;
+        *committed = data;
+        Ok(())
+    }
+}
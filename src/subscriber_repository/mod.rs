@@ -0,0 +1,234 @@
+mod in_memory;
+mod postgres;
+
+pub use in_memory::InMemorySubscriberRepository;
+pub use postgres::PostgresSubscriberRepository;
+
+use crate::confirmation_email_throttle::ConfirmationEmailHistory;
+use crate::domain::{
+    DigestFrequency, ListSlug, NewSubscriber, SubscriberEmail, SubscriberName, SubscriptionStatus,
+};
+use crate::suppression_list::SuppressionReason;
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use sha2::{Digest, Sha256};
+use uuid::Uuid;
+
+/// Where subscribers, the lists they are subscribed to, their subscription tokens and their
+/// consent are stored.
+/// All reads and writes go through a transaction, so that handlers can make several
+/// changes that are applied together or not at all.
+#[async_trait]
+pub trait SubscriberRepository: Send + Sync {
+    async fn begin(&self) -> Result<Box<dyn SubscriberTransaction>, sqlx::Error>;
+}
+
+/// Changes made through a transaction are discarded unless it is committed.
+/// Lookups lock what they return until the transaction ends.
+#[async_trait]
+pub trait SubscriberTransaction: Send {
+    /// Returns `None`, leaving the existing subscriber untouched, if the email is already taken
+    async fn insert_subscriber(
+        &mut self,
+        new_subscriber: &NewSubscriber,
+    ) -> Result<Option<Uuid>, sqlx::Error>;
+
+    async fn get_subscriber(
+        &mut self,
+        subscriber_id: Uuid,
+    ) -> Result<Option<Subscriber>, sqlx::Error>;
+
+    async fn get_subscriber_by_email(
+        &mut self,
+        email: &SubscriberEmail,
+    ) -> Result<Option<Subscriber>, sqlx::Error>;
+
+    /// Returns `None`, leaving the existing list untouched, if the slug is already taken
+    async fn create_list(
+        &mut self,
+        slug: &ListSlug,
+        name: &str,
+    ) -> Result<Option<Uuid>, sqlx::Error>;
+
+    /// Ordered by name
+    async fn get_lists(&mut self) -> Result<Vec<MailingList>, sqlx::Error>;
+
+    async fn get_list(&mut self, list_id: Uuid) -> Result<Option<MailingList>, sqlx::Error>;
+
+    async fn get_list_by_slug(
+        &mut self,
+        slug: &ListSlug,
+    ) -> Result<Option<MailingList>, sqlx::Error>;
+
+    async fn get_list_subscription(
+        &mut self,
+        subscriber_id: Uuid,
+        list_id: Uuid,
+    ) -> Result<Option<ListSubscription>, sqlx::Error>;
+
+    /// Every list the subscriber has ever signed up to, whatever their status on it
+    async fn get_list_subscriptions(
+        &mut self,
+        subscriber_id: Uuid,
+    ) -> Result<Vec<ListSubscription>, sqlx::Error>;
+
+    /// Sign the subscriber up to a list they have never been on, pending confirmation
+    async fn add_list_subscription(
+        &mut self,
+        subscriber_id: Uuid,
+        list_id: Uuid,
+    ) -> Result<(), sqlx::Error>;
+
+    async fn update_list_status(
+        &mut self,
+        subscriber_id: Uuid,
+        list_id: Uuid,
+        status: SubscriptionStatus,
+    ) -> Result<(), sqlx::Error>;
+
+    /// Put a subscriber who had unsubscribed from a list back through double opt-in.
+    /// A new name waits on the token, see `store_token`.
+    async fn resubscribe(&mut self, subscriber_id: Uuid, list_id: Uuid) -> Result<(), sqlx::Error>;
+
+    async fn rename_subscriber(
+        &mut self,
+        subscriber_id: Uuid,
+        name: &SubscriberName,
+    ) -> Result<(), sqlx::Error>;
+
+    /// Save what the subscriber chose on the preference page
+    async fn update_preferences(
+        &mut self,
+        subscriber_id: Uuid,
+        name: &SubscriberName,
+        digest_frequency: DigestFrequency,
+    ) -> Result<(), sqlx::Error>;
+
+    /// The token confirms the subscription to that one list, and renames the subscriber to
+    /// `pending_name` if there is one
+    async fn store_token(
+        &mut self,
+        subscriber_id: Uuid,
+        list_id: Uuid,
+        subscription_token: &str,
+        pending_name: Option<&SubscriberName>,
+    ) -> Result<(), sqlx::Error>;
+
+    async fn get_token(
+        &mut self,
+        subscription_token: &str,
+    ) -> Result<Option<SubscriptionToken>, sqlx::Error>;
+
+    async fn consume_token(&mut self, subscription_token: &str) -> Result<(), sqlx::Error>;
+
+    async fn record_consent_event(&mut self, event: &ConsentEvent) -> Result<(), sqlx::Error>;
+
+    /// Oldest first
+    async fn get_consent_events(
+        &mut self,
+        subscriber_id: Uuid,
+    ) -> Result<Vec<ConsentEvent>, sqlx::Error>;
+
+    /// The confirmation emails recently sent to the address, locked like a subscriber
+    async fn get_confirmation_email_history(
+        &mut self,
+        recipient: &SubscriberEmail,
+    ) -> Result<Option<ConfirmationEmailHistory>, sqlx::Error>;
+
+    async fn save_confirmation_email_history(
+        &mut self,
+        recipient: &SubscriberEmail,
+        history: &ConfirmationEmailHistory,
+    ) -> Result<(), sqlx::Error>;
+
+    /// Make sure nothing is sent to the address again, whether or not it belongs to a subscriber
+    async fn suppress_email(
+        &mut self,
+        email: &str,
+        reason: SuppressionReason,
+    ) -> Result<(), sqlx::Error>;
+
+    /// Forget that the address was erased on request, see `personal_data`,
+    /// once its owner has opted in to our emails again
+    async fn clear_erasure(&mut self, email: &str) -> Result<(), sqlx::Error>;
+
+    /// Queue an email to be sent once the transaction is committed
+    async fn enqueue_email(
+        &mut self,
+        recipient: &SubscriberEmail,
+        subject: &str,
+        html_body: &str,
+        text_body: &str,
+    ) -> Result<(), sqlx::Error>;
+
+    async fn commit(self: Box<Self>) -> Result<(), sqlx::Error>;
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Subscriber {
+    pub id: Uuid,
+    pub email: String,
+    pub name: String,
+    pub digest_frequency: DigestFrequency,
+}
+
+#[derive(Debug, Clone, PartialEq, serde::Serialize)]
+pub struct MailingList {
+    pub id: Uuid,
+    pub slug: String,
+    pub name: String,
+}
+
+/// Where a subscriber stands with one list
+#[derive(Debug, Clone, PartialEq)]
+pub struct ListSubscription {
+    pub subscriber_id: Uuid,
+    pub list_id: Uuid,
+    pub status: SubscriptionStatus,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SubscriptionToken {
+    pub subscriber_id: Uuid,
+    pub list_id: Uuid,
+    pub created_at: DateTime<Utc>,
+    pub consumed_at: Option<DateTime<Utc>>,
+    pub pending_name: Option<String>,
+}
+
+/// A step of the double opt-in, stored as text in `consent_events.event_type`.
+/// The database has a CHECK constraint listing the same values.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, sqlx::Type, serde::Serialize)]
+#[sqlx(type_name = "text", rename_all = "snake_case")]
+#[serde(rename_all = "snake_case")]
+pub enum ConsentEventType {
+    Signup,
+    ConfirmationEmailResent,
+    Confirmation,
+    /// Joined a list from the preference page, whose signed link stands in for the confirmation
+    PreferenceCenterSignup,
+}
+
+/// Evidence of how and when a subscriber consented, kept for as long as they are subscribed
+#[derive(Debug, Clone, PartialEq, serde::Serialize)]
+pub struct ConsentEvent {
+    pub subscriber_id: Uuid,
+    /// The list that was consented to
+    pub list_id: Uuid,
+    pub event_type: ConsentEventType,
+    pub occurred_at: DateTime<Utc>,
+    pub ip_address: Option<String>,
+    pub user_agent: Option<String>,
+    /// The form the signup came from, as named by the form or the `Referer` header
+    pub form_source: Option<String>,
+    /// The confirmation email sent because of this event, if any
+    pub email_subject: Option<String>,
+    pub email_html_body: Option<String>,
+    pub email_text_body: Option<String>,
+}
+
+/// Only this hash is stored, so that the tokens can't be recovered from a copy of the database.
+/// Tokens have enough entropy that a fast, unsalted hash is sufficient.
+pub fn hash_subscription_token(subscription_token: &str) -> Vec<u8> {
+    Sha256::digest(subscription_token.as_bytes()).to_vec()
+}
@@ -0,0 +1,542 @@
+use super::{
+    hash_subscription_token, ConsentEvent, ConsentEventType, ListSubscription, MailingList,
+    Subscriber, SubscriberRepository, SubscriberTransaction, SubscriptionToken,
+};
+use crate::confirmation_email_throttle::ConfirmationEmailHistory;
+use crate::domain::{
+    DigestFrequency, ListSlug, NewSubscriber, SubscriberEmail, SubscriberName, SubscriptionStatus,
+};
+use crate::email_outbox::enqueue_email;
+use crate::personal_data::clear_erasure;
+use crate::suppression_list::{suppress_email, SuppressionReason};
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use sqlx::{PgPool, Postgres, Transaction};
+use subtle::ConstantTimeEq;
+use uuid::Uuid;
+
+pub struct PostgresSubscriberRepository {
+    pool: PgPool,
+}
+
+impl PostgresSubscriberRepository {
+    pub fn new(pool: PgPool) -> Self {
+        Self { pool }
+    }
+}
+
+#[async_trait]
+impl SubscriberRepository for PostgresSubscriberRepository {
+    async fn begin(&self) -> Result<Box<dyn SubscriberTransaction>, sqlx::Error> {
+        let transaction = self.pool.begin().await?;
+        Ok(Box::new(PostgresSubscriberTransaction(transaction)))
+    }
+}
+
+struct PostgresSubscriberTransaction(Transaction<'static, Postgres>);
+
+struct TokenRow {
+    subscription_token_hash: Vec<u8>,
+    subscriber_id: Uuid,
+    list_id: Uuid,
+    created_at: DateTime<Utc>,
+    consumed_at: Option<DateTime<Utc>>,
+    pending_name: Option<String>,
+}
+
+#[async_trait]
+impl SubscriberTransaction for PostgresSubscriberTransaction {
+    #[tracing::instrument(
+        name = "Saving new subscriber details to the database",
+        skip(self, new_subscriber)
+    )]
+    async fn insert_subscriber(
+        &mut self,
+        new_subscriber: &NewSubscriber,
+    ) -> Result<Option<Uuid>, sqlx::Error> {
+        let subscriber_id = Uuid::new_v4();
+        let inserted = sqlx::query!(
+            r#"
+                INSERT INTO subscriptions (id, email, name, subscribed_at, digest_frequency)
+                VALUES ($1, $2, $3, $4, $5)
+                ON CONFLICT ((lower(email))) DO NOTHING
+            "#,
+            subscriber_id,
+            new_subscriber.email.as_ref(),
+            new_subscriber.name.as_ref(),
+            Utc::now(),
+            DigestFrequency::Immediately as DigestFrequency
+        )
+        .execute(&mut self.0)
+        .await?
+        .rows_affected();
+
+        Ok(if inserted == 1 {
+            Some(subscriber_id)
+        } else {
+            None
+        })
+    }
+
+    #[tracing::instrument(name = "Get subscriber", skip(self))]
+    async fn get_subscriber(
+        &mut self,
+        subscriber_id: Uuid,
+    ) -> Result<Option<Subscriber>, sqlx::Error> {
+        sqlx::query_as!(
+            Subscriber,
+            r#"
+                SELECT id, email, name,
+                    digest_frequency AS "digest_frequency: DigestFrequency"
+                FROM subscriptions
+                WHERE id = $1
+                FOR UPDATE
+            "#,
+            subscriber_id
+        )
+        .fetch_optional(&mut self.0)
+        .await
+    }
+
+    #[tracing::instrument(name = "Get subscriber by email", skip(self, email))]
+    async fn get_subscriber_by_email(
+        &mut self,
+        email: &SubscriberEmail,
+    ) -> Result<Option<Subscriber>, sqlx::Error> {
+        sqlx::query_as!(
+            Subscriber,
+            r#"
+                SELECT id, email, name,
+                    digest_frequency AS "digest_frequency: DigestFrequency"
+                FROM subscriptions
+                WHERE lower(email) = lower($1)
+                FOR UPDATE
+            "#,
+            email.as_ref()
+        )
+        .fetch_optional(&mut self.0)
+        .await
+    }
+
+    #[tracing::instrument(name = "Create a list", skip(self))]
+    async fn create_list(
+        &mut self,
+        slug: &ListSlug,
+        name: &str,
+    ) -> Result<Option<Uuid>, sqlx::Error> {
+        let list_id = Uuid::new_v4();
+        let inserted = sqlx::query!(
+            r#"
+                INSERT INTO lists (list_id, slug, name, created_at)
+                VALUES ($1, $2, $3, $4)
+                ON CONFLICT (slug) DO NOTHING
+            "#,
+            list_id,
+            slug.as_ref(),
+            name,
+            Utc::now()
+        )
+        .execute(&mut self.0)
+        .await?
+        .rows_affected();
+
+        Ok(if inserted == 1 { Some(list_id) } else { None })
+    }
+
+    #[tracing::instrument(name = "Get lists", skip(self))]
+    async fn get_lists(&mut self) -> Result<Vec<MailingList>, sqlx::Error> {
+        sqlx::query_as!(
+            MailingList,
+            r#"
+                SELECT list_id AS id, slug, name
+                FROM lists
+                ORDER BY name
+            "#
+        )
+        .fetch_all(&mut self.0)
+        .await
+    }
+
+    #[tracing::instrument(name = "Get list", skip(self))]
+    async fn get_list(&mut self, list_id: Uuid) -> Result<Option<MailingList>, sqlx::Error> {
+        sqlx::query_as!(
+            MailingList,
+            r#"
+                SELECT list_id AS id, slug, name
+                FROM lists
+                WHERE list_id = $1
+            "#,
+            list_id
+        )
+        .fetch_optional(&mut self.0)
+        .await
+    }
+
+    #[tracing::instrument(name = "Get list by slug", skip(self))]
+    async fn get_list_by_slug(
+        &mut self,
+        slug: &ListSlug,
+    ) -> Result<Option<MailingList>, sqlx::Error> {
+        sqlx::query_as!(
+            MailingList,
+            r#"
+                SELECT list_id AS id, slug, name
+                FROM lists
+                WHERE slug = $1
+            "#,
+            slug.as_ref()
+        )
+        .fetch_optional(&mut self.0)
+        .await
+    }
+
+    #[tracing::instrument(name = "Get list subscription", skip(self))]
+    async fn get_list_subscription(
+        &mut self,
+        subscriber_id: Uuid,
+        list_id: Uuid,
+    ) -> Result<Option<ListSubscription>, sqlx::Error> {
+        sqlx::query_as!(
+            ListSubscription,
+            r#"
+                SELECT subscriber_id, list_id, status AS "status: SubscriptionStatus"
+                FROM list_subscriptions
+                WHERE subscriber_id = $1 AND list_id = $2
+                FOR UPDATE
+            "#,
+            subscriber_id,
+            list_id
+        )
+        .fetch_optional(&mut self.0)
+        .await
+    }
+
+    #[tracing::instrument(name = "Get list subscriptions", skip(self))]
+    async fn get_list_subscriptions(
+        &mut self,
+        subscriber_id: Uuid,
+    ) -> Result<Vec<ListSubscription>, sqlx::Error> {
+        sqlx::query_as!(
+            ListSubscription,
+            r#"
+                SELECT subscriber_id, list_id, status AS "status: SubscriptionStatus"
+                FROM list_subscriptions
+                WHERE subscriber_id = $1
+                FOR UPDATE
+            "#,
+            subscriber_id
+        )
+        .fetch_all(&mut self.0)
+        .await
+    }
+
+    #[tracing::instrument(name = "Add list subscription", skip(self))]
+    async fn add_list_subscription(
+        &mut self,
+        subscriber_id: Uuid,
+        list_id: Uuid,
+    ) -> Result<(), sqlx::Error> {
+        sqlx::query!(
+            r#"
+                INSERT INTO list_subscriptions (subscriber_id, list_id, status, subscribed_at)
+                VALUES ($1, $2, $3, $4)
+            "#,
+            subscriber_id,
+            list_id,
+            SubscriptionStatus::PendingConfirmation as SubscriptionStatus,
+            Utc::now()
+        )
+        .execute(&mut self.0)
+        .await?;
+
+        Ok(())
+    }
+
+    #[tracing::instrument(name = "Update list subscription status", skip(self))]
+    async fn update_list_status(
+        &mut self,
+        subscriber_id: Uuid,
+        list_id: Uuid,
+        status: SubscriptionStatus,
+    ) -> Result<(), sqlx::Error> {
+        sqlx::query!(
+            r#"
+                UPDATE list_subscriptions SET status = $3
+                WHERE subscriber_id = $1 AND list_id = $2
+            "#,
+            subscriber_id,
+            list_id,
+            status as SubscriptionStatus
+        )
+        .execute(&mut self.0)
+        .await?;
+
+        Ok(())
+    }
+
+    #[tracing::instrument(name = "Restart a subscription", skip(self))]
+    async fn resubscribe(&mut self, subscriber_id: Uuid, list_id: Uuid) -> Result<(), sqlx::Error> {
+        sqlx::query!(
+            r#"
+                UPDATE list_subscriptions
+                SET status = $3, subscribed_at = $4
+                WHERE subscriber_id = $1 AND list_id = $2
+            "#,
+            subscriber_id,
+            list_id,
+            SubscriptionStatus::PendingConfirmation as SubscriptionStatus,
+            Utc::now()
+        )
+        .execute(&mut self.0)
+        .await?;
+
+        Ok(())
+    }
+
+    #[tracing::instrument(name = "Rename a subscriber", skip(self, name))]
+    async fn rename_subscriber(
+        &mut self,
+        subscriber_id: Uuid,
+        name: &SubscriberName,
+    ) -> Result<(), sqlx::Error> {
+        sqlx::query!(
+            "UPDATE subscriptions SET name = $2 WHERE id = $1",
+            subscriber_id,
+            name.as_ref()
+        )
+        .execute(&mut self.0)
+        .await?;
+
+        Ok(())
+    }
+
+    #[tracing::instrument(name = "Save subscriber preferences", skip(self, name))]
+    async fn update_preferences(
+        &mut self,
+        subscriber_id: Uuid,
+        name: &SubscriberName,
+        digest_frequency: DigestFrequency,
+    ) -> Result<(), sqlx::Error> {
+        sqlx::query!(
+            "UPDATE subscriptions SET name = $2, digest_frequency = $3 WHERE id = $1",
+            subscriber_id,
+            name.as_ref(),
+            digest_frequency as DigestFrequency
+        )
+        .execute(&mut self.0)
+        .await?;
+
+        Ok(())
+    }
+
+    #[tracing::instrument(
+        name = "Store subscription token in the database",
+        skip(self, subscription_token, pending_name)
+    )]
+    async fn store_token(
+        &mut self,
+        subscriber_id: Uuid,
+        list_id: Uuid,
+        subscription_token: &str,
+        pending_name: Option<&SubscriberName>,
+    ) -> Result<(), sqlx::Error> {
+        sqlx::query!(
+            r#"
+                INSERT INTO subscription_tokens
+                    (subscription_token_hash, subscriber_id, list_id, created_at, pending_name)
+                VALUES ($1, $2, $3, $4, $5)
+            "#,
+            hash_subscription_token(subscription_token),
+            subscriber_id,
+            list_id,
+            Utc::now(),
+            pending_name.map(|name| name.as_ref())
+        )
+        .execute(&mut self.0)
+        .await?;
+
+        Ok(())
+    }
+
+    #[tracing::instrument(name = "Get subscription token", skip(self, subscription_token))]
+    async fn get_token(
+        &mut self,
+        subscription_token: &str,
+    ) -> Result<Option<SubscriptionToken>, sqlx::Error> {
+        let subscription_token_hash = hash_subscription_token(subscription_token);
+        let token = sqlx::query_as!(
+            TokenRow,
+            r#"
+                SELECT subscription_token_hash, subscriber_id, list_id, created_at, consumed_at,
+                    pending_name
+                FROM subscription_tokens
+                WHERE subscription_token_hash = $1
+                FOR UPDATE
+            "#,
+            &subscription_token_hash
+        )
+        .fetch_optional(&mut self.0)
+        .await?;
+
+        // Tokens must be compared in constant time, so the match is checked here as well
+        // rather than left to how Postgres compares the hashes
+        Ok(token
+            .filter(|token| {
+                bool::from(
+                    token
+                        .subscription_token_hash
+                        .ct_eq(&subscription_token_hash),
+                )
+            })
+            .map(|token| SubscriptionToken {
+                subscriber_id: token.subscriber_id,
+                list_id: token.list_id,
+                created_at: token.created_at,
+                consumed_at: token.consumed_at,
+                pending_name: token.pending_name,
+            }))
+    }
+
+    #[tracing::instrument(
+        name = "Mark subscription token as consumed",
+        skip(self, subscription_token)
+    )]
+    async fn consume_token(&mut self, subscription_token: &str) -> Result<(), sqlx::Error> {
+        sqlx::query!(
+            r#"
+                UPDATE subscription_tokens SET consumed_at = $2 WHERE subscription_token_hash = $1
+            "#,
+            hash_subscription_token(subscription_token),
+            Utc::now()
+        )
+        .execute(&mut self.0)
+        .await?;
+
+        Ok(())
+    }
+
+    #[tracing::instrument(name = "Record consent", skip(self, event), fields(subscriber_id = %event.subscriber_id))]
+    async fn record_consent_event(&mut self, event: &ConsentEvent) -> Result<(), sqlx::Error> {
+        sqlx::query!(
+            r#"
+                INSERT INTO consent_events (
+                    event_id, subscriber_id, list_id, event_type, occurred_at, ip_address,
+                    user_agent, form_source, email_subject, email_html_body, email_text_body
+                )
+                VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11)
+            "#,
+            Uuid::new_v4(),
+            event.subscriber_id,
+            event.list_id,
+            event.event_type as ConsentEventType,
+            event.occurred_at,
+            event.ip_address,
+            event.user_agent,
+            event.form_source,
+            event.email_subject,
+            event.email_html_body,
+            event.email_text_body
+        )
+        .execute(&mut self.0)
+        .await?;
+
+        Ok(())
+    }
+
+    #[tracing::instrument(name = "Get consent events", skip(self))]
+    async fn get_consent_events(
+        &mut self,
+        subscriber_id: Uuid,
+    ) -> Result<Vec<ConsentEvent>, sqlx::Error> {
+        sqlx::query_as!(
+            ConsentEvent,
+            r#"
+                SELECT subscriber_id, list_id, event_type AS "event_type: ConsentEventType",
+                    occurred_at, ip_address, user_agent, form_source,
+                    email_subject, email_html_body, email_text_body
+                FROM consent_events
+                WHERE subscriber_id = $1
+                ORDER BY occurred_at
+            "#,
+            subscriber_id
+        )
+        .fetch_all(&mut self.0)
+        .await
+    }
+
+    #[tracing::instrument(name = "Get confirmation email history", skip(self, recipient))]
+    async fn get_confirmation_email_history(
+        &mut self,
+        recipient: &SubscriberEmail,
+    ) -> Result<Option<ConfirmationEmailHistory>, sqlx::Error> {
+        sqlx::query_as!(
+            ConfirmationEmailHistory,
+            r#"
+                SELECT window_started_at, sent_in_window, last_sent_at
+                FROM confirmation_email_throttle
+                WHERE recipient = lower($1)
+                FOR UPDATE
+            "#,
+            recipient.as_ref()
+        )
+        .fetch_optional(&mut self.0)
+        .await
+    }
+
+    #[tracing::instrument(
+        name = "Save confirmation email history",
+        skip(self, recipient, history)
+    )]
+    async fn save_confirmation_email_history(
+        &mut self,
+        recipient: &SubscriberEmail,
+        history: &ConfirmationEmailHistory,
+    ) -> Result<(), sqlx::Error> {
+        sqlx::query!(
+            r#"
+                INSERT INTO confirmation_email_throttle
+                    (recipient, window_started_at, sent_in_window, last_sent_at)
+                VALUES (lower($1), $2, $3, $4)
+                ON CONFLICT (recipient) DO UPDATE
+                SET window_started_at = EXCLUDED.window_started_at,
+                    sent_in_window = EXCLUDED.sent_in_window,
+                    last_sent_at = EXCLUDED.last_sent_at
+            "#,
+            recipient.as_ref(),
+            history.window_started_at,
+            history.sent_in_window,
+            history.last_sent_at
+        )
+        .execute(&mut self.0)
+        .await?;
+
+        Ok(())
+    }
+
+    async fn suppress_email(
+        &mut self,
+        email: &str,
+        reason: SuppressionReason,
+    ) -> Result<(), sqlx::Error> {
+        suppress_email(&mut self.0, email, reason).await
+    }
+
+    async fn clear_erasure(&mut self, email: &str) -> Result<(), sqlx::Error> {
+        clear_erasure(&mut self.0, email).await
+    }
+
+    async fn enqueue_email(
+        &mut self,
+        recipient: &SubscriberEmail,
+        subject: &str,
+        html_body: &str,
+        text_body: &str,
+    ) -> Result<(), sqlx::Error> {
+        enqueue_email(&mut self.0, recipient, subject, html_body, text_body).await?;
+        Ok(())
+    }
+
+    async fn commit(self: Box<Self>) -> Result<(), sqlx::Error> {
+        self.0.commit().await
+    }
+}
@@ -0,0 +1,62 @@
+use chrono::Utc;
+use sqlx::{Postgres, Transaction};
+use std::collections::HashSet;
+
+/// Why an address was added to the suppression list
+#[derive(Debug, Clone, Copy, PartialEq, Eq, sqlx::Type, serde::Serialize)]
+#[sqlx(type_name = "text", rename_all = "snake_case")]
+#[serde(rename_all = "snake_case")]
+pub enum SuppressionReason {
+    HardBounce,
+    SpamComplaint,
+}
+
+/// Stop sending to an address, whatever its case. Suppressing it again keeps the original reason.
+#[tracing::instrument(name = "Suppress an email address", skip(transaction, email))]
+pub async fn suppress_email(
+    transaction: &mut Transaction<'_, Postgres>,
+    email: &str,
+    reason: SuppressionReason,
+) -> Result<(), sqlx::Error> {
+    sqlx::query!(
+        r#"
+            INSERT INTO suppressed_emails (email, reason, suppressed_at)
+            VALUES (lower($1), $2, $3)
+            ON CONFLICT (email) DO NOTHING
+        "#,
+        email,
+        reason as SuppressionReason,
+        Utc::now()
+    )
+    .execute(transaction)
+    .await?;
+
+    Ok(())
+}
+
+/// The addresses among `emails` that must not be sent anything, spelled as they were given
+pub async fn suppressed_among(
+    transaction: &mut Transaction<'_, Postgres>,
+    emails: &[String],
+) -> Result<HashSet<String>, sqlx::Error> {
+    let rows = sqlx::query!(
+        r#"
+            SELECT email AS "email!"
+            FROM unnest($1::text[]) AS candidates(email)
+            WHERE lower(email) IN (SELECT email FROM suppressed_emails)
+        "#,
+        emails
+    )
+    .fetch_all(transaction)
+    .await?;
+
+    Ok(rows.into_iter().map(|row| row.email).collect())
+}
+
+pub async fn is_suppressed(
+    transaction: &mut Transaction<'_, Postgres>,
+    email: &str,
+) -> Result<bool, sqlx::Error> {
+    let suppressed = suppressed_among(transaction, &[email.to_owned()]).await?;
+    Ok(!suppressed.is_empty())
+}
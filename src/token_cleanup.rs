@@ -0,0 +1,50 @@
+use chrono::Utc;
+use sqlx::PgPool;
+use std::time::Duration;
+
+/// Tokens are kept this many days past their expiry or use, so that clicking an old link
+/// can still offer a resend, or tell a confirmed subscriber that nothing changed
+const STALE_TOKEN_RETENTION_DAYS: i64 = 7;
+const CLEANUP_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// Periodically deletes subscription tokens that can no longer be used
+pub struct TokenCleanupWorker {
+    pool: PgPool,
+    token_ttl: chrono::Duration,
+}
+
+impl TokenCleanupWorker {
+    pub fn new(pool: PgPool, token_ttl: chrono::Duration) -> Self {
+        Self { pool, token_ttl }
+    }
+
+    pub async fn run_until_stopped(self) -> Result<(), std::io::Error> {
+        loop {
+            if let Ok(n_deleted) = delete_stale_tokens(&self.pool, self.token_ttl).await {
+                tracing::info!("Deleted {} stale subscription tokens", n_deleted);
+            }
+            tokio::time::sleep(CLEANUP_INTERVAL).await;
+        }
+    }
+}
+
+/// Delete tokens that were consumed, or expired, longer ago than the retention period
+#[tracing::instrument(skip(pool), err)]
+pub async fn delete_stale_tokens(
+    pool: &PgPool,
+    token_ttl: chrono::Duration,
+) -> Result<u64, sqlx::Error> {
+    let cutoff = Utc::now() - token_ttl - chrono::Duration::days(STALE_TOKEN_RETENTION_DAYS);
+    let n_deleted = sqlx::query!(
+        r#"
+            DELETE FROM subscription_tokens
+            WHERE COALESCE(consumed_at, created_at) < $1
+        "#,
+        cutoff
+    )
+    .execute(pool)
+    .await?
+    .rows_affected();
+
+    Ok(n_deleted)
+}
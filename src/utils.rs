@@ -0,0 +1,31 @@
+use actix_web::http::header::ContentType;
+use actix_web::{HttpResponse, ResponseError};
+
+/// Format an error followed by every error in its `source` chain, one per line
+pub fn error_chain_fmt(
+    e: &impl std::error::Error,
+    f: &mut std::fmt::Formatter<'_>,
+) -> std::fmt::Result {
+    writeln!(f, "{}\n", e)?;
+    let mut current = e.source();
+    while let Some(cause) = current {
+        writeln!(f, "Caused by:\n\t{}", cause)?;
+        current = cause.source();
+    }
+    Ok(())
+}
+
+/// Build the response for a handler error, logging its full cause chain exactly once:
+/// as a warning when the client is at fault, as an error when we are.
+pub fn log_error_response<E: ResponseError>(e: &E) -> HttpResponse {
+    let status_code = e.status_code();
+    if status_code.is_server_error() {
+        tracing::error!(error.cause_chain = ?e, error.message = %e, "Request failed");
+    } else {
+        tracing::warn!(error.cause_chain = ?e, error.message = %e, "Request rejected");
+    }
+
+    HttpResponse::build(status_code)
+        .content_type(ContentType::plaintext())
+        .body(e.to_string())
+}
@@ -0,0 +1,72 @@
+use crate::helpers::{assert_is_redirect_to, spawn_app};
+
+#[actix_rt::test]
+async fn you_must_be_logged_in_to_access_the_admin_dashboard() {
+    // Arrange
+    let app = spawn_app().await;
+
+    // Act
+    let response = app.get_admin_dashboard().await;
+
+    // Assert
+    assert_is_redirect_to(&response, "/login");
+}
+
+#[actix_rt::test]
+async fn the_dashboard_shows_subscriber_counts() {
+    // Arrange
+    let app = spawn_app().await;
+    app.create_confirmed_subscriber().await;
+    app.login_as_test_user().await;
+
+    // Act
+    let html_page = app.get_admin_dashboard_html().await;
+
+    // Assert
+    assert!(html_page.contains("Confirmed subscribers: 1"));
+    assert!(html_page.contains("Pending subscribers: 0"));
+}
+
+#[actix_rt::test]
+async fn logout_clears_session_state() {
+    // Arrange
+    let app = spawn_app().await;
+
+    // Act - Part 1 - Login
+    app.login_as_test_user().await;
+    let html_page = app.get_admin_dashboard_html().await;
+    assert!(html_page.contains(&format!("Welcome {}", app.test_user.username)));
+
+    // Act - Part 2 - Logout
+    let response = app.post_logout().await;
+    assert_is_redirect_to(&response, "/login");
+
+    // Act - Part 3 - Follow the redirect
+    let html_page = app.get_login_html().await;
+    assert!(html_page.contains(r#"<p class="info"><i>You have successfully logged out.</i></p>"#));
+
+    // Act - Part 4 - Attempt to load admin panel
+    let response = app.get_admin_dashboard().await;
+    assert_is_redirect_to(&response, "/login");
+}
+
+#[actix_rt::test]
+async fn a_logged_out_session_cannot_be_reused() {
+    // Arrange
+    let app = spawn_app().await;
+    app.login_as_test_user().await;
+    let session_count = || async {
+        sqlx::query!("SELECT COUNT(*) AS \"count!\" FROM sessions")
+            .fetch_one(&app.db_pool)
+            .await
+            .unwrap()
+            .count
+    };
+    assert_eq!(session_count().await, 1);
+
+    // Act
+    app.post_logout().await;
+
+    // Assert
+    assert_eq!(session_count().await, 0);
+}
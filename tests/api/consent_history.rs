@@ -0,0 +1,133 @@
+use crate::helpers::{spawn_app, TestApp};
+use uuid::Uuid;
+use wiremock::matchers::{method, path};
+use wiremock::{Mock, ResponseTemplate};
+
+async fn subscriber_id(app: &TestApp) -> Uuid {
+    sqlx::query!("SELECT id FROM subscriptions")
+        .fetch_one(&app.db_pool)
+        .await
+        .expect("Failed to fetch saved subscriber")
+        .id
+}
+
+#[actix_rt::test]
+async fn you_must_be_logged_in_to_see_a_consent_history() {
+    // Arrange
+    let app = spawn_app().await;
+    app.create_unconfirmed_subscriber().await;
+
+    // Act
+    let response = app.get_consent_history(subscriber_id(&app).await).await;
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 401);
+}
+
+#[actix_rt::test]
+async fn the_consent_history_of_an_unknown_subscriber_is_not_found() {
+    // Arrange
+    let app = spawn_app().await;
+    app.login_as_test_user().await;
+
+    // Act
+    let response = app.get_consent_history(Uuid::new_v4()).await;
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 404);
+}
+
+#[actix_rt::test]
+async fn the_consent_history_records_the_signup_and_the_confirmation() {
+    // Arrange
+    let app = spawn_app().await;
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .mount(&app.email_server)
+        .await;
+    reqwest::Client::new()
+        .post(format!("{}/subscriptions", &app.address))
+        .header("X-Forwarded-For", "203.0.113.7")
+        .header("User-Agent", "Mozilla/5.0 (signup)")
+        .form(&[
+            ("name", "le guin"),
+            ("email", "ursula_le_guin@gmail.com"),
+            ("source", "footer"),
+        ])
+        .send()
+        .await
+        .unwrap()
+        .error_for_status()
+        .unwrap();
+    app.dispatch_all_pending_emails().await;
+    let email_request = &app.email_server.received_requests().await.unwrap()[0];
+    let confirmation_links = app.get_confirmation_links(email_request);
+    reqwest::Client::new()
+        .get(confirmation_links.html.clone())
+        .header("User-Agent", "Mozilla/5.0 (confirmation)")
+        .send()
+        .await
+        .unwrap()
+        .error_for_status()
+        .unwrap();
+    app.login_as_test_user().await;
+
+    // Act
+    let response = app.get_consent_history(subscriber_id(&app).await).await;
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 200);
+    let history: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(history["email"], "ursula_le_guin@gmail.com");
+    let events = history["events"].as_array().unwrap();
+    assert_eq!(events.len(), 2);
+
+    let signup = &events[0];
+    assert_eq!(signup["event_type"], "signup");
+    assert_eq!(signup["ip_address"], "203.0.113.7");
+    assert_eq!(signup["user_agent"], "Mozilla/5.0 (signup)");
+    assert_eq!(signup["form_source"], "footer");
+    assert_eq!(signup["email_subject"], "Welcome!");
+    let text_body = signup["email_text_body"].as_str().unwrap();
+    assert!(text_body.contains("Visit"));
+    // The record must not be usable to confirm on someone's behalf
+    assert!(!text_body.contains(&confirmation_links.subscription_token()));
+
+    let confirmation = &events[1];
+    assert_eq!(confirmation["event_type"], "confirmation");
+    assert_eq!(confirmation["user_agent"], "Mozilla/5.0 (confirmation)");
+    assert_eq!(confirmation["email_subject"], serde_json::Value::Null);
+}
+
+#[actix_rt::test]
+async fn resending_the_confirmation_email_is_recorded() {
+    // Arrange
+    let app = spawn_app().await;
+    let confirmation_links = app.create_unconfirmed_subscriber().await;
+    app.end_confirmation_email_cooldown().await;
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .mount(&app.email_server)
+        .await;
+    app.post_resend_confirmation(&confirmation_links.subscription_token())
+        .await
+        .error_for_status()
+        .unwrap();
+    app.login_as_test_user().await;
+
+    // Act
+    let response = app.get_consent_history(subscriber_id(&app).await).await;
+
+    // Assert
+    let history: serde_json::Value = response.json().await.unwrap();
+    let event_types: Vec<_> = history["events"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|event| event["event_type"].as_str().unwrap())
+        .collect();
+    assert_eq!(event_types, vec!["signup", "confirmation_email_resent"]);
+    assert_eq!(history["events"][1]["email_subject"], "Welcome!");
+}
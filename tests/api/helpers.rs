@@ -1,10 +1,22 @@
 use sqlx::{Connection, Executor, PgConnection, PgPool};
 use std::env;
+use std::net::{Ipv4Addr, Ipv6Addr};
+use std::sync::Arc;
 use uuid::Uuid;
-use wiremock::MockServer;
-use zero2prod::configuration::{get_configuration, DatabaseSettings};
+use wiremock::matchers::{method, path};
+use wiremock::{Mock, MockServer, Request, Respond, ResponseTemplate};
+use zero2prod::authentication::create_user;
+use zero2prod::configuration::{
+    get_configuration, DatabaseSettings, EmailBackendSettings, EmailRetrySettings,
+    PostmarkSettings, PostmarkWebhookSettings, RateLimitsSettings, Settings,
+};
+use zero2prod::email_client::{Email, EmailSender};
+use zero2prod::email_outbox::try_relay_email;
+use zero2prod::issue_delivery_worker::{try_execute_task, try_send_digests, ExecutionOutcome};
+use zero2prod::signing::HmacSecret;
 use zero2prod::startup;
 use zero2prod::startup::Application;
+use zero2prod::subscriber_repository::InMemorySubscriberRepository;
 use zero2prod::telemetry::{get_subscriber, init_subscriber};
 
 lazy_static::lazy_static! {
@@ -20,6 +32,34 @@ pub struct TestApp {
     pub address: String,
     pub db_pool: PgPool,
     pub email_server: MockServer,
+    pub email_client: Arc<dyn EmailSender>,
+    pub base_url: String,
+    pub hmac_secret: HmacSecret,
+    pub preferences_link_ttl: chrono::Duration,
+    /// Set when the app keeps subscribers in memory rather than in `db_pool`
+    pub in_memory_subscribers: Option<InMemorySubscriberRepository>,
+    pub postmark_webhook: PostmarkWebhookSettings,
+    pub rate_limits: RateLimitsSettings,
+    pub test_user: TestUser,
+    pub api_client: reqwest::Client,
+}
+
+/// An admin user stored in the test database
+pub struct TestUser {
+    pub username: String,
+    pub password: String,
+}
+
+impl TestUser {
+    async fn store(pool: &PgPool) -> Self {
+        let username = Uuid::new_v4().to_string();
+        let password = Uuid::new_v4().to_string();
+        create_user(pool, &username, &password)
+            .await
+            .expect("Failed to create test user");
+
+        Self { username, password }
+    }
 }
 
 /// Confirmation links embedded in email requests
@@ -28,17 +68,230 @@ pub struct ConfirmationLinks {
     pub plain_text: reqwest::Url,
 }
 
+impl ConfirmationLinks {
+    pub fn subscription_token(&self) -> String {
+        self.html
+            .query_pairs()
+            .find(|(key, _)| key == "subscription_token")
+            .map(|(_, value)| value.into_owned())
+            .expect("No subscription token in the confirmation link")
+    }
+}
+
 impl TestApp {
     pub async fn post_subscriptions(&self, body: String) -> reqwest::Response {
         reqwest::Client::new()
-            .post(&format!("{}/subscriptions", &self.address))
+            .post(format!("{}/subscriptions", &self.address))
+            .header("Content-Type", "application/x-www-form-urlencoded")
+            .body(body)
+            .send()
+            .await
+            .expect("Failed to execute request")
+    }
+
+    /// Post to `/subscriptions` on behalf of another client, through the trusted proxy
+    pub async fn post_subscriptions_from(
+        &self,
+        client_ip: &str,
+        body: String,
+    ) -> reqwest::Response {
+        reqwest::Client::new()
+            .post(format!("{}/subscriptions", &self.address))
             .header("Content-Type", "application/x-www-form-urlencoded")
+            .header("X-Forwarded-For", client_ip)
             .body(body)
             .send()
             .await
             .expect("Failed to execute request")
     }
 
+    pub async fn post_resend_confirmation(&self, subscription_token: &str) -> reqwest::Response {
+        reqwest::Client::new()
+            .post(format!("{}/subscriptions/confirm/resend", &self.address))
+            .form(&[("subscription_token", subscription_token)])
+            .send()
+            .await
+            .expect("Failed to execute request")
+    }
+
+    /// Send a webhook with the credentials configured for Postmark
+    pub async fn post_postmark_webhook(&self, body: serde_json::Value) -> reqwest::Response {
+        reqwest::Client::new()
+            .post(format!("{}/webhooks/postmark", &self.address))
+            .basic_auth(
+                &self.postmark_webhook.username,
+                Some(&self.postmark_webhook.password),
+            )
+            .json(&body)
+            .send()
+            .await
+            .expect("Failed to execute request.")
+    }
+
+    pub async fn post_newsletters(&self, body: serde_json::Value) -> reqwest::Response {
+        reqwest::Client::new()
+            .post(format!("{}/newsletters", &self.address))
+            .basic_auth(&self.test_user.username, Some(&self.test_user.password))
+            .json(&body)
+            .send()
+            .await
+            .expect("Failed to execute request")
+    }
+
+    /// Drain the email outbox and the issue delivery queue, as the background workers would
+    pub async fn dispatch_all_pending_emails(&self) {
+        loop {
+            if let ExecutionOutcome::EmptyQueue =
+                try_relay_email(&self.db_pool, self.email_client.as_ref())
+                    .await
+                    .unwrap()
+            {
+                break;
+            }
+        }
+        loop {
+            if let ExecutionOutcome::EmptyQueue = try_execute_task(
+                &self.db_pool,
+                self.email_client.as_ref(),
+                &self.base_url,
+                &self.hmac_secret,
+                self.preferences_link_ttl,
+            )
+            .await
+            .unwrap()
+            {
+                break;
+            }
+        }
+        loop {
+            if let ExecutionOutcome::EmptyQueue = try_send_digests(
+                &self.db_pool,
+                self.email_client.as_ref(),
+                &self.base_url,
+                &self.hmac_secret,
+                self.preferences_link_ttl,
+            )
+            .await
+            .unwrap()
+            {
+                break;
+            }
+        }
+    }
+
+    pub async fn post_newsletters_with_idempotency_key(
+        &self,
+        body: &serde_json::Value,
+        idempotency_key: &str,
+    ) -> reqwest::Response {
+        reqwest::Client::new()
+            .post(format!("{}/newsletters", &self.address))
+            .basic_auth(&self.test_user.username, Some(&self.test_user.password))
+            .header("Idempotency-Key", idempotency_key)
+            .json(body)
+            .send()
+            .await
+            .expect("Failed to execute request")
+    }
+
+    pub async fn post_login<Body>(&self, body: &Body) -> reqwest::Response
+    where
+        Body: serde::Serialize,
+    {
+        self.api_client
+            .post(format!("{}/login", &self.address))
+            .form(body)
+            .send()
+            .await
+            .expect("Failed to execute request")
+    }
+
+    pub async fn get_login_html(&self) -> String {
+        self.api_client
+            .get(format!("{}/login", &self.address))
+            .send()
+            .await
+            .expect("Failed to execute request")
+            .text()
+            .await
+            .unwrap()
+    }
+
+    pub async fn get_admin_dashboard(&self) -> reqwest::Response {
+        self.api_client
+            .get(format!("{}/admin/dashboard", &self.address))
+            .send()
+            .await
+            .expect("Failed to execute request")
+    }
+
+    pub async fn get_admin_dashboard_html(&self) -> String {
+        self.get_admin_dashboard().await.text().await.unwrap()
+    }
+
+    pub async fn post_logout(&self) -> reqwest::Response {
+        self.api_client
+            .post(format!("{}/admin/logout", &self.address))
+            .send()
+            .await
+            .expect("Failed to execute request")
+    }
+
+    pub async fn get_consent_history(&self, subscriber_id: Uuid) -> reqwest::Response {
+        self.api_client
+            .get(format!(
+                "{}/admin/subscribers/{}/consent",
+                &self.address, subscriber_id
+            ))
+            .send()
+            .await
+            .expect("Failed to execute request")
+    }
+
+    pub async fn post_export_personal_data(&self, email: &str) -> reqwest::Response {
+        self.api_client
+            .post(format!("{}/admin/personal_data/export", &self.address))
+            .json(&serde_json::json!({ "email": email }))
+            .send()
+            .await
+            .expect("Failed to execute request")
+    }
+
+    pub async fn post_erase_personal_data(&self, email: &str) -> reqwest::Response {
+        self.api_client
+            .post(format!("{}/admin/personal_data/erase", &self.address))
+            .json(&serde_json::json!({ "email": email }))
+            .send()
+            .await
+            .expect("Failed to execute request")
+    }
+
+    pub async fn get_lists(&self) -> reqwest::Response {
+        self.api_client
+            .get(format!("{}/admin/lists", &self.address))
+            .send()
+            .await
+            .expect("Failed to execute request")
+    }
+
+    pub async fn post_lists(&self, body: serde_json::Value) -> reqwest::Response {
+        self.api_client
+            .post(format!("{}/admin/lists", &self.address))
+            .json(&body)
+            .send()
+            .await
+            .expect("Failed to execute request")
+    }
+
+    /// Log in as the test user, keeping the session cookie in `api_client`
+    pub async fn login_as_test_user(&self) {
+        self.post_login(&serde_json::json!({
+            "username": &self.test_user.username,
+            "password": &self.test_user.password
+        }))
+        .await;
+    }
+
     pub async fn get_health_check(&self) -> reqwest::Response {
         let route = format!("{}/health_check", &self.address);
         reqwest::Client::new()
@@ -51,10 +304,23 @@ impl TestApp {
     pub fn get_confirmation_links(&self, email_request: &wiremock::Request) -> ConfirmationLinks {
         let body: serde_json::Value = serde_json::from_slice(&email_request.body).unwrap();
 
+        self.confirmation_links_in(
+            body["HtmlBody"].as_str().unwrap(),
+            body["TextBody"].as_str().unwrap(),
+        )
+    }
+
+    /// For emails queued by an app that keeps subscribers in memory
+    pub fn get_confirmation_links_from_email(&self, email: &Email) -> ConfirmationLinks {
+        self.confirmation_links_in(&email.html_body, &email.text_body)
+    }
+
+    fn confirmation_links_in(&self, html_body: &str, text_body: &str) -> ConfirmationLinks {
         let get_link = |s: &str| {
             let links: Vec<_> = linkify::LinkFinder::new()
                 .links(s)
                 .filter(|l| *l.kind() == linkify::LinkKind::Url)
+                .filter(|l| l.as_str().contains("/subscriptions/confirm"))
                 .collect();
             assert_eq!(links.len(), 1);
 
@@ -66,14 +332,144 @@ impl TestApp {
             confirmation_link
         };
 
-        let html = get_link(&body["HtmlBody"].as_str().unwrap());
-        let plain_text = get_link(&body["TextBody"].as_str().unwrap());
+        let html = get_link(html_body);
+        let plain_text = get_link(text_body);
 
         ConfirmationLinks { html, plain_text }
     }
+
+    /// Extract the link to the preference page from the plain text body of any email
+    pub fn get_preferences_link(&self, text_body: &str) -> reqwest::Url {
+        let links: Vec<_> = linkify::LinkFinder::new()
+            .links(text_body)
+            .filter(|l| l.as_str().contains("/subscriptions/preferences"))
+            .collect();
+        assert_eq!(links.len(), 1);
+
+        let mut preferences_link = reqwest::Url::parse(links[0].as_str()).unwrap();
+        assert_eq!(preferences_link.host_str().unwrap(), "127.0.0.1");
+        preferences_link.set_port(Some(self.port)).unwrap();
+
+        preferences_link
+    }
+
+    /// Extract the unsubscribe link from the `List-Unsubscribe` header of the first email in a batch
+    pub fn get_unsubscribe_link(&self, email_request: &wiremock::Request) -> reqwest::Url {
+        let body: serde_json::Value = serde_json::from_slice(&email_request.body).unwrap();
+        let headers = body[0]["Headers"].as_array().unwrap();
+        let header = headers
+            .iter()
+            .find(|header| header["Name"] == "List-Unsubscribe")
+            .expect("No List-Unsubscribe header");
+
+        let raw_link = header["Value"]
+            .as_str()
+            .unwrap()
+            .trim_start_matches('<')
+            .trim_end_matches('>');
+        let mut unsubscribe_link = reqwest::Url::parse(raw_link).unwrap();
+        assert_eq!(unsubscribe_link.host_str().unwrap(), "127.0.0.1");
+        unsubscribe_link.set_port(Some(self.port)).unwrap();
+
+        unsubscribe_link
+    }
+
+    /// Use the public API to create a subscriber that has not clicked their confirmation link
+    pub async fn create_unconfirmed_subscriber(&self) -> ConfirmationLinks {
+        let body = "name=le%20guin&email=ursula_le_guin%40gmail.com";
+
+        Mock::given(path("/email"))
+            .and(method("POST"))
+            .respond_with(ResponseTemplate::new(200))
+            .named("Create unconfirmed subscriber")
+            .expect(1)
+            .mount(&self.email_server)
+            .await;
+
+        self.post_subscriptions(body.into())
+            .await
+            .error_for_status()
+            .unwrap();
+        self.dispatch_all_pending_emails().await;
+
+        let email_request = &self.email_server.received_requests().await.unwrap()[0];
+        let confirmation_links = self.get_confirmation_links(email_request);
+
+        self.email_server.verify().await;
+        self.email_server.reset().await;
+
+        confirmation_links
+    }
+
+    /// Backdate the confirmation emails sent so far, as if the cooldown had passed
+    pub async fn end_confirmation_email_cooldown(&self) {
+        sqlx::query!(
+            "UPDATE confirmation_email_throttle SET last_sent_at = last_sent_at - interval '1 hour'"
+        )
+        .execute(&self.db_pool)
+        .await
+        .expect("Failed to backdate confirmation emails");
+    }
+
+    /// Use the public API to create a subscriber that has clicked their confirmation link
+    pub async fn create_confirmed_subscriber(&self) {
+        let confirmation_links = self.create_unconfirmed_subscriber().await;
+
+        reqwest::get(confirmation_links.html)
+            .await
+            .unwrap()
+            .error_for_status()
+            .unwrap();
+    }
+}
+
+/// Answers a Postmark `/email/batch` request with the given error code for every message
+pub struct PostmarkBatchResponder {
+    pub error_code: i64,
+}
+
+impl PostmarkBatchResponder {
+    pub fn accept_all() -> Self {
+        Self { error_code: 0 }
+    }
+}
+
+impl Respond for PostmarkBatchResponder {
+    fn respond(&self, request: &Request) -> ResponseTemplate {
+        let messages: Vec<serde_json::Value> = serde_json::from_slice(&request.body).unwrap();
+        let results: Vec<_> = messages
+            .iter()
+            .map(|message| {
+                serde_json::json!({
+                    "To": message["To"],
+                    "ErrorCode": self.error_code,
+                    "Message": if self.error_code == 0 { "OK" } else { "Rejected" },
+                })
+            })
+            .collect();
+
+        ResponseTemplate::new(200).set_body_json(results)
+    }
 }
 
 pub async fn spawn_app() -> TestApp {
+    spawn(None, |_| {}).await
+}
+
+/// Subscribers, their tokens and the emails queued for them stay out of Postgres
+pub async fn spawn_app_with_in_memory_subscribers() -> TestApp {
+    spawn(Some(InMemorySubscriberRepository::default()), |_| {}).await
+}
+
+/// Change the configuration the app is built with, after the test defaults are applied
+pub async fn spawn_app_with_config(configure: impl FnOnce(&mut Settings)) -> TestApp {
+    spawn(None, configure).await
+}
+
+async fn spawn(
+    in_memory_subscribers: Option<InMemorySubscriberRepository>,
+    configure: impl FnOnce(&mut Settings),
+) -> TestApp {
     lazy_static::initialize(&TRACING);
 
     let email_server = MockServer::start().await;
@@ -81,32 +477,75 @@ pub async fn spawn_app() -> TestApp {
         let mut c = get_configuration().expect("Failed to read configuration");
         c.database.database_name = Uuid::new_v4().to_string();
         c.application.port = 0;
-        c.email_client.base_url = email_server.uri();
+        c.email_client.backend = EmailBackendSettings::Postmark(PostmarkSettings {
+            base_url: email_server.uri(),
+            authorization_token: "test-postmark-token".into(),
+            // Failed sends are retried by the email queues; the tests exercise those instead
+            retry: EmailRetrySettings {
+                max_attempts: 1,
+                base_delay_milliseconds: 0,
+                max_delay_milliseconds: 0,
+                jitter: 0.0,
+            },
+        });
+        // Requests can claim to come from any client, as if the tests were our load balancer
+        c.application.trusted_proxies =
+            vec![Ipv4Addr::LOCALHOST.into(), Ipv6Addr::LOCALHOST.into()];
+        configure(&mut c);
         c
     };
 
     configure_db(&config.database).await;
 
-    let app = Application::build(config.clone())
-        .await
-        .expect("Failed to build application");
+    let app = match &in_memory_subscribers {
+        Some(repository) => {
+            Application::build_with_subscriber_repository(
+                config.clone(),
+                Arc::new(repository.clone()),
+            )
+            .await
+        }
+        None => Application::build(config.clone()).await,
+    }
+    .expect("Failed to build application");
     let app_port = app.port();
     let address = format!("http://localhost:{}", app_port);
     // This is torn down along with the runtime by the actix_rt::test macro
-    let _ = tokio::spawn(app.run_until_stopped());
+    tokio::spawn(app.run_until_stopped());
 
     let db_pool = startup::get_connection_pool(&config.database)
         .await
         .expect("Failed to connect to database");
 
+    let test_user = TestUser::store(&db_pool).await;
+    let api_client = reqwest::Client::builder()
+        .redirect(reqwest::redirect::Policy::none())
+        .cookie_store(true)
+        .build()
+        .unwrap();
+
     TestApp {
         port: app_port,
         address,
         db_pool,
         email_server,
+        email_client: config.email_client.client(),
+        preferences_link_ttl: config.application.preferences_link_ttl(),
+        base_url: config.application.base_url,
+        hmac_secret: HmacSecret(config.application.hmac_secret),
+        in_memory_subscribers,
+        postmark_webhook: config.postmark_webhook,
+        rate_limits: config.application.rate_limits,
+        test_user,
+        api_client,
     }
 }
 
+pub fn assert_is_redirect_to(response: &reqwest::Response, location: &str) {
+    assert_eq!(response.status().as_u16(), 303);
+    assert_eq!(response.headers().get("Location").unwrap(), location);
+}
+
 async fn configure_db(config: &DatabaseSettings) -> PgPool {
     let mut connection = PgConnection::connect_with(&config.without_db())
         .await
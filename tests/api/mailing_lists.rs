@@ -0,0 +1,318 @@
+use crate::helpers::{spawn_app, ConfirmationLinks, PostmarkBatchResponder, TestApp};
+use wiremock::matchers::{method, path};
+use wiremock::{Mock, ResponseTemplate};
+
+async fn create_book_club(app: &TestApp) {
+    app.login_as_test_user().await;
+    app.post_lists(serde_json::json!({ "slug": "book-club", "name": "Book club" }))
+        .await
+        .error_for_status()
+        .unwrap();
+}
+
+/// Sign the test subscriber up to a list, returning the links in the confirmation email
+async fn subscribe_to(app: &TestApp, list: &str) -> ConfirmationLinks {
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+
+    let body = format!(
+        "name=le%20guin&email=ursula_le_guin%40gmail.com&list={}",
+        list
+    );
+    app.post_subscriptions(body)
+        .await
+        .error_for_status()
+        .unwrap();
+    app.dispatch_all_pending_emails().await;
+
+    let email_request = &app.email_server.received_requests().await.unwrap()[0];
+    let confirmation_links = app.get_confirmation_links(email_request);
+
+    app.email_server.verify().await;
+    app.email_server.reset().await;
+    // So that the next list's confirmation email isn't throttled
+    app.end_confirmation_email_cooldown().await;
+
+    confirmation_links
+}
+
+async fn statuses_by_list(app: &TestApp) -> Vec<(String, String)> {
+    sqlx::query!(
+        r#"
+            SELECT l.slug, m.status
+            FROM list_subscriptions m
+            JOIN lists l ON l.list_id = m.list_id
+            ORDER BY l.slug
+        "#
+    )
+    .fetch_all(&app.db_pool)
+    .await
+    .unwrap()
+    .into_iter()
+    .map(|r| (r.slug, r.status))
+    .collect()
+}
+
+/// Confirm the test subscriber on both lists, then publish an issue to the book club and
+/// return the unsubscribe link sent with it
+async fn receive_book_club_unsubscribe_link(app: &TestApp) -> reqwest::Url {
+    create_book_club(app).await;
+    for list in ["newsletter", "book-club"].iter() {
+        let confirmation_links = subscribe_to(app, list).await;
+        reqwest::get(confirmation_links.html)
+            .await
+            .unwrap()
+            .error_for_status()
+            .unwrap();
+    }
+
+    Mock::given(path("/email/batch"))
+        .and(method("POST"))
+        .respond_with(PostmarkBatchResponder::accept_all())
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+    app.post_newsletters(serde_json::json!({
+        "title": "Book club title",
+        "content": {
+            "text": "Book club body as plain text",
+            "html": "<p>Book club body as HTML</p>",
+        },
+        "list": "book-club",
+    }))
+    .await
+    .error_for_status()
+    .unwrap();
+    app.dispatch_all_pending_emails().await;
+
+    let email_request = &app.email_server.received_requests().await.unwrap()[0];
+    let unsubscribe_link = app.get_unsubscribe_link(email_request);
+
+    app.email_server.verify().await;
+    app.email_server.reset().await;
+
+    unsubscribe_link
+}
+
+#[actix_rt::test]
+async fn you_must_be_logged_in_to_see_or_create_lists() {
+    // Arrange
+    let app = spawn_app().await;
+
+    // Act
+    let get_response = app.get_lists().await;
+    let post_response = app
+        .post_lists(serde_json::json!({ "slug": "book-club", "name": "Book club" }))
+        .await;
+
+    // Assert
+    assert_eq!(get_response.status().as_u16(), 401);
+    assert_eq!(post_response.status().as_u16(), 401);
+}
+
+#[actix_rt::test]
+async fn admins_can_create_lists() {
+    // Arrange
+    let app = spawn_app().await;
+    app.login_as_test_user().await;
+
+    // Act
+    let response = app
+        .post_lists(serde_json::json!({ "slug": "book-club", "name": "Book club" }))
+        .await;
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 201);
+    let created: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(created["slug"], "book-club");
+    let lists: serde_json::Value = app.get_lists().await.json().await.unwrap();
+    let slugs: Vec<_> = lists
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|list| list["slug"].as_str().unwrap())
+        .collect();
+    assert_eq!(slugs, vec!["book-club", "newsletter"]);
+}
+
+#[actix_rt::test]
+async fn creating_a_list_fails_for_a_taken_or_invalid_slug() {
+    // Arrange
+    let app = spawn_app().await;
+    app.login_as_test_user().await;
+    let test_cases = vec![
+        (
+            serde_json::json!({ "slug": "newsletter", "name": "Another newsletter" }),
+            409,
+            "a taken slug",
+        ),
+        (
+            serde_json::json!({ "slug": "Book Club", "name": "Book club" }),
+            400,
+            "an invalid slug",
+        ),
+        (
+            serde_json::json!({ "slug": "book-club", "name": " " }),
+            400,
+            "an empty name",
+        ),
+    ];
+
+    for (body, expected_status, description) in test_cases {
+        // Act
+        let response = app.post_lists(body).await;
+
+        // Assert
+        assert_eq!(
+            response.status().as_u16(),
+            expected_status,
+            "The API did not fail with {} when the payload had {}.",
+            expected_status,
+            description
+        );
+    }
+}
+
+#[actix_rt::test]
+async fn subscribing_to_an_unknown_list_is_rejected() {
+    // Arrange
+    let app = spawn_app().await;
+    let body = "name=le%20guin&email=ursula_le_guin%40gmail.com&list=book-club";
+
+    // Act
+    let response = app.post_subscriptions(body.into()).await;
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 400);
+    assert!(statuses_by_list(&app).await.is_empty());
+}
+
+#[actix_rt::test]
+async fn each_list_is_confirmed_separately() {
+    // Arrange
+    let app = spawn_app().await;
+    create_book_club(&app).await;
+    subscribe_to(&app, "newsletter").await;
+    let book_club_links = subscribe_to(&app, "book-club").await;
+
+    // Act
+    reqwest::get(book_club_links.html)
+        .await
+        .unwrap()
+        .error_for_status()
+        .unwrap();
+
+    // Assert
+    assert_eq!(
+        statuses_by_list(&app).await,
+        vec![
+            ("book-club".to_string(), "confirmed".to_string()),
+            ("newsletter".to_string(), "pending_confirmation".to_string()),
+        ]
+    );
+    let subscribers = sqlx::query!("SELECT COUNT(*) AS \"count!\" FROM subscriptions")
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap();
+    assert_eq!(subscribers.count, 1);
+}
+
+#[actix_rt::test]
+async fn issues_are_only_delivered_to_the_list_they_are_published_to() {
+    // Arrange
+    let app = spawn_app().await;
+    create_book_club(&app).await;
+    app.create_confirmed_subscriber().await;
+
+    // Act
+    let response = app
+        .post_newsletters(serde_json::json!({
+            "title": "Book club title",
+            "content": {
+                "text": "Book club body as plain text",
+                "html": "<p>Book club body as HTML</p>",
+            },
+            "list": "book-club",
+        }))
+        .await;
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 202);
+    let report: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(report["queued"], 0);
+}
+
+#[actix_rt::test]
+async fn publishing_to_an_unknown_list_is_rejected() {
+    // Arrange
+    let app = spawn_app().await;
+
+    // Act
+    let response = app
+        .post_newsletters(serde_json::json!({
+            "title": "Book club title",
+            "content": {
+                "text": "Book club body as plain text",
+                "html": "<p>Book club body as HTML</p>",
+            },
+            "list": "book-club",
+        }))
+        .await;
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 404);
+}
+
+#[actix_rt::test]
+async fn unsubscribing_leaves_the_other_lists_alone() {
+    // Arrange
+    let app = spawn_app().await;
+    let unsubscribe_link = receive_book_club_unsubscribe_link(&app).await;
+
+    // Act
+    let response = reqwest::Client::new()
+        .post(unsubscribe_link)
+        .send()
+        .await
+        .unwrap();
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 200);
+    assert_eq!(
+        statuses_by_list(&app).await,
+        vec![
+            ("book-club".to_string(), "unsubscribed".to_string()),
+            ("newsletter".to_string(), "confirmed".to_string()),
+        ]
+    );
+}
+
+#[actix_rt::test]
+async fn subscribers_can_unsubscribe_from_all_lists_at_once() {
+    // Arrange
+    let app = spawn_app().await;
+    let mut unsubscribe_link = receive_book_club_unsubscribe_link(&app).await;
+    let query = format!("{}&all=true", unsubscribe_link.query().unwrap());
+    unsubscribe_link.set_query(Some(&query));
+
+    // Act
+    let response = reqwest::Client::new()
+        .post(unsubscribe_link)
+        .send()
+        .await
+        .unwrap();
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 200);
+    assert_eq!(
+        statuses_by_list(&app).await,
+        vec![
+            ("book-club".to_string(), "unsubscribed".to_string()),
+            ("newsletter".to_string(), "unsubscribed".to_string()),
+        ]
+    );
+}
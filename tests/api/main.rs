@@ -1,4 +1,16 @@
+mod admin_dashboard;
+mod consent_history;
 mod health_check;
 mod helpers;
+mod login;
+mod mailing_lists;
+mod newsletters;
+mod personal_data;
+mod postmark_webhook;
+mod preferences;
+mod rate_limiting;
+mod signup_protection;
+mod subscriber_repository;
 mod subscriptions;
 mod subscriptions_confirm;
+mod unsubscribe;
@@ -0,0 +1,289 @@
+use crate::helpers::{spawn_app, PostmarkBatchResponder, TestApp};
+use wiremock::matchers::{method, path};
+use wiremock::Mock;
+
+const EMAIL: &str = "ursula_le_guin@gmail.com";
+
+/// A confirmed subscriber with a newsletter issue waiting to be delivered to them
+async fn create_subscriber_with_pending_delivery(app: &TestApp) {
+    app.create_confirmed_subscriber().await;
+    app.post_newsletters(serde_json::json!({
+        "title": "Newsletter title",
+        "content": {
+            "text": "Newsletter body as plain text",
+            "html": "<p>Newsletter body as HTML</p>",
+        }
+    }))
+    .await
+    .error_for_status()
+    .unwrap();
+}
+
+async fn count_rows(app: &TestApp) -> Vec<i64> {
+    let counts = sqlx::query!(
+        r#"
+            SELECT
+                (SELECT COUNT(*) FROM subscriptions) AS "subscriptions!",
+                (SELECT COUNT(*) FROM list_subscriptions) AS "list_subscriptions!",
+                (SELECT COUNT(*) FROM subscription_tokens) AS "subscription_tokens!",
+                (SELECT COUNT(*) FROM consent_events) AS "consent_events!",
+                (SELECT COUNT(*) FROM confirmation_email_throttle) AS "confirmation_emails!",
+                (SELECT COUNT(*) FROM email_outbox) AS "queued_emails!",
+                (SELECT COUNT(*) FROM issue_delivery_queue) AS "newsletter_deliveries!",
+                (SELECT COUNT(*) FROM issue_deliveries) AS "delivered_newsletters!",
+                (SELECT COUNT(*) FROM suppressed_emails) AS "suppressions!"
+        "#
+    )
+    .fetch_one(&app.db_pool)
+    .await
+    .unwrap();
+    vec![
+        counts.subscriptions,
+        counts.list_subscriptions,
+        counts.subscription_tokens,
+        counts.consent_events,
+        counts.confirmation_emails,
+        counts.queued_emails,
+        counts.newsletter_deliveries,
+        counts.delivered_newsletters,
+        counts.suppressions,
+    ]
+}
+
+#[actix_rt::test]
+async fn you_must_be_logged_in_to_export_or_erase_personal_data() {
+    // Arrange
+    let app = spawn_app().await;
+    app.create_confirmed_subscriber().await;
+
+    // Act
+    let export = app.post_export_personal_data(EMAIL).await;
+    let erase = app.post_erase_personal_data(EMAIL).await;
+
+    // Assert
+    assert_eq!(export.status().as_u16(), 401);
+    assert_eq!(erase.status().as_u16(), 401);
+    assert_eq!(count_rows(&app).await[0], 1);
+}
+
+#[actix_rt::test]
+async fn the_export_contains_everything_stored_about_the_address() {
+    // Arrange
+    let app = spawn_app().await;
+    create_subscriber_with_pending_delivery(&app).await;
+    app.login_as_test_user().await;
+
+    // Act
+    let response = app.post_export_personal_data(EMAIL).await;
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 200);
+    let export: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(export["email"], EMAIL);
+    let subscriptions = export["subscriptions"].as_array().unwrap();
+    assert_eq!(subscriptions.len(), 1);
+    assert_eq!(subscriptions[0]["name"], "le guin");
+    let list_subscriptions = export["list_subscriptions"].as_array().unwrap();
+    assert_eq!(list_subscriptions.len(), 1);
+    assert_eq!(list_subscriptions[0]["list"], "newsletter");
+    assert_eq!(list_subscriptions[0]["status"], "confirmed");
+    assert_eq!(export["subscription_tokens"].as_array().unwrap().len(), 1);
+    let event_types: Vec<_> = export["consent_events"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|event| event["event_type"].as_str().unwrap())
+        .collect();
+    assert_eq!(event_types, vec!["signup", "confirmation"]);
+    assert_eq!(export["confirmation_emails"][0]["sent_in_window"], 1);
+    let deliveries = export["newsletter_deliveries"].as_array().unwrap();
+    assert_eq!(deliveries.len(), 1);
+    assert_eq!(deliveries[0]["title"], "Newsletter title");
+    assert!(export["suppressions"].as_array().unwrap().is_empty());
+    assert_eq!(export["erased_at"], serde_json::Value::Null);
+}
+
+#[actix_rt::test]
+async fn the_export_of_an_unknown_address_is_empty() {
+    // Arrange
+    let app = spawn_app().await;
+    app.login_as_test_user().await;
+
+    // Act
+    let response = app.post_export_personal_data(EMAIL).await;
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 200);
+    let export: serde_json::Value = response.json().await.unwrap();
+    assert!(export["subscriptions"].as_array().unwrap().is_empty());
+    assert!(export["consent_events"].as_array().unwrap().is_empty());
+}
+
+#[actix_rt::test]
+async fn erasing_deletes_everything_stored_about_the_address() {
+    // Arrange
+    let app = spawn_app().await;
+    create_subscriber_with_pending_delivery(&app).await;
+    app.login_as_test_user().await;
+
+    // Act
+    let response = app.post_erase_personal_data(EMAIL).await;
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 200);
+    let report: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(report["subscriptions"], 1);
+    assert_eq!(report["list_subscriptions"], 1);
+    assert_eq!(report["consent_events"], 2);
+    assert_eq!(report["newsletter_deliveries"], 1);
+    assert_eq!(count_rows(&app).await, vec![0; 9]);
+    let export: serde_json::Value = app
+        .post_export_personal_data(EMAIL)
+        .await
+        .json()
+        .await
+        .unwrap();
+    assert!(export["subscriptions"].as_array().unwrap().is_empty());
+    assert_ne!(export["erased_at"], serde_json::Value::Null);
+}
+
+#[actix_rt::test]
+async fn newsletters_already_delivered_are_exported_and_erased() {
+    // Arrange
+    let app = spawn_app().await;
+    create_subscriber_with_pending_delivery(&app).await;
+    Mock::given(path("/email/batch"))
+        .and(method("POST"))
+        .respond_with(PostmarkBatchResponder::accept_all())
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+    app.dispatch_all_pending_emails().await;
+    app.login_as_test_user().await;
+
+    // Act - Part 1 - Export
+    let export: serde_json::Value = app
+        .post_export_personal_data(EMAIL)
+        .await
+        .error_for_status()
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+
+    // Act - Part 2 - Erase
+    let report: serde_json::Value = app
+        .post_erase_personal_data(EMAIL)
+        .await
+        .error_for_status()
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+
+    // Assert
+    assert!(export["newsletter_deliveries"]
+        .as_array()
+        .unwrap()
+        .is_empty());
+    let delivered = export["delivered_newsletters"].as_array().unwrap();
+    assert_eq!(delivered.len(), 1);
+    assert_eq!(delivered[0]["title"], "Newsletter title");
+    assert_eq!(report["delivered_newsletters"], 1);
+    assert_eq!(count_rows(&app).await, vec![0; 9]);
+}
+
+#[actix_rt::test]
+async fn addresses_are_exported_and_erased_ignoring_case() {
+    // Arrange
+    let app = spawn_app().await;
+    create_subscriber_with_pending_delivery(&app).await;
+    app.login_as_test_user().await;
+    let email = EMAIL.to_uppercase();
+
+    // Act - Part 1 - Export
+    let export: serde_json::Value = app
+        .post_export_personal_data(&email)
+        .await
+        .error_for_status()
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+
+    // Act - Part 2 - Erase
+    let response = app.post_erase_personal_data(&email).await;
+
+    // Assert
+    assert_eq!(export["subscriptions"].as_array().unwrap().len(), 1);
+    assert_eq!(export["newsletter_deliveries"].as_array().unwrap().len(), 1);
+    assert_eq!(response.status().as_u16(), 200);
+    let report: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(report["subscriptions"], 1);
+    assert_eq!(report["newsletter_deliveries"], 1);
+    assert_eq!(count_rows(&app).await, vec![0; 9]);
+}
+
+#[actix_rt::test]
+async fn erasing_leaves_a_hashed_tombstone_and_nothing_else() {
+    // Arrange
+    let app = spawn_app().await;
+    app.create_confirmed_subscriber().await;
+    app.login_as_test_user().await;
+
+    // Act
+    app.post_erase_personal_data(EMAIL)
+        .await
+        .error_for_status()
+        .unwrap();
+
+    // Assert
+    let tombstones = sqlx::query!("SELECT email_hash FROM erased_emails")
+        .fetch_all(&app.db_pool)
+        .await
+        .unwrap();
+    assert_eq!(tombstones.len(), 1);
+    assert_eq!(
+        tombstones[0].email_hash,
+        zero2prod::personal_data::hash_erased_email(EMAIL)
+    );
+}
+
+#[actix_rt::test]
+async fn erased_addresses_can_sign_up_again_by_confirming_a_new_subscription() {
+    // Arrange
+    let app = spawn_app().await;
+    app.create_confirmed_subscriber().await;
+    app.login_as_test_user().await;
+    app.post_erase_personal_data(EMAIL)
+        .await
+        .error_for_status()
+        .unwrap();
+
+    // Act - Part 1 - Sign up
+    let confirmation_links = app.create_unconfirmed_subscriber().await;
+    let tombstones_before_confirming = sqlx::query!("SELECT email_hash FROM erased_emails")
+        .fetch_all(&app.db_pool)
+        .await
+        .unwrap();
+
+    // Act - Part 2 - Confirm
+    reqwest::get(confirmation_links.html)
+        .await
+        .unwrap()
+        .error_for_status()
+        .unwrap();
+
+    // Assert
+    assert_eq!(tombstones_before_confirming.len(), 1);
+    let tombstones = sqlx::query!("SELECT email_hash FROM erased_emails")
+        .fetch_all(&app.db_pool)
+        .await
+        .unwrap();
+    assert!(tombstones.is_empty());
+    let saved = sqlx::query!("SELECT status FROM list_subscriptions")
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap();
+    assert_eq!(saved.status, "confirmed");
+}
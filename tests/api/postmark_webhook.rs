@@ -0,0 +1,320 @@
+use crate::helpers::{spawn_app, spawn_app_with_in_memory_subscribers};
+use wiremock::matchers::any;
+use wiremock::{Mock, ResponseTemplate};
+use zero2prod::domain::SubscriptionStatus;
+use zero2prod::suppression_list::SuppressionReason;
+
+fn bounce(bounce_type: &str, email: &str) -> serde_json::Value {
+    serde_json::json!({
+        "RecordType": "Bounce",
+        "ID": 4323372036854775807u64,
+        "Type": bounce_type,
+        "TypeCode": 1,
+        "Email": email,
+        "BouncedAt": "2021-05-23T14:18:57Z",
+        "Description": "The server was unable to deliver your message.",
+    })
+}
+
+fn spam_complaint(email: &str) -> serde_json::Value {
+    serde_json::json!({
+        "RecordType": "SpamComplaint",
+        "ID": 42,
+        "Type": "SpamComplaint",
+        "Email": email,
+        "BouncedAt": "2021-05-23T14:18:57Z",
+    })
+}
+
+#[actix_rt::test]
+async fn webhooks_without_valid_credentials_are_rejected() {
+    // Arrange
+    let app = spawn_app().await;
+    let body = bounce("HardBounce", "ursula_le_guin@gmail.com");
+    let test_cases = vec![
+        (None, "missing credentials"),
+        (Some(("postmark", "wrong-password")), "wrong password"),
+        (
+            Some(("someone", "development-webhook-password")),
+            "wrong username",
+        ),
+    ];
+
+    for (credentials, description) in test_cases {
+        // Act
+        let mut request = reqwest::Client::new()
+            .post(format!("{}/webhooks/postmark", &app.address))
+            .json(&body);
+        if let Some((username, password)) = credentials {
+            request = request.basic_auth(username, Some(password));
+        }
+        let response = request.send().await.expect("Failed to execute request.");
+
+        // Assert
+        assert_eq!(
+            401,
+            response.status().as_u16(),
+            "The API did not reject a webhook with {}.",
+            description
+        );
+        assert_eq!(
+            r#"Basic realm="postmark""#,
+            response.headers()["WWW-Authenticate"]
+        );
+    }
+    let suppressed = sqlx::query!("SELECT email FROM suppressed_emails")
+        .fetch_all(&app.db_pool)
+        .await
+        .unwrap();
+    assert!(suppressed.is_empty());
+}
+
+#[actix_rt::test]
+async fn malformed_payloads_are_rejected_with_a_400() {
+    // Arrange
+    let app = spawn_app().await;
+
+    // Act
+    let response = app
+        .post_postmark_webhook(serde_json::json!({"RecordType": "Bounce"}))
+        .await;
+
+    // Assert
+    assert_eq!(400, response.status().as_u16());
+}
+
+#[actix_rt::test]
+async fn a_hard_bounce_marks_the_subscriber_as_bounced_and_suppresses_the_address() {
+    // Arrange
+    let app = spawn_app().await;
+    app.create_confirmed_subscriber().await;
+
+    // Act
+    let response = app
+        .post_postmark_webhook(bounce("HardBounce", "ursula_le_guin@gmail.com"))
+        .await;
+
+    // Assert
+    assert_eq!(200, response.status().as_u16());
+    let saved = sqlx::query!(
+        r#"
+            SELECT m.status AS "status!"
+            FROM list_subscriptions m
+            JOIN subscriptions s ON s.id = m.subscriber_id
+            WHERE s.email = 'ursula_le_guin@gmail.com'
+        "#
+    )
+    .fetch_one(&app.db_pool)
+    .await
+    .unwrap();
+    assert_eq!(saved.status, "bounced");
+    let suppressed = sqlx::query!(
+        r#"SELECT reason FROM suppressed_emails WHERE email = 'ursula_le_guin@gmail.com'"#
+    )
+    .fetch_one(&app.db_pool)
+    .await
+    .unwrap();
+    assert_eq!(suppressed.reason, "hard_bounce");
+}
+
+#[actix_rt::test]
+async fn a_spam_complaint_marks_the_subscriber_as_complained_and_suppresses_the_address() {
+    // Arrange
+    let app = spawn_app_with_in_memory_subscribers().await;
+    let repository = app.in_memory_subscribers.clone().unwrap();
+    app.post_subscriptions("name=le%20guin&email=ursula_le_guin%40gmail.com".into())
+        .await
+        .error_for_status()
+        .unwrap();
+
+    // Act
+    let response = app
+        .post_postmark_webhook(spam_complaint("ursula_le_guin@gmail.com"))
+        .await;
+
+    // Assert
+    assert_eq!(200, response.status().as_u16());
+    let list_subscriptions = repository.list_subscriptions().await;
+    assert_eq!(list_subscriptions[0].status, SubscriptionStatus::Complained);
+    assert_eq!(
+        Some(&SuppressionReason::SpamComplaint),
+        repository
+            .suppressed_emails()
+            .await
+            .get("ursula_le_guin@gmail.com")
+    );
+}
+
+#[actix_rt::test]
+async fn soft_bounces_and_other_events_change_nothing() {
+    // Arrange
+    let app = spawn_app().await;
+    app.create_confirmed_subscriber().await;
+    let test_cases = vec![
+        bounce("SoftBounce", "ursula_le_guin@gmail.com"),
+        bounce("Transient", "ursula_le_guin@gmail.com"),
+        serde_json::json!({
+            "RecordType": "Delivery",
+            "Recipient": "ursula_le_guin@gmail.com",
+        }),
+    ];
+
+    for body in test_cases {
+        // Act
+        let response = app.post_postmark_webhook(body.clone()).await;
+
+        // Assert
+        assert_eq!(
+            200,
+            response.status().as_u16(),
+            "The API did not acknowledge {}.",
+            body
+        );
+    }
+    let saved = sqlx::query!("SELECT status FROM list_subscriptions")
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap();
+    assert_eq!(saved.status, "confirmed");
+    let suppressed = sqlx::query!("SELECT email FROM suppressed_emails")
+        .fetch_all(&app.db_pool)
+        .await
+        .unwrap();
+    assert!(suppressed.is_empty());
+}
+
+#[actix_rt::test]
+async fn addresses_without_a_subscriber_are_still_suppressed() {
+    // Arrange
+    let app = spawn_app().await;
+
+    // Act
+    let response = app
+        .post_postmark_webhook(bounce("HardBounce", "nobody@example.com"))
+        .await;
+
+    // Assert
+    assert_eq!(200, response.status().as_u16());
+    let suppressed = sqlx::query!("SELECT email FROM suppressed_emails")
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap();
+    assert_eq!(suppressed.email, "nobody@example.com");
+}
+
+#[actix_rt::test]
+async fn newsletters_are_not_delivered_to_suppressed_addresses() {
+    // Arrange
+    let app = spawn_app().await;
+    app.create_confirmed_subscriber().await;
+    // Suppressed without going through the webhook, so the subscriber is still confirmed
+    sqlx::query!(
+        r#"
+            INSERT INTO suppressed_emails (email, reason, suppressed_at)
+            VALUES ('ursula_le_guin@gmail.com', 'hard_bounce', now())
+        "#
+    )
+    .execute(&app.db_pool)
+    .await
+    .unwrap();
+
+    Mock::given(any())
+        .respond_with(ResponseTemplate::new(200))
+        .expect(0)
+        .mount(&app.email_server)
+        .await;
+
+    // Act
+    let response = app
+        .post_newsletters(serde_json::json!({
+            "title": "Newsletter title",
+            "content": {
+                "text": "Newsletter body as plain text",
+                "html": "<p>Newsletter body as HTML</p>",
+            }
+        }))
+        .await;
+    app.dispatch_all_pending_emails().await;
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 202);
+    let pending = sqlx::query!("SELECT subscriber_email FROM issue_delivery_queue")
+        .fetch_all(&app.db_pool)
+        .await
+        .unwrap();
+    assert!(pending.is_empty());
+    // relies on Mock::expect
+}
+
+#[actix_rt::test]
+async fn a_bounce_suppresses_the_address_whatever_its_case() {
+    // Arrange
+    let app = spawn_app().await;
+    app.create_confirmed_subscriber().await;
+    Mock::given(any())
+        .respond_with(ResponseTemplate::new(200))
+        .expect(0)
+        .mount(&app.email_server)
+        .await;
+
+    // Act
+    app.post_postmark_webhook(bounce("HardBounce", "Ursula_Le_Guin@Gmail.com"))
+        .await
+        .error_for_status()
+        .unwrap();
+    app.post_newsletters(serde_json::json!({
+        "title": "Newsletter title",
+        "content": {
+            "text": "Newsletter body as plain text",
+            "html": "<p>Newsletter body as HTML</p>",
+        }
+    }))
+    .await
+    .error_for_status()
+    .unwrap();
+    app.dispatch_all_pending_emails().await;
+
+    // Assert
+    let suppressed = sqlx::query!("SELECT email FROM suppressed_emails")
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap();
+    assert_eq!(suppressed.email, "ursula_le_guin@gmail.com");
+    // relies on Mock::expect
+}
+
+#[actix_rt::test]
+async fn confirmation_emails_are_not_sent_to_suppressed_addresses() {
+    // Arrange
+    let app = spawn_app().await;
+    sqlx::query!(
+        r#"
+            INSERT INTO suppressed_emails (email, reason, suppressed_at)
+            VALUES ('ursula_le_guin@gmail.com', 'spam_complaint', now())
+        "#
+    )
+    .execute(&app.db_pool)
+    .await
+    .unwrap();
+
+    Mock::given(any())
+        .respond_with(ResponseTemplate::new(200))
+        .expect(0)
+        .mount(&app.email_server)
+        .await;
+
+    // Act
+    let response = app
+        .post_subscriptions("name=le%20guin&email=ursula_le_guin%40gmail.com".into())
+        .await;
+    app.dispatch_all_pending_emails().await;
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 200);
+    let queued = sqlx::query!("SELECT email_id FROM email_outbox")
+        .fetch_all(&app.db_pool)
+        .await
+        .unwrap();
+    assert!(queued.is_empty());
+    // relies on Mock::expect
+}
@@ -0,0 +1,433 @@
+use crate::helpers::{spawn_app, PostmarkBatchResponder, TestApp};
+use chrono::Utc;
+use wiremock::matchers::{any, method, path};
+use wiremock::{Mock, ResponseTemplate};
+use zero2prod::routes::preferences_link;
+
+fn newsletter_request_body() -> serde_json::Value {
+    serde_json::json!({
+        "title": "Newsletter title",
+        "content": {
+            "text": "Newsletter body as plain text",
+            "html": "<p>Newsletter body as HTML</p>",
+        }
+    })
+}
+
+/// Publish an issue to a new confirmed subscriber and return the preference link sent to them
+async fn receive_preferences_link(app: &TestApp) -> reqwest::Url {
+    app.create_confirmed_subscriber().await;
+
+    Mock::given(path("/email/batch"))
+        .and(method("POST"))
+        .respond_with(PostmarkBatchResponder::accept_all())
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+
+    app.post_newsletters(newsletter_request_body()).await;
+    app.dispatch_all_pending_emails().await;
+
+    let email_request = &app.email_server.received_requests().await.unwrap()[0];
+    let body: serde_json::Value = serde_json::from_slice(&email_request.body).unwrap();
+    let preferences_link = app.get_preferences_link(body[0]["TextBody"].as_str().unwrap());
+
+    app.email_server.verify().await;
+    app.email_server.reset().await;
+
+    preferences_link
+}
+
+async fn post_preferences(link: &reqwest::Url, body: &str) -> reqwest::Response {
+    reqwest::Client::new()
+        .post(link.clone())
+        .header("Content-Type", "application/x-www-form-urlencoded")
+        .body(body.to_string())
+        .send()
+        .await
+        .expect("Failed to execute request")
+}
+
+async fn create_book_club(app: &TestApp) {
+    app.login_as_test_user().await;
+    app.post_lists(serde_json::json!({ "slug": "book-club", "name": "Book club" }))
+        .await
+        .error_for_status()
+        .unwrap();
+}
+
+async fn statuses_by_list(app: &TestApp) -> Vec<(String, String)> {
+    sqlx::query!(
+        r#"
+            SELECT l.slug, m.status
+            FROM list_subscriptions m
+            JOIN lists l ON l.list_id = m.list_id
+            ORDER BY l.slug
+        "#
+    )
+    .fetch_all(&app.db_pool)
+    .await
+    .unwrap()
+    .into_iter()
+    .map(|r| (r.slug, r.status))
+    .collect()
+}
+
+#[actix_rt::test]
+async fn confirmation_emails_link_to_the_preference_page() {
+    // Arrange
+    let app = spawn_app().await;
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+    app.post_subscriptions("name=le%20guin&email=ursula_le_guin%40gmail.com".into())
+        .await
+        .error_for_status()
+        .unwrap();
+
+    // Act
+    app.dispatch_all_pending_emails().await;
+
+    // Assert
+    let email_request = &app.email_server.received_requests().await.unwrap()[0];
+    let body: serde_json::Value = serde_json::from_slice(&email_request.body).unwrap();
+    let preferences_link = app.get_preferences_link(body["TextBody"].as_str().unwrap());
+    assert!(body["HtmlBody"]
+        .as_str()
+        .unwrap()
+        .contains(preferences_link.query().unwrap()));
+    let response = reqwest::get(preferences_link).await.unwrap();
+    assert_eq!(response.status().as_u16(), 200);
+}
+
+#[actix_rt::test]
+async fn the_preference_page_shows_the_current_choices() {
+    // Arrange
+    let app = spawn_app().await;
+    let preferences_link = receive_preferences_link(&app).await;
+
+    // Act
+    let response = reqwest::get(preferences_link).await.unwrap();
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 200);
+    let html = response.text().await.unwrap();
+    assert!(html.contains(&format!(
+        r#"name="name" value="{}""#,
+        htmlescape::encode_attribute("le guin")
+    )));
+    assert!(html.contains(r#"name="list" value="newsletter" checked"#));
+    assert!(html.contains(r#"name="digest_frequency" value="immediately" checked"#));
+    assert!(html.contains("One digest a day"));
+}
+
+#[actix_rt::test]
+async fn subscribers_can_change_their_name_and_digest_frequency() {
+    // Arrange
+    let app = spawn_app().await;
+    let preferences_link = receive_preferences_link(&app).await;
+
+    // Act
+    let response = post_preferences(
+        &preferences_link,
+        "name=Ursula&list=newsletter&digest_frequency=weekly",
+    )
+    .await;
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 200);
+    let saved = sqlx::query!("SELECT name, digest_frequency FROM subscriptions")
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap();
+    assert_eq!(saved.name, "Ursula");
+    assert_eq!(saved.digest_frequency, "weekly");
+    assert_eq!(
+        statuses_by_list(&app).await,
+        vec![("newsletter".to_string(), "confirmed".to_string())]
+    );
+}
+
+#[actix_rt::test]
+async fn subscribers_can_join_and_leave_lists() {
+    // Arrange
+    let app = spawn_app().await;
+    create_book_club(&app).await;
+    let preferences_link = receive_preferences_link(&app).await;
+
+    // Act
+    let response = post_preferences(
+        &preferences_link,
+        "name=le%20guin&list=book-club&digest_frequency=immediately",
+    )
+    .await;
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 200);
+    assert_eq!(
+        statuses_by_list(&app).await,
+        vec![
+            ("book-club".to_string(), "confirmed".to_string()),
+            ("newsletter".to_string(), "unsubscribed".to_string()),
+        ]
+    );
+    let consent = sqlx::query!(
+        r#"
+            SELECT c.form_source
+            FROM consent_events c
+            JOIN lists l ON l.list_id = c.list_id
+            WHERE l.slug = 'book-club' AND c.event_type = 'preference_center_signup'
+        "#
+    )
+    .fetch_one(&app.db_pool)
+    .await
+    .unwrap();
+    assert_eq!(consent.form_source.as_deref(), Some("preference_center"));
+}
+
+#[actix_rt::test]
+async fn saving_the_preferences_of_a_pending_subscriber_confirms_them() {
+    // Arrange
+    let app = spawn_app().await;
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .mount(&app.email_server)
+        .await;
+    app.post_subscriptions("name=le%20guin&email=ursula_le_guin%40gmail.com".into())
+        .await
+        .error_for_status()
+        .unwrap();
+    app.dispatch_all_pending_emails().await;
+    let email_request = &app.email_server.received_requests().await.unwrap()[0];
+    let body: serde_json::Value = serde_json::from_slice(&email_request.body).unwrap();
+    let preferences_link = app.get_preferences_link(body["TextBody"].as_str().unwrap());
+
+    // Act
+    let response = post_preferences(
+        &preferences_link,
+        "name=le%20guin&list=newsletter&digest_frequency=immediately",
+    )
+    .await;
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 200);
+    assert_eq!(
+        statuses_by_list(&app).await,
+        vec![("newsletter".to_string(), "confirmed".to_string())]
+    );
+}
+
+#[actix_rt::test]
+async fn subscribers_can_unsubscribe_from_everything() {
+    // Arrange
+    let app = spawn_app().await;
+    let preferences_link = receive_preferences_link(&app).await;
+
+    // Act
+    let response = post_preferences(&preferences_link, "unsubscribe_all=true").await;
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 200);
+    assert_eq!(
+        statuses_by_list(&app).await,
+        vec![("newsletter".to_string(), "unsubscribed".to_string())]
+    );
+}
+
+#[actix_rt::test]
+async fn invalid_preferences_are_rejected_with_a_400() {
+    // Arrange
+    let app = spawn_app().await;
+    let preferences_link = receive_preferences_link(&app).await;
+    let test_cases = vec![
+        (
+            "name=&list=newsletter&digest_frequency=weekly",
+            "an empty name",
+        ),
+        (
+            "name=Ursula&list=newsletter&digest_frequency=hourly",
+            "an unknown frequency",
+        ),
+        ("name=Ursula&list=newsletter", "no frequency"),
+    ];
+
+    for (body, description) in test_cases {
+        // Act
+        let response = post_preferences(&preferences_link, body).await;
+
+        // Assert
+        assert_eq!(
+            response.status().as_u16(),
+            400,
+            "The API did not fail with 400 Bad Request when the payload had {}.",
+            description
+        );
+    }
+    let saved = sqlx::query!("SELECT name, digest_frequency FROM subscriptions")
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap();
+    assert_eq!(saved.name, "le guin");
+    assert_eq!(saved.digest_frequency, "immediately");
+}
+
+#[actix_rt::test]
+async fn tampered_preference_links_are_rejected_with_a_400() {
+    // Arrange
+    let app = spawn_app().await;
+    let mut preferences_link = receive_preferences_link(&app).await;
+    let token = preferences_link
+        .query()
+        .unwrap()
+        .trim_start_matches("token=");
+    let (_, rest) = token.split_at(token.find('.').unwrap());
+    let forged_token = format!("{}{}", uuid::Uuid::new_v4(), rest);
+    preferences_link.set_query(Some(&format!("token={}", forged_token)));
+
+    // Act
+    let get_response = reqwest::get(preferences_link.clone()).await.unwrap();
+    let post_response = post_preferences(&preferences_link, "unsubscribe_all=true").await;
+
+    // Assert
+    assert_eq!(get_response.status().as_u16(), 400);
+    assert_eq!(post_response.status().as_u16(), 400);
+    assert_eq!(
+        statuses_by_list(&app).await,
+        vec![("newsletter".to_string(), "confirmed".to_string())]
+    );
+}
+
+#[actix_rt::test]
+async fn expired_preference_links_are_rejected_with_a_410() {
+    // Arrange
+    let app = spawn_app().await;
+    app.create_confirmed_subscriber().await;
+    let subscriber_id = sqlx::query!("SELECT id FROM subscriptions")
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap()
+        .id;
+    let mut expired_link = reqwest::Url::parse(&preferences_link(
+        &app.base_url,
+        &app.hmac_secret,
+        subscriber_id,
+        Utc::now() - chrono::Duration::minutes(1),
+    ))
+    .unwrap();
+    expired_link.set_port(Some(app.port)).unwrap();
+
+    // Act
+    let get_response = reqwest::get(expired_link.clone()).await.unwrap();
+    let post_response = post_preferences(&expired_link, "unsubscribe_all=true").await;
+
+    // Assert
+    assert_eq!(get_response.status().as_u16(), 410);
+    assert_eq!(post_response.status().as_u16(), 410);
+    assert_eq!(
+        statuses_by_list(&app).await,
+        vec![("newsletter".to_string(), "confirmed".to_string())]
+    );
+}
+
+#[actix_rt::test]
+async fn issues_for_digest_subscribers_wait_for_the_next_digest() {
+    // Arrange
+    let app = spawn_app().await;
+    let preferences_link = receive_preferences_link(&app).await;
+    post_preferences(
+        &preferences_link,
+        "name=le%20guin&list=newsletter&digest_frequency=daily",
+    )
+    .await
+    .error_for_status()
+    .unwrap();
+    Mock::given(any())
+        .respond_with(ResponseTemplate::new(200))
+        .expect(0)
+        .mount(&app.email_server)
+        .await;
+
+    // Act
+    let response = app.post_newsletters(newsletter_request_body()).await;
+    app.dispatch_all_pending_emails().await;
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 202);
+    let queued = sqlx::query!("SELECT execute_after FROM issue_delivery_queue")
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap();
+    assert!(queued.execute_after > Utc::now());
+    assert!(queued.execute_after <= Utc::now() + chrono::Duration::days(1));
+    // relies on Mock::expect
+}
+
+#[actix_rt::test]
+async fn held_issues_are_sent_together_in_one_digest() {
+    // Arrange
+    let app = spawn_app().await;
+    let preferences_link = receive_preferences_link(&app).await;
+    post_preferences(
+        &preferences_link,
+        "name=le%20guin&list=newsletter&digest_frequency=weekly",
+    )
+    .await
+    .error_for_status()
+    .unwrap();
+    for title in &["First issue", "Second issue"] {
+        app.post_newsletters(serde_json::json!({
+            "title": title,
+            "content": {
+                "text": format!("{} as plain text", title),
+                "html": format!("<p>{} as HTML</p>", title),
+            }
+        }))
+        .await
+        .error_for_status()
+        .unwrap();
+    }
+    app.dispatch_all_pending_emails().await;
+    // As if the week was over
+    sqlx::query!("UPDATE issue_delivery_queue SET execute_after = now()")
+        .execute(&app.db_pool)
+        .await
+        .unwrap();
+
+    Mock::given(path("/email/batch"))
+        .and(method("POST"))
+        .respond_with(PostmarkBatchResponder::accept_all())
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+
+    // Act
+    app.dispatch_all_pending_emails().await;
+
+    // Assert
+    let email_request = &app.email_server.received_requests().await.unwrap()[0];
+    let body: serde_json::Value = serde_json::from_slice(&email_request.body).unwrap();
+    let emails = body.as_array().unwrap();
+    assert_eq!(emails.len(), 1);
+    assert_eq!(emails[0]["Subject"], "Your Newsletter digest");
+    let text_body = emails[0]["TextBody"].as_str().unwrap();
+    let first = text_body.find("First issue as plain text").unwrap();
+    let second = text_body.find("Second issue as plain text").unwrap();
+    assert!(first < second);
+    assert!(text_body.contains("Unsubscribe: "));
+    let queued = sqlx::query!("SELECT subscriber_email FROM issue_delivery_queue")
+        .fetch_all(&app.db_pool)
+        .await
+        .unwrap();
+    assert!(queued.is_empty());
+    let delivered = sqlx::query!("SELECT newsletter_issue_id FROM issue_deliveries")
+        .fetch_all(&app.db_pool)
+        .await
+        .unwrap();
+    // The issue sent before they asked for a digest, and the two in the digest
+    assert_eq!(delivered.len(), 3);
+    // relies on Mock::expect
+}
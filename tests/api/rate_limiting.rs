@@ -0,0 +1,87 @@
+use crate::helpers::spawn_app;
+
+// Rejected before anything is stored or sent
+const INVALID_SIGNUP: &str = "name=le%20guin&email=";
+
+#[actix_rt::test]
+async fn subscribe_returns_a_429_once_a_client_uses_up_its_burst() {
+    // Arrange
+    let app = spawn_app().await;
+    let burst = app.rate_limits.subscriptions.burst;
+    for _ in 0..burst {
+        let response = app
+            .post_subscriptions_from("203.0.113.1", INVALID_SIGNUP.into())
+            .await;
+        assert_eq!(400, response.status().as_u16());
+    }
+
+    // Act
+    let response = app
+        .post_subscriptions_from("203.0.113.1", INVALID_SIGNUP.into())
+        .await;
+
+    // Assert
+    assert_eq!(429, response.status().as_u16());
+    let retry_after: u64 = response.headers()["Retry-After"]
+        .to_str()
+        .unwrap()
+        .parse()
+        .unwrap();
+    assert!(retry_after > 0);
+}
+
+#[actix_rt::test]
+async fn clients_behind_the_proxy_are_limited_separately() {
+    // Arrange
+    let app = spawn_app().await;
+    for _ in 0..app.rate_limits.subscriptions.burst {
+        app.post_subscriptions_from("203.0.113.1", INVALID_SIGNUP.into())
+            .await;
+    }
+
+    // Act
+    let limited = app
+        .post_subscriptions_from("203.0.113.1", INVALID_SIGNUP.into())
+        .await;
+    let other_client = app
+        .post_subscriptions_from("203.0.113.2", INVALID_SIGNUP.into())
+        .await;
+
+    // Assert
+    assert_eq!(429, limited.status().as_u16());
+    assert_eq!(400, other_client.status().as_u16());
+}
+
+#[actix_rt::test]
+async fn limits_apply_only_to_the_routes_they_are_configured_for() {
+    // Arrange
+    let app = spawn_app().await;
+    for _ in 0..=app.rate_limits.subscriptions.burst {
+        app.post_subscriptions(INVALID_SIGNUP.into()).await;
+    }
+
+    // Act
+    let subscribe = app.post_subscriptions(INVALID_SIGNUP.into()).await;
+    let resend = app.post_resend_confirmation("unknown-token").await;
+    let health_check = app.get_health_check().await;
+
+    // Assert
+    assert_eq!(429, subscribe.status().as_u16());
+    assert_ne!(429, resend.status().as_u16());
+    assert_eq!(200, health_check.status().as_u16());
+}
+
+#[actix_rt::test]
+async fn resend_confirmation_is_rate_limited() {
+    // Arrange
+    let app = spawn_app().await;
+    for _ in 0..app.rate_limits.resend_confirmation.burst {
+        app.post_resend_confirmation("unknown-token").await;
+    }
+
+    // Act
+    let response = app.post_resend_confirmation("unknown-token").await;
+
+    // Assert
+    assert_eq!(429, response.status().as_u16());
+}
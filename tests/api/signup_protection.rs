@@ -0,0 +1,220 @@
+use crate::helpers::{spawn_app, spawn_app_with_config, TestApp};
+use chrono::{Duration, Utc};
+use sha2::{Digest, Sha256};
+use wiremock::matchers::{any, method, path};
+use wiremock::{Mock, ResponseTemplate};
+use zero2prod::signup_protection::issue_form_token;
+
+const SIGNUP: &str = "name=le%20guin&email=ursula_le_guin%40gmail.com";
+
+async fn get_form_token(app: &TestApp) -> String {
+    let html = reqwest::get(format!("{}/subscriptions", &app.address))
+        .await
+        .unwrap()
+        .text()
+        .await
+        .unwrap();
+    let start = html.find(r#"name="form_token" value=""#).unwrap() + 25;
+    let end = start + html[start..].find('"').unwrap();
+    html[start..end].to_owned()
+}
+
+/// Brute-force a hashcash stamp, like the script on the signup form
+fn mint_proof_of_work(email: &str, bits: u32) -> String {
+    (0u64..)
+        .map(|counter| {
+            format!(
+                "1:{}:{}:{}::dGVzdA:{}",
+                bits,
+                Utc::now().timestamp(),
+                email,
+                counter
+            )
+        })
+        .find(|stamp| {
+            let hash = Sha256::digest(stamp.as_bytes());
+            let zero_bytes = hash.iter().take_while(|byte| **byte == 0).count() as u32;
+            let zero_bits = zero_bytes * 8
+                + hash
+                    .get(zero_bytes as usize)
+                    .map_or(0, |b| b.leading_zeros());
+            zero_bits >= bits
+        })
+        .unwrap()
+}
+
+async fn mount_confirmation_email(app: &TestApp, n_expected: u64) {
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(n_expected)
+        .mount(&app.email_server)
+        .await;
+}
+
+#[actix_rt::test]
+async fn the_signup_form_includes_a_signed_form_token_and_a_honeypot() {
+    // Arrange
+    let app = spawn_app().await;
+
+    // Act
+    let response = reqwest::get(format!("{}/subscriptions", &app.address))
+        .await
+        .unwrap();
+
+    // Assert
+    assert_eq!(200, response.status().as_u16());
+    let html = response.text().await.unwrap();
+    assert!(html.contains(r#"action="/subscriptions" method="post""#));
+    assert!(html.contains(r#"name="website""#));
+    assert!(html.contains(r#"name="form_token""#));
+}
+
+#[actix_rt::test]
+async fn filling_in_the_honeypot_succeeds_without_side_effects() {
+    // Arrange
+    let app = spawn_app().await;
+    Mock::given(any())
+        .respond_with(ResponseTemplate::new(200))
+        .expect(0)
+        .mount(&app.email_server)
+        .await;
+
+    // Act
+    let response = app
+        .post_subscriptions(format!("{}&website=http%3A%2F%2Fspam.example.com", SIGNUP))
+        .await;
+    app.dispatch_all_pending_emails().await;
+
+    // Assert
+    assert_eq!(200, response.status().as_u16());
+    let saved = sqlx::query!("SELECT email FROM subscriptions")
+        .fetch_optional(&app.db_pool)
+        .await
+        .unwrap();
+    assert!(saved.is_none());
+}
+
+#[actix_rt::test]
+async fn the_honeypot_is_ignored_when_turned_off() {
+    // Arrange
+    let app = spawn_app_with_config(|c| c.application.signup_protection.honeypot = false).await;
+    mount_confirmation_email(&app, 1).await;
+
+    // Act
+    let response = app
+        .post_subscriptions(format!("{}&website=http%3A%2F%2Fexample.com", SIGNUP))
+        .await;
+    app.dispatch_all_pending_emails().await;
+
+    // Assert
+    assert_eq!(200, response.status().as_u16());
+}
+
+#[actix_rt::test]
+async fn forms_submitted_too_fast_or_without_a_valid_token_are_rejected() {
+    // Arrange
+    let app =
+        spawn_app_with_config(|c| c.application.signup_protection.min_fill_seconds = 60).await;
+    let fresh_token = get_form_token(&app).await;
+    let test_cases = vec![
+        (SIGNUP.to_owned(), "no form token"),
+        (
+            format!("{}&form_token=1622000000.forged", SIGNUP),
+            "a forged form token",
+        ),
+        (
+            format!("{}&form_token={}", SIGNUP, fresh_token),
+            "a form submitted too fast",
+        ),
+    ];
+
+    for (body, description) in test_cases {
+        // Act
+        let response = app.post_subscriptions(body).await;
+
+        // Assert
+        assert_eq!(
+            400,
+            response.status().as_u16(),
+            "The API did not reject {}.",
+            description
+        );
+    }
+    let saved = sqlx::query!("SELECT email FROM subscriptions")
+        .fetch_optional(&app.db_pool)
+        .await
+        .unwrap();
+    assert!(saved.is_none());
+}
+
+#[actix_rt::test]
+async fn forms_filled_in_at_a_human_pace_are_accepted() {
+    // Arrange
+    let app =
+        spawn_app_with_config(|c| c.application.signup_protection.min_fill_seconds = 60).await;
+    let form_token = issue_form_token(&app.hmac_secret, Utc::now() - Duration::minutes(2));
+    mount_confirmation_email(&app, 1).await;
+
+    // Act
+    let response = app
+        .post_subscriptions(format!("{}&form_token={}", SIGNUP, form_token))
+        .await;
+    app.dispatch_all_pending_emails().await;
+
+    // Assert
+    assert_eq!(200, response.status().as_u16());
+}
+
+#[actix_rt::test]
+async fn signups_without_a_valid_proof_of_work_are_rejected() {
+    // Arrange
+    let app =
+        spawn_app_with_config(|c| c.application.signup_protection.proof_of_work_bits = 8).await;
+    let for_another_email = mint_proof_of_work("someone_else@gmail.com", 8);
+    let test_cases = vec![
+        (SIGNUP.to_owned(), "no proof of work"),
+        (
+            format!("{}&proof_of_work={}", SIGNUP, for_another_email),
+            "a proof of work for another email",
+        ),
+    ];
+
+    for (body, description) in test_cases {
+        // Act
+        let response = app.post_subscriptions(body).await;
+
+        // Assert
+        assert_eq!(
+            400,
+            response.status().as_u16(),
+            "The API did not reject {}.",
+            description
+        );
+    }
+}
+
+#[actix_rt::test]
+async fn signups_with_a_valid_proof_of_work_are_accepted() {
+    // Arrange
+    let app =
+        spawn_app_with_config(|c| c.application.signup_protection.proof_of_work_bits = 8).await;
+    let stamp = mint_proof_of_work("ursula_le_guin@gmail.com", 8);
+    mount_confirmation_email(&app, 1).await;
+
+    // Act
+    let response = reqwest::Client::new()
+        .post(format!("{}/subscriptions", &app.address))
+        .form(&[
+            ("name", "le guin"),
+            ("email", "ursula_le_guin@gmail.com"),
+            ("proof_of_work", stamp.as_str()),
+        ])
+        .send()
+        .await
+        .unwrap();
+    app.dispatch_all_pending_emails().await;
+
+    // Assert
+    assert_eq!(200, response.status().as_u16());
+}
@@ -0,0 +1,606 @@
+//! The behaviour every `SubscriberRepository` must have, checked against each implementation
+use crate::helpers::{spawn_app, spawn_app_with_in_memory_subscribers};
+use chrono::{TimeZone, Utc};
+use claim::{assert_none, assert_some};
+use uuid::Uuid;
+use zero2prod::confirmation_email_throttle::ConfirmationEmailHistory;
+use zero2prod::domain::{
+    DigestFrequency, ListSlug, NewSubscriber, SubscriberEmail, SubscriberName, SubscriptionStatus,
+};
+use zero2prod::subscriber_repository::{
+    ConsentEvent, ConsentEventType, InMemorySubscriberRepository, PostgresSubscriberRepository,
+    SubscriberRepository, SubscriberTransaction,
+};
+
+fn new_subscriber(email: &str) -> NewSubscriber {
+    NewSubscriber {
+        email: SubscriberEmail::parse(email.into()).unwrap(),
+        name: SubscriberName::parse("le guin".into()).unwrap(),
+    }
+}
+
+async fn default_list_id(transaction: &mut dyn SubscriberTransaction) -> Uuid {
+    transaction
+        .get_list_by_slug(&ListSlug::default_list())
+        .await
+        .unwrap()
+        .expect("Every deployment has the default list")
+        .id
+}
+
+/// Run each contract test against a fresh Postgres database and a fresh in-memory repository
+macro_rules! contract_tests {
+    ($($name:ident),* $(,)?) => {
+        mod postgres {
+            use super::*;
+            $(
+                #[actix_rt::test]
+                async fn $name() {
+                    let app = spawn_app().await;
+                    super::$name(&PostgresSubscriberRepository::new(app.db_pool.clone())).await;
+                }
+            )*
+        }
+
+        mod in_memory {
+            use super::*;
+            $(
+                #[actix_rt::test]
+                async fn $name() {
+                    super::$name(&InMemorySubscriberRepository::default()).await;
+                }
+            )*
+        }
+    };
+}
+
+contract_tests!(
+    inserted_subscribers_can_be_looked_up,
+    inserting_a_taken_email_leaves_the_existing_subscriber_alone,
+    subscribers_and_confirmation_email_history_are_matched_ignoring_case,
+    changes_are_discarded_unless_committed,
+    lists_can_be_created_and_looked_up,
+    subscriptions_to_a_list_start_pending,
+    status_changes_are_saved_per_list,
+    resubscribing_makes_a_subscriber_pending_without_renaming_them,
+    preferences_are_saved,
+    stored_tokens_can_be_looked_up_and_consumed,
+    unknown_tokens_are_not_found,
+    confirmation_email_history_is_saved_per_recipient,
+    consent_events_are_listed_per_subscriber_oldest_first,
+);
+
+async fn inserted_subscribers_can_be_looked_up(repository: &dyn SubscriberRepository) {
+    let subscriber = new_subscriber("ursula_le_guin@gmail.com");
+
+    let mut transaction = repository.begin().await.unwrap();
+    let subscriber_id = transaction
+        .insert_subscriber(&subscriber)
+        .await
+        .unwrap()
+        .unwrap();
+    transaction.commit().await.unwrap();
+
+    let mut transaction = repository.begin().await.unwrap();
+    let by_id = transaction
+        .get_subscriber(subscriber_id)
+        .await
+        .unwrap()
+        .unwrap();
+    let by_email = transaction
+        .get_subscriber_by_email(&subscriber.email)
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(by_id, by_email);
+    assert_eq!(by_id.id, subscriber_id);
+    assert_eq!(by_id.email, "ursula_le_guin@gmail.com");
+    assert_eq!(by_id.name, "le guin");
+}
+
+async fn inserting_a_taken_email_leaves_the_existing_subscriber_alone(
+    repository: &dyn SubscriberRepository,
+) {
+    let subscriber = new_subscriber("ursula_le_guin@gmail.com");
+    let mut transaction = repository.begin().await.unwrap();
+    let subscriber_id = transaction
+        .insert_subscriber(&subscriber)
+        .await
+        .unwrap()
+        .unwrap();
+    let list_id = default_list_id(transaction.as_mut()).await;
+    transaction
+        .add_list_subscription(subscriber_id, list_id)
+        .await
+        .unwrap();
+    transaction
+        .update_list_status(subscriber_id, list_id, SubscriptionStatus::Confirmed)
+        .await
+        .unwrap();
+    transaction.commit().await.unwrap();
+
+    let mut transaction = repository.begin().await.unwrap();
+    let outcome = transaction.insert_subscriber(&subscriber).await.unwrap();
+
+    assert_none!(outcome);
+    let existing = transaction
+        .get_subscriber_by_email(&subscriber.email)
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(existing.id, subscriber_id);
+    let membership = transaction
+        .get_list_subscription(subscriber_id, list_id)
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(membership.status, SubscriptionStatus::Confirmed);
+}
+
+async fn subscribers_and_confirmation_email_history_are_matched_ignoring_case(
+    repository: &dyn SubscriberRepository,
+) {
+    let subscriber = new_subscriber("ursula_le_guin@gmail.com");
+    let shouted = new_subscriber("URSULA_LE_GUIN@Gmail.com");
+    let history = ConfirmationEmailHistory {
+        window_started_at: Utc.timestamp(1_622_000_000, 0),
+        sent_in_window: 1,
+        last_sent_at: Utc.timestamp(1_622_000_000, 0),
+    };
+    let mut transaction = repository.begin().await.unwrap();
+    let subscriber_id = transaction
+        .insert_subscriber(&subscriber)
+        .await
+        .unwrap()
+        .unwrap();
+    transaction
+        .save_confirmation_email_history(&subscriber.email, &history)
+        .await
+        .unwrap();
+    transaction.commit().await.unwrap();
+
+    let mut transaction = repository.begin().await.unwrap();
+    assert_none!(transaction.insert_subscriber(&shouted).await.unwrap());
+    let existing = transaction
+        .get_subscriber_by_email(&shouted.email)
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(existing.id, subscriber_id);
+    assert_eq!(
+        transaction
+            .get_confirmation_email_history(&shouted.email)
+            .await
+            .unwrap(),
+        Some(history)
+    );
+}
+
+async fn changes_are_discarded_unless_committed(repository: &dyn SubscriberRepository) {
+    let subscriber = new_subscriber("ursula_le_guin@gmail.com");
+
+    let mut transaction = repository.begin().await.unwrap();
+    let subscriber_id = transaction
+        .insert_subscriber(&subscriber)
+        .await
+        .unwrap()
+        .unwrap();
+    let list_id = default_list_id(transaction.as_mut()).await;
+    transaction
+        .add_list_subscription(subscriber_id, list_id)
+        .await
+        .unwrap();
+    transaction
+        .store_token(subscriber_id, list_id, "a-subscription-token", None)
+        .await
+        .unwrap();
+    drop(transaction);
+
+    let mut transaction = repository.begin().await.unwrap();
+    assert_none!(transaction.get_subscriber(subscriber_id).await.unwrap());
+    assert_none!(transaction
+        .get_subscriber_by_email(&subscriber.email)
+        .await
+        .unwrap());
+    assert_none!(transaction.get_token("a-subscription-token").await.unwrap());
+    let list_id = default_list_id(transaction.as_mut()).await;
+    assert_none!(transaction
+        .get_list_subscription(subscriber_id, list_id)
+        .await
+        .unwrap());
+}
+
+async fn lists_can_be_created_and_looked_up(repository: &dyn SubscriberRepository) {
+    let slug = ListSlug::parse("book-club".into()).unwrap();
+
+    let mut transaction = repository.begin().await.unwrap();
+    let list_id = transaction
+        .create_list(&slug, "Book club")
+        .await
+        .unwrap()
+        .unwrap();
+    assert_none!(transaction
+        .create_list(&slug, "Another name")
+        .await
+        .unwrap());
+    transaction.commit().await.unwrap();
+
+    let mut transaction = repository.begin().await.unwrap();
+    let by_id = transaction.get_list(list_id).await.unwrap().unwrap();
+    let by_slug = transaction.get_list_by_slug(&slug).await.unwrap().unwrap();
+    assert_eq!(by_id, by_slug);
+    assert_eq!(by_id.slug, "book-club");
+    assert_eq!(by_id.name, "Book club");
+    let names: Vec<_> = transaction
+        .get_lists()
+        .await
+        .unwrap()
+        .into_iter()
+        .map(|list| list.name)
+        .collect();
+    assert_eq!(names, vec!["Book club", "Newsletter"]);
+}
+
+async fn subscriptions_to_a_list_start_pending(repository: &dyn SubscriberRepository) {
+    let mut transaction = repository.begin().await.unwrap();
+    let subscriber_id = transaction
+        .insert_subscriber(&new_subscriber("ursula_le_guin@gmail.com"))
+        .await
+        .unwrap()
+        .unwrap();
+    let list_id = default_list_id(transaction.as_mut()).await;
+    assert_none!(transaction
+        .get_list_subscription(subscriber_id, list_id)
+        .await
+        .unwrap());
+
+    transaction
+        .add_list_subscription(subscriber_id, list_id)
+        .await
+        .unwrap();
+
+    let membership = transaction
+        .get_list_subscription(subscriber_id, list_id)
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(membership.subscriber_id, subscriber_id);
+    assert_eq!(membership.list_id, list_id);
+    assert_eq!(membership.status, SubscriptionStatus::PendingConfirmation);
+}
+
+async fn status_changes_are_saved_per_list(repository: &dyn SubscriberRepository) {
+    let mut transaction = repository.begin().await.unwrap();
+    let subscriber_id = transaction
+        .insert_subscriber(&new_subscriber("ursula_le_guin@gmail.com"))
+        .await
+        .unwrap()
+        .unwrap();
+    let list_id = default_list_id(transaction.as_mut()).await;
+    let another_list_id = transaction
+        .create_list(&ListSlug::parse("book-club".into()).unwrap(), "Book club")
+        .await
+        .unwrap()
+        .unwrap();
+    for id in [list_id, another_list_id].iter() {
+        transaction
+            .add_list_subscription(subscriber_id, *id)
+            .await
+            .unwrap();
+    }
+    transaction.commit().await.unwrap();
+
+    let mut transaction = repository.begin().await.unwrap();
+    transaction
+        .update_list_status(subscriber_id, list_id, SubscriptionStatus::Confirmed)
+        .await
+        .unwrap();
+    transaction.commit().await.unwrap();
+
+    let mut transaction = repository.begin().await.unwrap();
+    let mut statuses: Vec<_> = transaction
+        .get_list_subscriptions(subscriber_id)
+        .await
+        .unwrap()
+        .into_iter()
+        .map(|m| (m.list_id == list_id, m.status))
+        .collect();
+    statuses.sort_by_key(|(is_default_list, _)| *is_default_list);
+    assert_eq!(
+        statuses,
+        vec![
+            (false, SubscriptionStatus::PendingConfirmation),
+            (true, SubscriptionStatus::Confirmed)
+        ]
+    );
+}
+
+async fn resubscribing_makes_a_subscriber_pending_without_renaming_them(
+    repository: &dyn SubscriberRepository,
+) {
+    let mut transaction = repository.begin().await.unwrap();
+    let subscriber_id = transaction
+        .insert_subscriber(&new_subscriber("ursula_le_guin@gmail.com"))
+        .await
+        .unwrap()
+        .unwrap();
+    let list_id = default_list_id(transaction.as_mut()).await;
+    transaction
+        .add_list_subscription(subscriber_id, list_id)
+        .await
+        .unwrap();
+    transaction
+        .update_list_status(subscriber_id, list_id, SubscriptionStatus::Unsubscribed)
+        .await
+        .unwrap();
+
+    let new_name = SubscriberName::parse("Ursula".into()).unwrap();
+    transaction
+        .resubscribe(subscriber_id, list_id)
+        .await
+        .unwrap();
+    transaction
+        .store_token(
+            subscriber_id,
+            list_id,
+            "a-subscription-token",
+            Some(&new_name),
+        )
+        .await
+        .unwrap();
+    transaction.commit().await.unwrap();
+
+    let mut transaction = repository.begin().await.unwrap();
+    let subscriber = transaction
+        .get_subscriber(subscriber_id)
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(subscriber.name, "le guin");
+    let token = transaction
+        .get_token("a-subscription-token")
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(token.pending_name.as_deref(), Some("Ursula"));
+    let membership = transaction
+        .get_list_subscription(subscriber_id, list_id)
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(membership.status, SubscriptionStatus::PendingConfirmation);
+}
+
+async fn preferences_are_saved(repository: &dyn SubscriberRepository) {
+    let mut transaction = repository.begin().await.unwrap();
+    let subscriber_id = transaction
+        .insert_subscriber(&new_subscriber("ursula_le_guin@gmail.com"))
+        .await
+        .unwrap()
+        .unwrap();
+    let subscriber = transaction
+        .get_subscriber(subscriber_id)
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(subscriber.digest_frequency, DigestFrequency::Immediately);
+
+    let new_name = SubscriberName::parse("Ursula".into()).unwrap();
+    transaction
+        .update_preferences(subscriber_id, &new_name, DigestFrequency::Weekly)
+        .await
+        .unwrap();
+    transaction.commit().await.unwrap();
+
+    let mut transaction = repository.begin().await.unwrap();
+    let subscriber = transaction
+        .get_subscriber(subscriber_id)
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(subscriber.name, "Ursula");
+    assert_eq!(subscriber.digest_frequency, DigestFrequency::Weekly);
+}
+
+async fn stored_tokens_can_be_looked_up_and_consumed(repository: &dyn SubscriberRepository) {
+    let mut transaction = repository.begin().await.unwrap();
+    let subscriber_id = transaction
+        .insert_subscriber(&new_subscriber("ursula_le_guin@gmail.com"))
+        .await
+        .unwrap()
+        .unwrap();
+    let list_id = default_list_id(transaction.as_mut()).await;
+    transaction
+        .store_token(subscriber_id, list_id, "a-subscription-token", None)
+        .await
+        .unwrap();
+    transaction.commit().await.unwrap();
+
+    let mut transaction = repository.begin().await.unwrap();
+    let token = transaction
+        .get_token("a-subscription-token")
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(token.subscriber_id, subscriber_id);
+    assert_eq!(token.list_id, list_id);
+    assert_none!(token.consumed_at);
+    assert_none!(token.pending_name);
+    transaction
+        .consume_token("a-subscription-token")
+        .await
+        .unwrap();
+    transaction.commit().await.unwrap();
+
+    let mut transaction = repository.begin().await.unwrap();
+    let token = transaction
+        .get_token("a-subscription-token")
+        .await
+        .unwrap()
+        .unwrap();
+    assert_some!(token.consumed_at);
+}
+
+async fn unknown_tokens_are_not_found(repository: &dyn SubscriberRepository) {
+    let mut transaction = repository.begin().await.unwrap();
+    let subscriber_id = transaction
+        .insert_subscriber(&new_subscriber("ursula_le_guin@gmail.com"))
+        .await
+        .unwrap()
+        .unwrap();
+    let list_id = default_list_id(transaction.as_mut()).await;
+    transaction
+        .store_token(subscriber_id, list_id, "a-subscription-token", None)
+        .await
+        .unwrap();
+
+    assert_none!(transaction.get_token("another-token").await.unwrap());
+}
+
+async fn confirmation_email_history_is_saved_per_recipient(repository: &dyn SubscriberRepository) {
+    let recipient = SubscriberEmail::parse("ursula_le_guin@gmail.com".into()).unwrap();
+    let another_recipient = SubscriberEmail::parse("another_ursula@gmail.com".into()).unwrap();
+    // Whole seconds, which Postgres stores exactly
+    let first_sent_at = Utc.timestamp(1_622_000_000, 0);
+    let history = ConfirmationEmailHistory {
+        window_started_at: first_sent_at,
+        sent_in_window: 1,
+        last_sent_at: first_sent_at,
+    };
+    let mut transaction = repository.begin().await.unwrap();
+    transaction
+        .save_confirmation_email_history(&recipient, &history)
+        .await
+        .unwrap();
+    let updated_history = ConfirmationEmailHistory {
+        sent_in_window: 2,
+        last_sent_at: first_sent_at + chrono::Duration::minutes(5),
+        ..history
+    };
+    transaction
+        .save_confirmation_email_history(&recipient, &updated_history)
+        .await
+        .unwrap();
+    transaction.commit().await.unwrap();
+
+    let mut transaction = repository.begin().await.unwrap();
+    assert_eq!(
+        transaction
+            .get_confirmation_email_history(&recipient)
+            .await
+            .unwrap(),
+        Some(updated_history)
+    );
+    assert_none!(transaction
+        .get_confirmation_email_history(&another_recipient)
+        .await
+        .unwrap());
+}
+
+async fn consent_events_are_listed_per_subscriber_oldest_first(
+    repository: &dyn SubscriberRepository,
+) {
+    let mut transaction = repository.begin().await.unwrap();
+    let subscriber_id = transaction
+        .insert_subscriber(&new_subscriber("ursula_le_guin@gmail.com"))
+        .await
+        .unwrap()
+        .unwrap();
+    let another_subscriber_id = transaction
+        .insert_subscriber(&new_subscriber("another_ursula@gmail.com"))
+        .await
+        .unwrap()
+        .unwrap();
+    let list_id = default_list_id(transaction.as_mut()).await;
+    // Whole seconds, which Postgres stores exactly
+    let signed_up_at = Utc.timestamp(1_622_000_000, 0);
+    let signup = ConsentEvent {
+        subscriber_id,
+        list_id,
+        event_type: ConsentEventType::Signup,
+        occurred_at: signed_up_at,
+        ip_address: Some("203.0.113.7".into()),
+        user_agent: Some("Mozilla/5.0".into()),
+        form_source: Some("footer".into()),
+        email_subject: Some("Welcome!".into()),
+        email_html_body: Some("<p>Welcome!</p>".into()),
+        email_text_body: Some("Welcome!".into()),
+    };
+    let confirmation = ConsentEvent {
+        event_type: ConsentEventType::Confirmation,
+        occurred_at: signed_up_at + chrono::Duration::minutes(5),
+        form_source: None,
+        email_subject: None,
+        email_html_body: None,
+        email_text_body: None,
+        ..signup.clone()
+    };
+    // Recorded out of order, to check the sorting
+    transaction
+        .record_consent_event(&confirmation)
+        .await
+        .unwrap();
+    transaction.record_consent_event(&signup).await.unwrap();
+    transaction
+        .record_consent_event(&ConsentEvent {
+            subscriber_id: another_subscriber_id,
+            ..signup.clone()
+        })
+        .await
+        .unwrap();
+    transaction.commit().await.unwrap();
+
+    let mut transaction = repository.begin().await.unwrap();
+    assert_eq!(
+        transaction.get_consent_events(subscriber_id).await.unwrap(),
+        vec![signup, confirmation]
+    );
+}
+
+#[actix_rt::test]
+async fn the_app_can_subscribe_and_confirm_with_subscribers_kept_in_memory() {
+    // Arrange
+    let app = spawn_app_with_in_memory_subscribers().await;
+    let repository = app.in_memory_subscribers.as_ref().unwrap();
+    let body = "name=le%20guin&email=ursula_le_guin%40gmail.com";
+
+    // Act - Part 1 - Subscribe
+    let response = app.post_subscriptions(body.into()).await;
+    assert_eq!(response.status().as_u16(), 200);
+
+    // Act - Part 2 - Click the link in the queued email
+    let outbox = repository.outbox().await;
+    assert_eq!(outbox.len(), 1);
+    let confirmation_links = app.get_confirmation_links_from_email(&outbox[0]);
+    let response = reqwest::get(confirmation_links.html).await.unwrap();
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 200);
+    let subscribers = repository.subscribers().await;
+    assert_eq!(subscribers.len(), 1);
+    assert_eq!(subscribers[0].email, "ursula_le_guin@gmail.com");
+    let list_subscriptions = repository.list_subscriptions().await;
+    assert_eq!(list_subscriptions.len(), 1);
+    assert_eq!(list_subscriptions[0].status, SubscriptionStatus::Confirmed);
+    let in_postgres = sqlx::query!("SELECT id FROM subscriptions")
+        .fetch_optional(&app.db_pool)
+        .await
+        .unwrap();
+    assert_none!(in_postgres);
+}
+
+#[actix_rt::test]
+async fn invalid_signups_are_rejected_with_subscribers_kept_in_memory() {
+    // Arrange
+    let app = spawn_app_with_in_memory_subscribers().await;
+    let body = "name=Ursula&email=definitely-not-an-email";
+
+    // Act
+    let response = app.post_subscriptions(body.into()).await;
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 400);
+    let repository = app.in_memory_subscribers.as_ref().unwrap();
+    assert!(repository.subscribers().await.is_empty());
+    assert!(repository.outbox().await.is_empty());
+}
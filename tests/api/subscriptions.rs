@@ -1,5 +1,5 @@
 use crate::helpers::spawn_app;
-use wiremock::matchers::{method, path};
+use wiremock::matchers::{any, method, path};
 use wiremock::{Mock, ResponseTemplate};
 
 #[actix_rt::test]
@@ -25,10 +25,16 @@ async fn subscribe_persists_the_new_subscriber() {
     app.post_subscriptions(body.into()).await;
 
     // Assert
-    let saved = sqlx::query!("SELECT email, name, status FROM subscriptions")
-        .fetch_one(&app.db_pool)
-        .await
-        .expect("Failed to fetch saved subscription");
+    let saved = sqlx::query!(
+        r#"
+            SELECT s.email AS "email!", s.name AS "name!", m.status AS "status!"
+            FROM subscriptions s
+            JOIN list_subscriptions m ON m.subscriber_id = s.id
+        "#
+    )
+    .fetch_one(&app.db_pool)
+    .await
+    .expect("Failed to fetch saved subscription");
 
     assert_eq!(saved.email, "ursula_le_guin@gmail.com");
     assert_eq!(saved.name, "le guin");
@@ -83,6 +89,49 @@ async fn subscribe_returns_a_200_when_fields_are_present_but_empty() {
     }
 }
 
+#[actix_rt::test]
+async fn subscribe_explains_why_invalid_fields_were_rejected() {
+    // Arrange
+    let app = spawn_app().await;
+    let test_cases = vec![
+        (
+            "name=&email=ursula_le_guin%40gmail.com",
+            " is not a valid subscriber name",
+        ),
+        (
+            "name=Ursula&email=definitely-not-an-email",
+            "definitely-not-an-email is not a valid subscriber email",
+        ),
+    ];
+
+    for (body, expected_message) in test_cases {
+        // Act
+        let response = app.post_subscriptions(body.into()).await;
+
+        // Assert
+        assert_eq!(400, response.status().as_u16());
+        assert_eq!(expected_message, response.text().await.unwrap());
+    }
+}
+
+#[actix_rt::test]
+async fn subscribe_fails_if_there_is_a_fatal_database_error() {
+    // Arrange
+    let app = spawn_app().await;
+    let body = "name=le%20guin&email=ursula_le_guin%40gmail.com";
+    // Sabotage the database
+    sqlx::query!("ALTER TABLE subscription_tokens DROP COLUMN subscription_token_hash;",)
+        .execute(&app.db_pool)
+        .await
+        .unwrap();
+
+    // Act
+    let response = app.post_subscriptions(body.into()).await;
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 500);
+}
+
 #[actix_rt::test]
 async fn subscribe_sends_confirmation_email_for_valid_data() {
     // Arrange
@@ -98,6 +147,7 @@ async fn subscribe_sends_confirmation_email_for_valid_data() {
 
     // Act
     app.post_subscriptions(body.into()).await;
+    app.dispatch_all_pending_emails().await;
 
     // Assert
     // relies on Mock::expect
@@ -117,10 +167,344 @@ async fn subscribe_sends_a_confirmation_email_with_a_link() {
 
     // Act
     app.post_subscriptions(body.into()).await;
+    app.dispatch_all_pending_emails().await;
 
     // Assert
     let email_request = &app.email_server.received_requests().await.unwrap()[0];
-    let confirmation_links = app.get_confirmation_links(&email_request);
+    let confirmation_links = app.get_confirmation_links(email_request);
 
     assert_eq!(confirmation_links.html, confirmation_links.plain_text);
 }
+
+#[actix_rt::test]
+async fn subscribe_succeeds_and_keeps_the_confirmation_email_if_sending_fails() {
+    // Arrange
+    let app = spawn_app().await;
+    let body = "name=le%20guin&email=ursula_le_guin%40gmail.com";
+
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(500))
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+
+    // Act
+    let response = app.post_subscriptions(body.into()).await;
+    app.dispatch_all_pending_emails().await;
+
+    // Assert
+    assert_eq!(200, response.status().as_u16());
+    let queued = sqlx::query!("SELECT recipient, n_retries FROM email_outbox")
+        .fetch_one(&app.db_pool)
+        .await
+        .expect("Failed to fetch the queued confirmation email");
+    assert_eq!(queued.recipient, "ursula_le_guin@gmail.com");
+    assert_eq!(queued.n_retries, 1);
+}
+
+#[actix_rt::test]
+async fn subscribe_does_not_persist_the_subscriber_if_the_email_cannot_be_queued() {
+    // Arrange
+    let app = spawn_app().await;
+    let body = "name=le%20guin&email=ursula_le_guin%40gmail.com";
+    // Sabotage the outbox
+    sqlx::query!("ALTER TABLE email_outbox DROP COLUMN subject;",)
+        .execute(&app.db_pool)
+        .await
+        .unwrap();
+
+    // Act
+    let response = app.post_subscriptions(body.into()).await;
+
+    // Assert
+    assert_eq!(500, response.status().as_u16());
+    let saved = sqlx::query!("SELECT email FROM subscriptions")
+        .fetch_optional(&app.db_pool)
+        .await
+        .unwrap();
+    assert!(saved.is_none());
+}
+
+#[actix_rt::test]
+async fn subscribing_again_while_pending_resends_the_confirmation_email() {
+    // Arrange
+    let app = spawn_app().await;
+    let first_links = app.create_unconfirmed_subscriber().await;
+    app.end_confirmation_email_cooldown().await;
+    let body = "name=le%20guin&email=ursula_le_guin%40gmail.com";
+
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+
+    // Act
+    let response = app.post_subscriptions(body.into()).await;
+    app.dispatch_all_pending_emails().await;
+
+    // Assert
+    assert_eq!(200, response.status().as_u16());
+    let email_request = &app.email_server.received_requests().await.unwrap()[0];
+    let second_links = app.get_confirmation_links(email_request);
+    assert_ne!(first_links.html, second_links.html);
+
+    let response = reqwest::get(second_links.html).await.unwrap();
+    assert_eq!(200, response.status().as_u16());
+    let saved = sqlx::query!("SELECT status FROM list_subscriptions")
+        .fetch_one(&app.db_pool)
+        .await
+        .expect("Failed to fetch saved subscription");
+    assert_eq!(saved.status, "confirmed");
+}
+
+#[actix_rt::test]
+async fn subscribing_again_when_confirmed_returns_a_200_without_sending_an_email() {
+    // Arrange
+    let app = spawn_app().await;
+    app.create_confirmed_subscriber().await;
+    let body = "name=le%20guin&email=ursula_le_guin%40gmail.com";
+
+    Mock::given(any())
+        .respond_with(ResponseTemplate::new(200))
+        .expect(0)
+        .mount(&app.email_server)
+        .await;
+
+    // Act
+    let response = app.post_subscriptions(body.into()).await;
+    app.dispatch_all_pending_emails().await;
+
+    // Assert
+    assert_eq!(200, response.status().as_u16());
+    let saved = sqlx::query!("SELECT status FROM list_subscriptions")
+        .fetch_one(&app.db_pool)
+        .await
+        .expect("Failed to fetch saved subscription");
+    assert_eq!(saved.status, "confirmed");
+}
+
+#[actix_rt::test]
+async fn subscribing_again_after_unsubscribing_starts_a_new_double_opt_in() {
+    // Arrange
+    let app = spawn_app().await;
+    app.create_confirmed_subscriber().await;
+    sqlx::query!("UPDATE list_subscriptions SET status = 'unsubscribed'")
+        .execute(&app.db_pool)
+        .await
+        .unwrap();
+    app.end_confirmation_email_cooldown().await;
+    let body = "name=Ursula&email=ursula_le_guin%40gmail.com";
+
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+
+    // Act
+    let response = app.post_subscriptions(body.into()).await;
+    app.dispatch_all_pending_emails().await;
+
+    // Assert
+    assert_eq!(200, response.status().as_u16());
+    let saved = sqlx::query!(
+        r#"
+            SELECT s.name AS "name!", m.status AS "status!"
+            FROM subscriptions s
+            JOIN list_subscriptions m ON m.subscriber_id = s.id
+        "#
+    )
+    .fetch_one(&app.db_pool)
+    .await
+    .expect("Failed to fetch saved subscription");
+    // Anyone could have typed in the address, so the new name waits for confirmation
+    assert_eq!(saved.name, "le guin");
+    assert_eq!(saved.status, "pending_confirmation");
+
+    let email_request = &app.email_server.received_requests().await.unwrap()[0];
+    let confirmation_links = app.get_confirmation_links(email_request);
+    reqwest::get(confirmation_links.html)
+        .await
+        .unwrap()
+        .error_for_status()
+        .unwrap();
+    let saved = sqlx::query!(
+        r#"
+            SELECT s.name AS "name!", m.status AS "status!"
+            FROM subscriptions s
+            JOIN list_subscriptions m ON m.subscriber_id = s.id
+        "#
+    )
+    .fetch_one(&app.db_pool)
+    .await
+    .expect("Failed to fetch saved subscription");
+    assert_eq!(saved.name, "Ursula");
+    assert_eq!(saved.status, "confirmed");
+}
+
+#[actix_rt::test]
+async fn subscribing_again_after_a_bounce_does_not_send_an_email() {
+    // Arrange
+    let app = spawn_app().await;
+    app.create_confirmed_subscriber().await;
+    sqlx::query!("UPDATE list_subscriptions SET status = 'bounced'")
+        .execute(&app.db_pool)
+        .await
+        .unwrap();
+    let body = "name=le%20guin&email=ursula_le_guin%40gmail.com";
+
+    Mock::given(any())
+        .respond_with(ResponseTemplate::new(200))
+        .expect(0)
+        .mount(&app.email_server)
+        .await;
+
+    // Act
+    let response = app.post_subscriptions(body.into()).await;
+    app.dispatch_all_pending_emails().await;
+
+    // Assert
+    assert_eq!(200, response.status().as_u16());
+    let saved = sqlx::query!("SELECT status FROM list_subscriptions")
+        .fetch_one(&app.db_pool)
+        .await
+        .expect("Failed to fetch saved subscription");
+    assert_eq!(saved.status, "bounced");
+}
+
+#[actix_rt::test]
+async fn the_database_rejects_unknown_subscription_statuses() {
+    // Arrange
+    let app = spawn_app().await;
+    app.create_unconfirmed_subscriber().await;
+
+    // Act
+    let outcome = sqlx::query!("UPDATE list_subscriptions SET status = 'not_a_status'")
+        .execute(&app.db_pool)
+        .await;
+
+    // Assert
+    assert!(outcome.is_err());
+}
+
+#[actix_rt::test]
+async fn subscribing_again_within_the_cooldown_returns_a_200_without_sending_an_email() {
+    // Arrange
+    let app = spawn_app().await;
+    app.create_unconfirmed_subscriber().await;
+    let body = "name=le%20guin&email=ursula_le_guin%40gmail.com";
+
+    Mock::given(any())
+        .respond_with(ResponseTemplate::new(200))
+        .expect(0)
+        .mount(&app.email_server)
+        .await;
+
+    // Act
+    let response = app.post_subscriptions(body.into()).await;
+    app.dispatch_all_pending_emails().await;
+
+    // Assert
+    assert_eq!(200, response.status().as_u16());
+    let tokens = sqlx::query!("SELECT subscriber_id FROM subscription_tokens")
+        .fetch_all(&app.db_pool)
+        .await
+        .unwrap();
+    assert_eq!(tokens.len(), 1);
+}
+
+#[actix_rt::test]
+async fn no_more_confirmation_emails_are_sent_to_an_address_once_its_daily_cap_is_reached() {
+    // Arrange
+    let app = spawn_app().await;
+    app.create_unconfirmed_subscriber().await;
+    // Far beyond any daily cap we would configure
+    sqlx::query!("UPDATE confirmation_email_throttle SET sent_in_window = 1000")
+        .execute(&app.db_pool)
+        .await
+        .unwrap();
+    app.end_confirmation_email_cooldown().await;
+    let body = "name=le%20guin&email=ursula_le_guin%40gmail.com";
+
+    Mock::given(any())
+        .respond_with(ResponseTemplate::new(200))
+        .expect(0)
+        .mount(&app.email_server)
+        .await;
+
+    // Act
+    let response = app.post_subscriptions(body.into()).await;
+    app.dispatch_all_pending_emails().await;
+
+    // Assert
+    assert_eq!(200, response.status().as_u16());
+}
+
+#[actix_rt::test]
+async fn the_daily_cap_applies_to_an_address_whatever_its_case() {
+    // Arrange
+    let app = spawn_app().await;
+    app.create_unconfirmed_subscriber().await;
+    sqlx::query!("UPDATE confirmation_email_throttle SET sent_in_window = 1000")
+        .execute(&app.db_pool)
+        .await
+        .unwrap();
+    let variants = vec![
+        "name=le%20guin&email=Ursula_Le_Guin%40Gmail.com",
+        "name=le%20guin&email=URSULA_LE_GUIN%40GMAIL.COM",
+    ];
+
+    Mock::given(any())
+        .respond_with(ResponseTemplate::new(200))
+        .expect(0)
+        .mount(&app.email_server)
+        .await;
+
+    for body in variants {
+        app.end_confirmation_email_cooldown().await;
+
+        // Act
+        let response = app.post_subscriptions(body.into()).await;
+        app.dispatch_all_pending_emails().await;
+
+        // Assert
+        assert_eq!(200, response.status().as_u16());
+    }
+    let subscribers = sqlx::query!("SELECT email FROM subscriptions")
+        .fetch_all(&app.db_pool)
+        .await
+        .unwrap();
+    assert_eq!(subscribers.len(), 1);
+    let throttled = sqlx::query!("SELECT recipient FROM confirmation_email_throttle")
+        .fetch_all(&app.db_pool)
+        .await
+        .unwrap();
+    assert_eq!(throttled.len(), 1);
+    // relies on Mock::expect
+}
+
+#[actix_rt::test]
+async fn the_throttle_is_per_recipient() {
+    // Arrange
+    let app = spawn_app().await;
+    app.create_unconfirmed_subscriber().await;
+    let body = "name=Ursula&email=another_ursula%40gmail.com";
+
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+
+    // Act
+    let response = app.post_subscriptions(body.into()).await;
+    app.dispatch_all_pending_emails().await;
+
+    // Assert
+    assert_eq!(200, response.status().as_u16());
+}
@@ -1,6 +1,25 @@
-use crate::helpers::spawn_app;
-use wiremock::matchers::{method, path};
+use crate::helpers::{spawn_app, TestApp};
+use chrono::Utc;
+use wiremock::matchers::{any, method, path};
 use wiremock::{Mock, ResponseTemplate};
+use zero2prod::subscriber_repository::hash_subscription_token;
+use zero2prod::token_cleanup::delete_stale_tokens;
+
+/// Backdate every subscription token past the configured TTL
+async fn expire_all_tokens(app: &TestApp) {
+    sqlx::query!("UPDATE subscription_tokens SET created_at = now() - interval '1 year'")
+        .execute(&app.db_pool)
+        .await
+        .expect("Failed to backdate subscription tokens");
+}
+
+async fn subscriber_status(app: &TestApp) -> String {
+    sqlx::query!("SELECT status FROM list_subscriptions")
+        .fetch_one(&app.db_pool)
+        .await
+        .expect("Failed to fetch saved subscription")
+        .status
+}
 
 #[actix_rt::test]
 async fn confirmations_without_token_are_rejected_with_a_400() {
@@ -16,6 +35,23 @@ async fn confirmations_without_token_are_rejected_with_a_400() {
     assert_eq!(response.status().as_u16(), 400);
 }
 
+#[actix_rt::test]
+async fn confirmations_with_an_unknown_token_are_rejected_with_a_401() {
+    // Arrange
+    let app = spawn_app().await;
+
+    // Act
+    let response = reqwest::get(&format!(
+        "{}/subscriptions/confirm?subscription_token=notarealtoken",
+        app.address
+    ))
+    .await
+    .unwrap();
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 401);
+}
+
 #[actix_rt::test]
 async fn the_link_returned_by_subscribe_returns_a_200_if_called() {
     // Arrange
@@ -29,8 +65,9 @@ async fn the_link_returned_by_subscribe_returns_a_200_if_called() {
         .await;
 
     app.post_subscriptions(body.into()).await;
+    app.dispatch_all_pending_emails().await;
     let email_request = &app.email_server.received_requests().await.unwrap()[0];
-    let confirmation_links = app.get_confirmation_links(&email_request);
+    let confirmation_links = app.get_confirmation_links(email_request);
 
     // Act
     let response = reqwest::get(confirmation_links.html)
@@ -56,8 +93,9 @@ async fn clicking_on_the_confirmation_link_confirms_a_subscriber() {
         .await;
 
     app.post_subscriptions(body.into()).await;
+    app.dispatch_all_pending_emails().await;
     let email_request = &app.email_server.received_requests().await.unwrap()[0];
-    let confirmation_links = app.get_confirmation_links(&email_request);
+    let confirmation_links = app.get_confirmation_links(email_request);
 
     // Act
     reqwest::get(confirmation_links.html)
@@ -67,12 +105,297 @@ async fn clicking_on_the_confirmation_link_confirms_a_subscriber() {
         .unwrap();
 
     // Assert
-    let saved = sqlx::query!("SELECT email, name, status FROM subscriptions")
-        .fetch_one(&app.db_pool)
-        .await
-        .expect("Failed to fetch saved subscriptions");
+    let saved = sqlx::query!(
+        r#"
+            SELECT s.email AS "email!", s.name AS "name!", m.status AS "status!"
+            FROM subscriptions s
+            JOIN list_subscriptions m ON m.subscriber_id = s.id
+        "#
+    )
+    .fetch_one(&app.db_pool)
+    .await
+    .expect("Failed to fetch saved subscriptions");
 
     assert_eq!(saved.email, "ursula_le_guin@gmail.com");
     assert_eq!(saved.name, "le guin");
     assert_eq!(saved.status, "confirmed");
 }
+
+#[actix_rt::test]
+async fn confirming_records_the_subscribers_consent() {
+    // Arrange
+    let app = spawn_app().await;
+    let confirmation_links = app.create_unconfirmed_subscriber().await;
+
+    // Act
+    reqwest::get(confirmation_links.html)
+        .await
+        .unwrap()
+        .error_for_status()
+        .unwrap();
+
+    // Assert
+    let event = sqlx::query!(
+        "SELECT subscriber_id, event_type FROM consent_events WHERE event_type = 'confirmation'"
+    )
+    .fetch_one(&app.db_pool)
+    .await
+    .expect("Failed to fetch consent event");
+    let subscriber = sqlx::query!("SELECT id FROM subscriptions")
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap();
+    assert_eq!(event.subscriber_id, subscriber.id);
+    assert_eq!(event.event_type, "confirmation");
+}
+
+#[actix_rt::test]
+async fn clicking_the_confirmation_link_again_is_a_no_op() {
+    // Arrange
+    let app = spawn_app().await;
+    let confirmation_links = app.create_unconfirmed_subscriber().await;
+    reqwest::get(confirmation_links.html.clone())
+        .await
+        .unwrap()
+        .error_for_status()
+        .unwrap();
+
+    // Act
+    let response = reqwest::get(confirmation_links.html).await.unwrap();
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 200);
+    assert_eq!(subscriber_status(&app).await, "confirmed");
+    let n_events = sqlx::query!(
+        r#"SELECT COUNT(*) AS "count!" FROM consent_events WHERE event_type = 'confirmation'"#
+    )
+    .fetch_one(&app.db_pool)
+    .await
+    .unwrap()
+    .count;
+    assert_eq!(n_events, 1);
+}
+
+#[actix_rt::test]
+async fn concurrent_clicks_confirm_the_subscriber_once() {
+    // Arrange
+    let app = spawn_app().await;
+    let confirmation_links = app.create_unconfirmed_subscriber().await;
+
+    // Act
+    let first = reqwest::get(confirmation_links.html.clone());
+    let second = reqwest::get(confirmation_links.html);
+    let (first, second) = tokio::join!(first, second);
+
+    // Assert
+    assert_eq!(first.unwrap().status().as_u16(), 200);
+    assert_eq!(second.unwrap().status().as_u16(), 200);
+    let n_events = sqlx::query!(
+        r#"SELECT COUNT(*) AS "count!" FROM consent_events WHERE event_type = 'confirmation'"#
+    )
+    .fetch_one(&app.db_pool)
+    .await
+    .unwrap()
+    .count;
+    assert_eq!(n_events, 1);
+}
+
+#[actix_rt::test]
+async fn expired_confirmation_links_offer_to_resend_the_email() {
+    // Arrange
+    let app = spawn_app().await;
+    let confirmation_links = app.create_unconfirmed_subscriber().await;
+    expire_all_tokens(&app).await;
+
+    // Act
+    let response = reqwest::get(confirmation_links.html.clone()).await.unwrap();
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 410);
+    let html = response.text().await.unwrap();
+    assert!(html.contains(r#"action="/subscriptions/confirm/resend""#));
+    assert!(html.contains(&confirmation_links.subscription_token()));
+    assert_eq!(subscriber_status(&app).await, "pending_confirmation");
+}
+
+#[actix_rt::test]
+async fn resending_the_confirmation_email_sends_a_new_working_link() {
+    // Arrange
+    let app = spawn_app().await;
+    let old_links = app.create_unconfirmed_subscriber().await;
+    expire_all_tokens(&app).await;
+    app.end_confirmation_email_cooldown().await;
+
+    Mock::given(path("/email"))
+        .and(method("POST"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+
+    // Act - Part 1 - Ask for a new link
+    let response = app
+        .post_resend_confirmation(&old_links.subscription_token())
+        .await;
+    assert_eq!(response.status().as_u16(), 200);
+    app.dispatch_all_pending_emails().await;
+
+    // Act - Part 2 - Follow the new link
+    let email_request = &app.email_server.received_requests().await.unwrap()[0];
+    let new_links = app.get_confirmation_links(email_request);
+    let old_link_response = reqwest::get(old_links.html).await.unwrap();
+    let response = reqwest::get(new_links.html).await.unwrap();
+
+    // Assert
+    assert_eq!(old_link_response.status().as_u16(), 401);
+    assert_eq!(response.status().as_u16(), 200);
+    assert_eq!(subscriber_status(&app).await, "confirmed");
+}
+
+#[actix_rt::test]
+async fn resending_within_the_cooldown_keeps_the_old_link_working() {
+    // Arrange
+    let app = spawn_app().await;
+    let old_links = app.create_unconfirmed_subscriber().await;
+
+    Mock::given(any())
+        .respond_with(ResponseTemplate::new(200))
+        .expect(0)
+        .mount(&app.email_server)
+        .await;
+
+    // Act
+    let response = app
+        .post_resend_confirmation(&old_links.subscription_token())
+        .await;
+    app.dispatch_all_pending_emails().await;
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 200);
+    let response = reqwest::get(old_links.html).await.unwrap();
+    assert_eq!(response.status().as_u16(), 200);
+    assert_eq!(subscriber_status(&app).await, "confirmed");
+}
+
+#[actix_rt::test]
+async fn resending_with_an_unknown_token_is_rejected_with_a_401() {
+    // Arrange
+    let app = spawn_app().await;
+
+    // Act
+    let response = app.post_resend_confirmation("notarealtoken").await;
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 401);
+}
+
+#[actix_rt::test]
+async fn the_cleanup_job_deletes_long_consumed_and_long_expired_tokens() {
+    // Arrange
+    let app = spawn_app().await;
+    let confirmation_links = app.create_unconfirmed_subscriber().await;
+    let membership = sqlx::query!("SELECT subscriber_id, list_id FROM list_subscriptions")
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap();
+    sqlx::query!(
+        r#"
+        INSERT INTO subscription_tokens (subscription_token_hash, subscriber_id, list_id, created_at, consumed_at)
+        VALUES
+            ('recentlyconsumedtoken', $1, $2, $3, $3),
+            ('longconsumedtoken', $1, $2, $3 - interval '1 year', $3 - interval '1 year'),
+            ('longexpiredtoken', $1, $2, $3 - interval '1 year', NULL)
+        "#,
+        membership.subscriber_id,
+        membership.list_id,
+        Utc::now()
+    )
+    .execute(&app.db_pool)
+    .await
+    .unwrap();
+
+    // Act
+    let n_deleted = delete_stale_tokens(&app.db_pool, chrono::Duration::hours(48))
+        .await
+        .unwrap();
+
+    // Assert
+    assert_eq!(n_deleted, 2);
+    let remaining: Vec<_> =
+        sqlx::query!("SELECT subscription_token_hash FROM subscription_tokens ORDER BY created_at")
+            .fetch_all(&app.db_pool)
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|r| r.subscription_token_hash)
+            .collect();
+    assert_eq!(remaining.len(), 2);
+    assert!(remaining.contains(&hash_subscription_token(
+        &confirmation_links.subscription_token()
+    )));
+    assert!(remaining.contains(&b"recentlyconsumedtoken".to_vec()));
+}
+
+#[actix_rt::test]
+async fn clicking_the_confirmation_link_again_after_the_cleanup_job_is_a_no_op() {
+    // Arrange
+    let app = spawn_app().await;
+    let confirmation_links = app.create_unconfirmed_subscriber().await;
+    reqwest::get(confirmation_links.html.clone())
+        .await
+        .unwrap()
+        .error_for_status()
+        .unwrap();
+    delete_stale_tokens(&app.db_pool, chrono::Duration::hours(48))
+        .await
+        .unwrap();
+
+    // Act
+    let response = reqwest::get(confirmation_links.html).await.unwrap();
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 200);
+    assert_eq!(subscriber_status(&app).await, "confirmed");
+}
+
+#[actix_rt::test]
+async fn only_a_hash_of_the_subscription_token_is_stored() {
+    // Arrange
+    let app = spawn_app().await;
+
+    // Act
+    let confirmation_links = app.create_unconfirmed_subscriber().await;
+
+    // Assert
+    let stored = sqlx::query!("SELECT subscription_token_hash FROM subscription_tokens")
+        .fetch_one(&app.db_pool)
+        .await
+        .unwrap();
+    let subscription_token = confirmation_links.subscription_token();
+    assert_ne!(
+        stored.subscription_token_hash,
+        subscription_token.as_bytes()
+    );
+    assert_eq!(
+        stored.subscription_token_hash,
+        hash_subscription_token(&subscription_token)
+    );
+}
+
+#[actix_rt::test]
+async fn confirmation_links_cannot_confirm_a_bounced_subscriber() {
+    // Arrange
+    let app = spawn_app().await;
+    let confirmation_links = app.create_unconfirmed_subscriber().await;
+    sqlx::query!("UPDATE list_subscriptions SET status = 'bounced'")
+        .execute(&app.db_pool)
+        .await
+        .unwrap();
+
+    // Act
+    let response = reqwest::get(confirmation_links.html).await.unwrap();
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 409);
+    assert_eq!(subscriber_status(&app).await, "bounced");
+}
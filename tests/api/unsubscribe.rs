@@ -0,0 +1,186 @@
+use crate::helpers::{spawn_app, PostmarkBatchResponder, TestApp};
+use wiremock::matchers::{any, method, path};
+use wiremock::{Mock, ResponseTemplate};
+
+fn newsletter_request_body() -> serde_json::Value {
+    serde_json::json!({
+        "title": "Newsletter title",
+        "content": {
+            "text": "Newsletter body as plain text",
+            "html": "<p>Newsletter body as HTML</p>",
+        }
+    })
+}
+
+/// Publish an issue to a new confirmed subscriber and return the unsubscribe link sent to them
+async fn receive_unsubscribe_link(app: &TestApp) -> reqwest::Url {
+    app.create_confirmed_subscriber().await;
+
+    Mock::given(path("/email/batch"))
+        .and(method("POST"))
+        .respond_with(PostmarkBatchResponder::accept_all())
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+
+    app.post_newsletters(newsletter_request_body()).await;
+    app.dispatch_all_pending_emails().await;
+
+    let email_request = &app.email_server.received_requests().await.unwrap()[0];
+    let unsubscribe_link = app.get_unsubscribe_link(email_request);
+
+    app.email_server.verify().await;
+    app.email_server.reset().await;
+
+    unsubscribe_link
+}
+
+async fn subscriber_status(app: &TestApp) -> String {
+    sqlx::query!("SELECT status FROM list_subscriptions")
+        .fetch_one(&app.db_pool)
+        .await
+        .expect("Failed to fetch saved subscription")
+        .status
+}
+
+#[actix_rt::test]
+async fn newsletter_emails_include_an_unsubscribe_link_and_one_click_headers() {
+    // Arrange
+    let app = spawn_app().await;
+    app.create_confirmed_subscriber().await;
+
+    Mock::given(path("/email/batch"))
+        .and(method("POST"))
+        .respond_with(PostmarkBatchResponder::accept_all())
+        .expect(1)
+        .mount(&app.email_server)
+        .await;
+
+    // Act
+    app.post_newsletters(newsletter_request_body()).await;
+    app.dispatch_all_pending_emails().await;
+
+    // Assert
+    let email_request = &app.email_server.received_requests().await.unwrap()[0];
+    let body: serde_json::Value = serde_json::from_slice(&email_request.body).unwrap();
+    let email = &body[0];
+    let headers = email["Headers"].as_array().unwrap();
+    assert!(headers.contains(&serde_json::json!({
+        "Name": "List-Unsubscribe-Post",
+        "Value": "List-Unsubscribe=One-Click",
+    })));
+
+    let unsubscribe_link = app.get_unsubscribe_link(email_request);
+    assert_eq!(unsubscribe_link.path(), "/subscriptions/unsubscribe");
+    let token = unsubscribe_link.query().unwrap();
+    assert!(email["HtmlBody"].as_str().unwrap().contains(token));
+    assert!(email["TextBody"].as_str().unwrap().contains(token));
+}
+
+#[actix_rt::test]
+async fn the_unsubscribe_link_shows_a_confirmation_form() {
+    // Arrange
+    let app = spawn_app().await;
+    let unsubscribe_link = receive_unsubscribe_link(&app).await;
+
+    // Act
+    let response = reqwest::get(unsubscribe_link).await.unwrap();
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 200);
+    let html = response.text().await.unwrap();
+    assert!(html.contains(r#"method="post""#));
+    assert_eq!(subscriber_status(&app).await, "confirmed");
+}
+
+#[actix_rt::test]
+async fn a_one_click_post_unsubscribes_the_subscriber() {
+    // Arrange
+    let app = spawn_app().await;
+    let unsubscribe_link = receive_unsubscribe_link(&app).await;
+
+    // Act
+    let response = reqwest::Client::new()
+        .post(unsubscribe_link)
+        .header("Content-Type", "application/x-www-form-urlencoded")
+        .body("List-Unsubscribe=One-Click")
+        .send()
+        .await
+        .unwrap();
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 200);
+    assert_eq!(subscriber_status(&app).await, "unsubscribed");
+}
+
+#[actix_rt::test]
+async fn unsubscribing_twice_succeeds() {
+    // Arrange
+    let app = spawn_app().await;
+    let unsubscribe_link = receive_unsubscribe_link(&app).await;
+    let client = reqwest::Client::new();
+
+    // Act
+    let first = client.post(unsubscribe_link.clone()).send().await.unwrap();
+    let second = client.post(unsubscribe_link).send().await.unwrap();
+
+    // Assert
+    assert_eq!(first.status().as_u16(), 200);
+    assert_eq!(second.status().as_u16(), 200);
+    assert_eq!(subscriber_status(&app).await, "unsubscribed");
+}
+
+#[actix_rt::test]
+async fn unsubscribed_subscribers_do_not_receive_newsletters() {
+    // Arrange
+    let app = spawn_app().await;
+    let unsubscribe_link = receive_unsubscribe_link(&app).await;
+    reqwest::Client::new()
+        .post(unsubscribe_link)
+        .send()
+        .await
+        .unwrap()
+        .error_for_status()
+        .unwrap();
+
+    Mock::given(any())
+        .respond_with(ResponseTemplate::new(200))
+        .expect(0)
+        .mount(&app.email_server)
+        .await;
+
+    // Act
+    let response = app.post_newsletters(newsletter_request_body()).await;
+    app.dispatch_all_pending_emails().await;
+
+    // Assert
+    assert_eq!(response.status().as_u16(), 202);
+    // relies on Mock::expect
+}
+
+#[actix_rt::test]
+async fn tampered_unsubscribe_links_are_rejected_with_a_400() {
+    // Arrange
+    let app = spawn_app().await;
+    let mut unsubscribe_link = receive_unsubscribe_link(&app).await;
+    let token = unsubscribe_link
+        .query()
+        .unwrap()
+        .trim_start_matches("token=");
+    let (_, signature) = token.split_at(token.find('.').unwrap());
+    let forged_token = format!("{}{}", uuid::Uuid::new_v4(), signature);
+    unsubscribe_link.set_query(Some(&format!("token={}", forged_token)));
+
+    // Act
+    let get_response = reqwest::get(unsubscribe_link.clone()).await.unwrap();
+    let post_response = reqwest::Client::new()
+        .post(unsubscribe_link)
+        .send()
+        .await
+        .unwrap();
+
+    // Assert
+    assert_eq!(get_response.status().as_u16(), 400);
+    assert_eq!(post_response.status().as_u16(), 400);
+    assert_eq!(subscriber_status(&app).await, "confirmed");
+}